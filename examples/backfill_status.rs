@@ -0,0 +1,24 @@
+use aide_de_camp_mongodb::MongoDbQueue;
+
+/// Gives jobs written by versions before the `status` field their status. Safe to run while
+/// workers are up, and to run again if interrupted.
+///
+/// Usage: `cargo run --example backfill_status -- mongodb://localhost:27017/queues`
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let uri = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "mongodb://localhost:27017/queues".to_string());
+    let queue = MongoDbQueue::new(&uri, None).await?;
+
+    let updated = queue
+        .backfill_status(1000, |progress| {
+            println!(
+                "{}: {} updated, {} remaining",
+                progress.collection, progress.updated, progress.remaining
+            );
+        })
+        .await?;
+    println!("Done, {} jobs updated", updated);
+    Ok(())
+}
@@ -0,0 +1,79 @@
+use std::collections::BTreeMap;
+
+use aide_de_camp::core::{queue::QueueError, Xid};
+use anyhow::Context;
+use bson::{doc, Document};
+use mongodb::options::FindOneOptions;
+use tracing::instrument;
+
+use crate::{hooks::ErrorContext, trace::traced, MongoDbQueue};
+
+/// Collections a job can be in while it can still be looked at.
+const JOB_COLLECTIONS: [&str; 3] = ["adc_queue", "adc_cold_queue", "adc_dead_queue"];
+
+impl MongoDbQueue {
+    /// Attach an operator note to a job, e.g. `"waiting on vendor fix, do not requeue"`,
+    /// replacing an earlier note with the same `key`. Works on jobs in the queue, in cold
+    /// storage and in the dead queue, and notes move with the job to the dead queue.
+    #[instrument(skip_all, err, fields(jid = %job_id, key = key))]
+    pub async fn annotate(&self, job_id: Xid, key: &str, value: &str) -> Result<(), QueueError> {
+        let jid = format!("{}", job_id);
+        let result = async {
+            let field = format!("annotations.{}", key);
+            for name in JOB_COLLECTIONS {
+                let collection = self.database.collection::<Document>(name);
+                let result = traced(
+                    &collection,
+                    "update_one",
+                    collection.update_one(
+                        self.scoped(doc! { "jid": &jid }),
+                        doc! { "$set": { &field: value } },
+                        None,
+                    ),
+                )
+                .await
+                .context("Failed to annotate job")?;
+                if result.matched_count > 0 {
+                    return Ok(());
+                }
+            }
+            Err(QueueError::JobNotFound(job_id))
+        }
+        .await;
+        let context = ErrorContext {
+            operation: "annotate",
+            jid: Some(&jid),
+            job_type: None,
+            correlation_id: None,
+        };
+        self.reported(&context, result)
+    }
+
+    /// The notes attached to a job with [`Self::annotate`], by key.
+    #[instrument(skip_all, err, fields(jid = %job_id))]
+    pub async fn annotations(&self, job_id: Xid) -> Result<BTreeMap<String, String>, QueueError> {
+        let jid = format!("{}", job_id);
+        let options = FindOneOptions::builder()
+            .projection(doc! { "annotations": 1 })
+            .build();
+        for name in JOB_COLLECTIONS {
+            let collection = self.database.collection::<Document>(name);
+            let row = traced(
+                &collection,
+                "find_one",
+                collection.find_one(self.scoped(doc! { "jid": &jid }), options.clone()),
+            )
+            .await
+            .context("Failed to find job")?;
+            if let Some(row) = row {
+                let annotations = match row.get_document("annotations") {
+                    Ok(annotations) => bson::from_document(annotations.clone())
+                        .context("Failed to read annotations")?,
+                    Err(_) => BTreeMap::new(),
+                };
+                return Ok(annotations);
+            }
+        }
+        Err(QueueError::JobNotFound(job_id))
+    }
+}
@@ -0,0 +1,325 @@
+use std::collections::BTreeMap;
+
+use aide_de_camp::core::{new_xid, queue::QueueError, DateTime, Duration, Xid};
+use anyhow::Context;
+use async_trait::async_trait;
+use bson::{doc, spec::BinarySubtype, Binary, Document};
+use chrono::Utc;
+use futures::TryStreamExt;
+use mongodb::{
+    options::{CreateCollectionOptions, FindOptions, IndexOptions},
+    Collection, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::{
+    timeseries::is_namespace_exists,
+    trace::traced,
+    types::{ArchivedJobRow, JobRow},
+    MongoDbQueue,
+};
+
+/// Collection completed jobs are archived in.
+pub const ARCHIVE_COLLECTION: &str = "adc_archive";
+
+/// zstd level for archived blobs, the library default. Higher levels save little on payloads
+/// this size and cost noticeably more CPU on the completing worker.
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// How long [`MongoDbQueue::ship_archive`] has to write the jobs it claimed before another
+/// call can claim them again.
+pub const SHIP_LEASE: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+
+/// A completed job read back from the archive, see [`MongoDbQueue::archived_job`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchivedJob {
+    pub jid: String,
+    pub public_id: Option<String>,
+    pub queue: String,
+    pub job_type: String,
+    /// The encoded payload, as it was scheduled.
+    pub payload: Vec<u8>,
+    pub retries: u32,
+    pub attempts: u32,
+    pub enqueued_at: DateTime,
+    pub started_at: Option<DateTime>,
+    pub completed_at: DateTime,
+    pub tenant: Option<String>,
+    pub correlation_id: Option<String>,
+    pub tags: Vec<String>,
+    /// Notes attached while the job ran, see [`MongoDbQueue::annotate`].
+    pub annotations: BTreeMap<String, String>,
+    /// See [`ScheduleOptions::headers`](crate::ScheduleOptions::headers).
+    pub headers: BTreeMap<String, String>,
+    /// Media type of `payload`, see [`ScheduleOptions::content_type`](crate::ScheduleOptions::content_type).
+    pub content_type: Option<String>,
+}
+
+/// Where [`MongoDbQueue::ship_archive`] moves archived jobs to, e.g. `S3ArchiveSink` with the
+/// `s3` feature. Set one up with [`MaintenanceRunner::with_archive_sink`] to ship the archive
+/// continuously.
+///
+/// [`MaintenanceRunner::with_archive_sink`]: crate::MaintenanceRunner::with_archive_sink
+#[async_trait]
+pub trait ArchiveSink: Send + Sync {
+    /// Store `jobs`, oldest first. The jobs are removed from the archive collection once this
+    /// returns `Ok`.
+    async fn write(&self, jobs: &[ArchivedJob]) -> Result<(), QueueError>;
+}
+
+/// The bulky part of an archived job, stored zstd-compressed.
+#[derive(Serialize, Deserialize)]
+struct ArchivedBody {
+    payload: Binary,
+    annotations: BTreeMap<String, String>,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    #[serde(default)]
+    content_type: Option<String>,
+}
+
+impl MongoDbQueue {
+    /// Copy completed jobs to the `adc_archive` collection, with the payload, annotations and
+    /// headers in a zstd-compressed blob. Create the collection with [`Self::create_archive`] first.
+    /// Failing to archive a job is handed to the [`ErrorReporter`](crate::ErrorReporter)
+    /// without failing the job.
+    pub fn with_archive(mut self) -> Self {
+        self.archive = true;
+        self
+    }
+
+    /// Create the collection [`Self::with_archive`] writes to, with zstd block compression
+    /// instead of the server default, dropping jobs completed more than `retention` ago.
+    #[instrument(skip_all, err)]
+    pub async fn create_archive(&self, retention: Duration) -> Result<(), QueueError> {
+        let options = CreateCollectionOptions::builder()
+            .storage_engine(doc! { "wiredTiger": { "configString": "block_compressor=zstd" } })
+            .build();
+        match self
+            .database
+            .create_collection(ARCHIVE_COLLECTION, options)
+            .await
+        {
+            Ok(()) => {}
+            Err(error) if is_namespace_exists(&error) => {}
+            Err(error) => {
+                return Err(anyhow::Error::new(error)
+                    .context("Failed to create archive")
+                    .into())
+            }
+        }
+
+        let indexes = [
+            IndexModel::builder().keys(doc! { "jid": 1 }).build(),
+            IndexModel::builder()
+                .keys(doc! { "completed_at": 1 })
+                .options(
+                    IndexOptions::builder()
+                        .expire_after(retention.to_std().unwrap_or_default())
+                        .build(),
+                )
+                .build(),
+        ];
+        let collection = self.archive_collection();
+        traced(
+            &collection,
+            "create_indexes",
+            collection.create_indexes(indexes, None),
+        )
+        .await
+        .context("Failed to create archive indexes")?;
+        Ok(())
+    }
+
+    /// A completed job from the archive.
+    #[instrument(skip_all, err, fields(jid = %job_id))]
+    pub async fn archived_job(&self, job_id: Xid) -> Result<Option<ArchivedJob>, QueueError> {
+        let collection = self.archive_collection();
+        let row = traced(
+            &collection,
+            "find_one",
+            collection.find_one(self.scoped(doc! { "jid": job_id.to_string() }), None),
+        )
+        .await
+        .context("Failed to read archived job")?;
+        row.map(ArchivedJobRow::unpack).transpose()
+    }
+
+    /// Move up to `limit` archived jobs, oldest first, from the `adc_archive` collection to
+    /// `sink`, e.g. object storage for retention beyond what the database should hold.
+    /// Returns how many were moved.
+    ///
+    /// The jobs are claimed for [`SHIP_LEASE`] first, so concurrent calls move different jobs.
+    /// If the sink fails, they are written again once the lease has passed. A process that
+    /// dies between writing and removing them can leave the sink with a batch twice.
+    #[instrument(skip_all, err, ret, fields(limit = limit))]
+    pub async fn ship_archive(
+        &self,
+        sink: &dyn ArchiveSink,
+        limit: usize,
+    ) -> Result<u64, QueueError> {
+        let collection = self.archive_collection();
+        let now = Utc::now();
+        let lease = Duration::from_std(SHIP_LEASE).unwrap_or_default();
+        let claimable = self.scoped(doc! { "$or": [
+            { "shipping_until": { "$exists": false } },
+            { "shipping_until": { "$lt": bson::DateTime::from_chrono(now) } },
+        ] });
+        let options = FindOptions::builder()
+            .sort(doc! { "completed_at": 1 })
+            .limit(limit as i64)
+            .projection(doc! { "jid": 1 })
+            .build();
+        let candidates: Vec<Document> = traced(
+            &collection,
+            "find",
+            collection
+                .clone_with_type::<Document>()
+                .find(claimable.clone(), options),
+        )
+        .await
+        .context("Failed to read archived jobs")?
+        .try_collect()
+        .await
+        .context("Failed to read archived jobs")?;
+        let jids: Vec<&str> = candidates
+            .iter()
+            .filter_map(|row| row.get_str("jid").ok())
+            .collect();
+        if jids.is_empty() {
+            return Ok(0);
+        }
+
+        let token = new_xid().to_string();
+        let mut claim = claimable;
+        claim.insert("jid", doc! { "$in": jids });
+        traced(
+            &collection,
+            "update_many",
+            collection.update_many(
+                claim,
+                doc! { "$set": {
+                    "shipping": &token,
+                    "shipping_until": bson::DateTime::from_chrono(now + lease),
+                } },
+                None,
+            ),
+        )
+        .await
+        .context("Failed to claim archived jobs")?;
+        let claimed = doc! { "shipping": &token };
+        let jobs = traced(
+            &collection,
+            "find",
+            collection.find(
+                claimed.clone(),
+                FindOptions::builder()
+                    .sort(doc! { "completed_at": 1 })
+                    .build(),
+            ),
+        )
+        .await
+        .context("Failed to read archived jobs")?
+        .try_collect::<Vec<_>>()
+        .await
+        .context("Failed to read archived jobs")?
+        .into_iter()
+        .map(ArchivedJobRow::unpack)
+        .collect::<Result<Vec<_>, _>>()?;
+        if jobs.is_empty() {
+            return Ok(0);
+        }
+
+        sink.write(&jobs).await?;
+        let removed = traced(
+            &collection,
+            "delete_many",
+            collection.delete_many(claimed, None),
+        )
+        .await
+        .context("Failed to remove shipped archived jobs")?;
+        Ok(removed.deleted_count)
+    }
+
+    /// Add completed jobs to the archive, if it is enabled.
+    pub(crate) async fn archive_completed(&self, rows: &[&JobRow]) -> Result<(), QueueError> {
+        if !self.archive || rows.is_empty() {
+            return Ok(());
+        }
+        let completed_at = bson::DateTime::now();
+        let archived = rows
+            .iter()
+            .map(|row| {
+                let body = bson::to_vec(&ArchivedBody {
+                    payload: row.payload.clone(),
+                    annotations: row.annotations.clone(),
+                    headers: row.headers.clone(),
+                    content_type: row.content_type.clone(),
+                })
+                .context("Failed to encode archived job")?;
+                let body = zstd::encode_all(body.as_slice(), COMPRESSION_LEVEL)
+                    .context("Failed to compress archived job")?;
+                Ok(ArchivedJobRow {
+                    jid: row.jid.clone(),
+                    public_id: row.public_id.clone(),
+                    queue: row.queue.clone(),
+                    job_type: row.job_type.clone(),
+                    body: Binary {
+                        subtype: BinarySubtype::Generic,
+                        bytes: body,
+                    },
+                    retries: row.retries,
+                    attempts: row.attempts,
+                    enqueued_at: row.enqueued_at,
+                    started_at: row.started_at,
+                    completed_at,
+                    tenant: row.tenant.clone(),
+                    correlation_id: row.correlation_id.clone(),
+                    tags: row.tags.clone(),
+                    namespace: row.namespace.clone(),
+                })
+            })
+            .collect::<Result<Vec<_>, QueueError>>()?;
+        let collection = self.archive_collection();
+        traced(
+            &collection,
+            "insert_many",
+            collection.insert_many(&archived, None),
+        )
+        .await
+        .context("Failed to archive completed jobs")?;
+        Ok(())
+    }
+
+    fn archive_collection(&self) -> Collection<ArchivedJobRow> {
+        self.database.collection(ARCHIVE_COLLECTION)
+    }
+}
+
+impl ArchivedJobRow {
+    pub(crate) fn unpack(self) -> Result<ArchivedJob, QueueError> {
+        let body = zstd::decode_all(self.body.bytes.as_slice())
+            .context("Failed to decompress archived job")?;
+        let body: ArchivedBody =
+            bson::from_slice(&body).context("Failed to decode archived job")?;
+        Ok(ArchivedJob {
+            jid: self.jid,
+            public_id: self.public_id,
+            queue: self.queue,
+            job_type: self.job_type,
+            payload: body.payload.bytes,
+            retries: self.retries as u32,
+            attempts: self.attempts as u32,
+            enqueued_at: self.enqueued_at.to_chrono(),
+            started_at: self.started_at.map(bson::DateTime::to_chrono),
+            completed_at: self.completed_at.to_chrono(),
+            tenant: self.tenant,
+            correlation_id: self.correlation_id,
+            tags: self.tags,
+            annotations: body.annotations,
+            headers: body.headers,
+            content_type: body.content_type,
+        })
+    }
+}
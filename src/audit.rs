@@ -0,0 +1,161 @@
+use aide_de_camp::core::{queue::QueueError, DateTime, Xid};
+use anyhow::Context;
+use bson::doc;
+use mongodb::{options::UpdateOptions, Collection};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::{
+    lease::worker_id,
+    status::JobStatus,
+    trace::traced,
+    types::{AuditAttemptRow, AuditRow, JobRow},
+    MongoDbQueue,
+};
+
+/// Collection the audit trail is kept in.
+pub const AUDIT_COLLECTION: &str = "adc_audit";
+
+/// How an attempt at a job ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AttemptOutcome {
+    Completed,
+    /// Put back to be retried.
+    Failed,
+    /// Failed with the [`RetryBudget`](crate::RetryBudget) spent, see [`JobStatus::Parked`].
+    Parked,
+    Dead,
+}
+
+/// One claim of a job and how it ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttemptRecord {
+    pub attempt: u32,
+    /// Host and process id of the worker that claimed the job.
+    pub claimed_by: String,
+    pub started_at: Option<DateTime>,
+    pub finished_at: DateTime,
+    pub outcome: AttemptOutcome,
+    /// The dead letter or park reason, if any.
+    pub error: Option<String>,
+}
+
+/// Everything that happened to a job, see [`MongoDbQueue::audit_trail`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditTrail {
+    pub jid: String,
+    pub job_type: String,
+    pub queue: String,
+    pub scheduled_at: DateTime,
+    /// See [`ScheduleOptions::correlation_id`](crate::ScheduleOptions::correlation_id).
+    pub correlation_id: Option<String>,
+    /// Outcome of the last attempt.
+    pub outcome: AttemptOutcome,
+    /// Oldest first.
+    pub attempts: Vec<AttemptRecord>,
+}
+
+impl MongoDbQueue {
+    /// Record every attempt at a job in one document per job in the `adc_audit` collection,
+    /// written when the attempt is completed, failed or dead-lettered, so
+    /// [`Self::audit_trail`] can tell what happened to a job with a single lookup. Failing to
+    /// record an attempt is handed to the [`ErrorReporter`](crate::ErrorReporter) without
+    /// failing the job.
+    pub fn with_audit_trail(mut self) -> Self {
+        self.audit_trail = true;
+        self
+    }
+
+    /// The recorded attempts at a job, see [`Self::with_audit_trail`].
+    #[instrument(skip_all, err, fields(jid = %job_id))]
+    pub async fn audit_trail(&self, job_id: Xid) -> Result<Option<AuditTrail>, QueueError> {
+        let collection = self.audit_collection();
+        let row = traced(
+            &collection,
+            "find_one",
+            collection.find_one(self.scoped(doc! { "_id": job_id.to_string() }), None),
+        )
+        .await
+        .context("Failed to read audit trail")?;
+        Ok(row.map(AuditRow::into_trail))
+    }
+
+    /// Add the attempts that just ended on `rows` to their audit trails, if it is enabled.
+    pub(crate) async fn record_attempts(&self, rows: &[&JobRow]) -> Result<(), QueueError> {
+        if !self.audit_trail {
+            return Ok(());
+        }
+        let collection = self.audit_collection();
+        let claimed_by = worker_id();
+        let finished_at = bson::DateTime::now();
+        for row in rows {
+            let outcome = match row.status {
+                JobStatus::Completed => AttemptOutcome::Completed,
+                JobStatus::Parked => AttemptOutcome::Parked,
+                JobStatus::Dead => AttemptOutcome::Dead,
+                _ => AttemptOutcome::Failed,
+            };
+            let attempt = AuditAttemptRow {
+                attempt: row.attempts,
+                claimed_by: claimed_by.clone(),
+                started_at: row.started_at,
+                finished_at,
+                outcome,
+                error: row.dead_reason.clone().or_else(|| row.parked.clone()),
+            };
+            let update = doc! {
+                "$setOnInsert": {
+                    "job_type": &row.job_type,
+                    "queue": &row.queue,
+                    "scheduled_at": row.scheduled_at,
+                    "namespace": row.namespace.as_deref(),
+                    "correlation_id": row.correlation_id.as_deref(),
+                },
+                "$set": { "outcome": bson::to_bson(&outcome).context("Failed to encode outcome")? },
+                "$push": { "attempts": bson::to_bson(&attempt).context("Failed to encode attempt")? },
+            };
+            traced(
+                &collection,
+                "update_one",
+                collection.update_one(
+                    doc! { "_id": &row.jid },
+                    update,
+                    UpdateOptions::builder().upsert(true).build(),
+                ),
+            )
+            .await
+            .context("Failed to record job attempt")?;
+        }
+        Ok(())
+    }
+
+    fn audit_collection(&self) -> Collection<AuditRow> {
+        self.database.collection(AUDIT_COLLECTION)
+    }
+}
+
+impl AuditRow {
+    fn into_trail(self) -> AuditTrail {
+        AuditTrail {
+            jid: self.jid,
+            job_type: self.job_type,
+            queue: self.queue,
+            scheduled_at: self.scheduled_at.to_chrono(),
+            correlation_id: self.correlation_id,
+            outcome: self.outcome,
+            attempts: self
+                .attempts
+                .into_iter()
+                .map(|attempt| AttemptRecord {
+                    attempt: attempt.attempt as u32,
+                    claimed_by: attempt.claimed_by,
+                    started_at: attempt.started_at.map(bson::DateTime::to_chrono),
+                    finished_at: attempt.finished_at.to_chrono(),
+                    outcome: attempt.outcome,
+                    error: attempt.error,
+                })
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use std::sync::Arc;
+
+use aide_de_camp::core::{job_processor::JobProcessor, DateTime, Duration};
+use chrono::Utc;
+
+use crate::MongoDbQueue;
+
+/// How long a failed job waits before it can be claimed again, see
+/// [`MongoDbQueue::with_retry_backoff`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryBackoff {
+    /// Wait after the first failure.
+    pub initial: Duration,
+    /// Factor the wait grows by with every further failure, 1 for a fixed wait.
+    pub multiplier: u32,
+    /// Longest wait.
+    pub max: Duration,
+}
+
+impl RetryBackoff {
+    /// Wait `delay` after every failure.
+    pub fn fixed(delay: Duration) -> Self {
+        Self {
+            initial: delay,
+            multiplier: 1,
+            max: delay,
+        }
+    }
+
+    /// Wait `initial` after the first failure and twice as long after each one after it, up
+    /// to `max`.
+    pub fn exponential(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            multiplier: 2,
+            max,
+        }
+    }
+
+    /// The wait after the `failures`th failure of a job, counting from 1.
+    pub fn delay(&self, failures: u32) -> Duration {
+        let factor = (self.multiplier as i32).checked_pow(failures.saturating_sub(1));
+        factor
+            .and_then(|factor| self.initial.checked_mul(factor))
+            .map_or(self.max, |delay| delay.min(self.max))
+    }
+}
+
+impl MongoDbQueue {
+    /// Put failed jobs of type `J` back with a delay instead of making them claimable right
+    /// away. Job types without a backoff are retried immediately.
+    pub fn with_retry_backoff<J>(mut self, backoff: RetryBackoff) -> Self
+    where
+        J: JobProcessor + 'static,
+    {
+        Arc::make_mut(&mut self.retry_backoffs).insert(J::name().to_string(), backoff);
+        self
+    }
+
+    /// When a job of `job_type` failing now would be retried after each of its next
+    /// `failures` failures, see [`Self::simulate_retry_schedule_at`].
+    pub fn simulate_retry_schedule(&self, job_type: &str, failures: u32) -> Vec<DateTime> {
+        self.simulate_retry_schedule_at(job_type, failures, Utc::now())
+    }
+
+    /// When a job of `job_type` that fails for the first time at `now` would be retried
+    /// after each of its first `failures` failures, given the [`RetryBackoff`] of the job
+    /// type. Assumes every retry fails as soon as it is claimed, and ignores any
+    /// [`RetryBudget`](crate::RetryBudget).
+    pub fn simulate_retry_schedule_at(
+        &self,
+        job_type: &str,
+        failures: u32,
+        now: DateTime,
+    ) -> Vec<DateTime> {
+        let mut failed_at = now;
+        (1..=failures)
+            .map(|failure| {
+                failed_at = self.retry_at(job_type, failure, failed_at);
+                failed_at
+            })
+            .collect()
+    }
+
+    /// When a job of `job_type` failing for the `failures`th time at `now` can be claimed again.
+    pub(crate) fn retry_at(&self, job_type: &str, failures: u32, now: DateTime) -> DateTime {
+        match self.retry_backoffs.get(job_type) {
+            Some(backoff) => now + backoff.delay(failures),
+            None => now,
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use aide_de_camp::core::{queue::QueueError, Duration};
+use anyhow::Context;
+use bson::{doc, Document};
+use chrono::Utc;
+use mongodb::options::FindOneOptions;
+use tracing::instrument;
+
+use crate::{status, trace::traced, MongoDbQueue};
+
+/// How unhealthy the backlog of a job type is, see [`MongoDbQueue::backpressure`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum BackpressureLevel {
+    /// The queue keeps up, enqueue as usual.
+    Normal,
+    /// The backlog is growing, consider delaying non-critical jobs.
+    Elevated,
+    /// The backlog is unhealthy, only enqueue what can't wait.
+    Critical,
+}
+
+/// Backlog size and age at which a job type reaches each [`BackpressureLevel`]. Whichever of
+/// depth and age is worse decides the level.
+#[derive(Debug, Clone, Copy)]
+pub struct BackpressureThresholds {
+    /// Number of jobs ready to run.
+    pub elevated_depth: u64,
+    pub critical_depth: u64,
+    /// How long the oldest job ready to run has been waiting.
+    pub elevated_age: Duration,
+    pub critical_age: Duration,
+}
+
+impl Default for BackpressureThresholds {
+    fn default() -> Self {
+        Self {
+            elevated_depth: 1_000,
+            critical_depth: 10_000,
+            elevated_age: Duration::minutes(5),
+            critical_age: Duration::minutes(30),
+        }
+    }
+}
+
+impl BackpressureThresholds {
+    fn level(&self, depth: u64, age: Duration) -> BackpressureLevel {
+        if depth >= self.critical_depth || age >= self.critical_age {
+            BackpressureLevel::Critical
+        } else if depth >= self.elevated_depth || age >= self.elevated_age {
+            BackpressureLevel::Elevated
+        } else {
+            BackpressureLevel::Normal
+        }
+    }
+}
+
+impl MongoDbQueue {
+    /// Current backpressure for `job_type`, computed from the number of jobs that are due but
+    /// not started and from how long the oldest of them has been waiting. Producers can use it
+    /// to shed or delay non-critical work instead of growing an unhealthy backlog.
+    #[instrument(skip_all, err, ret, fields(job_type = job_type))]
+    pub async fn backpressure(&self, job_type: &str) -> Result<BackpressureLevel, QueueError> {
+        let now = Utc::now();
+        let filter_doc = self.scoped(status::pending(doc! {
+            "queue": &self.queue_name,
+            "job_type": job_type,
+            "scheduled_at": { "$lte": bson::DateTime::from_millis(now.timestamp_millis()) },
+        }));
+
+        let collection = self.database.collection::<Document>("adc_queue");
+        let depth = traced(
+            &collection,
+            "count_documents",
+            collection.count_documents(filter_doc.clone(), None),
+        )
+        .await
+        .context("Failed to count ready jobs")?;
+
+        let options = FindOneOptions::builder()
+            .sort(doc! { "scheduled_at": 1 })
+            .projection(doc! { "scheduled_at": 1 })
+            .build();
+        let oldest = traced(
+            &collection,
+            "find_one",
+            collection.find_one(filter_doc, options),
+        )
+        .await
+        .context("Failed to find the oldest ready job")?;
+        let age = oldest
+            .and_then(|row| row.get_datetime("scheduled_at").ok().copied())
+            .map(|scheduled_at| now - scheduled_at.to_chrono())
+            .unwrap_or_else(Duration::zero);
+
+        Ok(self.backpressure_thresholds.level(depth, age))
+    }
+}
@@ -0,0 +1,375 @@
+use std::fmt;
+
+use aide_de_camp::core::{
+    job_handle::JobHandle,
+    queue::{Queue, QueueError},
+    Xid,
+};
+use anyhow::Context;
+use bson::{doc, Document};
+use futures::TryStreamExt;
+use tracing::instrument;
+
+use crate::{
+    events, hooks::ErrorContext, job_handle::MongoDbJobHandle, patch::Patch, status::JobStatus,
+    trace::traced, types::JobRow, MongoDbQueue,
+};
+
+/// Several claimed jobs handled together, for vectorized processors such as batched ML
+/// inference. Completions are collected and acknowledged in one round trip by
+/// [`Self::finalize`]; failures are passed on right away.
+pub struct MongoDbJobBatchHandle {
+    queue: MongoDbQueue,
+    pending: Vec<MongoDbJobHandle>,
+    completed: Vec<MongoDbJobHandle>,
+}
+
+/// What [`MongoDbJobBatchHandle::finalize`] did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BatchOutcome {
+    /// Jobs acknowledged as completed.
+    pub completed: u64,
+    /// Jobs neither completed nor failed, put back to be retried.
+    pub released: u64,
+}
+
+impl fmt::Debug for MongoDbJobBatchHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MongoDbJobBatchHandle")
+            .field("pending", &self.pending)
+            .field("completed", &self.completed)
+            .finish_non_exhaustive()
+    }
+}
+
+impl MongoDbJobBatchHandle {
+    /// Jobs not completed or failed yet.
+    pub fn jobs(&self) -> &[MongoDbJobHandle] {
+        &self.pending
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Mark a job of the batch as completed. It is acknowledged by [`Self::finalize`].
+    pub fn complete(&mut self, job_id: Xid) -> Result<(), QueueError> {
+        let job = self.take(job_id)?;
+        self.completed.push(job);
+        Ok(())
+    }
+
+    /// Fail a job of the batch, putting it back to be retried.
+    pub async fn fail(&mut self, job_id: Xid) -> Result<(), QueueError> {
+        self.take(job_id)?.fail().await
+    }
+
+    /// Move a job of the batch to the dead queue.
+    pub async fn dead_queue(&mut self, job_id: Xid) -> Result<(), QueueError> {
+        self.take(job_id)?.dead_queue().await
+    }
+
+    /// Acknowledge the completed jobs in bulk and release the ones left over, without counting
+    /// the claim as a failed attempt.
+    pub async fn finalize(self) -> Result<BatchOutcome, QueueError> {
+        let completed = self.queue.complete_many(self.completed).await?;
+        let released = self.pending.len() as u64;
+        for job in self.pending {
+            job.release().await?;
+        }
+        Ok(BatchOutcome {
+            completed,
+            released,
+        })
+    }
+
+    fn take(&mut self, job_id: Xid) -> Result<MongoDbJobHandle, QueueError> {
+        let position = self
+            .pending
+            .iter()
+            .position(|job| job.id() == job_id)
+            .ok_or(QueueError::JobNotFound(job_id))?;
+        Ok(self.pending.remove(position))
+    }
+}
+
+impl MongoDbQueue {
+    /// Claim up to `max` jobs of `job_types` as one batch. Returns `None` when nothing is due.
+    #[instrument(skip_all, err, fields(max = max))]
+    pub async fn poll_batch(
+        &self,
+        job_types: &[&str],
+        max: usize,
+    ) -> Result<Option<MongoDbJobBatchHandle>, QueueError> {
+        let mut pending = Vec::new();
+        while pending.len() < max {
+            match self.poll_next(job_types).await? {
+                Some(job) => pending.push(job),
+                None => break,
+            }
+        }
+        if pending.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(MongoDbJobBatchHandle {
+            queue: self.clone(),
+            pending,
+            completed: Vec::new(),
+        }))
+    }
+
+    /// Complete claimed jobs with a single delete, for processors that work through claimed
+    /// jobs in batches. A job whose claim was lost, e.g. because it timed out and another worker
+    /// claimed it again, is not deleted. Returns the number of jobs completed.
+    #[instrument(skip_all, err, ret, fields(jobs = handles.len()))]
+    pub async fn complete_many(&self, handles: Vec<MongoDbJobHandle>) -> Result<u64, QueueError> {
+        let result = self.delete_claimed(&handles).await;
+        let context = ErrorContext {
+            operation: "complete_many",
+            jid: None,
+            job_type: None,
+            correlation_id: None,
+        };
+        let completed = self.reported(&context, result)?;
+
+        let mut rows: Vec<JobRow> = handles
+            .into_iter()
+            .map(MongoDbJobHandle::into_row)
+            .filter(|row| completed.contains(&row.jid))
+            .collect();
+        let ended: Vec<&JobRow> = rows.iter().collect();
+        let ended = self.end_leases(&ended).await;
+        self.reported(&context, ended)?;
+        for row in &mut rows {
+            row.status = JobStatus::Completed;
+            events::completed(self, row);
+        }
+        // The jobs are done either way, missing usage is not worth failing them for.
+        let done: Vec<&JobRow> = rows.iter().collect();
+        if let Err(error) = self.record_usage_many(&done).await {
+            self.report_error(
+                &ErrorContext {
+                    operation: "record_usage",
+                    ..context
+                },
+                &error,
+            );
+        }
+        if let Err(error) = self.archive_completed(&done).await {
+            self.report_error(
+                &ErrorContext {
+                    operation: "archive",
+                    ..context
+                },
+                &error,
+            );
+        }
+        if let Err(error) = self.record_attempts(&done).await {
+            self.report_error(
+                &ErrorContext {
+                    operation: "audit_trail",
+                    ..context
+                },
+                &error,
+            );
+        }
+        Ok(rows.len() as u64)
+    }
+
+    /// Delete the jobs of `handles` that are still claimed by them, fenced on the attempt
+    /// number or lease, and release their dependents in the same transaction. Returns the ids
+    /// of the jobs deleted.
+    async fn delete_claimed(
+        &self,
+        handles: &[MongoDbJobHandle],
+    ) -> Result<Vec<String>, QueueError> {
+        let held = self.hold_claims(&rows_of(handles)).await?;
+        let handles: Vec<&MongoDbJobHandle> = handles
+            .iter()
+            .filter(|handle| held.contains(&handle.row().jid))
+            .collect();
+        if handles.is_empty() {
+            return Ok(Vec::new());
+        }
+        let claims: Vec<_> = handles
+            .iter()
+            .map(|handle| self.claim_fence(handle.row()))
+            .collect();
+        let collection = self.collection();
+        let mut session = collection
+            .client()
+            .start_session(None)
+            .await
+            .context("Failed to start session")?;
+        session
+            .start_transaction(None)
+            .await
+            .context("Failed to start transaction")?;
+        let result = traced(
+            &collection,
+            "delete_many",
+            collection.delete_many_with_session(doc! { "$or": claims }, None, &mut session),
+        )
+        .await
+        .context("Failed to mark jobs as completed")?;
+        let mut jids: Vec<String> = handles
+            .iter()
+            .map(|handle| handle.row().jid.clone())
+            .collect();
+        if result.deleted_count < handles.len() as u64 {
+            // Some claims were lost; the jobs still in the queue belong to someone else now.
+            let remaining: Vec<String> = traced(
+                &collection,
+                "find",
+                collection.find_with_session(doc! { "jid": { "$in": &jids } }, None, &mut session),
+            )
+            .await
+            .context("Failed to find jobs")?
+            .stream(&mut session)
+            .try_collect::<Vec<JobRow>>()
+            .await
+            .context("Failed to read jobs")?
+            .into_iter()
+            .map(|row| row.jid)
+            .collect();
+            jids.retain(|jid| !remaining.contains(jid));
+        }
+        let deleted: Vec<&str> = jids.iter().map(String::as_str).collect();
+        self.release_dependents(&deleted, &mut session).await?;
+        session
+            .commit_transaction()
+            .await
+            .context("Failed to commit transaction")?;
+        Ok(jids)
+    }
+
+    /// Move claimed jobs to the dead queue in one transaction, recording `reason` on each,
+    /// e.g. when a whole batch failed because a downstream system is down. Jobs that are no
+    /// longer running, e.g. because their claim was lost, are left alone. Returns the number of
+    /// jobs moved.
+    #[instrument(skip_all, err, ret, fields(jobs = handles.len(), reason = reason))]
+    pub async fn dead_letter_many(
+        &self,
+        handles: Vec<MongoDbJobHandle>,
+        reason: &str,
+    ) -> Result<u64, QueueError> {
+        let result = self.move_many_to_dead_queue(&handles, reason).await;
+        let context = ErrorContext {
+            operation: "dead_letter_many",
+            jid: None,
+            job_type: None,
+            correlation_id: None,
+        };
+        let mut moved = self.reported(&context, result)?;
+        // Dead rows don't keep when they were claimed, the handles do.
+        for row in &mut moved {
+            row.started_at = handles
+                .iter()
+                .find(|handle| handle.row().jid == row.jid)
+                .and_then(|handle| handle.row().started_at);
+        }
+        if let Err(error) = self
+            .record_attempts(&moved.iter().collect::<Vec<_>>())
+            .await
+        {
+            self.report_error(
+                &ErrorContext {
+                    operation: "audit_trail",
+                    ..context
+                },
+                &error,
+            );
+        }
+        for row in &moved {
+            events::dead(self, row);
+            self.error_reporter.report_dead_job(&ErrorContext {
+                operation: "dead_letter_many",
+                jid: Some(&row.jid),
+                job_type: Some(&row.job_type),
+                correlation_id: row.correlation_id.as_deref(),
+            });
+        }
+        Ok(moved.len() as u64)
+    }
+
+    async fn move_many_to_dead_queue(
+        &self,
+        handles: &[MongoDbJobHandle],
+        reason: &str,
+    ) -> Result<Vec<JobRow>, QueueError> {
+        let held = self.hold_claims(&rows_of(handles)).await?;
+        if held.is_empty() {
+            return Ok(Vec::new());
+        }
+        let collection = self.database.collection::<Document>("adc_queue");
+        let dead_collection = self.database.collection::<Document>("adc_dead_queue");
+        let jids: Vec<&str> = held.iter().map(String::as_str).collect();
+        let filter = doc! { "jid": { "$in": &jids }, "status": self.claimed_status() };
+
+        let mut session = collection
+            .client()
+            .start_session(None)
+            .await
+            .context("Failed to start session")?;
+        session
+            .start_transaction(None)
+            .await
+            .context("Failed to start transaction")?;
+
+        // The stored rows, not the handles' copies, carry annotations added since the claim.
+        let mut dead: Vec<Document> = traced(
+            &collection,
+            "find",
+            collection.find_with_session(filter.clone(), None, &mut session),
+        )
+        .await
+        .context("Failed to find jobs to dead-letter")?
+        .stream(&mut session)
+        .try_collect()
+        .await
+        .context("Failed to read jobs to dead-letter")?;
+        if dead.is_empty() {
+            return Ok(Vec::new());
+        }
+        let patch = Patch::dead_letter(Some(reason));
+        for row in &mut dead {
+            patch.apply(row);
+        }
+
+        traced(
+            &collection,
+            "delete_many",
+            collection.delete_many_with_session(filter, None, &mut session),
+        )
+        .await
+        .context("Failed to delete jobs from the queue")?;
+        traced(
+            &dead_collection,
+            "insert_many",
+            dead_collection.insert_many_with_session(&dead, None, &mut session),
+        )
+        .await
+        .context("Failed to mark jobs as dead")?;
+
+        session
+            .commit_transaction()
+            .await
+            .context("Failed to commit transaction")?;
+        let ended: Vec<&JobRow> = handles
+            .iter()
+            .map(MongoDbJobHandle::row)
+            .filter(|row| held.contains(&row.jid))
+            .collect();
+        self.end_leases(&ended).await?;
+        let dead = dead
+            .into_iter()
+            .map(bson::from_document)
+            .collect::<Result<_, _>>()
+            .context("Failed to read dead jobs")?;
+        Ok(dead)
+    }
+}
+
+fn rows_of(handles: &[MongoDbJobHandle]) -> Vec<&JobRow> {
+    handles.iter().map(MongoDbJobHandle::row).collect()
+}
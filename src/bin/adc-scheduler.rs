@@ -0,0 +1,81 @@
+//! Runs only the recurring job materializer and the maintenance tasks of a queue, for
+//! deployments that keep scheduling in one small process apart from the workers.
+//!
+//! Usage: `adc-scheduler mongodb://localhost:27017/queues`
+//!
+//! Configured through the environment:
+//!
+//! | variable                | default                 |                                      |
+//! |-------------------------|-------------------------|--------------------------------------|
+//! | `ADC_MONGODB_URI`       | -                       | used when no URI is given            |
+//! | `ADC_CERT_FILE`         | -                       | CA certificate, enables TLS          |
+//! | `ADC_QUEUE`             | `default`               | see `MongoDbQueue::with_queue_name`  |
+//! | `ADC_NAMESPACE`         | -                       | see `MongoDbQueue::with_namespace`   |
+//! | `ADC_INTERVAL_SECS`     | `10`                    | time between maintenance runs        |
+//! | `ADC_TIERING`           | off                     | `1` to tier far-future jobs          |
+//! | `ADC_STATS_HISTORY`     | off                     | `1` to record queue depth history    |
+//! | `ADC_METRICS_ADDR`      | off                     | e.g. `0.0.0.0:9187` to serve metrics |
+//!
+//! Stops after the run in progress on Ctrl-C or SIGTERM.
+
+use std::env;
+
+use aide_de_camp::core::Duration;
+use aide_de_camp_mongodb::{MaintenanceRunner, MongoDbQueue, TieringOptions};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tracing_subscriber::fmt::init();
+
+    let uri = env::args()
+        .nth(1)
+        .or_else(|| env::var("ADC_MONGODB_URI").ok())
+        .ok_or("Usage: adc-scheduler <mongodb uri>")?;
+    let mut queue = MongoDbQueue::new(uri, env::var("ADC_CERT_FILE").ok()).await?;
+    if let Ok(name) = env::var("ADC_QUEUE") {
+        queue = queue.with_queue_name(name);
+    }
+    if let Ok(namespace) = env::var("ADC_NAMESPACE") {
+        queue = queue.with_namespace(namespace);
+    }
+
+    let mut runner = MaintenanceRunner::new(queue);
+    if let Ok(interval) = env::var("ADC_INTERVAL_SECS") {
+        runner = runner.with_interval(Duration::seconds(interval.parse()?));
+    }
+    if enabled("ADC_TIERING") {
+        runner = runner.with_tiering(TieringOptions::default());
+    }
+    if enabled("ADC_STATS_HISTORY") {
+        runner = runner.with_stats_history();
+    }
+    if let Ok(addr) = env::var("ADC_METRICS_ADDR") {
+        runner = runner.with_metrics_endpoint(addr.parse()?);
+    }
+
+    tracing::info!("Scheduler started");
+    runner.run_with_shutdown(shutdown()).await;
+    tracing::info!("Scheduler stopped");
+    Ok(())
+}
+
+fn enabled(variable: &str) -> bool {
+    env::var(variable).is_ok_and(|value| value == "1")
+}
+
+async fn shutdown() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
@@ -0,0 +1,266 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use aide_de_camp::core::{job_processor::JobProcessor, queue::QueueError};
+use anyhow::Context;
+use bson::{doc, Document};
+use mongodb::options::{CountOptions, FindOneOptions};
+
+use crate::{error::MongoDbQueueError, status, trace::traced, MongoDbQueue};
+
+/// Park reason of jobs diverted to cold storage by [`BudgetAction::Divert`].
+pub const OVER_BUDGET: &str = "over_budget";
+
+/// How long a queue goes by the pending jobs it counted for a [`PendingBudget`] before
+/// counting again.
+pub const BUDGET_RECOUNT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often a job type over its [`PendingBudget`] is reported at most, per queue instance.
+pub const BUDGET_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What happens to a job scheduled while its type is over its [`PendingBudget`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BudgetAction {
+    /// Fail the schedule with [`MongoDbQueueError::PendingBudgetExceeded`].
+    #[default]
+    Reject,
+    /// Add the job anyway and tell the
+    /// [`ErrorReporter`](crate::ErrorReporter::report_pending_budget_exceeded), like the other
+    /// actions at most once per [`BUDGET_REPORT_INTERVAL`].
+    Warn,
+    /// Cancel the pending job of the type that was enqueued first to make room, as
+    /// [`MongoDbQueue::cancel_job_by`] would with the actor [`OVER_BUDGET`].
+    DropOldest,
+    /// Add the job to cold storage, parked with the reason [`OVER_BUDGET`], so it is kept but
+    /// neither claimed nor counted. [`MongoDbQueue::unpark`] moves it back into the queue.
+    Divert,
+}
+
+/// What [`MongoDbQueue::enforce_pending_budget`] decided for a job about to be added.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum BudgetVerdict {
+    Admit,
+    /// Add the job parked to cold storage, see [`BudgetAction::Divert`].
+    Divert,
+    /// Add the job, then cancel the oldest pending job matching the filter.
+    DropOldest(Document),
+}
+
+/// Queue, namespace and job type a count is for.
+type BudgetKey = (String, Option<String>, String);
+
+/// The pending jobs last counted per queue and job type, shared by the clones of a queue.
+#[derive(Default)]
+pub(crate) struct BudgetCounts {
+    counts: Mutex<HashMap<BudgetKey, BudgetCount>>,
+}
+
+struct BudgetCount {
+    counted_at: Instant,
+    pending: u64,
+    /// Jobs admitted by this instance since the count.
+    added: u64,
+    reported_at: Option<Instant>,
+}
+
+/// Ceiling on the jobs of one type waiting in the queue, due or not, to catch runaway
+/// producers before they fill the database. Set with [`MongoDbQueue::with_pending_budget`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PendingBudget {
+    pub max_pending: u64,
+    pub action: BudgetAction,
+}
+
+impl PendingBudget {
+    /// Reject jobs once `max_pending` are waiting.
+    pub fn reject(max_pending: u64) -> Self {
+        Self {
+            max_pending,
+            action: BudgetAction::Reject,
+        }
+    }
+
+    /// Report jobs scheduled once `max_pending` are waiting, but add them.
+    pub fn warn(max_pending: u64) -> Self {
+        Self {
+            max_pending,
+            action: BudgetAction::Warn,
+        }
+    }
+
+    /// Cancel the oldest waiting job for each job scheduled once `max_pending` are waiting.
+    pub fn drop_oldest(max_pending: u64) -> Self {
+        Self {
+            max_pending,
+            action: BudgetAction::DropOldest,
+        }
+    }
+
+    /// Divert jobs scheduled once `max_pending` are waiting to cold storage.
+    pub fn divert(max_pending: u64) -> Self {
+        Self {
+            max_pending,
+            action: BudgetAction::Divert,
+        }
+    }
+}
+
+/// A job type that went over its [`PendingBudget`], passed to
+/// [`ErrorReporter::report_pending_budget_exceeded`](crate::ErrorReporter::report_pending_budget_exceeded).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingBudgetExceeded {
+    pub job_type: String,
+    pub limit: u64,
+    /// What was done about it, anything but [`BudgetAction::Reject`].
+    pub action: BudgetAction,
+}
+
+impl MongoDbQueue {
+    /// Check the number of pending jobs of type `J` in this queue against `budget` whenever
+    /// one is scheduled, see [`PendingBudget`]. A count of up to `max_pending` documents is
+    /// reused for [`BUDGET_RECOUNT_INTERVAL`], adding the jobs this instance scheduled since,
+    /// and only repeated earlier once that estimate reaches the limit. Jobs other producers
+    /// schedule in the meantime can take a type briefly over its budget, and room made by
+    /// claims can take as long to be seen.
+    pub fn with_pending_budget<J>(mut self, budget: PendingBudget) -> Self
+    where
+        J: JobProcessor + 'static,
+    {
+        Arc::make_mut(&mut self.pending_budgets).insert(J::name().to_string(), budget);
+        self
+    }
+
+    /// Use `budget` for every job type without one of its own, bounding each type in the queue
+    /// the same way.
+    pub fn with_default_pending_budget(mut self, budget: PendingBudget) -> Self {
+        self.default_pending_budget = Some(budget);
+        self
+    }
+
+    /// Check the budget of `job_type`, if it has one, before adding one of its jobs. Room is
+    /// only made once the job is in, so a failed insert doesn't cost a job.
+    pub(crate) async fn enforce_pending_budget(
+        &self,
+        job_type: &str,
+    ) -> Result<BudgetVerdict, QueueError> {
+        let Some(budget) = self
+            .pending_budgets
+            .get(job_type)
+            .or(self.default_pending_budget.as_ref())
+        else {
+            return Ok(BudgetVerdict::Admit);
+        };
+        let filter = self.scoped(status::pending(doc! {
+            "queue": &self.queue_name,
+            "job_type": job_type,
+        }));
+        let key = (
+            self.queue_name.clone(),
+            self.namespace.clone(),
+            job_type.to_string(),
+        );
+        let now = Instant::now();
+        let cached = {
+            let mut counts = self.budget_counts.counts.lock().unwrap();
+            match counts.get_mut(&key) {
+                Some(count) if now.duration_since(count.counted_at) < BUDGET_RECOUNT_INTERVAL => {
+                    if count.pending + count.added < budget.max_pending {
+                        count.added += 1;
+                        return Ok(BudgetVerdict::Admit);
+                    }
+                    count.pending >= budget.max_pending
+                }
+                _ => false,
+            }
+        };
+        if !cached {
+            let collection = self.collection();
+            // Only whether the limit is reached matters, so stop counting there.
+            let options = CountOptions::builder().limit(budget.max_pending).build();
+            let pending = traced(
+                &collection,
+                "count_documents",
+                collection.count_documents(filter.clone(), options),
+            )
+            .await
+            .context("Failed to count pending jobs")?;
+            let mut counts = self.budget_counts.counts.lock().unwrap();
+            let reported_at = counts.get(&key).and_then(|count| count.reported_at);
+            let admit = pending < budget.max_pending;
+            counts.insert(
+                key.clone(),
+                BudgetCount {
+                    counted_at: now,
+                    pending,
+                    added: admit.into(),
+                    reported_at,
+                },
+            );
+            if admit {
+                return Ok(BudgetVerdict::Admit);
+            }
+        }
+        if budget.action == BudgetAction::Reject {
+            return Err(MongoDbQueueError::PendingBudgetExceeded {
+                job_type: job_type.to_string(),
+                limit: budget.max_pending,
+            }
+            .into());
+        }
+        let report = match self.budget_counts.counts.lock().unwrap().get_mut(&key) {
+            Some(count)
+                if count
+                    .reported_at
+                    .is_none_or(|at| now.duration_since(at) >= BUDGET_REPORT_INTERVAL) =>
+            {
+                count.reported_at = Some(now);
+                true
+            }
+            _ => false,
+        };
+        if report {
+            self.error_reporter
+                .report_pending_budget_exceeded(&PendingBudgetExceeded {
+                    job_type: job_type.to_string(),
+                    limit: budget.max_pending,
+                    action: budget.action,
+                });
+        }
+        Ok(match budget.action {
+            BudgetAction::Divert => BudgetVerdict::Divert,
+            BudgetAction::DropOldest => BudgetVerdict::DropOldest(filter),
+            _ => BudgetVerdict::Admit,
+        })
+    }
+
+    /// Cancel the first enqueued job matching `filter` other than `added`, the job it makes
+    /// room for. Another producer may get to the same job first, then nothing is cancelled and
+    /// the queue briefly holds one job more.
+    pub(crate) async fn drop_oldest(
+        &self,
+        mut filter: Document,
+        added: &str,
+    ) -> Result<(), QueueError> {
+        filter.insert("jid", doc! { "$ne": added });
+        let collection = self.collection().clone_with_type::<Document>();
+        let options = FindOneOptions::builder()
+            .sort(doc! { "enqueued_at": 1 })
+            .projection(doc! { "jid": 1 })
+            .build();
+        let oldest = traced(
+            &collection,
+            "find_one",
+            collection.find_one(filter, options),
+        )
+        .await
+        .context("Failed to find the oldest pending job")?;
+        if let Some(jid) = oldest.as_ref().and_then(|row| row.get_str("jid").ok()) {
+            self.remove_unstarted(doc! { "jid": jid }, Some(OVER_BUDGET))
+                .await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+use std::path::PathBuf;
+
+use aide_de_camp::core::queue::QueueError;
+use anyhow::Context;
+use mongodb::{
+    options::{ClientOptions, Tls, TlsOptions, WriteConcern},
+    Client,
+};
+use tracing::{field::Empty, instrument};
+
+use crate::{uri::SafeUri, MongoDbQueue};
+
+/// Connection settings for a [`MongoDbQueue`], for when [`MongoDbQueue::new`] is not enough.
+/// Settings left alone are taken from the connection string. Created with
+/// [`MongoDbQueue::builder`].
+#[derive(Debug, Clone)]
+pub struct MongoDbQueueBuilder {
+    uri: SafeUri,
+    database: Option<String>,
+    collection_prefix: Option<String>,
+    tls: Option<TlsOptions>,
+    max_pool_size: Option<u32>,
+    min_pool_size: Option<u32>,
+    write_concern: Option<WriteConcern>,
+}
+
+impl MongoDbQueue {
+    /// Start building a queue connected to `uri`.
+    pub fn builder(uri: impl Into<SafeUri>) -> MongoDbQueueBuilder {
+        MongoDbQueueBuilder {
+            uri: uri.into(),
+            database: None,
+            collection_prefix: None,
+            tls: None,
+            max_pool_size: None,
+            min_pool_size: None,
+            write_concern: None,
+        }
+    }
+}
+
+impl MongoDbQueueBuilder {
+    /// Keep the queue in the database `name` instead of the one named in the connection
+    /// string, or `adc` if there is none.
+    pub fn with_database_name(mut self, name: impl Into<String>) -> Self {
+        self.database = Some(name.into());
+        self
+    }
+
+    /// Name the collections with `prefix` in place of `adc_`, e.g. `billing_queue` and
+    /// `billing_dead_queue` with `"billing_"`, so several independent queues can share one
+    /// database. Unlike [`MongoDbQueue::with_namespace`], the queues share no indexes or
+    /// collections.
+    pub fn with_collection_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.collection_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Connect with TLS using `options`, replacing the TLS settings of the connection string.
+    pub fn with_tls_options(mut self, options: TlsOptions) -> Self {
+        self.tls = Some(options);
+        self
+    }
+
+    /// Connect with TLS, verifying the server certificate against the CA file at `path`.
+    pub fn with_ca_file(self, path: impl Into<PathBuf>) -> Self {
+        let options = TlsOptions::builder().ca_file_path(path.into()).build();
+        self.with_tls_options(options)
+    }
+
+    /// Open at most `size` connections per server.
+    pub fn with_max_pool_size(mut self, size: u32) -> Self {
+        self.max_pool_size = Some(size);
+        self
+    }
+
+    /// Keep at least `size` connections per server open.
+    pub fn with_min_pool_size(mut self, size: u32) -> Self {
+        self.min_pool_size = Some(size);
+        self
+    }
+
+    /// Acknowledge writes as `write_concern` asks, e.g. by a majority of the replica set so
+    /// claimed and completed jobs survive a failover.
+    pub fn with_write_concern(mut self, write_concern: WriteConcern) -> Self {
+        self.write_concern = Some(write_concern);
+        self
+    }
+
+    /// Create the client and the queue.
+    #[instrument(skip_all, err, fields(uri = Empty))]
+    pub async fn build(self) -> Result<MongoDbQueue, QueueError> {
+        tracing::Span::current().record("uri", tracing::field::display(&self.uri));
+        let mut options = ClientOptions::parse(self.uri.expose())
+            .await
+            .map_err(|error| self.uri.scrub_error(error))
+            .with_context(|| format!("Failed to parse connection string {}", self.uri))?;
+        if let Some(tls) = self.tls {
+            options.tls = Some(Tls::Enabled(tls));
+        }
+        if let Some(size) = self.max_pool_size {
+            options.max_pool_size = Some(size);
+        }
+        if let Some(size) = self.min_pool_size {
+            options.min_pool_size = Some(size);
+        }
+        if let Some(write_concern) = self.write_concern {
+            options.write_concern = Some(write_concern);
+        }
+        let client = Client::with_options(options)
+            .map_err(|error| self.uri.scrub_error(error))
+            .context("Failed to create client")?;
+        let database = match &self.database {
+            Some(name) => client.database(name),
+            None => client.default_database().unwrap_or(client.database("adc")),
+        };
+        let mut queue = MongoDbQueue::with_database(database);
+        if let Some(prefix) = &self.collection_prefix {
+            queue.database = queue.database.with_collection_prefix(prefix);
+        }
+        Ok(queue)
+    }
+}
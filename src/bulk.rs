@@ -0,0 +1,489 @@
+use std::str::FromStr;
+
+use aide_de_camp::core::{queue::QueueError, DateTime, Xid};
+use anyhow::Context;
+use bson::{doc, Bson, Document};
+use chrono::Utc;
+use futures::TryStreamExt;
+use mongodb::options::FindOptions;
+use tracing::instrument;
+
+use crate::{
+    cancel::CancelMode,
+    patch::Patch,
+    status::JobStatus,
+    trace::traced,
+    types::{DeadJobInfo, JobInfo, JobRow},
+    MongoDbQueue,
+};
+
+/// Number of job ids a dry run returns as a sample of what would be affected.
+pub const DRY_RUN_SAMPLE_SIZE: i64 = 20;
+
+/// Maximum number of jobs moved in one transaction by [`MongoDbQueue::bulk_requeue`].
+pub const BULK_BATCH_SIZE: i64 = 500;
+
+/// Selects jobs for the bulk operations. Unset fields match every job.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct JobFilter {
+    pub job_type: Option<String>,
+    pub tenant: Option<String>,
+    pub correlation_id: Option<String>,
+    /// Jobs scheduled with this tag, see [`ScheduleOptions::tags`](crate::ScheduleOptions::tags).
+    pub tag: Option<String>,
+    /// Jobs of this owner, see [`ScheduleOptions::owner`](crate::ScheduleOptions::owner).
+    pub owner: Option<String>,
+}
+
+/// What [`MongoDbQueue::bulk_update`] changes. Unset fields are left alone.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct JobChanges {
+    pub priority: Option<i8>,
+    pub scheduled_at: Option<DateTime>,
+}
+
+/// What a destructive operation would affect, returned by its `_dry_run` variant without
+/// changing anything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DryRun {
+    pub matched: u64,
+    /// Up to [`DRY_RUN_SAMPLE_SIZE`] of the matched jobs.
+    pub sample: Vec<Xid>,
+}
+
+impl JobFilter {
+    fn query(&self, queue: &MongoDbQueue, status: Document) -> Document {
+        let mut query = queue.scoped(doc! {
+            "queue": &queue.queue_name,
+            "status": status,
+        });
+        if let Some(job_type) = &self.job_type {
+            query.insert("job_type", job_type);
+        }
+        if let Some(tenant) = &self.tenant {
+            query.insert("tenant", tenant);
+        }
+        if let Some(correlation_id) = &self.correlation_id {
+            query.insert("correlation_id", correlation_id);
+        }
+        if let Some(tag) = &self.tag {
+            query.insert("tags", tag);
+        }
+        if let Some(owner) = &self.owner {
+            query.insert("owner", owner);
+        }
+        query
+    }
+
+    fn pending(&self, queue: &MongoDbQueue) -> Document {
+        self.query(queue, doc! { "$eq": JobStatus::Pending })
+    }
+
+    fn unstarted(&self, queue: &MongoDbQueue) -> Document {
+        self.query(
+            queue,
+            doc! { "$in": [JobStatus::Pending, JobStatus::Parked] },
+        )
+    }
+
+    fn queued(&self, queue: &MongoDbQueue) -> Document {
+        self.query(
+            queue,
+            doc! { "$in": [JobStatus::Pending, JobStatus::Running, JobStatus::Parked] },
+        )
+    }
+
+    fn dead(&self, queue: &MongoDbQueue) -> Document {
+        self.query(queue, doc! { "$eq": JobStatus::Dead })
+    }
+}
+
+impl MongoDbQueue {
+    /// Change the priority and/or schedule of all pending jobs matching `filter`, including
+    /// jobs in cold storage. Returns the number of jobs matched; with `dry_run` that is all it
+    /// does, see [`Self::bulk_update_dry_run`] for a sample of them as well.
+    #[instrument(skip_all, err, ret, fields(dry_run = dry_run))]
+    pub async fn bulk_update(
+        &self,
+        filter: &JobFilter,
+        changes: JobChanges,
+        dry_run: bool,
+    ) -> Result<u64, QueueError> {
+        let query = filter.pending(self);
+        let mut set = Document::new();
+        if let Some(priority) = changes.priority {
+            set.insert("priority", priority as i64);
+        }
+        if let Some(scheduled_at) = changes.scheduled_at {
+            set.insert("scheduled_at", bson::DateTime::from_chrono(scheduled_at));
+        }
+
+        let mut matched = 0;
+        for name in ["adc_queue", "adc_cold_queue"] {
+            let collection = self.database.collection::<Document>(name);
+            if dry_run || set.is_empty() {
+                matched += traced(
+                    &collection,
+                    "count_documents",
+                    collection.count_documents(query.clone(), None),
+                )
+                .await
+                .context("Failed to count jobs")?;
+                continue;
+            }
+            let result = traced(
+                &collection,
+                "update_many",
+                collection.update_many(query.clone(), doc! { "$set": &set }, None),
+            )
+            .await
+            .context("Failed to update jobs")?;
+            matched += result.matched_count;
+        }
+        Ok(matched)
+    }
+
+    /// The jobs [`Self::bulk_update`] would change.
+    #[instrument(skip_all, err, ret)]
+    pub async fn bulk_update_dry_run(&self, filter: &JobFilter) -> Result<DryRun, QueueError> {
+        self.dry_run(&["adc_queue", "adc_cold_queue"], filter.pending(self))
+            .await
+    }
+
+    /// Move all jobs matching `filter` that have not started, including parked jobs and jobs in
+    /// cold storage, to the queue named `target_queue`, e.g. when a dedicated worker pool
+    /// takes over a hot job type. All jobs move in one transaction, so workers see either
+    /// none or all of them on the new queue. Returns the number of jobs moved.
+    #[instrument(skip_all, err, ret, fields(target_queue = target_queue))]
+    pub async fn move_jobs(
+        &self,
+        filter: &JobFilter,
+        target_queue: &str,
+    ) -> Result<u64, QueueError> {
+        if target_queue == self.queue_name {
+            return Ok(0);
+        }
+        let query = filter.unstarted(self);
+        let mut session = self
+            .collection()
+            .client()
+            .start_session(None)
+            .await
+            .context("Failed to start session")?;
+        session
+            .start_transaction(None)
+            .await
+            .context("Failed to start transaction")?;
+        let mut moved = 0;
+        for name in ["adc_queue", "adc_cold_queue"] {
+            let collection = self.database.collection::<Document>(name);
+            let result = traced(
+                &collection,
+                "update_many",
+                collection.update_many_with_session(
+                    query.clone(),
+                    doc! { "$set": { "queue": target_queue } },
+                    None,
+                    &mut session,
+                ),
+            )
+            .await
+            .context("Failed to move jobs")?;
+            moved += result.modified_count;
+        }
+        session
+            .commit_transaction()
+            .await
+            .context("Failed to commit transaction")?;
+        Ok(moved)
+    }
+
+    /// The jobs [`Self::move_jobs`] would move.
+    #[instrument(skip_all, err, ret)]
+    pub async fn move_jobs_dry_run(&self, filter: &JobFilter) -> Result<DryRun, QueueError> {
+        self.dry_run(&["adc_queue", "adc_cold_queue"], filter.unstarted(self))
+            .await
+    }
+
+    /// Cancel all jobs matching `filter` that have not started, like
+    /// [`Self::cancel_job_by`] does for one. Returns the number of jobs cancelled.
+    #[instrument(skip_all, err, ret, fields(actor = actor))]
+    pub async fn bulk_cancel(&self, filter: &JobFilter, actor: &str) -> Result<u64, QueueError> {
+        let query = filter.unstarted(self);
+        let mut cancelled = 0;
+        for name in ["adc_queue", "adc_cold_queue"] {
+            if self.cancel_mode == CancelMode::Delete {
+                let collection = self.database.collection::<Document>(name);
+                let result = traced(
+                    &collection,
+                    "delete_many",
+                    collection.delete_many(query.clone(), None),
+                )
+                .await
+                .context("Failed to cancel jobs")?;
+                cancelled += result.deleted_count;
+                continue;
+            }
+            loop {
+                let moved = self.cancel_batch(name, query.clone(), actor).await?;
+                cancelled += moved;
+                if moved < BULK_BATCH_SIZE as u64 {
+                    break;
+                }
+            }
+        }
+        Ok(cancelled)
+    }
+
+    /// The jobs [`Self::bulk_cancel`] would cancel.
+    #[instrument(skip_all, err, ret)]
+    pub async fn bulk_cancel_dry_run(&self, filter: &JobFilter) -> Result<DryRun, QueueError> {
+        self.dry_run(&["adc_queue", "adc_cold_queue"], filter.unstarted(self))
+            .await
+    }
+
+    /// Move all jobs matching `filter` from the dead queue back to the queue, due now and with
+    /// their retries reset. Returns the number of jobs requeued.
+    #[instrument(skip_all, err, ret)]
+    pub async fn bulk_requeue(&self, filter: &JobFilter) -> Result<u64, QueueError> {
+        let query = filter.dead(self);
+        let mut requeued = 0;
+        loop {
+            let moved = self.requeue_batch(query.clone()).await?;
+            requeued += moved;
+            if moved < BULK_BATCH_SIZE as u64 {
+                break;
+            }
+        }
+        Ok(requeued)
+    }
+
+    /// Up to `limit` jobs in the queue matching `filter`, pending, running or parked, most
+    /// recently enqueued first.
+    #[instrument(skip_all, err)]
+    pub async fn list_jobs(
+        &self,
+        filter: &JobFilter,
+        limit: i64,
+    ) -> Result<Vec<JobInfo>, QueueError> {
+        let options = FindOptions::builder()
+            .sort(doc! { "enqueued_at": -1 })
+            .limit(limit)
+            .build();
+        let collection = self.collection();
+        let rows: Vec<JobRow> = traced(
+            &collection,
+            "find",
+            collection.find(filter.queued(self), options),
+        )
+        .await
+        .context("Failed to find jobs")?
+        .try_collect()
+        .await
+        .context("Failed to read jobs")?;
+        Ok(rows.into_iter().map(JobRow::into_info).collect())
+    }
+
+    /// Up to `limit` dead jobs matching `filter`, most recently enqueued first.
+    #[instrument(skip_all, err)]
+    pub async fn dead_jobs(
+        &self,
+        filter: &JobFilter,
+        limit: i64,
+    ) -> Result<Vec<DeadJobInfo>, QueueError> {
+        let options = FindOptions::builder()
+            .sort(doc! { "enqueued_at": -1 })
+            .limit(limit)
+            .build();
+        let dead = self.database.collection::<JobRow>("adc_dead_queue");
+        let rows: Vec<JobRow> = traced(&dead, "find", dead.find(filter.dead(self), options))
+            .await
+            .context("Failed to find dead jobs")?
+            .try_collect()
+            .await
+            .context("Failed to read dead jobs")?;
+        Ok(rows.into_iter().map(JobRow::into_dead_info).collect())
+    }
+
+    /// The jobs [`Self::bulk_requeue`] would requeue.
+    #[instrument(skip_all, err, ret)]
+    pub async fn bulk_requeue_dry_run(&self, filter: &JobFilter) -> Result<DryRun, QueueError> {
+        self.dry_run(&["adc_dead_queue"], filter.dead(self)).await
+    }
+
+    /// The jobs [`Self::purge_cancelled`] would delete.
+    #[instrument(skip_all, err, ret)]
+    pub async fn purge_cancelled_dry_run(&self, before: DateTime) -> Result<DryRun, QueueError> {
+        self.dry_run(
+            &["adc_cancelled"],
+            self.scoped(doc! { "cancelled_at": { "$lt": bson::DateTime::from_chrono(before) } }),
+        )
+        .await
+    }
+
+    async fn dry_run(&self, collections: &[&str], query: Document) -> Result<DryRun, QueueError> {
+        let mut report = DryRun::default();
+        for name in collections {
+            let collection = self.database.collection::<Document>(name);
+            report.matched += traced(
+                &collection,
+                "count_documents",
+                collection.count_documents(query.clone(), None),
+            )
+            .await
+            .context("Failed to count jobs")?;
+
+            let limit = DRY_RUN_SAMPLE_SIZE - report.sample.len() as i64;
+            if limit <= 0 {
+                continue;
+            }
+            let options = FindOptions::builder()
+                .projection(doc! { "jid": 1 })
+                .limit(limit)
+                .build();
+            let rows: Vec<Document> =
+                traced(&collection, "find", collection.find(query.clone(), options))
+                    .await
+                    .context("Failed to find jobs")?
+                    .try_collect()
+                    .await
+                    .context("Failed to read jobs")?;
+            for row in rows {
+                let jid = row.get_str("jid").context("Job without jid")?;
+                report
+                    .sample
+                    .push(Xid::from_str(jid).with_context(|| format!("Malformed jid {jid:?}"))?);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Move up to [`BULK_BATCH_SIZE`] jobs in the collection `name` matching `query` to
+    /// `adc_cancelled` in one transaction.
+    async fn cancel_batch(
+        &self,
+        name: &str,
+        query: Document,
+        actor: &str,
+    ) -> Result<u64, QueueError> {
+        let collection = self.database.collection::<Document>(name);
+        let cancelled = self.database.collection::<Document>("adc_cancelled");
+        let mut session = collection
+            .client()
+            .start_session(None)
+            .await
+            .context("Failed to start session")?;
+        session
+            .start_transaction(None)
+            .await
+            .context("Failed to start transaction")?;
+
+        let options = FindOptions::builder().limit(BULK_BATCH_SIZE).build();
+        let mut rows: Vec<Document> = traced(
+            &collection,
+            "find",
+            collection.find_with_session(query.clone(), options, &mut session),
+        )
+        .await
+        .context("Failed to find jobs")?
+        .stream(&mut session)
+        .try_collect()
+        .await
+        .context("Failed to read jobs")?;
+        if rows.is_empty() {
+            return Ok(0);
+        }
+
+        let jids: Vec<Bson> = rows
+            .iter()
+            .filter_map(|row| row.get("jid").cloned())
+            .collect();
+        let patch = Patch::cancelled(Some(actor));
+        for row in &mut rows {
+            patch.apply(row);
+        }
+        traced(
+            &cancelled,
+            "insert_many",
+            cancelled.insert_many_with_session(&rows, None, &mut session),
+        )
+        .await
+        .context("Failed to retain cancelled jobs")?;
+        let mut batch = query;
+        batch.insert("jid", doc! { "$in": &jids });
+        traced(
+            &collection,
+            "delete_many",
+            collection.delete_many_with_session(batch, None, &mut session),
+        )
+        .await
+        .context("Failed to remove cancelled jobs from the queue")?;
+
+        session
+            .commit_transaction()
+            .await
+            .context("Failed to commit transaction")?;
+        Ok(rows.len() as u64)
+    }
+
+    /// Move up to [`BULK_BATCH_SIZE`] dead jobs matching `query` back to the queue in one
+    /// transaction.
+    async fn requeue_batch(&self, query: Document) -> Result<u64, QueueError> {
+        let dead = self.database.collection::<Document>("adc_dead_queue");
+        let collection = self.database.collection::<Document>("adc_queue");
+        let mut session = collection
+            .client()
+            .start_session(None)
+            .await
+            .context("Failed to start session")?;
+        session
+            .start_transaction(None)
+            .await
+            .context("Failed to start transaction")?;
+
+        let options = FindOptions::builder().limit(BULK_BATCH_SIZE).build();
+        let mut rows: Vec<Document> = traced(
+            &dead,
+            "find",
+            dead.find_with_session(query, options, &mut session),
+        )
+        .await
+        .context("Failed to find dead jobs")?
+        .stream(&mut session)
+        .try_collect()
+        .await
+        .context("Failed to read dead jobs")?;
+        if rows.is_empty() {
+            return Ok(0);
+        }
+
+        let jids: Vec<Bson> = rows
+            .iter()
+            .filter_map(|row| row.get("jid").cloned())
+            .collect();
+        let patch = Patch::requeued(bson::DateTime::from_chrono(Utc::now()));
+        for row in &mut rows {
+            patch.apply(row);
+        }
+        traced(
+            &collection,
+            "insert_many",
+            collection.insert_many_with_session(&rows, None, &mut session),
+        )
+        .await
+        .context("Failed to requeue dead jobs")?;
+        traced(
+            &dead,
+            "delete_many",
+            dead.delete_many_with_session(doc! { "jid": { "$in": &jids } }, None, &mut session),
+        )
+        .await
+        .context("Failed to remove requeued jobs from the dead queue")?;
+
+        session
+            .commit_transaction()
+            .await
+            .context("Failed to commit transaction")?;
+        Ok(rows.len() as u64)
+    }
+}
@@ -0,0 +1,150 @@
+use aide_de_camp::core::{queue::QueueError, DateTime, Xid};
+use anyhow::Context;
+use bson::{doc, Document};
+use mongodb::Collection;
+use tracing::instrument;
+
+use crate::{
+    hooks::ErrorContext, patch::Patch, status::JobStatus, trace::traced, types::JobRow,
+    MongoDbQueue,
+};
+
+/// What happens to jobs removed with `cancel_job` or `unschedule_job`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CancelMode {
+    /// Delete them.
+    #[default]
+    Delete,
+    /// Move them to the `adc_cancelled` collection with `cancelled_at` and `cancelled_by` set,
+    /// until they are removed with [`MongoDbQueue::purge_cancelled`].
+    Retain,
+}
+
+impl MongoDbQueue {
+    /// Cancel a job that has not started yet, recording `actor` as the one who cancelled it
+    /// when cancelled jobs are retained (see [`CancelMode::Retain`]).
+    #[instrument(skip_all, err, fields(actor = actor))]
+    pub async fn cancel_job_by(&self, job_id: Xid, actor: &str) -> Result<(), QueueError> {
+        let jid = format!("{}", job_id);
+        let result = self
+            .remove_unstarted(doc! { "jid": &jid }, Some(actor))
+            .await
+            .and_then(|row| row.map(|_| ()).ok_or(QueueError::JobNotFound(job_id)));
+        let context = ErrorContext {
+            operation: "cancel_job",
+            jid: Some(&jid),
+            job_type: None,
+            correlation_id: None,
+        };
+        self.reported(&context, result)
+    }
+
+    /// Delete retained cancelled jobs that were cancelled before `before`. Returns the number of
+    /// jobs deleted.
+    #[instrument(skip_all, err, ret)]
+    pub async fn purge_cancelled(&self, before: DateTime) -> Result<u64, QueueError> {
+        let collection = self.cancelled_collection();
+        let result = traced(
+            &collection,
+            "delete_many",
+            collection.delete_many(
+                self.scoped(
+                    doc! { "cancelled_at": { "$lt": bson::DateTime::from_chrono(before) } },
+                ),
+                None,
+            ),
+        )
+        .await
+        .context("Failed to purge cancelled jobs")?;
+        Ok(result.deleted_count)
+    }
+
+    /// Remove the job matching `filter` from the queue if it has not started, keeping it in
+    /// `adc_cancelled` if cancelled jobs are retained. Jobs moved to cold storage by
+    /// [`Self::tier_jobs`] are found as well.
+    pub(crate) async fn remove_unstarted(
+        &self,
+        filter: Document,
+        actor: Option<&str>,
+    ) -> Result<Option<JobRow>, QueueError> {
+        match self
+            .remove_unstarted_from(self.collection(), filter.clone(), actor)
+            .await?
+        {
+            Some(row) => Ok(Some(row)),
+            None => {
+                self.remove_unstarted_from(self.cold_collection(), filter, actor)
+                    .await
+            }
+        }
+    }
+
+    async fn remove_unstarted_from(
+        &self,
+        collection: Collection<JobRow>,
+        filter: Document,
+        actor: Option<&str>,
+    ) -> Result<Option<JobRow>, QueueError> {
+        let collection = collection.clone_with_type::<Document>();
+        let mut filter = self.scoped(filter);
+        filter.insert(
+            "status",
+            doc! { "$in": [JobStatus::Pending, JobStatus::Parked] },
+        );
+        if self.cancel_mode == CancelMode::Delete {
+            let row = traced(
+                &collection,
+                "find_one_and_delete",
+                collection.find_one_and_delete(filter, None),
+            )
+            .await
+            .context("Failed to remove job from the queue")?;
+            let row = row
+                .map(bson::from_document)
+                .transpose()
+                .context("Failed to read cancelled job")?;
+            return Ok(row);
+        }
+
+        let cancelled = self.cancelled_collection().clone_with_type::<Document>();
+        let mut session = collection
+            .client()
+            .start_session(None)
+            .await
+            .context("Failed to start session")?;
+        session
+            .start_transaction(None)
+            .await
+            .context("Failed to start transaction")?;
+
+        let row = traced(
+            &collection,
+            "find_one_and_delete",
+            collection.find_one_and_delete_with_session(filter, None, &mut session),
+        )
+        .await
+        .context("Failed to remove job from the queue")?;
+        let Some(mut row) = row else {
+            return Ok(None);
+        };
+        Patch::cancelled(actor).apply(&mut row);
+        traced(
+            &cancelled,
+            "insert_one",
+            cancelled.insert_one_with_session(&row, None, &mut session),
+        )
+        .await
+        .context("Failed to retain cancelled job")?;
+
+        session
+            .commit_transaction()
+            .await
+            .context("Failed to commit transaction")?;
+        let row = bson::from_document(row).context("Failed to read cancelled job")?;
+        Ok(Some(row))
+    }
+
+    fn cancelled_collection(&self) -> Collection<JobRow> {
+        self.database.collection("adc_cancelled")
+    }
+}
@@ -0,0 +1,218 @@
+use std::{
+    future::Future,
+    pin::pin,
+    time::{Duration, Instant},
+};
+
+use aide_de_camp::core::queue::QueueError;
+use anyhow::Context;
+use bson::{doc, Document};
+use futures::{
+    future::{select, Either},
+    StreamExt,
+};
+use mongodb::{
+    change_stream::{
+        event::{ChangeStreamEvent, ResumeToken},
+        ChangeStream,
+    },
+    error::ErrorKind,
+    options::{ChangeStreamOptions, UpdateOptions},
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::{trace::traced, MongoDbQueue};
+
+/// How often the resume token is saved while events keep coming.
+pub const RESUME_TOKEN_SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Delivers new jobs from a MongoDB change stream on the queue collection, so workers can be
+/// woken up when work arrives instead of only polling on an interval. Needs a replica set.
+///
+/// The resume token is kept in `adc_meta` under the consumer name, so a restarted listener
+/// picks up the inserts it missed. With [`Self::with_max_events_per_second`] a burst of
+/// inserts is consumed at a bounded rate, the rest waits in the change stream.
+pub struct ChangeStreamListener {
+    queue: MongoDbQueue,
+    consumer: String,
+    max_events_per_second: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ResumeTokenRow {
+    #[serde(rename = "_id")]
+    id: String,
+    resume_token: ResumeToken,
+}
+
+impl ChangeStreamListener {
+    /// `consumer` names the listener's saved position; listeners sharing a name resume from the
+    /// same token.
+    pub fn new(queue: MongoDbQueue, consumer: impl Into<String>) -> Self {
+        Self {
+            queue,
+            consumer: consumer.into(),
+            max_events_per_second: None,
+        }
+    }
+
+    /// Consume at most `max` events per second.
+    pub fn with_max_events_per_second(mut self, max: u32) -> Self {
+        self.max_events_per_second = Some(max.max(1));
+        self
+    }
+
+    /// Call `on_job` with the job type of every job added to the queue until `shutdown`
+    /// completes, starting after the last event seen by this consumer.
+    #[instrument(skip_all, err, fields(consumer = %self.consumer))]
+    pub async fn run_with_shutdown<F>(
+        &self,
+        mut on_job: impl FnMut(&str) + Send,
+        shutdown: F,
+    ) -> Result<(), QueueError>
+    where
+        F: Future<Output = ()>,
+    {
+        let mut stream = self.watch().await?;
+        let mut shutdown = pin!(shutdown);
+        let mut saved_at = Instant::now();
+        let mut window = (Instant::now(), 0);
+
+        loop {
+            let event = match select(shutdown.as_mut(), stream.next()).await {
+                Either::Left(_) => break,
+                Either::Right((None, _)) => break,
+                Either::Right((Some(Ok(event)), _)) => event,
+                // The saved position can also turn out to be gone on the first read.
+                Either::Right((Some(Err(error)), _)) if is_history_lost(&error) => {
+                    tracing::warn!(?error, "Change stream history lost, starting from now");
+                    stream = self.watch_from_now().await?;
+                    continue;
+                }
+                Either::Right((Some(Err(error)), _)) => {
+                    return Err(anyhow::Error::from(error)
+                        .context("Change stream failed")
+                        .into())
+                }
+            };
+            if let Some(job_type) = event
+                .full_document
+                .as_ref()
+                .and_then(|row| row.get_str("job_type").ok())
+            {
+                on_job(job_type);
+            }
+
+            if saved_at.elapsed() >= RESUME_TOKEN_SAVE_INTERVAL {
+                self.save_resume_token(stream.resume_token()).await?;
+                saved_at = Instant::now();
+            }
+            if let Some(max) = self.max_events_per_second {
+                let (started, count) = &mut window;
+                if started.elapsed() >= Duration::from_secs(1) {
+                    *started = Instant::now();
+                    *count = 0;
+                }
+                *count += 1;
+                if *count >= max {
+                    tokio::time::sleep(Duration::from_secs(1).saturating_sub(started.elapsed()))
+                        .await;
+                }
+            }
+        }
+        self.save_resume_token(stream.resume_token()).await
+    }
+
+    async fn watch(&self) -> Result<ChangeStream<ChangeStreamEvent<Document>>, QueueError> {
+        let resume_token = self.load_resume_token().await?;
+        if resume_token.is_none() {
+            return self.watch_from_now().await;
+        }
+        match self.open_after(resume_token).await {
+            Ok(stream) => Ok(stream),
+            // The saved position fell off the oplog, start from now.
+            Err(error) if is_history_lost(&error) => {
+                tracing::warn!(?error, "Change stream history lost, starting from now");
+                self.watch_from_now().await
+            }
+            Err(error) => Err(anyhow::Error::from(error)
+                .context("Failed to open change stream")
+                .into()),
+        }
+    }
+
+    async fn watch_from_now(
+        &self,
+    ) -> Result<ChangeStream<ChangeStreamEvent<Document>>, QueueError> {
+        Ok(self
+            .open_after(None)
+            .await
+            .context("Failed to open change stream")?)
+    }
+
+    /// Open the change stream after `resume_token`, or from now without one.
+    async fn open_after(
+        &self,
+        resume_token: Option<ResumeToken>,
+    ) -> Result<ChangeStream<ChangeStreamEvent<Document>>, mongodb::error::Error> {
+        let collection = self.queue.database.collection::<Document>("adc_queue");
+        let pipeline = [doc! { "$match": {
+            "operationType": "insert",
+            "fullDocument.queue": &self.queue.queue_name,
+            "fullDocument.namespace": self.queue.namespace(),
+        } }];
+        let options = ChangeStreamOptions::builder()
+            .resume_after(resume_token)
+            .build();
+        collection.watch(pipeline, options).await
+    }
+
+    async fn load_resume_token(&self) -> Result<Option<ResumeToken>, QueueError> {
+        let collection = self.meta_collection();
+        let row = traced(
+            &collection,
+            "find_one",
+            collection.find_one(doc! { "_id": self.meta_id() }, None),
+        )
+        .await
+        .context("Failed to load resume token")?;
+        Ok(row.map(|row| row.resume_token))
+    }
+
+    async fn save_resume_token(&self, token: Option<ResumeToken>) -> Result<(), QueueError> {
+        let Some(token) = token else {
+            return Ok(());
+        };
+        let collection = self.meta_collection();
+        let token = bson::to_bson(&token).context("Failed to encode resume token")?;
+        traced(
+            &collection,
+            "update_one",
+            collection.update_one(
+                doc! { "_id": self.meta_id() },
+                doc! { "$set": { "resume_token": token } },
+                UpdateOptions::builder().upsert(true).build(),
+            ),
+        )
+        .await
+        .context("Failed to save resume token")?;
+        Ok(())
+    }
+
+    fn meta_collection(&self) -> Collection<ResumeTokenRow> {
+        self.queue.database.collection("adc_meta")
+    }
+
+    fn meta_id(&self) -> String {
+        self.queue
+            .scoped_key(&format!("change_stream:{}", self.consumer))
+    }
+}
+
+/// Whether the change stream can't resume because its position is no longer in the oplog.
+fn is_history_lost(error: &mongodb::error::Error) -> bool {
+    // ChangeStreamHistoryLost
+    matches!(error.kind.as_ref(), ErrorKind::Command(command) if command.code == 286)
+}
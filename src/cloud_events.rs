@@ -0,0 +1,115 @@
+use aide_de_camp::core::DateTime;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{events::JobEvent, status::JobStatus, types::JobRow, MongoDbQueue};
+
+/// Prefix of the `type` of every [`CloudEvent`], followed by the event name, e.g.
+/// `io.aide-de-camp.job.completed`.
+pub const CLOUD_EVENT_TYPE_PREFIX: &str = "io.aide-de-camp";
+
+/// A lifecycle event in the CloudEvents 1.0 JSON format, for consumers like Knative or
+/// EventBridge. `subject` is the jid and `data` holds the job type, queue, status, attempt
+/// and, for finished attempts, `duration_ms`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CloudEvent {
+    pub specversion: String,
+    pub id: String,
+    pub source: String,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub subject: String,
+    pub time: DateTime,
+    pub datacontenttype: String,
+    pub data: serde_json::Value,
+}
+
+impl CloudEvent {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        source: &str,
+        name: &str,
+        jid: &str,
+        at: DateTime,
+        job_type: &str,
+        queue: &str,
+        status: JobStatus,
+        attempt: i64,
+        duration_ms: Option<i64>,
+    ) -> Self {
+        Self {
+            specversion: "1.0".to_string(),
+            // Unique per source and the same every time a stored event is converted, so
+            // consumers can drop events relayed twice.
+            id: format!("{jid}.{name}.{}", at.timestamp_millis()),
+            source: source.to_string(),
+            event_type: format!("{CLOUD_EVENT_TYPE_PREFIX}.{name}"),
+            subject: jid.to_string(),
+            time: at,
+            datacontenttype: "application/json".to_string(),
+            data: json!({
+                "job_type": job_type,
+                "queue": queue,
+                "status": status,
+                "attempt": attempt,
+                "duration_ms": duration_ms,
+            }),
+        }
+    }
+}
+
+impl JobEvent {
+    /// This event as a [`CloudEvent`] from `source`, e.g. for relaying the
+    /// [event log](MongoDbQueue::with_event_log) to an event bus.
+    pub fn to_cloud_event(&self, source: &str) -> CloudEvent {
+        CloudEvent::new(
+            source,
+            &self.name,
+            &self.jid,
+            self.at,
+            &self.job_type,
+            &self.queue,
+            self.status,
+            self.attempt as i64,
+            self.duration_ms,
+        )
+    }
+}
+
+impl MongoDbQueue {
+    /// Also emit every lifecycle event as CloudEvents JSON, as the message of a `tracing`
+    /// event with the target `aide_de_camp_mongodb::cloudevents`, so a log shipper can
+    /// forward them to an event bus without translating them. `source` identifies this
+    /// deployment, e.g. `/queues/billing`.
+    pub fn with_cloud_events(mut self, source: impl Into<String>) -> Self {
+        self.cloud_events_source = Some(source.into());
+        self
+    }
+
+    pub(crate) fn emit_cloud_event(
+        &self,
+        name: &str,
+        row: &JobRow,
+        at: DateTime,
+        duration_ms: Option<i64>,
+    ) {
+        let Some(source) = &self.cloud_events_source else {
+            return;
+        };
+        let event = CloudEvent::new(
+            source,
+            name,
+            &row.jid,
+            at,
+            &row.job_type,
+            &row.queue,
+            row.status,
+            row.attempts,
+            duration_ms,
+        );
+        match serde_json::to_string(&event) {
+            Ok(json) => tracing::info!(target: "aide_de_camp_mongodb::cloudevents", "{json}"),
+            Err(error) => tracing::warn!(%error, "Failed to encode CloudEvent"),
+        }
+    }
+}
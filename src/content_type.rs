@@ -0,0 +1,103 @@
+use std::sync::Arc;
+
+use aide_de_camp::core::{job_processor::JobProcessor, queue::QueueError};
+use bson::{spec::BinarySubtype, Binary};
+
+use crate::{error::MongoDbQueueError, hooks::PayloadCodec, MongoDbQueue};
+
+/// Media type of payloads encoded by this crate, see [`MongoDbQueue::with_content_type`].
+pub const BINCODE_CONTENT_TYPE: &str = "application/x-bincode";
+
+impl MongoDbQueue {
+    /// Store payloads with the BSON binary `subtype` instead of the generic one, e.g. a
+    /// user-defined subtype that tools reading the collection recognize. Jobs already in the
+    /// queue keep theirs.
+    pub fn with_payload_subtype(mut self, subtype: BinarySubtype) -> Self {
+        self.payload_subtype = subtype;
+        self
+    }
+
+    /// Tag the payload of every job this queue adds with the media type `content_type`, e.g.
+    /// [`BINCODE_CONTENT_TYPE`] or `application/json` when a
+    /// [`PayloadValidator`](crate::PayloadValidator) re-encodes payloads, so that consumers
+    /// in other languages or with other codecs know how to read the bytes. Jobs can override
+    /// it with [`ScheduleOptions::content_type`](crate::ScheduleOptions::content_type), and
+    /// workers read it with [`MongoDbJobHandle::content_type`]. Without one, jobs are stored
+    /// untagged.
+    ///
+    /// [`MongoDbJobHandle::content_type`]: crate::job_handle::MongoDbJobHandle::content_type
+    pub fn with_content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.content_type = Some(content_type.into());
+        self
+    }
+
+    /// Store payloads of job type `J` as `codec` encodes them, tagged with its content type
+    /// instead of the queue's or the one a job asks for. Workers still get the bincode encoding from
+    /// [`JobHandle::payload`](aide_de_camp::core::job_handle::JobHandle::payload), so job
+    /// processors don't change. [`PayloadMerger`](crate::PayloadMerger)s and exports see the
+    /// stored bytes.
+    pub fn with_payload_codec<J>(mut self, codec: impl PayloadCodec + 'static) -> Self
+    where
+        J: JobProcessor + 'static,
+    {
+        Arc::make_mut(&mut self.payload_codecs).insert(J::name().to_string(), Arc::new(codec));
+        self
+    }
+
+    /// Store payloads of job types without a codec of their own as `codec` encodes them, see
+    /// [`Self::with_payload_codec`]. Jobs added before, which are not tagged with the codec's
+    /// content type, are still read as they were stored.
+    pub fn with_default_payload_codec(mut self, codec: impl PayloadCodec + 'static) -> Self {
+        self.default_payload_codec = Some(Arc::new(codec));
+        self
+    }
+
+    pub(crate) fn payload_codec(&self, job_type: &str) -> Option<&dyn PayloadCodec> {
+        self.payload_codecs
+            .get(job_type)
+            .or(self.default_payload_codec.as_ref())
+            .map(Arc::as_ref)
+    }
+
+    /// The content type new jobs of `job_type` are tagged with, `requested` unless their
+    /// payload goes through a codec.
+    pub(crate) fn content_type_of(
+        &self,
+        job_type: &str,
+        requested: Option<&str>,
+    ) -> Option<String> {
+        match self.payload_codec(job_type) {
+            Some(codec) => Some(codec.content_type().to_string()),
+            None => requested
+                .or(self.content_type.as_deref())
+                .map(str::to_string),
+        }
+    }
+
+    /// The bincode encoding of a `stored` payload of `job_type` tagged with `content_type`.
+    /// Only payloads tagged with the content type of the job type's codec are decoded.
+    pub(crate) fn decode_stored(
+        &self,
+        job_type: &str,
+        content_type: Option<&str>,
+        stored: &[u8],
+    ) -> Result<Vec<u8>, QueueError> {
+        match self.payload_codec(job_type) {
+            Some(codec) if content_type == Some(codec.content_type()) => Ok(codec
+                .decode(job_type, stored)
+                .map_err(|reason| MongoDbQueueError::InvalidPayload {
+                    job_type: job_type.to_string(),
+                    reason,
+                })?),
+            _ => Ok(stored.to_vec()),
+        }
+    }
+
+    /// `bytes` as a stored payload.
+    pub(crate) fn payload_binary(&self, bytes: Vec<u8>) -> Binary {
+        Binary {
+            subtype: self.payload_subtype,
+            bytes,
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::MongoDbQueue;
+
+/// How claiming went for one queue and its clones since it was created or the stats were
+/// last reset, see [`MongoDbQueue::claim_stats`]. Kept in memory per process.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClaimStats {
+    /// Calls to `poll_next`, including those served by no job.
+    pub polls: u64,
+    /// Polls that claimed a job.
+    pub claims: u64,
+    /// Polls that found nothing to claim.
+    pub empty_polls: u64,
+    /// Jobs another worker claimed between being picked and being claimed: a claim pass
+    /// aimed at one jid, like the candidate picked by
+    /// [`ClaimOrder::WeightedRandom`](crate::ClaimOrder::WeightedRandom), that found it gone,
+    /// or a job leased first under [`ClaimMode::Lease`](crate::ClaimMode::Lease).
+    pub lost_races: u64,
+    /// Jobs claimed and handed back within a poll, by the [`ClaimFilter`](crate::ClaimFilter)
+    /// or because their jid was malformed.
+    pub rejected: u64,
+    /// Time spent in polls that claimed a job.
+    pub claim_time: Duration,
+}
+
+impl ClaimStats {
+    /// Lost races and rejections per claimed job.
+    pub fn retries_per_claim(&self) -> f64 {
+        if self.claims == 0 {
+            return 0.0;
+        }
+        (self.lost_races + self.rejected) as f64 / self.claims as f64
+    }
+
+    /// Mean duration of a poll that claimed a job.
+    pub fn mean_claim_latency(&self) -> Option<Duration> {
+        (self.claims > 0).then(|| self.claim_time / self.claims as u32)
+    }
+}
+
+/// Counters behind [`ClaimStats`], shared by the clones of a queue.
+#[derive(Debug, Default)]
+pub(crate) struct ClaimCounters {
+    polls: AtomicU64,
+    claims: AtomicU64,
+    empty_polls: AtomicU64,
+    lost_races: AtomicU64,
+    rejected: AtomicU64,
+    claim_time_us: AtomicU64,
+}
+
+impl ClaimCounters {
+    pub(crate) fn poll(&self, claimed: bool, elapsed: Duration) {
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        if claimed {
+            self.claims.fetch_add(1, Ordering::Relaxed);
+            self.claim_time_us
+                .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        } else {
+            self.empty_polls.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn lost_race(&self) {
+        self.lost_races.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> ClaimStats {
+        ClaimStats {
+            polls: self.polls.load(Ordering::Relaxed),
+            claims: self.claims.load(Ordering::Relaxed),
+            empty_polls: self.empty_polls.load(Ordering::Relaxed),
+            lost_races: self.lost_races.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            claim_time: Duration::from_micros(self.claim_time_us.load(Ordering::Relaxed)),
+        }
+    }
+
+    fn reset(&self) {
+        for counter in [
+            &self.polls,
+            &self.claims,
+            &self.empty_polls,
+            &self.lost_races,
+            &self.rejected,
+            &self.claim_time_us,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl MongoDbQueue {
+    /// Claim counters of this queue and its clones in this process, for tuning worker counts
+    /// and the claim order. Errors are not counted.
+    pub fn claim_stats(&self) -> ClaimStats {
+        self.claim_counters.snapshot()
+    }
+
+    /// Start counting [`Self::claim_stats`] from zero, e.g. at the start of each reporting
+    /// interval.
+    pub fn reset_claim_stats(&self) {
+        self.claim_counters.reset()
+    }
+}
@@ -0,0 +1,182 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use aide_de_camp::core::queue::QueueError;
+use anyhow::Context;
+use async_trait::async_trait;
+use mongodb::{
+    error::Error,
+    options::{ClientOptions, CreateCollectionOptions, Tls, TlsOptions},
+    Client, Collection, Database,
+};
+use tracing::{field::Empty, instrument};
+
+use crate::{uri::SafeUri, MongoDbQueue};
+
+/// Connection secrets resolved by a [`CredentialsProvider`]. Anything left unset is taken
+/// from the connection string.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Database the user is defined in, `admin` by default.
+    pub source: Option<String>,
+    /// CA certificate to verify the server with.
+    pub ca_file: Option<PathBuf>,
+    /// Client certificate and key, in one PEM file.
+    pub cert_key_file: Option<PathBuf>,
+}
+
+/// Supplies [`Credentials`] when the queue connects, so secrets can come from Vault, AWS
+/// Secrets Manager and the like instead of the connection string. Used by
+/// [`MongoDbQueue::connect`] and asked again on every [`MongoDbQueue::reconnect`], which
+/// picks up rotated secrets.
+///
+/// When an operation through the [`Queue`](aide_de_camp::core::queue::Queue) trait fails
+/// because the server rejected the credentials, e.g. once a short-lived IAM or Vault password
+/// expired, the queue reconnects with fresh credentials and retries the operation once.
+///
+/// [`Credentials`] implement this trait by always returning themselves.
+#[async_trait]
+pub trait CredentialsProvider: Send + Sync {
+    async fn credentials(&self) -> Result<Credentials, QueueError>;
+}
+
+#[async_trait]
+impl CredentialsProvider for Credentials {
+    async fn credentials(&self) -> Result<Credentials, QueueError> {
+        Ok(self.clone())
+    }
+}
+
+/// What a queue created with [`MongoDbQueue::connect`] needs to connect again.
+#[derive(Clone)]
+pub(crate) struct ConnectionSource {
+    uri: SafeUri,
+    provider: Arc<dyn CredentialsProvider>,
+}
+
+impl ConnectionSource {
+    /// A new client with freshly resolved credentials, opened on the database named in the
+    /// connection string.
+    async fn open(&self) -> Result<Database, QueueError> {
+        let credentials = self.provider.credentials().await?;
+        let mut options = ClientOptions::parse(self.uri.expose())
+            .await
+            .map_err(|error| self.uri.scrub_error(error))
+            .with_context(|| format!("Failed to parse connection string {}", self.uri))?;
+        if credentials.username.is_some()
+            || credentials.password.is_some()
+            || credentials.source.is_some()
+        {
+            let mut credential = options.credential.take().unwrap_or_default();
+            credential.username = credentials.username.or(credential.username);
+            credential.password = credentials.password.or(credential.password);
+            credential.source = credentials.source.or(credential.source);
+            options.credential = Some(credential);
+        }
+        if credentials.ca_file.is_some() || credentials.cert_key_file.is_some() {
+            let mut tls = match options.tls.take() {
+                Some(Tls::Enabled(tls)) => tls,
+                _ => TlsOptions::default(),
+            };
+            tls.ca_file_path = credentials.ca_file.or(tls.ca_file_path);
+            tls.cert_key_file_path = credentials.cert_key_file.or(tls.cert_key_file_path);
+            options.tls = Some(Tls::Enabled(tls));
+        }
+        let client = Client::with_options(options)
+            .map_err(|error| self.uri.scrub_error(error))
+            .context("Failed to create client")?;
+        Ok(client.default_database().unwrap_or(client.database("adc")))
+    }
+}
+
+impl MongoDbQueue {
+    /// Like [`Self::new`], with the username, password and certificates taken from `provider`
+    /// rather than `uri`.
+    #[instrument(skip_all, err, fields(uri = Empty))]
+    pub async fn connect(
+        uri: impl Into<SafeUri>,
+        provider: impl CredentialsProvider + 'static,
+    ) -> Result<Self, QueueError> {
+        let source = ConnectionSource {
+            uri: uri.into(),
+            provider: Arc::new(provider),
+        };
+        tracing::Span::current().record("uri", tracing::field::display(&source.uri));
+        let mut queue = Self::with_database(source.open().await?);
+        queue.connection_source = Some(source);
+        Ok(queue)
+    }
+
+    /// Replace the client with a new one, asking the [`CredentialsProvider`] for credentials
+    /// again. Every clone of the queue, including claimed job handles, switches to the new
+    /// client. Does nothing for queues not created with [`Self::connect`].
+    #[instrument(skip_all, err)]
+    pub async fn reconnect(&self) -> Result<(), QueueError> {
+        if let Some(source) = &self.connection_source {
+            self.database.replace(source.open().await?);
+        }
+        Ok(())
+    }
+}
+
+/// The database of a queue, shared by all its clones so [`MongoDbQueue::reconnect`] reaches
+/// them all.
+#[derive(Clone)]
+pub(crate) struct SharedDatabase {
+    database: Arc<RwLock<Database>>,
+    collection_prefix: Option<Arc<str>>,
+}
+
+impl SharedDatabase {
+    pub(crate) fn new(database: Database) -> Self {
+        Self {
+            database: Arc::new(RwLock::new(database)),
+            collection_prefix: None,
+        }
+    }
+
+    /// Name the collections with `prefix` in place of `adc_`.
+    pub(crate) fn with_collection_prefix(mut self, prefix: &str) -> Self {
+        self.collection_prefix = Some(prefix.into());
+        self
+    }
+
+    fn get(&self) -> Database {
+        self.database.read().unwrap().clone()
+    }
+
+    fn replace(&self, database: Database) {
+        *self.database.write().unwrap() = database;
+    }
+
+    /// The actual name of the collection called `name` by default, e.g. in a `$lookup`.
+    pub(crate) fn collection_name(&self, name: &str) -> String {
+        match (&self.collection_prefix, name.strip_prefix("adc_")) {
+            (Some(prefix), Some(name)) => format!("{prefix}{name}"),
+            _ => name.to_string(),
+        }
+    }
+
+    pub(crate) fn collection<T>(&self, name: &str) -> Collection<T> {
+        self.get().collection(&self.collection_name(name))
+    }
+
+    pub(crate) async fn create_collection(
+        &self,
+        name: &str,
+        options: impl Into<Option<CreateCollectionOptions>>,
+    ) -> Result<(), Error> {
+        self.get()
+            .create_collection(&self.collection_name(name), options)
+            .await
+    }
+
+    #[cfg(test)]
+    pub(crate) async fn drop(&self) -> Result<(), Error> {
+        self.get().drop(None).await
+    }
+}
@@ -0,0 +1,215 @@
+use std::{collections::BTreeMap, str::FromStr};
+
+use aide_de_camp::core::{
+    bincode::Encode, job_processor::JobProcessor, new_xid, queue::QueueError, Duration, Xid,
+};
+use anyhow::{anyhow, Context};
+use bson::{doc, Document};
+use chrono::Utc;
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use tracing::instrument;
+
+use crate::{
+    error::MongoDbQueueError,
+    events,
+    hooks::{ErrorContext, PayloadMerger},
+    status::JobStatus,
+    trace::traced,
+    types::JobRow,
+    MongoDbQueue,
+};
+
+/// How often [`MongoDbQueue::schedule_debounced`] retries merging into a pending job that was
+/// changed concurrently by another producer.
+pub const MAX_MERGE_ATTEMPTS: usize = 10;
+
+impl MongoDbQueue {
+    /// Schedule a job to run once `window` has passed without another job of the same type
+    /// being scheduled with the same `key`. While a job with `key` is pending, scheduling again
+    /// replaces its payload and pushes it back to `window` from now instead of adding another
+    /// job, e.g. to run one "reindex user X" after a burst of edits. Returns the id of the
+    /// pending job.
+    ///
+    /// With a [`PayloadMerger`] registered for `J`, the payloads are combined instead of the
+    /// new one replacing the pending one.
+    ///
+    /// Two producers debouncing the same new key at the very same moment can still add a job
+    /// each.
+    #[instrument(skip_all, err, ret, fields(job_type = J::name(), key = key))]
+    pub async fn schedule_debounced<J>(
+        &self,
+        payload: J::Payload,
+        key: &str,
+        window: Duration,
+    ) -> Result<Xid, QueueError>
+    where
+        J: JobProcessor + 'static,
+        J::Payload: Encode,
+    {
+        let payload = self.encode_payload::<J>(&payload);
+        let result = async {
+            let payload = self.payload_binary(payload?);
+            self.check_job_type(J::name()).await?;
+            let now = Utc::now();
+            let scheduled_at = bson::DateTime::from_chrono(now + window);
+            let new_jid = new_xid();
+            let row = JobRow {
+                jid: format!("{}", new_jid),
+                queue: self.queue_name.clone(),
+                job_type: J::name().to_string(),
+                payload: payload.clone(),
+                retries: 0,
+                priority: 0,
+                scheduled_at,
+                enqueued_at: bson::DateTime::from_chrono(now),
+                started_at: None,
+                recurring_key: None,
+                cancel_requested: false,
+                tenant: None,
+                correlation_id: None,
+                cancelled_at: None,
+                cancelled_by: None,
+                completion_token: None,
+                shadow_of: None,
+                region: None,
+                deadline: None,
+                dedup_key: Some(key.to_string()),
+                blocked_by: Vec::new(),
+                annotations: BTreeMap::new(),
+                headers: BTreeMap::new(),
+                content_type: self.content_type_of(J::name(), None),
+                handler_version: None,
+                parked: None,
+                status: JobStatus::Pending,
+                dead_reason: None,
+                tags: Vec::new(),
+                attempts: 0,
+                public_id: self.public_id(&new_jid, J::name()),
+                namespace: self.namespace.clone(),
+                owner: self.owner_of(J::name(), None)?,
+                extra: Document::new(),
+            };
+            let filter = self.scoped(doc! {
+                "queue": &self.queue_name,
+                "job_type": J::name(),
+                "dedup_key": key,
+                "status": JobStatus::Pending,
+            });
+            let pending = match self.payload_mergers.get(J::name()) {
+                Some(merger) => self.merge_debounced(merger.as_ref(), filter, &row).await?,
+                None => {
+                    let mut on_insert = bson::to_document(&row).context("Failed to encode job")?;
+                    on_insert.remove("payload");
+                    on_insert.remove("scheduled_at");
+                    let update = doc! {
+                        "$set": { "payload": payload, "scheduled_at": scheduled_at },
+                        "$setOnInsert": on_insert,
+                    };
+                    self.upsert_pending(filter, update).await?
+                }
+            };
+            if pending.jid == row.jid {
+                events::scheduled(self, &pending);
+            }
+            let jid = Xid::from_str(&pending.jid)
+                .with_context(|| format!("Malformed jid {:?}", pending.jid))?;
+            Ok(jid)
+        }
+        .await;
+        let context = ErrorContext {
+            operation: "schedule_debounced",
+            jid: None,
+            job_type: Some(J::name()),
+            correlation_id: None,
+        };
+        self.reported(&context, result)
+    }
+
+    /// Fold the payload of `row` into the pending job matching `filter`, or add `row` if there
+    /// is none. The pending payload is only replaced if it didn't change since it was read.
+    async fn merge_debounced(
+        &self,
+        merger: &dyn PayloadMerger,
+        filter: Document,
+        row: &JobRow,
+    ) -> Result<JobRow, QueueError> {
+        let collection = self.collection();
+        for _ in 0..MAX_MERGE_ATTEMPTS {
+            let pending = traced(
+                &collection,
+                "find_one",
+                collection.find_one(filter.clone(), None),
+            )
+            .await
+            .context("Failed to find pending job")?;
+
+            match pending {
+                Some(pending) => {
+                    let merged = merger
+                        .merge(&row.job_type, &pending.payload.bytes, &row.payload.bytes)
+                        .map_err(|reason| MongoDbQueueError::InvalidPayload {
+                            job_type: row.job_type.clone(),
+                            reason,
+                        })?;
+                    self.check_payload_size(&row.job_type, &merged)?;
+                    let mut unchanged = filter.clone();
+                    unchanged.insert("jid", &pending.jid);
+                    unchanged.insert("payload", pending.payload);
+                    let merged = self.payload_binary(merged);
+                    let options = FindOneAndUpdateOptions::builder()
+                        .return_document(ReturnDocument::After)
+                        .build();
+                    let updated = traced(
+                        &collection,
+                        "find_one_and_update",
+                        collection.find_one_and_update(
+                            unchanged,
+                            doc! { "$set": { "payload": merged, "scheduled_at": row.scheduled_at } },
+                            options,
+                        ),
+                    )
+                    .await
+                    .context("Failed to update pending job")?;
+                    if let Some(updated) = updated {
+                        return Ok(updated);
+                    }
+                }
+                None => {
+                    let on_insert = bson::to_document(row).context("Failed to encode job")?;
+                    let inserted = self
+                        .upsert_pending(filter.clone(), doc! { "$setOnInsert": on_insert })
+                        .await?;
+                    // Someone else added a job for this key first, merge into theirs.
+                    if inserted.jid == row.jid {
+                        return Ok(inserted);
+                    }
+                }
+            }
+        }
+        Err(anyhow!(
+            "Gave up merging into pending job after {MAX_MERGE_ATTEMPTS} concurrent updates"
+        )
+        .into())
+    }
+
+    async fn upsert_pending(
+        &self,
+        filter: Document,
+        update: Document,
+    ) -> Result<JobRow, QueueError> {
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+        let collection = self.collection();
+        let row = traced(
+            &collection,
+            "find_one_and_update",
+            collection.find_one_and_update(filter, update, options),
+        )
+        .await
+        .context("Failed to add job to the queue")?
+        .context("Upsert returned no job")?;
+        Ok(row)
+    }
+}
@@ -0,0 +1,31 @@
+use bson::{doc, Bson};
+
+use crate::MongoDbQueue;
+
+/// Lowers the priority of a job each time it fails, see [`MongoDbQueue::with_priority_decay`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PriorityDecay {
+    /// Priority taken off per failed attempt.
+    pub step: i8,
+    /// Decay stops here. Jobs scheduled below it keep their priority.
+    pub floor: i8,
+}
+
+impl PriorityDecay {
+    /// Update expression for the decayed priority, for use in an aggregation pipeline update.
+    pub(crate) fn priority(&self) -> Bson {
+        let floor = doc! { "$min": ["$priority", self.floor as i64] };
+        let decayed = doc! { "$subtract": ["$priority", self.step as i64] };
+        Bson::Document(doc! { "$max": [decayed, floor] })
+    }
+}
+
+impl MongoDbQueue {
+    /// Lower the priority of a job by `decay.step` every time it fails, down to `decay.floor`,
+    /// so a flapping high priority job stops crowding out healthy work of the same priority
+    /// while it keeps retrying. The priority is not restored when the job later succeeds.
+    pub fn with_priority_decay(mut self, decay: PriorityDecay) -> Self {
+        self.priority_decay = Some(decay);
+        self
+    }
+}
@@ -0,0 +1,208 @@
+use aide_de_camp::core::queue::QueueError;
+use anyhow::Context;
+use bson::{doc, Document};
+use futures::TryStreamExt;
+use mongodb::{options::FindOptions, ClientSession};
+
+use crate::{trace::traced, MongoDbQueue};
+
+// Jobs scheduled with `ScheduleOptions::depends_on` carry the ids of the jobs they wait for in
+// `blocked_by` and are not claimed until it is empty. Completing a job pulls its id from the
+// jobs waiting for it, in the transaction that removes it. A job that ends up in the dead
+// queue, or that isn't known at all, keeps its dependents waiting until they are cancelled.
+
+/// Collections holding jobs that can wait for, or be waited for by, other jobs.
+const WAITING_COLLECTIONS: [&str; 2] = ["adc_queue", "adc_cold_queue"];
+
+/// How far up a chain of dependencies priority inheritance goes.
+pub const MAX_INHERITANCE_DEPTH: usize = 16;
+
+impl MongoDbQueue {
+    /// The jobs of `jids` that are still in the queue, including cold storage.
+    pub(crate) async fn pending_jids(&self, jids: &[String]) -> Result<Vec<String>, QueueError> {
+        let mut pending = Vec::new();
+        if jids.is_empty() {
+            return Ok(pending);
+        }
+        for collection in WAITING_COLLECTIONS {
+            let rows = self
+                .find_jobs(
+                    collection,
+                    doc! { "jid": { "$in": jids } },
+                    doc! { "jid": 1 },
+                )
+                .await?;
+            pending.extend(
+                rows.iter()
+                    .filter_map(|row| row.get_str("jid").ok().map(str::to_string)),
+            );
+        }
+        Ok(pending)
+    }
+
+    /// The jobs of `depends_on` a new job has to wait for: the `pending` ones still in the
+    /// queue, and those that aren't known to have completed, e.g. because they are dead or
+    /// never existed.
+    pub(crate) async fn unfinished_dependencies(
+        &self,
+        depends_on: &[String],
+        pending: &[String],
+    ) -> Result<Vec<String>, QueueError> {
+        let mut unfinished = Vec::new();
+        for jid in depends_on {
+            let completed = !pending.contains(jid)
+                && match jid.parse() {
+                    Ok(job_id) => self.completion_record(job_id).await?.is_some(),
+                    Err(_) => false,
+                };
+            if !completed {
+                unfinished.push(jid.clone());
+            }
+        }
+        Ok(unfinished)
+    }
+
+    /// Stop `dependent` from waiting for the jobs of `waiting`, which were in the queue before
+    /// it was added, that completed before it was added and so never released it. A job that
+    /// left the queue in that time completed unless it is in the dead queue or was cancelled.
+    pub(crate) async fn drop_finished_dependencies(
+        &self,
+        dependent: &str,
+        waiting: &[String],
+    ) -> Result<(), QueueError> {
+        if waiting.is_empty() {
+            return Ok(());
+        }
+        let pending = self.pending_jids(waiting).await?;
+        let gone: Vec<&String> = waiting
+            .iter()
+            .filter(|jid| !pending.contains(jid))
+            .collect();
+        if gone.is_empty() {
+            return Ok(());
+        }
+        let mut unfinished = Vec::new();
+        for collection in ["adc_dead_queue", "adc_cancelled"] {
+            let rows = self
+                .find_jobs(
+                    collection,
+                    doc! { "jid": { "$in": &gone } },
+                    doc! { "jid": 1 },
+                )
+                .await?;
+            unfinished.extend(
+                rows.iter()
+                    .filter_map(|row| row.get_str("jid").ok().map(str::to_string)),
+            );
+        }
+        let finished: Vec<&String> = gone
+            .into_iter()
+            .filter(|jid| !unfinished.contains(jid))
+            .collect();
+        if finished.is_empty() {
+            return Ok(());
+        }
+        for collection in WAITING_COLLECTIONS {
+            let collection = self.database.collection::<Document>(collection);
+            traced(
+                &collection,
+                "update_one",
+                collection.update_one(
+                    doc! { "jid": dependent },
+                    doc! { "$pull": { "blocked_by": { "$in": &finished } } },
+                    None,
+                ),
+            )
+            .await
+            .context("Failed to update job dependencies")?;
+        }
+        Ok(())
+    }
+
+    /// Let jobs waiting for `jids` run once their other dependencies are done too, as part of
+    /// the transaction of `session` that removes them.
+    pub(crate) async fn release_dependents(
+        &self,
+        jids: &[&str],
+        session: &mut ClientSession,
+    ) -> Result<(), QueueError> {
+        for collection in WAITING_COLLECTIONS {
+            let collection = self.database.collection::<Document>(collection);
+            traced(
+                &collection,
+                "update_many",
+                collection.update_many_with_session(
+                    doc! { "blocked_by": { "$in": jids } },
+                    doc! { "$pull": { "blocked_by": { "$in": jids } } },
+                    None,
+                    session,
+                ),
+            )
+            .await
+            .context("Failed to release dependent jobs")?;
+        }
+        Ok(())
+    }
+
+    /// Raise the jobs in `depends_on`, and the ones they wait for in turn, to at least
+    /// `priority`, so a low priority job doesn't hold up a high priority chain.
+    pub(crate) async fn inherit_priority(
+        &self,
+        depends_on: &[String],
+        priority: i64,
+    ) -> Result<(), QueueError> {
+        let mut parents = depends_on.to_vec();
+        for _ in 0..MAX_INHERITANCE_DEPTH {
+            if parents.is_empty() {
+                break;
+            }
+            let mut rows = Vec::new();
+            for name in WAITING_COLLECTIONS {
+                let collection = self.database.collection::<Document>(name);
+                traced(
+                    &collection,
+                    "update_many",
+                    collection.update_many(
+                        doc! { "jid": { "$in": &parents }, "priority": { "$lt": priority } },
+                        doc! { "$set": { "priority": priority } },
+                        None,
+                    ),
+                )
+                .await
+                .context("Failed to raise priority of dependencies")?;
+                rows.extend(
+                    self.find_jobs(
+                        name,
+                        doc! { "jid": { "$in": &parents }, "blocked_by.0": { "$exists": true } },
+                        doc! { "blocked_by": 1 },
+                    )
+                    .await?,
+                );
+            }
+            parents = rows
+                .iter()
+                .filter_map(|row| row.get_array("blocked_by").ok())
+                .flatten()
+                .filter_map(|jid| jid.as_str().map(str::to_string))
+                .collect();
+        }
+        Ok(())
+    }
+
+    async fn find_jobs(
+        &self,
+        collection: &str,
+        filter: Document,
+        projection: Document,
+    ) -> Result<Vec<Document>, QueueError> {
+        let collection = self.database.collection::<Document>(collection);
+        let options = FindOptions::builder().projection(projection).build();
+        let rows = traced(&collection, "find", collection.find(filter, options))
+            .await
+            .context("Failed to find jobs")?
+            .try_collect()
+            .await
+            .context("Failed to read jobs")?;
+        Ok(rows)
+    }
+}
@@ -0,0 +1,101 @@
+use aide_de_camp::core::queue::QueueError;
+use anyhow::Context;
+use bson::{doc, Document};
+use chrono::Utc;
+
+use crate::{hooks::ErrorContext, status::JobStatus, trace::traced, MongoDbQueue};
+
+/// What happens to a claimed job whose handle is dropped without an outcome, e.g. after a
+/// panic in the processor glue or an early return. Set with
+/// [`MongoDbQueue::with_drop_behavior`].
+///
+/// Anything but [`DropBehavior::Keep`] spawns the update on the current Tokio runtime, so it
+/// may not run if the process is shutting down.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DropBehavior {
+    /// Leave the job claimed.
+    #[default]
+    Keep,
+    /// Put the job back without counting the attempt, like
+    /// [`MongoDbJobHandle::release`](crate::job_handle::MongoDbJobHandle::release).
+    Release,
+    /// Put the job back as a failed attempt. Unlike a failure through the handle, this does
+    /// not spend the [`RetryBudget`](crate::RetryBudget).
+    Fail,
+    /// Leave the job claimed with a `dropped` annotation holding the time it was dropped.
+    Mark,
+}
+
+/// Applies the [`DropBehavior`] when a job handle is dropped while still armed.
+pub(crate) struct DropGuard {
+    behavior: DropBehavior,
+    jid: String,
+    job_type: String,
+    attempts: i64,
+    /// Taken when the handle reaches an outcome.
+    queue: Option<MongoDbQueue>,
+}
+
+impl DropGuard {
+    pub(crate) fn new(queue: &MongoDbQueue, jid: &str, job_type: &str, attempts: i64) -> Self {
+        let behavior = queue.drop_behavior;
+        Self {
+            behavior,
+            jid: jid.to_string(),
+            job_type: job_type.to_string(),
+            attempts,
+            queue: (behavior != DropBehavior::Keep).then(|| queue.clone()),
+        }
+    }
+
+    pub(crate) fn disarm(&mut self) {
+        self.queue = None;
+    }
+
+    fn update(&self) -> Option<Document> {
+        let requeue = doc! { "status": JobStatus::Pending, "started_at": None::<bson::DateTime> };
+        match self.behavior {
+            DropBehavior::Keep => None,
+            DropBehavior::Release => Some(doc! { "$set": requeue, "$inc": { "attempts": -1 } }),
+            DropBehavior::Fail => Some(doc! { "$set": requeue, "$inc": { "retries": 1 } }),
+            DropBehavior::Mark => Some(doc! {
+                "$set": { "annotations.dropped": Utc::now().to_rfc3339() },
+            }),
+        }
+    }
+}
+
+impl Drop for DropGuard {
+    fn drop(&mut self) {
+        let (Some(queue), Some(update)) = (self.queue.take(), self.update()) else {
+            return;
+        };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            tracing::warn!(jid = %self.jid, "Job handle dropped outside a runtime, job stays claimed");
+            return;
+        };
+        let jid = std::mem::take(&mut self.jid);
+        let job_type = std::mem::take(&mut self.job_type);
+        // Only touch the job if it is still held by this claim.
+        let filter = queue
+            .scoped(doc! { "jid": &jid, "status": JobStatus::Running, "attempts": self.attempts });
+        runtime.spawn(async move {
+            let collection = queue.collection();
+            let result: Result<_, QueueError> = traced(
+                &collection,
+                "update_one",
+                collection.update_one(filter, update, None),
+            )
+            .await
+            .context("Failed to handle dropped job")
+            .map_err(Into::into);
+            let context = ErrorContext {
+                operation: "drop",
+                jid: Some(&jid),
+                job_type: Some(&job_type),
+                correlation_id: None,
+            };
+            let _ = queue.reported(&context, result);
+        });
+    }
+}
@@ -0,0 +1,90 @@
+use std::str::FromStr;
+
+use aide_de_camp::core::{queue::QueueError, DateTime, Duration, Xid};
+use anyhow::Context;
+use bson::{doc, Document};
+use chrono::Utc;
+use futures::TryStreamExt;
+use serde::Deserialize;
+use tracing::instrument;
+
+use crate::{status::JobStatus, trace::traced, MongoDbQueue};
+
+/// Pending jobs with identical payloads, see [`MongoDbQueue::find_duplicates`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateCluster {
+    /// In the order they were enqueued.
+    pub jids: Vec<Xid>,
+    pub first_enqueued_at: DateTime,
+    pub last_enqueued_at: DateTime,
+}
+
+#[derive(Deserialize)]
+struct ClusterRow {
+    jids: Vec<String>,
+    first_enqueued_at: bson::DateTime,
+    last_enqueued_at: bson::DateTime,
+}
+
+impl MongoDbQueue {
+    /// Group pending jobs of `job_type` enqueued within the last `window` by payload and report
+    /// the groups with more than one job, largest first. Meant for tracking down producers that
+    /// enqueue the same work twice; nothing is removed.
+    #[instrument(skip_all, err, fields(job_type = job_type))]
+    pub async fn find_duplicates(
+        &self,
+        job_type: &str,
+        window: Duration,
+    ) -> Result<Vec<DuplicateCluster>, QueueError> {
+        let since = bson::DateTime::from_chrono(Utc::now() - window);
+        let pipeline = vec![
+            doc! { "$match": self.scoped(doc! {
+                "queue": &self.queue_name,
+                "job_type": job_type,
+                "status": JobStatus::Pending,
+                "enqueued_at": { "$gte": since },
+            }) },
+            doc! { "$sort": { "enqueued_at": 1 } },
+            doc! { "$group": {
+                "_id": "$payload",
+                "jids": { "$push": "$jid" },
+                "first_enqueued_at": { "$min": "$enqueued_at" },
+                "last_enqueued_at": { "$max": "$enqueued_at" },
+            } },
+            doc! { "$match": { "jids.1": { "$exists": true } } },
+            doc! { "$project": { "_id": 0 } },
+            doc! { "$sort": { "first_enqueued_at": 1 } },
+        ];
+        let collection = self.database.collection::<Document>("adc_queue");
+        let rows: Vec<Document> = traced(
+            &collection,
+            "aggregate",
+            collection.aggregate(pipeline, None),
+        )
+        .await
+        .context("Failed to aggregate duplicates")?
+        .try_collect()
+        .await
+        .context("Failed to read duplicates")?;
+
+        let mut clusters = rows
+            .into_iter()
+            .map(|row| {
+                let row: ClusterRow =
+                    bson::from_document(row).context("Failed to decode duplicates")?;
+                let jids = row
+                    .jids
+                    .iter()
+                    .map(|jid| Xid::from_str(jid).with_context(|| format!("Malformed jid {jid:?}")))
+                    .collect::<Result<_, _>>()?;
+                Ok(DuplicateCluster {
+                    jids,
+                    first_enqueued_at: row.first_enqueued_at.to_chrono(),
+                    last_enqueued_at: row.last_enqueued_at.to_chrono(),
+                })
+            })
+            .collect::<Result<Vec<_>, QueueError>>()?;
+        clusters.sort_by_key(|cluster| std::cmp::Reverse(cluster.jids.len()));
+        Ok(clusters)
+    }
+}
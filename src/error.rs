@@ -0,0 +1,72 @@
+use aide_de_camp::core::queue::QueueError;
+use thiserror::Error;
+
+use crate::{quota::QuotaKind, receipts::JobOutcome};
+
+/// Errors specific to the MongoDB backend.
+///
+/// The `Queue` trait only knows about [`QueueError`], so these are carried inside
+/// `QueueError::Other`. Use [`MongoDbQueueError::from_queue_error`] to get them back.
+#[derive(Error, Debug)]
+pub enum MongoDbQueueError {
+    #[error("Invalid payload for job type {job_type}: {reason}")]
+    InvalidPayload { job_type: String, reason: String },
+    #[error(
+        "Payload for job type {job_type} is {size} bytes, more than the limit of {limit} bytes"
+    )]
+    PayloadTooLarge {
+        job_type: String,
+        size: usize,
+        limit: usize,
+    },
+    #[error("Invalid schedule expression {expression:?}: {reason}")]
+    InvalidSchedule { expression: String, reason: String },
+    #[error("Invalid month {year}-{month}")]
+    InvalidMonth { year: i32, month: u32 },
+    #[error("Unknown timezone {0:?}")]
+    InvalidTimezone(String),
+    #[error("Recurring job {0} not found")]
+    RecurringJobNotFound(String),
+    #[error("Recurring job {0} already exists")]
+    RecurringJobExists(String),
+    #[error("Job type {0} is not registered")]
+    UnknownJobType(String),
+    #[error("Job type {job_type} scheduled without an owner")]
+    MissingOwner { job_type: String },
+    #[error("Invalid header name {0:?}")]
+    InvalidHeader(String),
+    #[error("Tenant {tenant} exceeded its quota of {limit} {kind}")]
+    QuotaExceeded {
+        tenant: String,
+        kind: QuotaKind,
+        limit: u64,
+    },
+    #[error("Job type {job_type} has {limit} or more pending jobs")]
+    PendingBudgetExceeded { job_type: String, limit: u64 },
+    #[error("Job {jid} is no longer held by this claim")]
+    ClaimLost { jid: String },
+    #[error("Job {jid} did not finish in time")]
+    JobTimedOut { jid: String },
+    #[error("Job {jid} did not complete: {outcome:?}")]
+    JobNotCompleted { jid: String, outcome: JobOutcome },
+    #[error("Job {jid} completed without a result")]
+    MissingResult { jid: String },
+    #[error("Job results are only kept with the archive enabled")]
+    ArchiveRequired,
+}
+
+impl MongoDbQueueError {
+    /// Returns the backend error wrapped in `error`, if there is one.
+    pub fn from_queue_error(error: &QueueError) -> Option<&Self> {
+        match error {
+            QueueError::Other(inner) => inner.downcast_ref(),
+            _ => None,
+        }
+    }
+}
+
+impl From<MongoDbQueueError> for QueueError {
+    fn from(error: MongoDbQueueError) -> Self {
+        QueueError::Other(error.into())
+    }
+}
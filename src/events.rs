@@ -0,0 +1,339 @@
+//! Job lifecycle events.
+//!
+//! Every state change of a job emits a `tracing` event with the target
+//! `aide_de_camp_mongodb::events`, one of the names below and these fields, so log pipelines
+//! can alert on queue activity without parsing messages:
+//!
+//! | field            | events                   |                                        |
+//! |------------------|--------------------------|----------------------------------------|
+//! | `jid`            | all                      |                                        |
+//! | `public_id`      | all                      | only with an `IdGenerator`             |
+//! | `job_type`       | all                      |                                        |
+//! | `queue`          | all                      |                                        |
+//! | `priority`       | all                      |                                        |
+//! | `status`         | all                      | after the event, see `JobStatus`       |
+//! | `attempt`        | all                      | number of times the job was claimed    |
+//! | `scheduled_at`   | all                      | RFC 3339                               |
+//! | `correlation_id` | all                      | only when set                          |
+//! | `tenant`         | all                      | only when set                          |
+//! | `recurring_key`  | all                      | only for occurrences of recurring jobs |
+//! | `duration_ms`    | completed, failed, dead  | time since the job was claimed         |
+//!
+//! These names and fields are part of the public API and only change in a major release.
+//!
+//! With [`MongoDbQueue::with_event_log`] the events are also stored in the `adc_events`
+//! collection and can be read back per job with [`MongoDbQueue::job_events`]. With
+//! [`MongoDbQueue::with_cloud_events`] they are also emitted as CloudEvents JSON, see
+//! [`CloudEvent`](crate::CloudEvent).
+
+use std::sync::{Arc, Mutex};
+
+use aide_de_camp::core::{queue::QueueError, DateTime, Duration, Xid};
+use anyhow::Context;
+use bson::{doc, Document};
+use chrono::Utc;
+use futures::TryStreamExt;
+use mongodb::{
+    error::ErrorKind,
+    options::{FindOptions, IndexOptions, InsertManyOptions, TimeseriesGranularity},
+    IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::instrument;
+
+use crate::{
+    hooks::ErrorContext,
+    status::JobStatus,
+    trace::traced,
+    types::{EventMeta, EventRow, JobRow},
+    MongoDbQueue,
+};
+
+/// Collection the event log is kept in.
+pub const EVENTS_COLLECTION: &str = "adc_events";
+
+/// Number of events waiting to be stored before new ones are dropped, see
+/// [`MongoDbQueue::with_event_log`].
+pub const EVENT_LOG_CAPACITY: usize = 1024;
+
+/// Maximum number of events stored in one write.
+pub const EVENT_LOG_BATCH_SIZE: usize = 100;
+
+/// A job was added to the queue.
+pub const JOB_SCHEDULED: &str = "job.scheduled";
+/// A worker claimed a job.
+pub const JOB_CLAIMED: &str = "job.claimed";
+/// A job finished successfully and was removed from the queue.
+pub const JOB_COMPLETED: &str = "job.completed";
+/// A job failed and was put back to be retried.
+pub const JOB_FAILED: &str = "job.failed";
+/// A job was moved to the dead queue.
+pub const JOB_DEAD: &str = "job.dead";
+
+macro_rules! job_event {
+    ($level:expr, $name:expr, $row:expr $(, $field:ident = $value:expr)*) => {
+        tracing::event!(
+            name: $name,
+            target: "aide_de_camp_mongodb::events",
+            $level,
+            jid = %$row.jid,
+            public_id = $row.public_id.as_deref(),
+            job_type = %$row.job_type,
+            queue = %$row.queue,
+            priority = $row.priority,
+            status = %$row.status,
+            attempt = $row.attempts,
+            scheduled_at = %$row.scheduled_at.to_chrono().to_rfc3339(),
+            correlation_id = $row.correlation_id.as_deref(),
+            tenant = $row.tenant.as_deref(),
+            recurring_key = $row.recurring_key.as_deref(),
+            $($field = $value,)*
+            "{}",
+            $name
+        )
+    };
+}
+
+pub(crate) fn scheduled(queue: &MongoDbQueue, row: &JobRow) {
+    job_event!(tracing::Level::INFO, JOB_SCHEDULED, row);
+    queue.log_event(JOB_SCHEDULED, row, None);
+}
+
+pub(crate) fn claimed(queue: &MongoDbQueue, row: &JobRow) {
+    job_event!(tracing::Level::INFO, JOB_CLAIMED, row);
+    queue.log_event(JOB_CLAIMED, row, None);
+}
+
+pub(crate) fn completed(queue: &MongoDbQueue, row: &JobRow) {
+    let duration_ms = duration_ms(row);
+    job_event!(
+        tracing::Level::INFO,
+        JOB_COMPLETED,
+        row,
+        duration_ms = duration_ms
+    );
+    queue.log_event(JOB_COMPLETED, row, duration_ms);
+}
+
+pub(crate) fn failed(queue: &MongoDbQueue, row: &JobRow) {
+    let duration_ms = duration_ms(row);
+    job_event!(
+        tracing::Level::WARN,
+        JOB_FAILED,
+        row,
+        duration_ms = duration_ms
+    );
+    queue.log_event(JOB_FAILED, row, duration_ms);
+}
+
+pub(crate) fn dead(queue: &MongoDbQueue, row: &JobRow) {
+    let duration_ms = duration_ms(row);
+    job_event!(
+        tracing::Level::WARN,
+        JOB_DEAD,
+        row,
+        duration_ms = duration_ms
+    );
+    queue.log_event(JOB_DEAD, row, duration_ms);
+}
+
+fn duration_ms(row: &JobRow) -> Option<i64> {
+    row.started_at
+        .map(|started_at| (Utc::now() - started_at.to_chrono()).num_milliseconds())
+}
+
+/// The events waiting to be stored, shared by the clones of a queue. The writer is started with
+/// the first event, so it sees the queue fully configured, and stops when the last clone is
+/// dropped.
+pub(crate) struct EventLog {
+    sender: mpsc::Sender<EventRow>,
+    receiver: Mutex<Option<mpsc::Receiver<EventRow>>>,
+}
+
+/// A stored lifecycle event of a job, see [`MongoDbQueue::job_events`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobEvent {
+    pub at: DateTime,
+    /// One of the event names in this module, e.g. [`JOB_CLAIMED`].
+    pub name: String,
+    pub jid: String,
+    pub job_type: String,
+    pub queue: String,
+    /// Status after the event.
+    pub status: JobStatus,
+    pub attempt: u32,
+    pub duration_ms: Option<i64>,
+    /// See [`ScheduleOptions::correlation_id`](crate::ScheduleOptions::correlation_id).
+    pub correlation_id: Option<String>,
+}
+
+impl MongoDbQueue {
+    /// Store every lifecycle event in the `adc_events` collection, next to emitting it through
+    /// `tracing`. Events are written in the background in batches of up to
+    /// [`EVENT_LOG_BATCH_SIZE`]; a failed write is handed to the
+    /// [`ErrorReporter`](crate::ErrorReporter) without failing the job, and so are events
+    /// dropped while [`EVENT_LOG_CAPACITY`] of them are waiting. Create the collection with
+    /// [`Self::create_event_log`] first to get a time-series collection with expiry.
+    pub fn with_event_log(mut self) -> Self {
+        let (sender, receiver) = mpsc::channel(EVENT_LOG_CAPACITY);
+        self.event_log = Some(Arc::new(EventLog {
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+        }));
+        self
+    }
+
+    /// Create the collection [`Self::with_event_log`] writes to, dropping events older than
+    /// `retention`. It is a time-series collection on servers that support them (MongoDB 5.0
+    /// and later) and a plain collection with a TTL index elsewhere.
+    #[instrument(skip_all, err)]
+    pub async fn create_event_log(&self, retention: Duration) -> Result<(), QueueError> {
+        self.create_time_series(
+            EVENTS_COLLECTION,
+            "at",
+            TimeseriesGranularity::Seconds,
+            retention,
+        )
+        .await?;
+
+        // For `job_events`.
+        let index = IndexModel::builder()
+            .keys(doc! { "jid": 1, "at": 1 })
+            .options(IndexOptions::builder().name("jid_at".to_string()).build())
+            .build();
+        let collection = self.database.collection::<Document>(EVENTS_COLLECTION);
+        match collection.create_indexes([index], None).await {
+            Ok(_) => Ok(()),
+            // Time-series collections before MongoDB 6.0 only index the time and meta fields.
+            Err(error) if matches!(error.kind.as_ref(), ErrorKind::Command(_)) => {
+                tracing::debug!(?error, "Index on event jids unavailable");
+                Ok(())
+            }
+            Err(error) => Err(anyhow::Error::new(error)
+                .context(format!("Failed to create index on {EVENTS_COLLECTION}"))
+                .into()),
+        }
+    }
+
+    /// Stored events of a job, oldest first.
+    #[instrument(skip_all, err, fields(jid = %job_id))]
+    pub async fn job_events(&self, job_id: Xid) -> Result<Vec<JobEvent>, QueueError> {
+        let collection = self.database.collection::<EventRow>(EVENTS_COLLECTION);
+        let rows: Vec<EventRow> = traced(
+            &collection,
+            "find",
+            collection.find(
+                doc! { "jid": job_id.to_string(), "meta.namespace": self.namespace.as_deref() },
+                FindOptions::builder().sort(doc! { "at": 1 }).build(),
+            ),
+        )
+        .await
+        .context("Failed to read job events")?
+        .try_collect()
+        .await
+        .context("Failed to read job events")?;
+        Ok(rows.into_iter().map(EventRow::into_event).collect())
+    }
+
+    fn log_event(&self, name: &'static str, row: &JobRow, duration_ms: Option<i64>) {
+        // Millisecond precision, so the CloudEvent and the stored event agree.
+        let at = bson::DateTime::now();
+        self.emit_cloud_event(name, row, at.to_chrono(), duration_ms);
+        let Some(log) = &self.event_log else {
+            return;
+        };
+        let event = EventRow {
+            at,
+            meta: EventMeta {
+                queue: row.queue.clone(),
+                job_type: row.job_type.clone(),
+                namespace: self.namespace.clone(),
+            },
+            name: name.to_string(),
+            jid: row.jid.clone(),
+            status: row.status,
+            attempt: row.attempts,
+            duration_ms,
+            correlation_id: row.correlation_id.clone(),
+        };
+        let receiver = log.receiver.lock().unwrap().take();
+        if let Some(receiver) = receiver {
+            match tokio::runtime::Handle::try_current() {
+                Ok(runtime) => {
+                    // Without the log, so the writer doesn't keep its own channel open.
+                    let mut writer = self.clone();
+                    writer.event_log = None;
+                    runtime.spawn(writer.write_events(receiver));
+                }
+                Err(_) => {
+                    *log.receiver.lock().unwrap() = Some(receiver);
+                    return;
+                }
+            }
+        }
+        if let Err(error) = log.sender.try_send(event) {
+            let context = ErrorContext {
+                operation: "event_log",
+                jid: Some(&row.jid),
+                job_type: Some(&row.job_type),
+                correlation_id: row.correlation_id.as_deref(),
+            };
+            let reason = match error {
+                mpsc::error::TrySendError::Full(_) => "too many events waiting",
+                mpsc::error::TrySendError::Closed(_) => "writer stopped",
+            };
+            let error = anyhow::anyhow!("Dropped {name} event from the event log, {reason}");
+            self.report_error(&context, &error.into());
+        }
+    }
+
+    /// Store the events sent to `receiver` until every sender is gone, writing the ones that
+    /// piled up during a write together.
+    async fn write_events(self, mut receiver: mpsc::Receiver<EventRow>) {
+        let options = InsertManyOptions::builder().ordered(false).build();
+        let mut batch = Vec::with_capacity(EVENT_LOG_BATCH_SIZE);
+        while let Some(event) = receiver.recv().await {
+            batch.push(event);
+            while batch.len() < EVENT_LOG_BATCH_SIZE {
+                match receiver.try_recv() {
+                    Ok(event) => batch.push(event),
+                    Err(_) => break,
+                }
+            }
+            let collection = self.database.collection::<EventRow>(EVENTS_COLLECTION);
+            let result: Result<_, QueueError> = traced(
+                &collection,
+                "insert_many",
+                collection.insert_many(&batch, options.clone()),
+            )
+            .await
+            .context("Failed to store job events")
+            .map_err(Into::into);
+            let context = ErrorContext {
+                operation: "event_log",
+                jid: None,
+                job_type: None,
+                correlation_id: None,
+            };
+            let _ = self.reported(&context, result);
+            batch.clear();
+        }
+    }
+}
+
+impl EventRow {
+    fn into_event(self) -> JobEvent {
+        JobEvent {
+            at: self.at.to_chrono(),
+            name: self.name,
+            jid: self.jid,
+            job_type: self.meta.job_type,
+            queue: self.meta.queue,
+            status: self.status,
+            attempt: self.attempt as u32,
+            duration_ms: self.duration_ms,
+            correlation_id: self.correlation_id,
+        }
+    }
+}
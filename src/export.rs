@@ -0,0 +1,281 @@
+use std::{collections::BTreeMap, io::Write};
+
+use aide_de_camp::core::{new_xid, queue::QueueError, DateTime, Duration};
+use anyhow::Context;
+use bson::{doc, Document};
+use chrono::Utc;
+use futures::TryStreamExt;
+use mongodb::options::FindOptions;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tracing::instrument;
+
+use crate::{status::JobStatus, trace::traced, types::JobRow, MongoDbQueue};
+
+/// How [`MongoDbQueue::drain_to_export`] writes jobs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// One JSON object per line.
+    #[default]
+    JsonLines,
+    /// bincode records back to back, in the standard bincode configuration.
+    Bincode,
+}
+
+/// A job as written by [`MongoDbQueue::drain_to_export`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportedJob {
+    pub jid: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_id: Option<String>,
+    pub job_type: String,
+    /// The encoded payload, as it was scheduled.
+    pub payload: Vec<u8>,
+    pub priority: i8,
+    pub retries: u32,
+    pub scheduled_at: DateTime,
+    pub enqueued_at: DateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    /// Media type of `payload`, see [`ScheduleOptions::content_type`](crate::ScheduleOptions::content_type).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+}
+
+/// Jobs taken out of the queue and written per round trip by [`MongoDbQueue::drain_to_export`].
+pub const EXPORT_BATCH_SIZE: i64 = 100;
+
+/// How long jobs taken by a [`MongoDbQueue::drain_to_export`] that never finished, e.g.
+/// because the process died, are held before they are put back in the queue.
+pub const EXPORT_LEASE: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+impl MongoDbQueue {
+    /// Take every pending job of `job_type` out of the queue and write it to `writer`, for
+    /// moving a workload into a batch pipeline. Returns the number of jobs written.
+    ///
+    /// Jobs are taken [`EXPORT_BATCH_SIZE`] at a time. A batch is marked
+    /// [`JobStatus::Exported`] so workers and concurrent exports can't pick it up, written and
+    /// flushed, and only then committed. If writing fails the batch is put back in the queue.
+    /// Batches of an export that never finished are put back after [`EXPORT_LEASE`], so a
+    /// batch flushed right before a crash can be written twice. Committed jobs stay in the
+    /// queue collection until removed with [`Self::delete_exported`].
+    #[instrument(skip_all, err, ret, fields(job_type = job_type, format = ?format))]
+    pub async fn drain_to_export<W: AsyncWrite + Unpin + Send>(
+        &self,
+        job_type: &str,
+        format: ExportFormat,
+        mut writer: W,
+    ) -> Result<u64, QueueError> {
+        self.put_back_stale_exports().await?;
+        let token = new_xid().to_string();
+        let mut exported = 0;
+        loop {
+            let rows = self.take_export_batch(job_type, &token).await?;
+            if rows.is_empty() {
+                break;
+            }
+            let written = async {
+                let mut buffer = Vec::new();
+                for row in &rows {
+                    write_record(&mut buffer, format, row)?;
+                }
+                writer
+                    .write_all(&buffer)
+                    .await
+                    .context("Failed to write exported jobs")?;
+                writer
+                    .flush()
+                    .await
+                    .context("Failed to write exported jobs")?;
+                Ok::<_, QueueError>(())
+            }
+            .await;
+            if let Err(error) = written {
+                self.unexport(doc! { "export_token": &token }).await?;
+                return Err(error);
+            }
+            let collection = self.collection();
+            traced(
+                &collection,
+                "update_many",
+                collection.update_many(
+                    self.scoped(doc! { "export_token": &token }),
+                    doc! { "$unset": { "export_token": "" } },
+                    None,
+                ),
+            )
+            .await
+            .context("Failed to commit exported jobs")?;
+            exported += rows.len() as u64;
+        }
+        Ok(exported)
+    }
+
+    /// Remove the jobs left behind by [`Self::drain_to_export`]. Returns the number removed.
+    #[instrument(skip_all, err, ret)]
+    pub async fn delete_exported(&self) -> Result<u64, QueueError> {
+        self.put_back_stale_exports().await?;
+        let collection = self.collection();
+        let result = traced(
+            &collection,
+            "delete_many",
+            collection.delete_many(
+                self.scoped(doc! {
+                    "queue": &self.queue_name,
+                    "status": JobStatus::Exported,
+                    "export_token": { "$exists": false },
+                }),
+                None,
+            ),
+        )
+        .await
+        .context("Failed to delete exported jobs")?;
+        Ok(result.deleted_count)
+    }
+
+    /// Mark up to [`EXPORT_BATCH_SIZE`] pending jobs of `job_type` as exported under `token`,
+    /// highest priority first, and return them.
+    async fn take_export_batch(
+        &self,
+        job_type: &str,
+        token: &str,
+    ) -> Result<Vec<JobRow>, QueueError> {
+        let collection = self.collection();
+        let options = FindOptions::builder()
+            .sort(doc! { "priority": -1, "scheduled_at": 1 })
+            .projection(doc! { "jid": 1 })
+            .limit(EXPORT_BATCH_SIZE)
+            .build();
+        let pending = self.scoped(doc! {
+            "status": JobStatus::Pending,
+            "queue": &self.queue_name,
+            "job_type": job_type,
+        });
+        let candidates = collection.clone_with_type::<Document>();
+        let jids: Vec<String> = traced(
+            &candidates,
+            "find",
+            candidates.find(pending.clone(), options),
+        )
+        .await
+        .context("Failed to find jobs to export")?
+        .try_collect::<Vec<Document>>()
+        .await
+        .context("Failed to find jobs to export")?
+        .iter()
+        .filter_map(|row| row.get_str("jid").ok().map(str::to_string))
+        .collect();
+        if jids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Jobs claimed in the meantime no longer match and are left out.
+        let mut take = pending;
+        take.insert("jid", doc! { "$in": &jids });
+        traced(
+            &collection,
+            "update_many",
+            collection.update_many(
+                take,
+                doc! { "$set": {
+                    "status": JobStatus::Exported,
+                    "exported_at": bson::DateTime::now(),
+                    "export_token": token,
+                } },
+                None,
+            ),
+        )
+        .await
+        .context("Failed to take jobs for export")?;
+        let options = FindOptions::builder()
+            .sort(doc! { "priority": -1, "scheduled_at": 1 })
+            .build();
+        let rows = traced(
+            &collection,
+            "find",
+            collection.find(
+                self.scoped(doc! { "export_token": token, "jid": { "$in": &jids } }),
+                options,
+            ),
+        )
+        .await
+        .context("Failed to read jobs to export")?
+        .try_collect()
+        .await
+        .context("Failed to read jobs to export")?;
+        Ok(rows)
+    }
+
+    /// Put back the jobs of exports that didn't finish within [`EXPORT_LEASE`].
+    async fn put_back_stale_exports(&self) -> Result<(), QueueError> {
+        let lease = Duration::from_std(EXPORT_LEASE).unwrap_or_default();
+        self.unexport(doc! {
+            "export_token": { "$exists": true },
+            "exported_at": { "$lt": bson::DateTime::from_chrono(Utc::now() - lease) },
+        })
+        .await
+    }
+
+    /// Put the uncommitted exported jobs matching `filter` back in the queue.
+    async fn unexport(&self, filter: Document) -> Result<(), QueueError> {
+        let collection = self.collection();
+        let mut filter = self.scoped(filter);
+        filter.insert("status", JobStatus::Exported);
+        traced(
+            &collection,
+            "update_many",
+            collection.update_many(
+                filter,
+                doc! {
+                    "$set": { "status": JobStatus::Pending },
+                    "$unset": { "exported_at": "", "export_token": "" },
+                },
+                None,
+            ),
+        )
+        .await
+        .context("Failed to put back jobs after a failed export")?;
+        Ok(())
+    }
+}
+
+fn write_record(
+    writer: &mut impl Write,
+    format: ExportFormat,
+    row: &JobRow,
+) -> Result<(), QueueError> {
+    let job = ExportedJob {
+        jid: row.jid.clone(),
+        public_id: row.public_id.clone(),
+        job_type: row.job_type.clone(),
+        payload: row.payload.bytes.clone(),
+        priority: row.priority as i8,
+        retries: row.retries as u32,
+        scheduled_at: row.scheduled_at.to_chrono(),
+        enqueued_at: row.enqueued_at.to_chrono(),
+        tenant: row.tenant.clone(),
+        correlation_id: row.correlation_id.clone(),
+        tags: row.tags.clone(),
+        headers: row.headers.clone(),
+        content_type: row.content_type.clone(),
+    };
+    match format {
+        ExportFormat::JsonLines => {
+            serde_json::to_writer(&mut *writer, &job).context("Failed to write exported job")?;
+            writer
+                .write_all(b"\n")
+                .context("Failed to write exported job")?;
+        }
+        ExportFormat::Bincode => {
+            bincode::serde::encode_into_std_write(&job, writer, bincode::config::standard())
+                .context("Failed to write exported job")?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,154 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use aide_de_camp::core::{
+    bincode::{Decode, Encode},
+    job_processor::JobProcessor,
+    queue::{Queue, QueueError},
+    DateTime, Xid,
+};
+use async_trait::async_trait;
+use tracing::instrument;
+
+use crate::{job_handle::MongoDbJobHandle, stats::QueueStats, MongoDbQueue};
+
+type Partition = dyn Fn(&str) -> usize + Send + Sync;
+
+/// A [`Queue`] spread over several MongoDB clusters or databases, e.g. one per region.
+///
+/// Jobs are scheduled to the primary member unless a partition function picks another one by
+/// job type. Polling goes around all members, and [`Self::stats`] adds up their counts. Job
+/// handles always complete against the member the job was claimed from.
+#[derive(Clone)]
+pub struct FederatedMongoDbQueue {
+    members: Vec<MongoDbQueue>,
+    partition: Option<Arc<Partition>>,
+    next_poll: Arc<AtomicUsize>,
+}
+
+impl FederatedMongoDbQueue {
+    pub fn new(primary: MongoDbQueue) -> Self {
+        Self {
+            members: vec![primary],
+            partition: None,
+            next_poll: Default::default(),
+        }
+    }
+
+    /// Add a member. Members are numbered in the order they are added, the primary is 0.
+    pub fn with_member(mut self, member: MongoDbQueue) -> Self {
+        self.members.push(member);
+        self
+    }
+
+    /// Pick the member jobs of a type are scheduled to. Out of range indexes go to the
+    /// primary.
+    pub fn with_partition(
+        mut self,
+        partition: impl Fn(&str) -> usize + Send + Sync + 'static,
+    ) -> Self {
+        self.partition = Some(Arc::new(partition));
+        self
+    }
+
+    pub fn members(&self) -> &[MongoDbQueue] {
+        &self.members
+    }
+
+    /// Counts summed over all members. Consistent only if every member could read its counts
+    /// from a snapshot, and even then each member has its own point in time.
+    #[instrument(skip_all, err, ret)]
+    pub async fn stats(&self) -> Result<QueueStats, QueueError> {
+        let mut total = QueueStats {
+            consistent: true,
+            ..Default::default()
+        };
+        for member in &self.members {
+            let stats = member.stats().await?;
+            total.ready += stats.ready;
+            total.scheduled += stats.scheduled;
+            total.running += stats.running;
+            total.parked += stats.parked;
+            total.dead += stats.dead;
+            total.cancelled += stats.cancelled;
+            total.oldest_ready_age_ms = total.oldest_ready_age_ms.max(stats.oldest_ready_age_ms);
+            total.consistent &= stats.consistent;
+        }
+        Ok(total)
+    }
+
+    fn member_for(&self, job_type: &str) -> &MongoDbQueue {
+        let index = self
+            .partition
+            .as_ref()
+            .map(|partition| partition(job_type))
+            .unwrap_or(0);
+        self.members.get(index).unwrap_or(&self.members[0])
+    }
+
+    /// All members, starting with a different one on every call so none is polled first all
+    /// the time.
+    fn rotated_members(&self) -> impl Iterator<Item = &MongoDbQueue> {
+        let start = self.next_poll.fetch_add(1, Ordering::Relaxed) % self.members.len();
+        self.members[start..].iter().chain(&self.members[..start])
+    }
+}
+
+#[async_trait]
+impl Queue for FederatedMongoDbQueue {
+    type JobHandle = MongoDbJobHandle;
+
+    async fn schedule_at<J>(
+        &self,
+        payload: J::Payload,
+        scheduled_at: DateTime,
+        priority: i8,
+    ) -> Result<Xid, QueueError>
+    where
+        J: JobProcessor + 'static,
+        J::Payload: Encode,
+    {
+        self.member_for(J::name())
+            .schedule_at::<J>(payload, scheduled_at, priority)
+            .await
+    }
+
+    async fn poll_next_with_instant(
+        &self,
+        job_types: &[&str],
+        now: DateTime,
+    ) -> Result<Option<MongoDbJobHandle>, QueueError> {
+        for member in self.rotated_members() {
+            if let Some(job) = member.poll_next_with_instant(job_types, now).await? {
+                return Ok(Some(job));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn cancel_job(&self, job_id: Xid) -> Result<(), QueueError> {
+        for member in &self.members {
+            match member.cancel_job(job_id).await {
+                Err(QueueError::JobNotFound(_)) => continue,
+                result => return result,
+            }
+        }
+        Err(QueueError::JobNotFound(job_id))
+    }
+
+    async fn unschedule_job<J>(&self, job_id: Xid) -> Result<J::Payload, QueueError>
+    where
+        J: JobProcessor + 'static,
+        J::Payload: Decode,
+    {
+        for member in &self.members {
+            match member.unschedule_job::<J>(job_id).await {
+                Err(QueueError::JobNotFound(_)) => continue,
+                result => return result,
+            }
+        }
+        Err(QueueError::JobNotFound(job_id))
+    }
+}
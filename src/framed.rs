@@ -0,0 +1,17 @@
+use bincode::config::standard;
+
+/// The bytes in a bincode-encoded payload that wraps a message in another encoding, e.g.
+/// [`Protobuf`](crate::protobuf::Protobuf), for codecs that store the message as it is.
+pub(crate) fn unframe(payload: &[u8], format: &str) -> Result<Vec<u8>, String> {
+    let (bytes, read): (Vec<u8>, usize) = bincode::decode_from_slice(payload, standard())
+        .map_err(|error| format!("Not a {format} payload: {error}"))?;
+    if read != payload.len() {
+        return Err(format!("Not a {format} payload: trailing bytes"));
+    }
+    Ok(bytes)
+}
+
+/// `stored` framed as a bincode-encoded payload.
+pub(crate) fn frame(stored: &[u8]) -> Result<Vec<u8>, String> {
+    bincode::encode_to_vec(stored, standard()).map_err(|error| error.to_string())
+}
@@ -0,0 +1,69 @@
+use aide_de_camp::core::queue::QueueError;
+use anyhow::Context;
+use bson::{doc, Document};
+use mongodb::options::FindOneOptions;
+
+use crate::{ordering::ClaimPass, trace::traced, MongoDbQueue};
+
+impl MongoDbQueue {
+    /// Claim jobs of `job_types` newest first (by scheduled time) among jobs of the same
+    /// priority, instead of in the claim order. Suits jobs whose value fades with age, like
+    /// cache warming or retries a user is waiting on.
+    ///
+    /// A poll for only these job types needs no extra work. A poll that mixes them with other
+    /// job types first looks up the highest ready priority, then claims the newest of these
+    /// job types at that priority, falling back to the claim order when there is none.
+    pub fn with_lifo_job_types(mut self, job_types: &[&str]) -> Self {
+        self.lifo_job_types = job_types
+            .iter()
+            .map(|job_type| job_type.to_string())
+            .collect();
+        self
+    }
+
+    /// The passes that claim LIFO job types newest first ahead of `passes`, or `None` when
+    /// nothing is ready.
+    pub(crate) async fn lifo_passes(
+        &self,
+        job_types: &[&str],
+        filter_doc: &Document,
+        passes: Vec<ClaimPass>,
+    ) -> Result<Option<Vec<ClaimPass>>, QueueError> {
+        let lifo: Vec<&str> = job_types
+            .iter()
+            .copied()
+            .filter(|job_type| self.lifo_job_types.iter().any(|lifo| lifo == job_type))
+            .collect();
+        let newest_first = doc! { "scheduled_at": -1, "enqueued_at": -1 };
+        if lifo.is_empty() {
+            return Ok(Some(passes));
+        }
+        if lifo.len() == job_types.len() {
+            let mut sort = doc! { "priority": -1 };
+            sort.extend(newest_first);
+            return Ok(Some(vec![ClaimPass { filter: None, sort }]));
+        }
+
+        let options = FindOneOptions::builder()
+            .sort(doc! { "priority": -1 })
+            .projection(doc! { "priority": 1 })
+            .build();
+        let collection = self.database.collection::<Document>("adc_queue");
+        let head = traced(
+            &collection,
+            "find_one",
+            collection.find_one(filter_doc.clone(), options),
+        )
+        .await
+        .context("Failed to look up the highest ready priority")?;
+        let Some(priority) = head.as_ref().and_then(|head| head.get("priority")) else {
+            return Ok(None);
+        };
+        let mut lifo_passes = vec![ClaimPass {
+            filter: Some(doc! { "job_type": { "$in": lifo }, "priority": priority }),
+            sort: newest_first,
+        }];
+        lifo_passes.extend(passes);
+        Ok(Some(lifo_passes))
+    }
+}
@@ -0,0 +1,164 @@
+use aide_de_camp::core::{queue::QueueError, DateTime, Duration};
+use anyhow::Context;
+use bson::{doc, Document};
+use futures::{Stream, StreamExt, TryStreamExt};
+use mongodb::{options::TimeseriesGranularity, Collection};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::{
+    stats::QueueStats,
+    trace::traced,
+    types::{StatsSnapshotMeta, StatsSnapshotRow},
+    MongoDbQueue,
+};
+
+/// Collection the queue depth history is kept in.
+pub const STATS_HISTORY_COLLECTION: &str = "adc_stats_history";
+
+/// Job counts at one point in time, see [`MongoDbQueue::stats_history`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatsSnapshot {
+    pub taken_at: DateTime,
+    pub queue: String,
+    #[serde(flatten)]
+    pub stats: QueueStats,
+}
+
+impl MongoDbQueue {
+    /// Create the collection [`Self::record_stats_snapshot`] writes to, dropping snapshots
+    /// older than `retention`. It is a time-series collection on servers that support them
+    /// (MongoDB 5.0 and later) and a plain collection with a TTL index elsewhere.
+    #[instrument(skip_all, err)]
+    pub async fn create_stats_history(&self, retention: Duration) -> Result<(), QueueError> {
+        self.create_time_series(
+            STATS_HISTORY_COLLECTION,
+            "taken_at",
+            TimeseriesGranularity::Minutes,
+            retention,
+        )
+        .await
+    }
+
+    /// Add the current [`Self::stats`] to the history. Called on every run of a
+    /// [`MaintenanceRunner`](crate::MaintenanceRunner) set up
+    /// [`with_stats_history`](crate::MaintenanceRunner::with_stats_history).
+    #[instrument(skip_all, err)]
+    pub async fn record_stats_snapshot(&self, now: DateTime) -> Result<StatsSnapshot, QueueError> {
+        let stats = self.stats().await?;
+        let row = StatsSnapshotRow {
+            taken_at: bson::DateTime::from_chrono(now),
+            meta: StatsSnapshotMeta {
+                queue: self.queue_name.clone(),
+                namespace: self.namespace.clone(),
+            },
+            ready: stats.ready as i64,
+            scheduled: stats.scheduled as i64,
+            running: stats.running as i64,
+            parked: stats.parked as i64,
+            dead: stats.dead as i64,
+            cancelled: stats.cancelled as i64,
+            oldest_ready_age_ms: stats.oldest_ready_age_ms.map(|age| age as i64),
+            consistent: stats.consistent,
+        };
+        let collection = self.stats_history_collection();
+        traced(&collection, "insert_one", collection.insert_one(&row, None))
+            .await
+            .context("Failed to record stats snapshot")?;
+        Ok(row.into_snapshot())
+    }
+
+    /// Snapshots of this queue taken from `from` up to `to`, oldest first. With a `step`,
+    /// snapshots are combined into one per step, keeping the highest count of each kind, so
+    /// long ranges can be charted without fetching every snapshot.
+    #[instrument(skip_all, err)]
+    pub async fn stats_history(
+        &self,
+        from: DateTime,
+        to: DateTime,
+        step: Option<Duration>,
+    ) -> Result<Vec<StatsSnapshot>, QueueError> {
+        let mut pipeline = self.history_pipeline(from, to);
+        if let Some(step) = step {
+            let step_ms = step.num_milliseconds().max(1);
+            let millis = doc! { "$toLong": "$taken_at" };
+            pipeline.extend([
+                doc! { "$group": {
+                    "_id": { "$subtract": [&millis, { "$mod": [&millis, step_ms] }] },
+                    "meta": { "$first": "$meta" },
+                    "ready": { "$max": "$ready" },
+                    "scheduled": { "$max": "$scheduled" },
+                    "running": { "$max": "$running" },
+                    "parked": { "$max": "$parked" },
+                    "dead": { "$max": "$dead" },
+                    "cancelled": { "$max": "$cancelled" },
+                    "oldest_ready_age_ms": { "$max": "$oldest_ready_age_ms" },
+                    "consistent": { "$min": "$consistent" },
+                } },
+                doc! { "$set": { "taken_at": { "$toDate": "$_id" } } },
+                doc! { "$sort": { "taken_at": 1 } },
+            ]);
+        }
+
+        self.stats_history_stream(pipeline)
+            .await?
+            .try_collect()
+            .await
+    }
+
+    /// Pipeline of the snapshots of this queue taken from `from` up to `to`, oldest first.
+    pub(crate) fn history_pipeline(&self, from: DateTime, to: DateTime) -> Vec<Document> {
+        vec![
+            doc! { "$match": {
+                "meta.queue": &self.queue_name,
+                "meta.namespace": self.namespace.as_deref(),
+                "taken_at": {
+                    "$gte": bson::DateTime::from_chrono(from),
+                    "$lt": bson::DateTime::from_chrono(to),
+                },
+            } },
+            doc! { "$sort": { "taken_at": 1 } },
+        ]
+    }
+
+    /// The snapshots `pipeline` returns, as they are read.
+    pub(crate) async fn stats_history_stream(
+        &self,
+        pipeline: Vec<Document>,
+    ) -> Result<impl Stream<Item = Result<StatsSnapshot, QueueError>>, QueueError> {
+        let collection = self.stats_history_collection();
+        let rows = traced(
+            &collection,
+            "aggregate",
+            collection.aggregate(pipeline, None),
+        )
+        .await
+        .context("Failed to read stats history")?;
+        Ok(rows
+            .and_then(|row| async move { Ok(bson::from_document::<StatsSnapshotRow>(row)?) })
+            .map(|row| Ok(row.context("Failed to read stats history")?.into_snapshot())))
+    }
+
+    fn stats_history_collection(&self) -> Collection<StatsSnapshotRow> {
+        self.database.collection(STATS_HISTORY_COLLECTION)
+    }
+}
+
+impl StatsSnapshotRow {
+    fn into_snapshot(self) -> StatsSnapshot {
+        StatsSnapshot {
+            taken_at: self.taken_at.to_chrono(),
+            queue: self.meta.queue,
+            stats: QueueStats {
+                ready: self.ready as u64,
+                scheduled: self.scheduled as u64,
+                running: self.running as u64,
+                parked: self.parked as u64,
+                dead: self.dead as u64,
+                cancelled: self.cancelled as u64,
+                oldest_ready_age_ms: self.oldest_ready_age_ms.map(|age| age as u64),
+                consistent: self.consistent,
+            },
+        }
+    }
+}
@@ -0,0 +1,210 @@
+//! Extension points for plugging application logic into the queue.
+
+use aide_de_camp::core::{queue::QueueError, Duration, Xid};
+use bson::Document;
+
+use crate::{budget::PendingBudgetExceeded, monitors::MissedCheckIn, orphans::OrphanedJobType};
+
+/// Checks, and optionally rewrites, the encoded payload of a job before it is added to the
+/// queue. Registered per job type with [`MongoDbQueue::with_payload_validator`].
+///
+/// Plain functions and closures with the same signature as [`PayloadValidator::validate`]
+/// implement this trait.
+///
+/// [`MongoDbQueue::with_payload_validator`]: crate::MongoDbQueue::with_payload_validator
+pub trait PayloadValidator: Send + Sync {
+    /// Returns the bytes to store, or the reason the job is rejected.
+    fn validate(&self, job_type: &str, payload: Vec<u8>) -> Result<Vec<u8>, String>;
+}
+
+impl<F> PayloadValidator for F
+where
+    F: Fn(&str, Vec<u8>) -> Result<Vec<u8>, String> + Send + Sync,
+{
+    fn validate(&self, job_type: &str, payload: Vec<u8>) -> Result<Vec<u8>, String> {
+        self(job_type, payload)
+    }
+}
+
+/// Gives jobs an id of their own next to the [`Xid`] the queue API works with, e.g. a ULID or
+/// an id prefixed by job type (`email_…`) that matches existing tracing conventions.
+/// Installed with [`MongoDbQueue::with_id_generator`].
+///
+/// The id is stored in the `public_id` field, recorded in lifecycle events and returned by
+/// [`MongoDbJobHandle::public_id`]. Look jobs up by it with [`MongoDbQueue::resolve_id`].
+///
+/// Plain functions and closures with the same signature as [`IdGenerator::generate`]
+/// implement this trait.
+///
+/// [`MongoDbQueue::with_id_generator`]: crate::MongoDbQueue::with_id_generator
+/// [`MongoDbQueue::resolve_id`]: crate::MongoDbQueue::resolve_id
+/// [`MongoDbJobHandle::public_id`]: crate::job_handle::MongoDbJobHandle::public_id
+pub trait IdGenerator: Send + Sync {
+    /// The id of a new job. Must be unique.
+    fn generate(&self, jid: &Xid, job_type: &str) -> String;
+}
+
+impl<F> IdGenerator for F
+where
+    F: Fn(&Xid, &str) -> String + Send + Sync,
+{
+    fn generate(&self, jid: &Xid, job_type: &str) -> String {
+        self(jid, job_type)
+    }
+}
+
+/// Combines the payload of a new debounced job with the one of the job already pending under
+/// the same key, so coalescing loses no information (e.g. the union of ids to reindex).
+/// Registered per job type with [`MongoDbQueue::with_payload_merger`].
+///
+/// Both payloads are encoded. May be called more than once for one job when other producers
+/// update the pending job concurrently.
+///
+/// [`MongoDbQueue::with_payload_merger`]: crate::MongoDbQueue::with_payload_merger
+pub trait PayloadMerger: Send + Sync {
+    /// Returns the payload to store, or the reason the job is rejected.
+    fn merge(&self, job_type: &str, pending: &[u8], new: &[u8]) -> Result<Vec<u8>, String>;
+}
+
+impl<F> PayloadMerger for F
+where
+    F: Fn(&str, &[u8], &[u8]) -> Result<Vec<u8>, String> + Send + Sync,
+{
+    fn merge(&self, job_type: &str, pending: &[u8], new: &[u8]) -> Result<Vec<u8>, String> {
+        self(job_type, pending, new)
+    }
+}
+
+/// Converts the payload of a job between the bincode encoding the [`Queue`] API and job
+/// processors work with and the bytes kept in the database, e.g. a protobuf message other
+/// services can read. Registered per job type with [`MongoDbQueue::with_payload_codec`], which
+/// also tags the jobs with [`PayloadCodec::content_type`].
+///
+/// [`Queue`]: aide_de_camp::core::queue::Queue
+/// [`MongoDbQueue::with_payload_codec`]: crate::MongoDbQueue::with_payload_codec
+pub trait PayloadCodec: Send + Sync {
+    /// Media type of the stored bytes, e.g. `application/protobuf`.
+    fn content_type(&self) -> &str;
+
+    /// Returns the bytes to store for a bincode-encoded `payload`.
+    fn encode(&self, job_type: &str, payload: &[u8]) -> Result<Vec<u8>, String>;
+
+    /// Returns the bincode encoding of the `stored` payload.
+    fn decode(&self, job_type: &str, stored: &[u8]) -> Result<Vec<u8>, String>;
+}
+
+/// What a [`ClaimFilter`] decided about a job that was just claimed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClaimDecision {
+    /// Hand the job to the worker.
+    Accept,
+    /// Put the job back and try the next one.
+    Skip,
+    /// Put the job back, runnable again only after the delay, and try the next one.
+    Defer(Duration),
+}
+
+/// Application level control over which jobs get dispatched, e.g. to hold back jobs of tenants
+/// that are currently rate limited. Installed with [`MongoDbQueue::with_claim_filter`].
+///
+/// [`MongoDbQueue::with_claim_filter`]: crate::MongoDbQueue::with_claim_filter
+pub trait ClaimFilter: Send + Sync {
+    /// Extra conditions for the claim query. Prefer this over [`ClaimFilter::decide`] when the
+    /// rule can be expressed as a MongoDB filter, it avoids claiming jobs just to put them back.
+    fn query(&self) -> Option<Document> {
+        None
+    }
+
+    /// Decide on a claimed job, given its document.
+    fn decide(&self, job: &Document) -> ClaimDecision;
+}
+
+/// What was going on when an [`ErrorReporter`] is called.
+#[derive(Debug, Clone, Copy)]
+pub struct ErrorContext<'a> {
+    /// The queue or job handle operation, e.g. `"schedule_at"` or `"dead_queue"`.
+    pub operation: &'static str,
+    pub jid: Option<&'a str>,
+    pub job_type: Option<&'a str>,
+    pub correlation_id: Option<&'a str>,
+}
+
+/// Receives queue failures and dead-lettered jobs, e.g. to forward them to Sentry. Installed
+/// with [`MongoDbQueue::with_error_reporter`], the default is [`TracingErrorReporter`].
+///
+/// `JobNotFound` errors are an expected outcome of cancelling jobs and are not reported.
+///
+/// [`MongoDbQueue::with_error_reporter`]: crate::MongoDbQueue::with_error_reporter
+pub trait ErrorReporter: Send + Sync {
+    /// A queue operation failed.
+    fn report_error(&self, context: &ErrorContext<'_>, error: &QueueError);
+
+    /// A job was moved to the dead queue.
+    fn report_dead_job(&self, context: &ErrorContext<'_>);
+
+    /// A job type looks abandoned, see [`MongoDbQueue::orphaned_job_types`]. Logged as a
+    /// warning by default.
+    ///
+    /// [`MongoDbQueue::orphaned_job_types`]: crate::MongoDbQueue::orphaned_job_types
+    fn report_orphaned_job_type(&self, orphan: &OrphanedJobType) {
+        tracing::warn!(
+            job_type = %orphan.job_type,
+            pending = orphan.pending,
+            oldest_due = %orphan.oldest_due.to_rfc3339(),
+            "No worker claimed jobs of this type in a while"
+        );
+    }
+
+    /// A monitor's deadline passed without a check-in, see
+    /// [`MongoDbQueue::missed_check_ins`]. Logged as an error by default.
+    ///
+    /// [`MongoDbQueue::missed_check_ins`]: crate::MongoDbQueue::missed_check_ins
+    fn report_missed_check_in(&self, missed: &MissedCheckIn) {
+        tracing::error!(
+            monitor = %missed.monitor,
+            deadline = %missed.deadline.to_rfc3339(),
+            last_check_in = missed.last_check_in.map(|at| at.to_rfc3339()),
+            "Monitor missed its check-in"
+        );
+    }
+
+    /// A job was scheduled while its type was over a [`PendingBudget`] that doesn't reject
+    /// it. Logged as a warning by default.
+    ///
+    /// [`PendingBudget`]: crate::PendingBudget
+    fn report_pending_budget_exceeded(&self, exceeded: &PendingBudgetExceeded) {
+        tracing::warn!(
+            job_type = %exceeded.job_type,
+            limit = exceeded.limit,
+            action = ?exceeded.action,
+            "Job scheduled over its pending budget"
+        );
+    }
+}
+
+/// Reports through `tracing` events.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TracingErrorReporter;
+
+impl ErrorReporter for TracingErrorReporter {
+    fn report_error(&self, context: &ErrorContext<'_>, error: &QueueError) {
+        tracing::error!(
+            operation = context.operation,
+            jid = context.jid,
+            job_type = context.job_type,
+            correlation_id = context.correlation_id,
+            error = ?error,
+            "Queue operation failed"
+        );
+    }
+
+    fn report_dead_job(&self, context: &ErrorContext<'_>) {
+        tracing::warn!(
+            operation = context.operation,
+            jid = context.jid,
+            job_type = context.job_type,
+            correlation_id = context.correlation_id,
+            "Job moved to the dead queue"
+        );
+    }
+}
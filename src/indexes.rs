@@ -0,0 +1,58 @@
+use aide_de_camp::core::queue::QueueError;
+use anyhow::Context;
+use bson::{doc, Document};
+use mongodb::{options::IndexOptions, IndexModel};
+use tracing::instrument;
+
+use crate::{
+    lease::{ClaimMode, CLAIMS_COLLECTION},
+    trace::traced,
+    MongoDbQueue,
+};
+
+impl MongoDbQueue {
+    /// Create the indexes used by lookups on the queue collections. Safe to call on every
+    /// start, existing indexes are left alone.
+    #[instrument(skip_all, err)]
+    pub async fn create_indexes(&self) -> Result<(), QueueError> {
+        let indexes = vec![
+            IndexModel::builder()
+                .keys(doc! { "status": 1, "queue": 1, "job_type": 1, "scheduled_at": 1 })
+                .build(),
+            // The head of the queue across job types, see `oldest_runnable_age`.
+            IndexModel::builder()
+                .keys(doc! { "status": 1, "queue": 1, "scheduled_at": 1 })
+                .build(),
+            IndexModel::builder()
+                .keys(doc! { "correlation_id": 1 })
+                .options(IndexOptions::builder().sparse(true).build())
+                .build(),
+            IndexModel::builder()
+                .keys(doc! { "public_id": 1 })
+                .options(IndexOptions::builder().sparse(true).unique(true).build())
+                .build(),
+        ];
+
+        let collection = self.database.collection::<Document>("adc_queue");
+        traced(
+            &collection,
+            "create_indexes",
+            collection.create_indexes(indexes, None),
+        )
+        .await
+        .context("Failed to create indexes")?;
+
+        if let ClaimMode::Lease(_) = self.claim_mode {
+            let index = IndexModel::builder().keys(doc! { "expires_at": 1 }).build();
+            let collection = self.database.collection::<Document>(CLAIMS_COLLECTION);
+            traced(
+                &collection,
+                "create_indexes",
+                collection.create_indexes([index], None),
+            )
+            .await
+            .context("Failed to create claim indexes")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+
+use aide_de_camp::core::queue::QueueError;
+use anyhow::Context;
+use bson::{doc, Document};
+use futures::TryStreamExt;
+use mongodb::options::FindOptions;
+use tracing::instrument;
+
+use crate::{status::JobStatus, trace::traced, MongoDbQueue};
+
+/// A broken queue invariant found by [`InvariantChecker::check`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    /// The job is in the queue and in the dead queue at the same time.
+    InQueueAndDeadQueue { jid: String },
+    /// More than one document in the queue has this jid.
+    DuplicateJid { jid: String, count: u64 },
+    /// The job is running but has no claim time.
+    RunningWithoutStartedAt { jid: String },
+    /// The job's retry count went down since the previous check.
+    RetriesDecreased {
+        jid: String,
+        before: i64,
+        after: i64,
+    },
+}
+
+/// Checks properties that must hold for a queue however many workers use it, for soak tests
+/// and production audits. Every check reads whole collections, so run it against large queues
+/// sparingly.
+///
+/// Retry counts are compared with those seen by the previous [`Self::check`] of the same
+/// checker. A claim rejected by a [`ClaimFilter`](crate::ClaimFilter) is undone, so a check
+/// that runs while a claim is being undone can report a false decrease.
+pub struct InvariantChecker {
+    queue: MongoDbQueue,
+    retries: HashMap<String, i64>,
+}
+
+impl InvariantChecker {
+    pub fn new(queue: MongoDbQueue) -> Self {
+        Self {
+            queue,
+            retries: HashMap::new(),
+        }
+    }
+
+    /// Check every invariant and return the violations found.
+    #[instrument(skip_all, err)]
+    pub async fn check(&mut self) -> Result<Vec<Violation>, QueueError> {
+        let mut violations = Vec::new();
+        let collection = self.queue.database.collection::<Document>("adc_queue");
+
+        let pipeline = vec![
+            doc! { "$lookup": {
+                "from": self.queue.database.collection_name("adc_dead_queue"),
+                "localField": "jid",
+                "foreignField": "jid",
+                "as": "dead",
+            } },
+            doc! { "$match": { "dead.0": { "$exists": true } } },
+            doc! { "$project": { "jid": 1 } },
+        ];
+        for row in self.aggregate(pipeline).await? {
+            violations.push(Violation::InQueueAndDeadQueue {
+                jid: jid_of(&row, "jid")?,
+            });
+        }
+
+        let pipeline = vec![
+            doc! { "$group": { "_id": "$jid", "count": { "$sum": 1_i64 } } },
+            doc! { "$match": { "count": { "$gt": 1_i64 } } },
+        ];
+        for row in self.aggregate(pipeline).await? {
+            violations.push(Violation::DuplicateJid {
+                jid: jid_of(&row, "_id")?,
+                count: row.get_i64("count").unwrap_or_default() as u64,
+            });
+        }
+
+        let options = FindOptions::builder()
+            .projection(doc! { "jid": 1, "retries": 1, "status": 1, "started_at": 1 })
+            .build();
+        let rows: Vec<Document> = traced(&collection, "find", collection.find(None, options))
+            .await
+            .context("Failed to find jobs")?
+            .try_collect()
+            .await
+            .context("Failed to read jobs")?;
+        let mut retries = HashMap::with_capacity(rows.len());
+        for row in rows {
+            let jid = jid_of(&row, "jid")?;
+            let running = row.get_str("status") == Ok(JobStatus::Running.as_str());
+            if running && matches!(row.get("started_at"), None | Some(bson::Bson::Null)) {
+                violations.push(Violation::RunningWithoutStartedAt { jid: jid.clone() });
+            }
+            let after = row.get_i64("retries").unwrap_or_default();
+            if let Some(&before) = self.retries.get(&jid) {
+                if after < before {
+                    violations.push(Violation::RetriesDecreased {
+                        jid: jid.clone(),
+                        before,
+                        after,
+                    });
+                }
+            }
+            retries.insert(jid, after);
+        }
+        self.retries = retries;
+
+        Ok(violations)
+    }
+
+    async fn aggregate(&self, pipeline: Vec<Document>) -> Result<Vec<Document>, QueueError> {
+        let collection = self.queue.database.collection::<Document>("adc_queue");
+        let rows = traced(
+            &collection,
+            "aggregate",
+            collection.aggregate(pipeline, None),
+        )
+        .await
+        .context("Failed to check invariants")?
+        .try_collect()
+        .await
+        .context("Failed to read invariant check")?;
+        Ok(rows)
+    }
+}
+
+fn jid_of(row: &Document, field: &str) -> Result<String, QueueError> {
+    Ok(row.get_str(field).context("Job without jid")?.to_string())
+}
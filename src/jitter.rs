@@ -0,0 +1,39 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+/// Spreads the claims of competing workers over a short window, so a large enqueue burst
+/// doesn't send every idle worker at the primary in the same instant.
+///
+/// Each claim first waits in one of `slots` buckets picked at random, `slot_width` apart, plus
+/// up to `jitter` on top. With 4 slots of 5ms and 3ms of jitter, claims are spread over 18ms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClaimJitter {
+    pub slots: u32,
+    pub slot_width: Duration,
+    pub jitter: Duration,
+}
+
+impl Default for ClaimJitter {
+    fn default() -> Self {
+        Self {
+            slots: 4,
+            slot_width: Duration::from_millis(5),
+            jitter: Duration::from_millis(3),
+        }
+    }
+}
+
+impl ClaimJitter {
+    /// How long the next claim waits.
+    pub(crate) fn delay(&self) -> Duration {
+        let mut rng = rand::thread_rng();
+        let slot = rng.gen_range(0..self.slots.max(1));
+        let jitter = rng.gen_range(0..=self.jitter.as_micros() as u64);
+        self.slot_width * slot + Duration::from_micros(jitter)
+    }
+
+    pub(crate) async fn wait(&self) {
+        tokio::time::sleep(self.delay()).await;
+    }
+}
@@ -1,69 +1,335 @@
 use aide_de_camp::core::job_handle::JobHandle;
 use aide_de_camp::core::queue::QueueError;
-use aide_de_camp::core::{Bytes, Xid};
+use aide_de_camp::core::{new_xid, Bytes, DateTime, Xid};
 use anyhow::Context;
 use async_trait::async_trait;
-use bson::doc;
-use mongodb::{Collection, Database};
-use std::str::FromStr;
+use bson::{doc, Document};
+use chrono::Utc;
+use mongodb::{
+    options::{FindOneAndUpdateOptions, FindOneOptions, ReturnDocument, UpdateModifications},
+    Collection,
+};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::instrument;
 
-use crate::types::JobRow;
+use crate::{
+    drop_guard::DropGuard, error::MongoDbQueueError, events, hooks::ErrorContext, patch::Patch,
+    retry_budget::BUDGET_EXHAUSTED, status::JobStatus, trace::traced, types::JobRow, MongoDbQueue,
+};
+
+/// How long [`MongoDbJobHandle::is_cancellation_requested`] trusts its last answer.
+pub const CANCELLATION_CHECK_INTERVAL: Duration = Duration::from_secs(5);
 
-#[derive(Debug)]
 pub struct MongoDbJobHandle {
+    /// Parsed from `row.jid` when the job was claimed.
+    id: Xid,
     row: JobRow,
-    database: Database,
+    queue: MongoDbQueue,
+    cancellation: Mutex<CancellationCheck>,
+    drop_guard: DropGuard,
+}
+
+#[derive(Debug)]
+struct CancellationCheck {
+    requested: bool,
+    checked_at: Instant,
 }
 
 #[async_trait]
 impl JobHandle for MongoDbJobHandle {
     fn id(&self) -> Xid {
-        Xid::from_str(&self.row.jid).unwrap()
+        self.id
     }
 
     fn job_type(&self) -> &str {
         &self.row.job_type
     }
 
+    /// The bincode-encoded payload. With a [`PayloadCodec`](crate::PayloadCodec) for the job
+    /// type, the stored bytes are decoded first, and passed on as they are if that fails so
+    /// the job fails to decode like any other malformed payload.
     fn payload(&self) -> Bytes {
-        self.row.payload.bytes.clone().into()
+        match self.queue.decode_stored(
+            &self.row.job_type,
+            self.row.content_type.as_deref(),
+            &self.row.payload.bytes,
+        ) {
+            Ok(payload) => payload.into(),
+            Err(error) => {
+                tracing::warn!(jid = %self.row.jid, %error, "Failed to decode stored payload");
+                self.row.payload.bytes.clone().into()
+            }
+        }
     }
 
+    /// Failed attempts before this one, 0 on the first run. See [`MongoDbJobHandle::attempts`]
+    /// for the number of claims.
     fn retries(&self) -> u32 {
         self.row.retries as u32
     }
 
+    #[instrument(skip_all, err, fields(jid = %self.row.jid, job_type = %self.row.job_type, attempt = self.row.attempts, correlation_id = self.row.correlation_id.as_deref()))]
     async fn complete(mut self) -> Result<(), QueueError> {
-        self.collection()
-            .delete_one(doc! { "jid": self.row.jid }, None)
+        self.drop_guard.disarm();
+        let result = async {
+            self.queue.hold_claim(&self.row).await?;
+            let collection = self.collection();
+            let mut session = collection
+                .client()
+                .start_session(None)
+                .await
+                .context("Failed to start session")?;
+            session
+                .start_transaction(None)
+                .await
+                .context("Failed to start transaction")?;
+            let stored = traced(
+                &collection,
+                "find_one_and_delete",
+                collection.find_one_and_delete_with_session(
+                    self.queue.claim_fence(&self.row),
+                    None,
+                    &mut session,
+                ),
+            )
             .await
             .context("Failed to mark job as completed")?;
-        Ok(())
+            let Some(stored) = stored else {
+                session
+                    .abort_transaction()
+                    .await
+                    .context("Failed to abort transaction")?;
+                return Err(self.claim_lost());
+            };
+            self.queue
+                .release_dependents(&[&self.row.jid], &mut session)
+                .await?;
+            session
+                .commit_transaction()
+                .await
+                .context("Failed to commit transaction")?;
+            // Annotations may have been added since the claim.
+            self.row.annotations = stored.annotations;
+            self.row.status = JobStatus::Completed;
+            self.queue.end_leases(&[&self.row]).await
+        }
+        .await;
+        if result.is_ok() {
+            events::completed(&self.queue, &self.row);
+            // The job is done either way, missing usage is not worth failing it for.
+            if let Err(error) = self.queue.record_usage(&self.row).await {
+                self.queue
+                    .report_error(&self.error_context("record_usage"), &error);
+            }
+            if let Err(error) = self.queue.archive_completed(&[&self.row]).await {
+                self.queue
+                    .report_error(&self.error_context("archive"), &error);
+            }
+            self.record_attempt().await;
+        }
+        self.reported("complete", result)
     }
 
+    #[instrument(skip_all, err, fields(jid = %self.row.jid, job_type = %self.row.job_type, attempt = self.row.attempts, correlation_id = self.row.correlation_id.as_deref()))]
     async fn fail(mut self) -> Result<(), QueueError> {
-        self.collection()
-            .update_one(
-                doc! { "jid": self.row.jid },
-                doc! { "$set": { "started_at": None::<bson::DateTime> } },
-                None,
+        self.drop_guard.disarm();
+        let result = async {
+            self.queue.hold_claim(&self.row).await?;
+            let mut update = doc! { "started_at": None::<bson::DateTime> };
+            self.row.status = JobStatus::Pending;
+            if !self.queue.spend_retry(&self.row.job_type).await? {
+                self.row.status = JobStatus::Parked;
+                update.insert("parked", BUDGET_EXHAUSTED);
+            } else if self.queue.retry_backoffs.contains_key(&self.row.job_type) {
+                let failures = self.row.retries as u32 + 1;
+                let retry_at = self
+                    .queue
+                    .retry_at(&self.row.job_type, failures, Utc::now());
+                update.insert("scheduled_at", bson::DateTime::from_chrono(retry_at));
+            }
+            update.insert("status", self.row.status);
+            let collection = self.collection();
+            let options = FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build();
+            let modifications: UpdateModifications = match self.queue.priority_decay {
+                Some(decay) => {
+                    update.insert("retries", doc! { "$add": ["$retries", 1] });
+                    update.insert("priority", decay.priority());
+                    vec![doc! { "$set": update }].into()
+                }
+                None => doc! { "$set": update, "$inc": { "retries": 1 } }.into(),
+            };
+            let row = traced(
+                &collection,
+                "find_one_and_update",
+                collection.find_one_and_update(
+                    self.queue.claim_fence(&self.row),
+                    modifications,
+                    options,
+                ),
             )
             .await
-            .context("Failed to mark job as failed")?;
-        Ok(())
+            .context("Failed to mark job as failed")?
+            .ok_or_else(|| self.claim_lost())?;
+            // Leased jobs don't store their attempts
+            let attempts = self.row.attempts.max(row.attempts);
+            let started_at = self.row.started_at;
+            self.row = row;
+            self.row.attempts = attempts;
+            self.row.started_at = started_at;
+            self.queue.end_leases(&[&self.row]).await?;
+            events::failed(&self.queue, &self.row);
+            Ok(())
+        }
+        .await;
+        if result.is_ok() {
+            self.record_attempt().await;
+        }
+        self.reported("fail", result)
     }
 
+    #[instrument(skip_all, err, fields(jid = %self.row.jid, job_type = %self.row.job_type, attempt = self.row.attempts, correlation_id = self.row.correlation_id.as_deref()))]
     async fn dead_queue(mut self) -> Result<(), QueueError> {
-        let collection = self.collection().clone();
-        let dead_collection = self.dead_queue_collection().clone();
-        let client = collection.client();
+        self.drop_guard.disarm();
+        let result = match self.move_to_dead_queue().await {
+            Ok(row) => {
+                // Leased jobs don't store when they were claimed
+                let started_at = self.row.started_at;
+                self.row = row;
+                self.row.started_at = self.row.started_at.or(started_at);
+                Ok(())
+            }
+            Err(error) => Err(error),
+        };
+        if result.is_ok() {
+            self.row.status = JobStatus::Dead;
+            events::dead(&self.queue, &self.row);
+            self.queue
+                .error_reporter
+                .report_dead_job(&self.error_context("dead_queue"));
+            self.record_attempt().await;
+        }
+        self.reported("dead_queue", result)
+    }
+}
+
+impl MongoDbJobHandle {
+    pub(crate) fn row(&self) -> &JobRow {
+        &self.row
+    }
 
-        let jid = self.row.jid;
-        let retries = self.row.retries;
-        let job_type = self.row.job_type.clone();
-        let payload = self.row.payload.clone();
-        let scheduled_at = self.row.scheduled_at;
-        let enqueued_at = self.row.enqueued_at;
+    pub(crate) fn into_row(mut self) -> JobRow {
+        self.drop_guard.disarm();
+        self.row
+    }
+
+    /// Reload the job, picking up changes made since it was claimed, e.g. with
+    /// [`MongoDbQueue::annotate`](crate::MongoDbQueue::annotate) or
+    /// [`MongoDbQueue::bulk_update`](crate::MongoDbQueue::bulk_update).
+    #[instrument(skip_all, err, fields(jid = %self.row.jid, job_type = %self.row.job_type))]
+    pub async fn refresh(&mut self) -> Result<(), QueueError> {
+        let result = async {
+            let collection = self.collection();
+            let row = traced(
+                &collection,
+                "find_one",
+                collection.find_one(self.queue.scoped(doc! { "jid": &self.row.jid }), None),
+            )
+            .await
+            .context("Failed to reload job")?;
+            row.ok_or_else(|| QueueError::JobNotFound(self.id()))
+        }
+        .await;
+        let row = self.reported("refresh", result)?;
+        self.cancellation.lock().unwrap().requested |= row.cancel_requested;
+        self.row = row;
+        Ok(())
+    }
+
+    pub(crate) fn new(id: Xid, row: JobRow, queue: MongoDbQueue) -> Self {
+        let cancellation = Mutex::new(CancellationCheck {
+            requested: row.cancel_requested,
+            checked_at: Instant::now(),
+        });
+        let drop_guard = DropGuard::new(&queue, &row.jid, &row.job_type, row.attempts);
+        Self {
+            id,
+            row,
+            queue,
+            cancellation,
+            drop_guard,
+        }
+    }
+
+    /// Put the job back without counting the claim as an attempt, for a worker that claimed a job
+    /// it can't process after all, e.g. because it is shutting down.
+    #[instrument(skip_all, err, fields(jid = %self.row.jid, job_type = %self.row.job_type))]
+    pub async fn release(mut self) -> Result<(), QueueError> {
+        self.drop_guard.disarm();
+        let result = self.queue.release_claim(&self.row, None).await;
+        self.reported("release", result)
+    }
+
+    /// Finish processing but keep the job until the outcome is confirmed with
+    /// [`MongoDbQueue::confirm_complete`] and the returned token, e.g. from a callback of the
+    /// downstream system that did the real work. Without confirmation within `timeout`, the
+    /// job is claimed and run again.
+    #[instrument(skip_all, err, fields(jid = %self.row.jid, job_type = %self.row.job_type, attempt = self.row.attempts, correlation_id = self.row.correlation_id.as_deref()))]
+    pub async fn complete_pending(
+        mut self,
+        timeout: chrono::Duration,
+    ) -> Result<String, QueueError> {
+        self.drop_guard.disarm();
+        let token = new_xid().to_string();
+        let result = async {
+            self.queue.hold_claim(&self.row).await?;
+            let retry_at = Utc::now() + timeout;
+            let collection = self.collection();
+            let result = traced(
+                &collection,
+                "update_one",
+                collection.update_one(
+                    self.queue.claim_fence(&self.row),
+                    doc! {
+                        "$set": {
+                            "status": JobStatus::Pending,
+                            "started_at": None::<bson::DateTime>,
+                            "scheduled_at": bson::DateTime::from_chrono(retry_at),
+                            "completion_token": &token,
+                        },
+                    },
+                    None,
+                ),
+            )
+            .await
+            .context("Failed to mark job completion as pending")?;
+            if result.matched_count == 0 {
+                return Err(self.claim_lost());
+            }
+            self.queue.end_leases(&[&self.row]).await?;
+            Ok(token)
+        }
+        .await;
+        if result.is_ok() {
+            if let Err(error) = self.queue.record_usage(&self.row).await {
+                self.queue
+                    .report_error(&self.error_context("record_usage"), &error);
+            }
+        }
+        self.reported("complete_pending", result)
+    }
+
+    /// Move the job to the dead queue as it is stored now, returning the stored row. Fails with
+    /// [`MongoDbQueueError::ClaimLost`] if the job is no longer held by this claim.
+    async fn move_to_dead_queue(&self) -> Result<JobRow, QueueError> {
+        self.queue.hold_claim(&self.row).await?;
+        let collection = self.collection().clone_with_type::<Document>();
+        let dead_collection = self.dead_queue_collection().clone_with_type::<Document>();
+        let client = collection.client();
 
         let mut session = client
             .start_session(None)
@@ -74,49 +340,229 @@ impl JobHandle for MongoDbJobHandle {
             .await
             .context("Failed to start transaction")?;
 
-        collection
-            .delete_one_with_session(doc! { "jid": jid.clone() }, None, &mut session)
-            .await
-            .context("Failed to delete job from the queue")?;
-
-        dead_collection
-            .insert_one_with_session(
-                JobRow {
-                    jid,
-                    queue: "default".to_string(),
-                    job_type,
-                    payload,
-                    retries,
-                    scheduled_at,
-                    enqueued_at,
-                    priority: 0,
-                    started_at: None,
-                },
+        let deleted = traced(
+            &collection,
+            "find_one_and_delete",
+            collection.find_one_and_delete_with_session(
+                self.queue.claim_fence(&self.row),
                 None,
                 &mut session,
-            )
-            .await
-            .context("Failed to mark job as dead")?;
+            ),
+        )
+        .await
+        .context("Failed to delete job from the queue")?;
+        let Some(deleted) = deleted else {
+            // Finished by someone else, or claimed again after this claim was reaped
+            session
+                .abort_transaction()
+                .await
+                .context("Failed to abort transaction")?;
+            return Err(self.claim_lost());
+        };
+        // Retries and notes may have changed since the job was claimed.
+        let mut dead = deleted.clone();
+        Patch::dead_letter(None).apply(&mut dead);
+
+        traced(
+            &dead_collection,
+            "insert_one",
+            dead_collection.insert_one_with_session(dead, None, &mut session),
+        )
+        .await
+        .context("Failed to mark job as dead")?;
 
         session
             .commit_transaction()
             .await
             .context("Failed to commit transaction")?;
+        self.queue.end_leases(&[&self.row]).await?;
+
+        let deleted = bson::from_document(deleted).context("Failed to read dead job")?;
+        Ok(deleted)
+    }
+
+    /// The id given by the [`IdGenerator`](crate::IdGenerator), or the jid without one.
+    pub fn public_id(&self) -> &str {
+        self.row.public_id.as_deref().unwrap_or(&self.row.jid)
+    }
 
+    /// Times the job was claimed, including this one. Claims undone with [`Self::release`] or
+    /// a [`ClaimFilter`](crate::ClaimFilter) don't count.
+    pub fn attempts(&self) -> u32 {
+        self.row.attempts as u32
+    }
+
+    /// The instant the job was claimed at, as passed to
+    /// [`Queue::poll_next_with_instant`](aide_de_camp::core::queue::Queue::poll_next_with_instant).
+    pub fn started_at(&self) -> DateTime {
+        // Set on every claim, the scheduled time is only there for rows that were never claimed.
+        self.row
+            .started_at
+            .unwrap_or(self.row.scheduled_at)
+            .to_chrono()
+    }
+
+    /// The correlation id the job was scheduled with, see
+    /// [`ScheduleOptions::correlation_id`](crate::ScheduleOptions::correlation_id).
+    pub fn correlation_id(&self) -> Option<&str> {
+        self.row.correlation_id.as_deref()
+    }
+
+    /// For a copy added by a [`CanaryMode::Duplicate`](crate::CanaryMode::Duplicate) route,
+    /// the id of the original job.
+    pub fn shadow_of(&self) -> Option<&str> {
+        self.row.shadow_of.as_deref()
+    }
+
+    /// Notes attached with [`MongoDbQueue::annotate`](crate::MongoDbQueue::annotate) before
+    /// the job was claimed or last refreshed with [`Self::refresh`].
+    pub fn annotations(&self) -> &BTreeMap<String, String> {
+        &self.row.annotations
+    }
+
+    /// Headers for middleware, see [`ScheduleOptions::headers`](crate::ScheduleOptions::headers).
+    pub fn headers(&self) -> &BTreeMap<String, String> {
+        &self.row.headers
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.row.headers.get(name).map(String::as_str)
+    }
+
+    /// Media type of the payload, if the job was tagged with one, see
+    /// [`ScheduleOptions::content_type`](crate::ScheduleOptions::content_type).
+    pub fn content_type(&self) -> Option<&str> {
+        self.row.content_type.as_deref()
+    }
+
+    /// Version of the handler the job was scheduled for, see
+    /// [`ScheduleOptions::handler_version`](crate::ScheduleOptions::handler_version).
+    pub fn handler_version(&self) -> Option<u32> {
+        self.row.handler_version.map(|version| version as u32)
+    }
+
+    /// Set the header `name` of the stored job, so it is kept if the job is retried or moved
+    /// to the dead queue, e.g. for middleware that records which key it encrypted a result
+    /// with.
+    #[instrument(skip_all, err, fields(jid = %self.row.jid, name = name))]
+    pub async fn set_header(&mut self, name: &str, value: &str) -> Result<(), QueueError> {
+        let result = async {
+            check_header_name(name)?;
+            let collection = self.collection();
+            let result = traced(
+                &collection,
+                "update_one",
+                collection.update_one(
+                    self.queue.scoped(doc! { "jid": &self.row.jid }),
+                    doc! { "$set": { format!("headers.{name}"): value } },
+                    None,
+                ),
+            )
+            .await
+            .context("Failed to set header")?;
+            if result.matched_count == 0 {
+                return Err(QueueError::JobNotFound(self.id()));
+            }
+            Ok(())
+        }
+        .await;
+        self.reported("set_header", result)?;
+        self.row.headers.insert(name.to_string(), value.to_string());
         Ok(())
     }
-}
 
-impl MongoDbJobHandle {
-    pub(crate) fn new(row: JobRow, database: Database) -> Self {
-        Self { row, database }
+    /// Whether cancellation of this job was requested with
+    /// [`MongoDbQueue::request_cancellation`](crate::MongoDbQueue::request_cancellation).
+    ///
+    /// Cheap enough to call in a loop: the job document is read at most once per
+    /// [`CANCELLATION_CHECK_INTERVAL`], and never again once a request was seen.
+    pub async fn is_cancellation_requested(&self) -> Result<bool, QueueError> {
+        {
+            let check = self.cancellation.lock().unwrap();
+            if check.requested || check.checked_at.elapsed() < CANCELLATION_CHECK_INTERVAL {
+                return Ok(check.requested);
+            }
+        }
+
+        let options = FindOneOptions::builder()
+            .projection(doc! { "cancel_requested": 1 })
+            .build();
+        let collection = self.queue.database.collection::<Document>("adc_queue");
+        let requested = traced(
+            &collection,
+            "find_one",
+            collection.find_one(self.queue.scoped(doc! { "jid": &self.row.jid }), options),
+        )
+        .await
+        .context("Failed to check job cancellation")?
+        .and_then(|row| row.get_bool("cancel_requested").ok())
+        .unwrap_or(false);
+
+        let mut check = self.cancellation.lock().unwrap();
+        check.requested |= requested;
+        check.checked_at = Instant::now();
+        Ok(check.requested)
+    }
+
+    /// Add the attempt to the audit trail. The job has its outcome either way, so a failure is
+    /// only reported.
+    async fn record_attempt(&self) {
+        if let Err(error) = self.queue.record_attempts(&[&self.row]).await {
+            self.queue
+                .report_error(&self.error_context("audit_trail"), &error);
+        }
+    }
+
+    fn claim_lost(&self) -> QueueError {
+        MongoDbQueueError::ClaimLost {
+            jid: self.row.jid.clone(),
+        }
+        .into()
+    }
+
+    fn error_context(&self, operation: &'static str) -> ErrorContext<'_> {
+        ErrorContext {
+            operation,
+            jid: Some(&self.row.jid),
+            job_type: Some(&self.row.job_type),
+            correlation_id: self.row.correlation_id.as_deref(),
+        }
+    }
+
+    fn reported<T>(
+        &self,
+        operation: &'static str,
+        result: Result<T, QueueError>,
+    ) -> Result<T, QueueError> {
+        if let Err(error) = &result {
+            self.queue
+                .report_error(&self.error_context(operation), error);
+        }
+        result
     }
 
     fn collection(&self) -> Collection<JobRow> {
-        self.database.collection("adc_queue")
+        self.queue.collection()
     }
 
     fn dead_queue_collection(&self) -> Collection<JobRow> {
-        self.database.collection("adc_dead_queue")
+        self.queue.database.collection("adc_dead_queue")
+    }
+}
+
+impl fmt::Debug for MongoDbJobHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MongoDbJobHandle")
+            .field("row", &self.row)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Header names become field names of the stored job, so dots and leading dollar signs would
+/// turn them into paths or operators.
+pub(crate) fn check_header_name(name: &str) -> Result<(), MongoDbQueueError> {
+    if name.is_empty() || name.contains('.') || name.starts_with('$') {
+        return Err(MongoDbQueueError::InvalidHeader(name.to_string()));
     }
+    Ok(())
 }
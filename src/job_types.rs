@@ -0,0 +1,145 @@
+use std::{
+    collections::HashSet,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use aide_de_camp::core::queue::QueueError;
+use anyhow::Context;
+use bson::doc;
+use mongodb::{
+    options::{FindOneOptions, UpdateOptions},
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::{error::MongoDbQueueError, trace::traced, MongoDbQueue};
+
+/// How long a queue keeps using the allow-list it read before reading it again. Job types
+/// missing from it are looked up again right away, so only removals made through other queue
+/// instances take up to this long to be seen.
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The allow-list as last read, shared by the clones of a queue.
+#[derive(Default)]
+pub(crate) struct AllowedJobTypes {
+    job_types: Mutex<Option<(Instant, HashSet<String>)>>,
+}
+
+impl AllowedJobTypes {
+    fn invalidate(&self) {
+        *self.job_types.lock().unwrap() = None;
+    }
+}
+
+/// The job type allow-list in `adc_meta`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct JobTypesRow {
+    #[serde(default)]
+    job_types: Vec<String>,
+}
+
+impl MongoDbQueue {
+    /// Refuse to schedule jobs, including debounced and recurring ones, whose type is not
+    /// registered with [`Self::register_job_types`], with
+    /// [`MongoDbQueueError::UnknownJobType`]. Catches typos and producers ahead of their
+    /// workers when scheduling, instead of leaving jobs that are never claimed.
+    ///
+    /// With no job types registered, every job is refused. A job type on the allow-list is
+    /// trusted for [`REFRESH_INTERVAL`], so unregistering it through another queue instance
+    /// takes up to that long to apply. One that isn't is looked up again before the job is
+    /// refused, so types registered elsewhere are accepted right away.
+    pub fn with_registered_job_types_only(mut self) -> Self {
+        self.registered_job_types_only = true;
+        self
+    }
+
+    /// Add `job_types` to the allow-list kept in `adc_meta`, e.g. the job types a worker
+    /// handles, registered on startup.
+    #[instrument(skip_all, err, fields(job_types = ?job_types))]
+    pub async fn register_job_types(&self, job_types: &[&str]) -> Result<(), QueueError> {
+        let collection = self.meta_collection();
+        traced(
+            &collection,
+            "update_one",
+            collection.update_one(
+                doc! { "_id": self.scoped_key("job_types") },
+                doc! { "$addToSet": { "job_types": { "$each": job_types } } },
+                UpdateOptions::builder().upsert(true).build(),
+            ),
+        )
+        .await
+        .context("Failed to register job types")?;
+        self.allowed_job_types.invalidate();
+        Ok(())
+    }
+
+    /// Remove `job_type` from the allow-list, e.g. once no worker handles it anymore.
+    #[instrument(skip_all, err, fields(job_type = job_type))]
+    pub async fn unregister_job_type(&self, job_type: &str) -> Result<(), QueueError> {
+        let collection = self.meta_collection();
+        traced(
+            &collection,
+            "update_one",
+            collection.update_one(
+                doc! { "_id": self.scoped_key("job_types") },
+                doc! { "$pull": { "job_types": job_type } },
+                None,
+            ),
+        )
+        .await
+        .context("Failed to unregister job type")?;
+        self.allowed_job_types.invalidate();
+        Ok(())
+    }
+
+    /// The job types on the allow-list.
+    #[instrument(skip_all, err)]
+    pub async fn registered_job_types(&self) -> Result<Vec<String>, QueueError> {
+        let collection = self.meta_collection();
+        let row = traced(
+            &collection,
+            "find_one",
+            collection.find_one(
+                doc! { "_id": self.scoped_key("job_types") },
+                FindOneOptions::builder()
+                    .projection(doc! { "job_types": 1 })
+                    .build(),
+            ),
+        )
+        .await
+        .context("Failed to read registered job types")?;
+        Ok(row.unwrap_or_default().job_types)
+    }
+
+    /// Fail if this queue only takes registered job types and `job_type` isn't one. Trusts
+    /// the allow-list read up to [`REFRESH_INTERVAL`] ago only for the job types on it, the
+    /// others are checked against the stored list.
+    pub(crate) async fn check_job_type(&self, job_type: &str) -> Result<(), QueueError> {
+        if !self.registered_job_types_only {
+            return Ok(());
+        }
+        let cached = match &*self.allowed_job_types.job_types.lock().unwrap() {
+            Some((read_at, job_types)) => {
+                read_at.elapsed() < REFRESH_INTERVAL && job_types.contains(job_type)
+            }
+            None => false,
+        };
+        if cached {
+            return Ok(());
+        }
+        // Possibly registered since the list was read, e.g. by a worker that just started
+        let job_types: HashSet<String> = self.registered_job_types().await?.into_iter().collect();
+        let allowed = job_types.contains(job_type);
+        *self.allowed_job_types.job_types.lock().unwrap() = Some((Instant::now(), job_types));
+        if !allowed {
+            return Err(MongoDbQueueError::UnknownJobType(job_type.to_string()).into());
+        }
+        Ok(())
+    }
+
+    fn meta_collection(&self) -> Collection<JobTypesRow> {
+        self.database.collection("adc_meta")
+    }
+}
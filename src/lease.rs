@@ -0,0 +1,306 @@
+use aide_de_camp::core::{queue::QueueError, DateTime, Duration};
+use anyhow::Context;
+use bson::{doc, Document};
+use chrono::Utc;
+use futures::TryStreamExt;
+use mongodb::{options::UpdateOptions, Collection};
+use tracing::instrument;
+
+use crate::{
+    error::MongoDbQueueError,
+    ordering::ClaimPass,
+    recurring::is_duplicate_key,
+    status::{self, JobStatus},
+    trace::traced,
+    types::JobRow,
+    MongoDbQueue,
+};
+
+/// Collection the leases of [`ClaimMode::Lease`] are kept in.
+pub const CLAIMS_COLLECTION: &str = "adc_claims";
+
+/// Candidates a poll tries before giving up when other workers keep leasing them first.
+const MAX_LEASE_RACES: usize = 3;
+
+/// How a claimed job is marked, set with [`MongoDbQueue::with_claim_mode`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClaimMode {
+    /// Set the job's status, start time and attempt count in the job document.
+    #[default]
+    Inline,
+    /// Leave the job document alone and record the claim as a lease in the `adc_claims`
+    /// collection (jid, worker, expiry). A lease that is not ended by an outcome within the
+    /// duration expires and the job can be claimed again.
+    ///
+    /// The job collection then only changes when jobs are added, fail or finish, and claims
+    /// can be rebuilt after an incident by dropping the leases with
+    /// [`MongoDbQueue::clear_claims`]. Leased jobs keep their pending status, so
+    /// [`MongoDbQueue::stats`] counts them as ready, and their attempt count is derived from
+    /// the number of failures. The [`DropBehavior`](crate::DropBehavior) does not apply; a
+    /// dropped handle's lease simply expires.
+    Lease(Duration),
+}
+
+impl MongoDbQueue {
+    /// How claims are recorded, [`ClaimMode::Inline`] by default.
+    pub fn with_claim_mode(mut self, mode: ClaimMode) -> Self {
+        self.claim_mode = mode;
+        self
+    }
+
+    /// Drop every lease of this queue's namespace, making all leased jobs claimable again.
+    /// Returns the number of leases dropped.
+    #[instrument(skip_all, err, ret)]
+    pub async fn clear_claims(&self) -> Result<u64, QueueError> {
+        let collection = self.claims_collection();
+        let result = traced(
+            &collection,
+            "delete_many",
+            collection.delete_many(self.scoped(doc! {}), None),
+        )
+        .await
+        .context("Failed to clear claims")?;
+        Ok(result.deleted_count)
+    }
+
+    /// Lease the first job found by `passes` that isn't leased already.
+    pub(crate) async fn lease_first(
+        &self,
+        passes: &[ClaimPass],
+        filter_doc: &Document,
+        now: DateTime,
+        duration: Duration,
+    ) -> Result<Option<JobRow>, QueueError> {
+        let mut excluded = Vec::new();
+        if let Ok(rejected) = filter_doc
+            .get_document("jid")
+            .and_then(|jid| jid.get_array("$nin"))
+        {
+            excluded.extend(
+                rejected
+                    .iter()
+                    .filter_map(|jid| jid.as_str().map(str::to_string)),
+            );
+        }
+
+        let collection = self.collection();
+        for pass in passes {
+            for _ in 0..MAX_LEASE_RACES {
+                let mut filter_doc = pass.apply(filter_doc);
+                if !excluded.is_empty() {
+                    filter_doc.insert("jid", doc! { "$nin": &excluded });
+                }
+                let Some(mut row) = self.first_unleased(filter_doc, &pass.sort, now).await? else {
+                    break;
+                };
+                let acquired = self
+                    .retrying("lease", || self.acquire_lease(&row.jid, now, duration))
+                    .await?;
+                if !acquired {
+                    self.claim_counters.lost_race();
+                    excluded.push(row.jid);
+                    continue;
+                }
+
+                if row.completion_token.take().is_some() {
+                    // A completion that was never confirmed is being retried
+                    traced(
+                        &collection,
+                        "update_one",
+                        collection.update_one(
+                            doc! { "jid": &row.jid },
+                            doc! { "$unset": { "completion_token": "" } },
+                            None,
+                        ),
+                    )
+                    .await
+                    .context("Failed to clear completion token")?;
+                }
+                row.status = JobStatus::Running;
+                row.started_at = Some(bson::DateTime::from_chrono(now));
+                row.attempts = row.retries + 1;
+                return Ok(Some(row));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The first job matching `filter_doc` in `sort` order without a lease that is still live
+    /// at `now`. Leases are joined per candidate, so live leases don't have to be loaded.
+    async fn first_unleased(
+        &self,
+        filter_doc: Document,
+        sort: &Document,
+        now: DateTime,
+    ) -> Result<Option<JobRow>, QueueError> {
+        let collection = self.collection();
+        let pipeline = vec![
+            doc! { "$match": filter_doc },
+            doc! { "$sort": sort.clone() },
+            doc! { "$lookup": {
+                "from": self.database.collection_name(CLAIMS_COLLECTION),
+                "let": { "jid": "$jid" },
+                "pipeline": [
+                    { "$match": { "$expr": { "$and": [
+                        { "$eq": ["$_id", "$$jid"] },
+                        { "$gt": ["$expires_at", bson::DateTime::from_chrono(now)] },
+                    ] } } },
+                    { "$project": { "_id": 1 } },
+                ],
+                "as": "live_leases",
+            } },
+            doc! { "$match": { "live_leases.0": { "$exists": false } } },
+            doc! { "$limit": 1 },
+            doc! { "$unset": "live_leases" },
+        ];
+        let row: Option<Document> = traced(
+            &collection,
+            "aggregate",
+            collection.aggregate(pipeline, None),
+        )
+        .await
+        .context("Failed to look up a job to lease")?
+        .try_next()
+        .await
+        .context("Failed to look up a job to lease")?;
+        Ok(row
+            .map(bson::from_document)
+            .transpose()
+            .context("Failed to read job to lease")?)
+    }
+
+    /// Take the lease on `jid`, unless another worker holds one that has not expired.
+    async fn acquire_lease(
+        &self,
+        jid: &str,
+        now: DateTime,
+        duration: Duration,
+    ) -> Result<bool, QueueError> {
+        let collection = self.claims_collection();
+        let result = traced(
+            &collection,
+            "update_one",
+            collection.update_one(
+                doc! { "_id": jid, "expires_at": { "$lte": bson::DateTime::from_chrono(now) } },
+                doc! { "$set": {
+                    "worker": worker_id(),
+                    "claimed_at": bson::DateTime::from_chrono(now),
+                    "expires_at": bson::DateTime::from_chrono(now + duration),
+                    "namespace": self.namespace.as_deref(),
+                } },
+                UpdateOptions::builder().upsert(true).build(),
+            ),
+        )
+        .await;
+        match result {
+            Ok(_) => Ok(true),
+            // A live lease exists, so the upsert tried to insert a second one
+            Err(error) if is_duplicate_key(&error) => Ok(false),
+            Err(error) => Err(anyhow::Error::new(error)
+                .context("Failed to lease job")
+                .into()),
+        }
+    }
+
+    /// End the leases `rows` were claimed with, if claims are leases. Leases taken over by
+    /// another claim are left alone.
+    pub(crate) async fn end_leases(&self, rows: &[&JobRow]) -> Result<(), QueueError> {
+        if self.claim_mode == ClaimMode::Inline || rows.is_empty() {
+            return Ok(());
+        }
+        let leases: Vec<Document> = rows.iter().map(|row| lease_of(row)).collect();
+        let collection = self.claims_collection();
+        traced(
+            &collection,
+            "delete_many",
+            collection.delete_many(doc! { "$or": leases }, None),
+        )
+        .await
+        .context("Failed to end lease")?;
+        Ok(())
+    }
+
+    /// The jids of `rows` whose claim is still held. For leases, these are the ones whose
+    /// lease wasn't taken over or expired, and they are extended by the lease duration so they
+    /// can't be taken over while the outcome is recorded.
+    pub(crate) async fn hold_claims(&self, rows: &[&JobRow]) -> Result<Vec<String>, QueueError> {
+        let ClaimMode::Lease(duration) = self.claim_mode else {
+            return Ok(rows.iter().map(|row| row.jid.clone()).collect());
+        };
+        let now = Utc::now();
+        let collection = self.claims_collection();
+        let mut held = Vec::with_capacity(rows.len());
+        for row in rows {
+            let mut lease = lease_of(row);
+            lease.insert(
+                "expires_at",
+                doc! { "$gt": bson::DateTime::from_chrono(now) },
+            );
+            let result = traced(
+                &collection,
+                "update_one",
+                collection.update_one(
+                    lease,
+                    doc! { "$set": {
+                        "expires_at": bson::DateTime::from_chrono(now + duration),
+                    } },
+                    None,
+                ),
+            )
+            .await
+            .context("Failed to hold lease")?;
+            if result.matched_count > 0 {
+                held.push(row.jid.clone());
+            }
+        }
+        Ok(held)
+    }
+
+    /// Fail with [`MongoDbQueueError::ClaimLost`] unless the claim `row` was handed out with
+    /// is still held, see [`Self::hold_claims`].
+    pub(crate) async fn hold_claim(&self, row: &JobRow) -> Result<(), QueueError> {
+        if self.hold_claims(&[row]).await?.is_empty() {
+            return Err(MongoDbQueueError::ClaimLost {
+                jid: row.jid.clone(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Filter matching `row` in this queue's namespace only while it is still held by the
+    /// claim it was handed out with. Leased jobs keep their pending status, so their claim has
+    /// to be checked with [`Self::hold_claims`] first.
+    pub(crate) fn claim_fence(&self, row: &JobRow) -> Document {
+        self.scoped(match self.claim_mode {
+            ClaimMode::Inline => {
+                doc! { "jid": &row.jid, "attempts": row.attempts, "status": JobStatus::Running }
+            }
+            ClaimMode::Lease(_) => status::pending(doc! { "jid": &row.jid }),
+        })
+    }
+
+    /// The stored status of claimed jobs.
+    pub(crate) fn claimed_status(&self) -> JobStatus {
+        match self.claim_mode {
+            ClaimMode::Inline => JobStatus::Running,
+            ClaimMode::Lease(_) => JobStatus::Pending,
+        }
+    }
+
+    fn claims_collection(&self) -> Collection<Document> {
+        self.database.collection(CLAIMS_COLLECTION)
+    }
+}
+
+/// Filter matching the lease `row` was claimed with: the one this process took when it
+/// claimed the job, not a later lease of the same or another worker.
+fn lease_of(row: &JobRow) -> Document {
+    doc! { "_id": &row.jid, "worker": worker_id(), "claimed_at": row.started_at }
+}
+
+/// Identifies this process in leases.
+pub(crate) fn worker_id() -> String {
+    let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "unknown".to_string());
+    format!("{host}:{}", std::process::id())
+}
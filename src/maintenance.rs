@@ -0,0 +1,245 @@
+#[cfg(feature = "metrics")]
+use std::net::SocketAddr;
+use std::{
+    future::Future,
+    pin::pin,
+    sync::{Arc, Mutex},
+};
+
+use aide_de_camp::core::{queue::QueueError, Duration};
+use chrono::Utc;
+use futures::future::{select, Either};
+use tracing::instrument;
+
+use crate::{
+    archive::ArchiveSink,
+    hooks::ErrorContext,
+    orphans::OrphanAction,
+    tiering::{TieringOptions, TieringReport},
+    MongoDbQueue,
+};
+
+/// Most archived jobs [`MaintenanceRunner::with_archive_sink`] ships per run.
+pub const ARCHIVE_SHIP_BATCH_SIZE: usize = 1000;
+
+/// Periodic housekeeping next to the workers: materializing recurring jobs and, when enabled,
+/// moving jobs between hot and cold storage, recording queue depth history, looking for
+/// orphaned job types, checking monitors and shipping the archive.
+///
+/// Every task is safe to run from several processes at once.
+#[derive(Clone)]
+pub struct MaintenanceRunner {
+    pub(crate) queue: MongoDbQueue,
+    interval: Duration,
+    tiering: Option<TieringOptions>,
+    stats_history: bool,
+    orphan_check: Option<(Duration, OrphanAction)>,
+    monitors: bool,
+    archive_sink: Option<Arc<dyn ArchiveSink>>,
+    totals: Arc<Mutex<MaintenanceTotals>>,
+    #[cfg(feature = "metrics")]
+    pub(crate) metrics_addr: Option<SocketAddr>,
+}
+
+/// What one [`MaintenanceRunner::run_once`] did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MaintenanceReport {
+    pub recurring_materialized: usize,
+    pub tiering: TieringReport,
+    pub stats_recorded: bool,
+    /// Orphaned job types found, see [`MaintenanceRunner::with_orphan_check`].
+    pub orphaned_job_types: usize,
+    /// Jobs parked because their job type was orphaned.
+    pub orphans_parked: u64,
+    /// Monitor deadlines that passed without a check-in, see
+    /// [`MaintenanceRunner::with_monitors`].
+    pub missed_check_ins: usize,
+    /// Archived jobs moved to the sink, see [`MaintenanceRunner::with_archive_sink`].
+    pub archived_shipped: u64,
+}
+
+/// What a [`MaintenanceRunner`] and its clones did since it was created, see
+/// [`MaintenanceRunner::totals`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MaintenanceTotals {
+    pub runs: u64,
+    pub failed_runs: u64,
+    pub recurring_materialized: u64,
+    pub demoted: u64,
+    pub promoted: u64,
+    pub orphans_parked: u64,
+    pub missed_check_ins: u64,
+    pub archived_shipped: u64,
+}
+
+impl MaintenanceTotals {
+    fn add(&mut self, result: &Result<MaintenanceReport, QueueError>) {
+        self.runs += 1;
+        match result {
+            Ok(report) => {
+                self.recurring_materialized += report.recurring_materialized as u64;
+                self.demoted += report.tiering.demoted;
+                self.promoted += report.tiering.promoted;
+                self.orphans_parked += report.orphans_parked;
+                self.missed_check_ins += report.missed_check_ins as u64;
+                self.archived_shipped += report.archived_shipped;
+            }
+            Err(_) => self.failed_runs += 1,
+        }
+    }
+}
+
+impl MaintenanceRunner {
+    pub fn new(queue: MongoDbQueue) -> Self {
+        Self {
+            queue,
+            interval: Duration::seconds(10),
+            tiering: None,
+            stats_history: false,
+            orphan_check: None,
+            monitors: false,
+            archive_sink: None,
+            totals: Arc::default(),
+            #[cfg(feature = "metrics")]
+            metrics_addr: None,
+        }
+    }
+
+    /// Time between runs, 10 seconds by default.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Move far-future jobs to cold storage and back, see [`MongoDbQueue::tier_jobs`].
+    pub fn with_tiering(mut self, options: TieringOptions) -> Self {
+        self.tiering = Some(options);
+        self
+    }
+
+    /// Record a snapshot of [`MongoDbQueue::stats`] on every run, see
+    /// [`MongoDbQueue::stats_history`]. The interval sets the resolution of the history.
+    pub fn with_stats_history(mut self) -> Self {
+        self.stats_history = true;
+        self
+    }
+
+    /// Look for job types whose due jobs no worker claimed for `idle`, see
+    /// [`MongoDbQueue::orphaned_job_types`], and report or park them on every run.
+    pub fn with_orphan_check(mut self, idle: Duration, action: OrphanAction) -> Self {
+        self.orphan_check = Some((idle, action));
+        self
+    }
+
+    /// Look for [`MongoDbQueue::missed_check_ins`] on every run and hand each one to the
+    /// queue's [`ErrorReporter`](crate::ErrorReporter).
+    pub fn with_monitors(mut self) -> Self {
+        self.monitors = true;
+        self
+    }
+
+    /// Move up to [`ARCHIVE_SHIP_BATCH_SIZE`] archived jobs to `sink` on every run, see
+    /// [`MongoDbQueue::ship_archive`].
+    pub fn with_archive_sink(mut self, sink: impl ArchiveSink + 'static) -> Self {
+        self.archive_sink = Some(Arc::new(sink));
+        self
+    }
+
+    /// Totals over every run so far.
+    pub fn totals(&self) -> MaintenanceTotals {
+        *self.totals.lock().unwrap()
+    }
+
+    /// Run every task once.
+    #[instrument(skip_all, err, ret)]
+    pub async fn run_once(&self) -> Result<MaintenanceReport, QueueError> {
+        let result = self.run_tasks().await;
+        self.totals.lock().unwrap().add(&result);
+        result
+    }
+
+    async fn run_tasks(&self) -> Result<MaintenanceReport, QueueError> {
+        let now = Utc::now();
+        let mut report = MaintenanceReport {
+            recurring_materialized: self.queue.materialize_recurring(now).await?,
+            ..Default::default()
+        };
+        if let Some(tiering) = self.tiering {
+            report.tiering = self.queue.tier_jobs(tiering, now).await?;
+        }
+        if self.stats_history {
+            self.queue.record_stats_snapshot(now).await?;
+            report.stats_recorded = true;
+        }
+        if let Some((idle, action)) = self.orphan_check {
+            let orphans = self.queue.orphaned_job_types(idle, now).await?;
+            report.orphaned_job_types = orphans.len();
+            for orphan in &orphans {
+                self.queue.error_reporter.report_orphaned_job_type(orphan);
+                if action == OrphanAction::Park {
+                    report.orphans_parked += self
+                        .queue
+                        .park_orphaned(&orphan.job_type, idle, now)
+                        .await?;
+                }
+            }
+        }
+        if self.monitors {
+            let missed = self.queue.missed_check_ins(now).await?;
+            report.missed_check_ins = missed.len();
+            for missed in &missed {
+                self.queue.error_reporter.report_missed_check_in(missed);
+            }
+        }
+        if let Some(sink) = &self.archive_sink {
+            report.archived_shipped = self
+                .queue
+                .ship_archive(sink.as_ref(), ARCHIVE_SHIP_BATCH_SIZE)
+                .await?;
+        }
+        Ok(report)
+    }
+
+    /// Run every task each interval until `shutdown` completes. Failed runs are handed to the
+    /// queue's [`ErrorReporter`](crate::ErrorReporter) and retried on the next interval.
+    pub async fn run_with_shutdown<F>(&self, shutdown: F)
+    where
+        F: Future<Output = ()>,
+    {
+        #[cfg(feature = "metrics")]
+        if let Some(addr) = self.metrics_addr {
+            let mut maintenance = pin!(self.run_until(shutdown));
+            // The endpoint only stops when it can't be served, maintenance carries on then.
+            if let Either::Right(_) =
+                select(maintenance.as_mut(), pin!(self.serve_metrics(addr))).await
+            {
+                maintenance.await;
+            }
+            return;
+        }
+        self.run_until(shutdown).await
+    }
+
+    async fn run_until<F>(&self, shutdown: F)
+    where
+        F: Future<Output = ()>,
+    {
+        let interval = self.interval.to_std().unwrap_or_default();
+        let mut shutdown = pin!(shutdown);
+        loop {
+            if let Err(error) = self.run_once().await {
+                let context = ErrorContext {
+                    operation: "maintenance",
+                    jid: None,
+                    job_type: None,
+                    correlation_id: None,
+                };
+                self.queue.report_error(&context, &error);
+            }
+            let sleep = pin!(tokio::time::sleep(interval));
+            if let Either::Left(_) = select(shutdown.as_mut(), sleep).await {
+                return;
+            }
+        }
+    }
+}
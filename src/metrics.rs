@@ -0,0 +1,215 @@
+use std::{fmt::Write, net::SocketAddr, sync::Arc, time::Duration};
+
+use aide_de_camp::core::queue::QueueError;
+use anyhow::Context;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::Semaphore,
+};
+use tracing::instrument;
+
+use crate::{hooks::ErrorContext, maintenance::MaintenanceRunner};
+
+const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Largest request head read from a scraper. Anything longer is answered with a 400.
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// How long a scraper gets to send its request head before the connection is closed.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Scrapes answered at once. Further connections wait in the listen backlog.
+const MAX_CONNECTIONS: usize = 16;
+
+impl MaintenanceRunner {
+    /// Serve [`Self::render_metrics`] at `http://{addr}/metrics` while
+    /// [`Self::run_with_shutdown`] runs, so a scheduler deployment can be scraped even when
+    /// the workers don't expose metrics. Failing to bind is handed to the queue's
+    /// [`ErrorReporter`](crate::ErrorReporter) and maintenance runs on without the endpoint.
+    pub fn with_metrics_endpoint(mut self, addr: SocketAddr) -> Self {
+        self.metrics_addr = Some(addr);
+        self
+    }
+
+    /// Queue depth by state, the age of the oldest ready job and the totals of this runner,
+    /// in the OpenMetrics text format.
+    #[instrument(skip_all, err)]
+    pub async fn render_metrics(&self) -> Result<String, QueueError> {
+        let stats = self.queue.stats().await?;
+        let totals = self.totals();
+        let queue = escape(&self.queue.queue_name);
+
+        let mut out = String::new();
+        out.push_str("# TYPE adc_jobs gauge\n# HELP adc_jobs Jobs by state.\n");
+        for (state, count) in [
+            ("ready", stats.ready),
+            ("scheduled", stats.scheduled),
+            ("running", stats.running),
+            ("parked", stats.parked),
+            ("dead", stats.dead),
+            ("cancelled", stats.cancelled),
+        ] {
+            let _ = writeln!(
+                out,
+                "adc_jobs{{queue=\"{queue}\",state=\"{state}\"}} {count}"
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# TYPE adc_oldest_ready_job_age_seconds gauge\n\
+             # HELP adc_oldest_ready_job_age_seconds How long the oldest ready job has waited.\n\
+             adc_oldest_ready_job_age_seconds{{queue=\"{queue}\"}} {}",
+            stats.oldest_ready_age_ms.unwrap_or(0) as f64 / 1000.0
+        );
+        for (name, help, value) in [
+            ("adc_maintenance_runs", "Maintenance runs.", totals.runs),
+            (
+                "adc_maintenance_failed_runs",
+                "Maintenance runs that failed.",
+                totals.failed_runs,
+            ),
+            (
+                "adc_recurring_materialized",
+                "Recurring job occurrences added.",
+                totals.recurring_materialized,
+            ),
+            (
+                "adc_jobs_demoted",
+                "Jobs moved to cold storage.",
+                totals.demoted,
+            ),
+            (
+                "adc_jobs_promoted",
+                "Jobs moved back from cold storage.",
+                totals.promoted,
+            ),
+            (
+                "adc_orphans_parked",
+                "Jobs parked because their job type was orphaned.",
+                totals.orphans_parked,
+            ),
+            (
+                "adc_missed_check_ins",
+                "Monitor deadlines without a check-in.",
+                totals.missed_check_ins,
+            ),
+            (
+                "adc_archived_shipped",
+                "Archived jobs moved to the archive sink.",
+                totals.archived_shipped,
+            ),
+        ] {
+            let _ = writeln!(
+                out,
+                "# TYPE {name} counter\n# HELP {name} {help}\n{name}_total{{queue=\"{queue}\"}} {value}"
+            );
+        }
+        out.push_str("# EOF\n");
+        Ok(out)
+    }
+
+    /// Answer scrapes on `addr` until the listener fails.
+    pub(crate) async fn serve_metrics(&self, addr: SocketAddr) {
+        let context = ErrorContext {
+            operation: "metrics",
+            jid: None,
+            job_type: None,
+            correlation_id: None,
+        };
+        let listener = match TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Failed to bind metrics endpoint to {addr}"))
+        {
+            Ok(listener) => listener,
+            Err(error) => return self.queue.report_error(&context, &error.into()),
+        };
+        let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+        loop {
+            let Ok(permit) = connections.clone().acquire_owned().await else {
+                return;
+            };
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let runner = self.clone();
+                    tokio::spawn(async move {
+                        runner.answer(stream).await;
+                        drop(permit);
+                    });
+                }
+                Err(error) => {
+                    let error = anyhow::Error::new(error).context("Failed to accept scrape");
+                    return self.queue.report_error(&context, &error.into());
+                }
+            }
+        }
+    }
+
+    async fn answer(&self, mut stream: TcpStream) {
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        let read = async {
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                match stream.read(&mut buf).await {
+                    Ok(0) | Err(_) => return false,
+                    Ok(read) => request.extend_from_slice(&buf[..read]),
+                }
+                if request.len() > MAX_REQUEST_SIZE {
+                    break;
+                }
+            }
+            true
+        };
+        if !matches!(tokio::time::timeout(READ_TIMEOUT, read).await, Ok(true)) {
+            return;
+        }
+
+        let request_line = request
+            .split(|&byte| byte == b'\r')
+            .next()
+            .unwrap_or_default();
+        let scrape = request_line.starts_with(b"GET /metrics ")
+            || request_line.starts_with(b"GET /metrics?");
+        let response = if request.len() > MAX_REQUEST_SIZE {
+            response("400 Bad Request", "text/plain", "")
+        } else if !scrape {
+            response("404 Not Found", "text/plain", "")
+        } else {
+            match self.render_metrics().await {
+                Ok(body) => response("200 OK", CONTENT_TYPE, &body),
+                // The error can contain hosts and collection names; scrapers don't need them.
+                Err(error) => {
+                    let context = ErrorContext {
+                        operation: "metrics",
+                        jid: None,
+                        job_type: None,
+                        correlation_id: None,
+                    };
+                    self.queue.report_error(&context, &error);
+                    response(
+                        "503 Service Unavailable",
+                        "text/plain",
+                        "Queue stats unavailable\n",
+                    )
+                }
+            }
+        };
+        let _ = stream.write_all(response.as_bytes()).await;
+        let _ = stream.shutdown().await;
+    }
+}
+
+fn response(status: &str, content_type: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+/// `value` as an OpenMetrics label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
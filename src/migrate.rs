@@ -0,0 +1,114 @@
+use aide_de_camp::core::queue::QueueError;
+use anyhow::Context;
+use bson::{doc, Bson, Document};
+use futures::TryStreamExt;
+use mongodb::options::FindOptions;
+use tracing::instrument;
+
+use crate::{status::JobStatus, trace::traced, MongoDbQueue};
+
+/// Progress of [`MongoDbQueue::backfill_status`], reported after every batch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackfillProgress {
+    pub collection: &'static str,
+    /// Documents given a status in this collection so far.
+    pub updated: u64,
+    /// Documents in this collection still without a status.
+    pub remaining: u64,
+}
+
+impl MongoDbQueue {
+    /// Give documents written before the `status` field existed their status, derived from
+    /// where they are stored and from `started_at` and `parked`. Runs in batches of
+    /// `batch_size` while workers keep going, calling `progress` after each batch. Returns the
+    /// number of documents updated; running it again only picks up what is left.
+    ///
+    /// Until it is done, claims and stats take legacy jobs that were never started or parked
+    /// for pending ones and miss the others.
+    #[instrument(skip_all, err, ret)]
+    pub async fn backfill_status(
+        &self,
+        batch_size: u32,
+        mut progress: impl FnMut(BackfillProgress) + Send,
+    ) -> Result<u64, QueueError> {
+        let unstarted = doc! { "$cond": [
+            { "$gt": ["$parked", None::<String>] },
+            JobStatus::Parked,
+            JobStatus::Pending,
+        ] };
+        let derived = [
+            (
+                "adc_queue",
+                doc! { "$cond": [
+                    { "$gt": ["$started_at", None::<bson::DateTime>] },
+                    JobStatus::Running,
+                    unstarted.clone(),
+                ] },
+            ),
+            ("adc_cold_queue", unstarted),
+            ("adc_dead_queue", doc! { "$literal": JobStatus::Dead }),
+            ("adc_cancelled", doc! { "$literal": JobStatus::Cancelled }),
+        ];
+
+        let mut total = 0;
+        for (name, status) in derived {
+            let collection = self.database.collection::<Document>(name);
+            let missing = doc! { "status": { "$exists": false } };
+            let options = FindOptions::builder()
+                .projection(doc! { "_id": 1 })
+                .limit(batch_size.max(1) as i64)
+                .build();
+            let mut updated = 0;
+            loop {
+                let rows: Vec<Document> = traced(
+                    &collection,
+                    "find",
+                    collection.find(missing.clone(), options.clone()),
+                )
+                .await
+                .context("Failed to find jobs without status")?
+                .try_collect()
+                .await
+                .context("Failed to read jobs without status")?;
+                if rows.is_empty() {
+                    break;
+                }
+                // By `_id`, which every document has, so a batch can't be picked again without
+                // being updated.
+                let ids: Vec<&Bson> = rows.iter().filter_map(|row| row.get("_id")).collect();
+
+                let mut filter = missing.clone();
+                filter.insert("_id", doc! { "$in": ids });
+                // The status is computed from the document as it is now, so claims that happen
+                // between the find and the update are taken into account.
+                let result = traced(
+                    &collection,
+                    "update_many",
+                    collection.update_many(
+                        filter,
+                        vec![doc! { "$set": { "status": status.clone() } }],
+                        None,
+                    ),
+                )
+                .await
+                .context("Failed to backfill job status")?;
+                updated += result.modified_count;
+
+                let remaining = traced(
+                    &collection,
+                    "count_documents",
+                    collection.count_documents(missing.clone(), None),
+                )
+                .await
+                .context("Failed to count jobs without status")?;
+                progress(BackfillProgress {
+                    collection: name,
+                    updated,
+                    remaining,
+                });
+            }
+            total += updated;
+        }
+        Ok(total)
+    }
+}
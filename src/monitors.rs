@@ -0,0 +1,209 @@
+use aide_de_camp::core::{queue::QueueError, DateTime, Duration};
+use anyhow::Context;
+use bson::doc;
+use chrono::Utc;
+use chrono_tz::Tz;
+use futures::TryStreamExt;
+use mongodb::{options::UpdateOptions, Collection};
+use tracing::instrument;
+
+use crate::{
+    recurring::{fire_times_after, parse_schedule, parse_timezone},
+    trace::traced,
+    types::MonitorRow,
+    MongoDbQueue,
+};
+
+/// Collection monitors and their last check-ins are kept in.
+pub const MONITORS_COLLECTION: &str = "adc_monitors";
+
+/// A check-in expected before every deadline of a schedule, e.g. "the nightly export has
+/// finished by 06:00". See [`MongoDbQueue::add_monitor`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Monitor {
+    /// Cron expression (with seconds) for the deadlines, e.g. `"0 0 6 * * *"`.
+    pub deadline: String,
+    /// How long before a deadline a check-in counts for it.
+    pub window: Duration,
+    /// Timezone `deadline` is evaluated in. Defaults to UTC.
+    pub timezone: Option<Tz>,
+}
+
+impl Monitor {
+    pub fn new(deadline: impl Into<String>, window: Duration) -> Self {
+        Self {
+            deadline: deadline.into(),
+            window,
+            timezone: None,
+        }
+    }
+
+    pub fn with_timezone(mut self, timezone: Tz) -> Self {
+        self.timezone = Some(timezone);
+        self
+    }
+}
+
+/// A deadline that passed without a check-in, see [`MongoDbQueue::missed_check_ins`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissedCheckIn {
+    pub monitor: String,
+    pub deadline: DateTime,
+    pub last_check_in: Option<DateTime>,
+}
+
+impl MongoDbQueue {
+    /// Add or replace the monitor `name`, which expects a [`Self::check_in`] before each of
+    /// its deadlines. Run [`Self::missed_check_ins`], or a
+    /// [`MaintenanceRunner`](crate::MaintenanceRunner) set up
+    /// [`with_monitors`](crate::MaintenanceRunner::with_monitors), to find out when one
+    /// didn't come, e.g. because a recurring job never ran. Deadlines before the monitor was
+    /// first added are not checked.
+    #[instrument(skip_all, err, fields(name = name, deadline = %monitor.deadline))]
+    pub async fn add_monitor(&self, name: &str, monitor: Monitor) -> Result<(), QueueError> {
+        parse_schedule(&monitor.deadline)?;
+        let collection = self.monitors_collection();
+        traced(
+            &collection,
+            "update_one",
+            collection.update_one(
+                doc! { "_id": self.scoped_key(name) },
+                doc! {
+                    "$set": {
+                        "name": name,
+                        "deadline": &monitor.deadline,
+                        "window_ms": monitor.window.num_milliseconds(),
+                        "timezone": monitor.timezone.map(|tz| tz.name()),
+                        "namespace": self.namespace.as_deref(),
+                    },
+                    "$setOnInsert": { "checked_until": bson::DateTime::from_chrono(Utc::now()) },
+                },
+                UpdateOptions::builder().upsert(true).build(),
+            ),
+        )
+        .await
+        .context("Failed to add monitor")?;
+        Ok(())
+    }
+
+    /// Remove the monitor `name`. Returns whether there was one.
+    #[instrument(skip_all, err, fields(name = name))]
+    pub async fn remove_monitor(&self, name: &str) -> Result<bool, QueueError> {
+        let collection = self.monitors_collection();
+        let result = traced(
+            &collection,
+            "delete_one",
+            collection.delete_one(doc! { "_id": self.scoped_key(name) }, None),
+        )
+        .await
+        .context("Failed to remove monitor")?;
+        Ok(result.deleted_count > 0)
+    }
+
+    /// The monitors by name.
+    #[instrument(skip_all, err)]
+    pub async fn monitors(&self) -> Result<Vec<(String, Monitor)>, QueueError> {
+        self.read_monitors()
+            .await?
+            .into_iter()
+            .map(|row| {
+                let monitor = Monitor {
+                    deadline: row.deadline,
+                    window: Duration::milliseconds(row.window_ms),
+                    timezone: match row.timezone.as_deref() {
+                        Some(name) => Some(parse_timezone(Some(name))?),
+                        None => None,
+                    },
+                };
+                Ok((row.name, monitor))
+            })
+            .collect()
+    }
+
+    /// Tell the monitor `name` that the work it watches was done at `now`, typically at the
+    /// end of the job it watches. Returns whether there is such a monitor.
+    #[instrument(skip_all, err, fields(name = name))]
+    pub async fn check_in(&self, name: &str, now: DateTime) -> Result<bool, QueueError> {
+        let collection = self.monitors_collection();
+        let result = traced(
+            &collection,
+            "update_one",
+            collection.update_one(
+                doc! { "_id": self.scoped_key(name) },
+                doc! { "$max": { "last_check_in": bson::DateTime::from_chrono(now) } },
+                None,
+            ),
+        )
+        .await
+        .context("Failed to check in")?;
+        Ok(result.matched_count > 0)
+    }
+
+    /// Deadlines that passed by `now` without a check-in in the window before them. Only the
+    /// latest deadline of each monitor is checked, and each one only once, so with several
+    /// processes calling this every miss is returned by exactly one of them. A check-in that
+    /// comes after the deadline but before the check still counts.
+    #[instrument(skip_all, err)]
+    pub async fn missed_check_ins(&self, now: DateTime) -> Result<Vec<MissedCheckIn>, QueueError> {
+        let collection = self.monitors_collection();
+        let mut missed = Vec::new();
+        for row in self.read_monitors().await? {
+            let schedule = parse_schedule(&row.deadline)?;
+            let timezone = parse_timezone(row.timezone.as_deref())?;
+            let Some(deadline) =
+                fire_times_after(&schedule, timezone, row.checked_until.to_chrono())
+                    .take_while(|deadline| *deadline <= now)
+                    .last()
+            else {
+                continue;
+            };
+            let claimed = traced(
+                &collection,
+                "update_one",
+                collection.update_one(
+                    doc! {
+                        "_id": self.scoped_key(&row.name),
+                        "checked_until": row.checked_until,
+                    },
+                    doc! { "$set": { "checked_until": bson::DateTime::from_chrono(deadline) } },
+                    None,
+                ),
+            )
+            .await
+            .context("Failed to check monitor")?;
+            if claimed.modified_count == 0 {
+                continue;
+            }
+            let last_check_in = row.last_check_in.map(|at| at.to_chrono());
+            let window_start = deadline - Duration::milliseconds(row.window_ms);
+            let checked_in = last_check_in.is_some_and(|at| at > window_start);
+            if !checked_in {
+                missed.push(MissedCheckIn {
+                    monitor: row.name,
+                    deadline,
+                    last_check_in,
+                });
+            }
+        }
+        Ok(missed)
+    }
+
+    async fn read_monitors(&self) -> Result<Vec<MonitorRow>, QueueError> {
+        let collection = self.monitors_collection();
+        let rows = traced(
+            &collection,
+            "find",
+            collection.find(self.scoped(doc! {}), None),
+        )
+        .await
+        .context("Failed to read monitors")?
+        .try_collect()
+        .await
+        .context("Failed to read monitors")?;
+        Ok(rows)
+    }
+
+    fn monitors_collection(&self) -> Collection<MonitorRow> {
+        self.database.collection(MONITORS_COLLECTION)
+    }
+}
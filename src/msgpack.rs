@@ -0,0 +1,61 @@
+use bincode::{
+    de::Decoder,
+    enc::Encoder,
+    error::{DecodeError, EncodeError},
+    Decode, Encode,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    framed::{frame, unframe},
+    hooks::PayloadCodec,
+};
+
+/// Media type of payloads stored by [`MessagePackCodec`].
+pub const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+
+/// A serde value as the payload of a job, e.g. `type Payload = MessagePack<Invoice>`. Encodes
+/// to MessagePack, with field names, wrapped in bincode, which [`MessagePackCodec`] unwraps
+/// so the queue stores plain MessagePack.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MessagePack<T>(pub T);
+
+impl<T: Serialize> Encode for MessagePack<T> {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        rmp_serde::to_vec_named(&self.0)
+            .map_err(|error| EncodeError::OtherString(error.to_string()))?
+            .encode(encoder)
+    }
+}
+
+impl<T: DeserializeOwned> Decode for MessagePack<T> {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        let bytes = Vec::<u8>::decode(decoder)?;
+        rmp_serde::from_slice(&bytes)
+            .map(MessagePack)
+            .map_err(|error| DecodeError::OtherString(error.to_string()))
+    }
+}
+
+/// Stores [`MessagePack`] payloads as plain MessagePack, tagged with
+/// [`MSGPACK_CONTENT_TYPE`]. More compact than JSON, and unlike bincode readable from other
+/// languages. Register it for single job types with
+/// [`MongoDbQueue::with_payload_codec`](crate::MongoDbQueue::with_payload_codec), or for the
+/// whole queue with
+/// [`MongoDbQueue::with_default_payload_codec`](crate::MongoDbQueue::with_default_payload_codec).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePackCodec;
+
+impl PayloadCodec for MessagePackCodec {
+    fn content_type(&self) -> &str {
+        MSGPACK_CONTENT_TYPE
+    }
+
+    fn encode(&self, _job_type: &str, payload: &[u8]) -> Result<Vec<u8>, String> {
+        unframe(payload, "MessagePack")
+    }
+
+    fn decode(&self, _job_type: &str, stored: &[u8]) -> Result<Vec<u8>, String> {
+        frame(stored)
+    }
+}
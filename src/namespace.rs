@@ -0,0 +1,34 @@
+use bson::Document;
+
+use crate::MongoDbQueue;
+
+impl MongoDbQueue {
+    /// Label every job and setting with `namespace`, e.g. `"staging"` or `"prod-eu"`, and only
+    /// see those with the same label, so several environments can share one database without
+    /// claiming each other's jobs. Routes, quotas, retry budgets and recurring jobs are kept
+    /// per namespace as well.
+    ///
+    /// Jobs added without a namespace are only seen by queues without one.
+    pub fn with_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.namespace = Some(namespace.into());
+        self
+    }
+
+    pub fn namespace(&self) -> Option<&str> {
+        self.namespace.as_deref()
+    }
+
+    /// `filter` restricted to jobs of this queue's namespace.
+    pub(crate) fn scoped(&self, mut filter: Document) -> Document {
+        filter.insert("namespace", self.namespace.as_deref());
+        filter
+    }
+
+    /// The `_id` of a per-namespace settings document, e.g. a route keyed by job type.
+    pub(crate) fn scoped_key(&self, key: &str) -> String {
+        match &self.namespace {
+            Some(namespace) => format!("{namespace}:{key}"),
+            None => key.to_string(),
+        }
+    }
+}
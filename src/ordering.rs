@@ -0,0 +1,123 @@
+use bson::{doc, Document};
+use rand::Rng;
+
+/// Decides the order in which a poll claims jobs, installed with
+/// [`MongoDbQueue::with_claim_strategy`]. [`ClaimOrder`] covers the common orders; implement
+/// this for bespoke dispatch policies.
+///
+/// [`MongoDbQueue::with_claim_strategy`]: crate::MongoDbQueue::with_claim_strategy
+pub trait ClaimStrategy: Send + Sync {
+    /// Number of claimable jobs, highest priority first, to look up before each claim and pass
+    /// to [`ClaimStrategy::passes`]. The lookup is skipped when this is 0.
+    fn head_candidates(&self) -> usize {
+        0
+    }
+
+    /// The claim passes to try in turn, given the jids of the head candidates.
+    fn passes(&self, candidates: &[String]) -> Vec<ClaimPass>;
+
+    /// A poll claimed a job of `job_type` with the passes it was given, e.g. to charge a
+    /// share to whoever actually got the claim.
+    fn claimed(&self, _job_type: &str) {}
+}
+
+/// The order in which jobs are claimed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClaimOrder {
+    /// Highest priority first.
+    #[default]
+    Priority,
+    /// Oldest first by scheduled time, ignoring priority.
+    Fifo,
+    /// Earliest deadline first, see [`ScheduleOptions::deadline`](crate::ScheduleOptions::deadline),
+    /// then by priority. Jobs without a deadline are claimed when no job with one is ready.
+    EarliestDeadline,
+    /// Pick one of the `top_k` highest priority jobs at random, favouring the ones nearer the
+    /// head. With many workers, most claims then go for different documents instead of all
+    /// fighting over the first one, at the cost of only approximate priority order.
+    WeightedRandom { top_k: usize },
+}
+
+/// One claim attempt: jobs matching `filter` on top of the claim query, in `sort` order.
+/// Passes are tried in turn until one finds a job.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClaimPass {
+    pub filter: Option<Document>,
+    pub sort: Document,
+}
+
+impl ClaimPass {
+    /// All claimable jobs, highest priority first.
+    pub fn by_priority() -> Self {
+        Self {
+            filter: None,
+            sort: doc! { "priority": -1 },
+        }
+    }
+
+    /// The claim query `filter_doc` narrowed to this pass. Combined with `$and` so the pass
+    /// can filter on fields the claim query already uses, like `job_type`.
+    pub(crate) fn apply(&self, filter_doc: &Document) -> Document {
+        match &self.filter {
+            Some(filter) => doc! { "$and": [filter_doc, filter] },
+            None => filter_doc.clone(),
+        }
+    }
+}
+
+impl ClaimStrategy for ClaimOrder {
+    fn head_candidates(&self) -> usize {
+        match self {
+            ClaimOrder::WeightedRandom { top_k } => *top_k,
+            _ => 0,
+        }
+    }
+
+    fn passes(&self, candidates: &[String]) -> Vec<ClaimPass> {
+        match self {
+            ClaimOrder::Priority => vec![ClaimPass::by_priority()],
+            ClaimOrder::Fifo => vec![ClaimPass {
+                filter: None,
+                sort: doc! { "scheduled_at": 1, "enqueued_at": 1 },
+            }],
+            // Missing deadlines sort first in MongoDB, so they get a pass of their own.
+            ClaimOrder::EarliestDeadline => vec![
+                ClaimPass {
+                    filter: Some(doc! { "deadline": { "$ne": None::<bson::DateTime> } }),
+                    sort: doc! { "deadline": 1, "priority": -1 },
+                },
+                ClaimPass::by_priority(),
+            ],
+            ClaimOrder::WeightedRandom { .. } => match pick_weighted(candidates) {
+                // If another worker got there first, fall back to the head.
+                Some(jid) => vec![
+                    ClaimPass {
+                        filter: Some(doc! { "jid": jid }),
+                        sort: doc! { "priority": -1 },
+                    },
+                    ClaimPass::by_priority(),
+                ],
+                None => vec![ClaimPass::by_priority()],
+            },
+        }
+    }
+}
+
+/// Pick one of `candidates` (in priority order) at random, the first `k` times as likely as
+/// the last.
+fn pick_weighted(candidates: &[String]) -> Option<&String> {
+    let k = candidates.len();
+    if k == 0 {
+        return None;
+    }
+    let total = k * (k + 1) / 2;
+    let mut ticket = rand::thread_rng().gen_range(0..total);
+    for (rank, candidate) in candidates.iter().enumerate() {
+        let weight = k - rank;
+        if ticket < weight {
+            return Some(candidate);
+        }
+        ticket -= weight;
+    }
+    candidates.last()
+}
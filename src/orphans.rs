@@ -0,0 +1,210 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration as StdDuration, Instant},
+};
+
+use aide_de_camp::core::{queue::QueueError, DateTime, Duration};
+use anyhow::Context;
+use bson::{doc, Document};
+use futures::TryStreamExt;
+use mongodb::{options::UpdateOptions, Collection};
+use tracing::instrument;
+
+use crate::{hooks::ErrorContext, status::JobStatus, trace::traced, MongoDbQueue};
+
+/// Park reason of jobs parked by [`MongoDbQueue::park_orphaned`].
+pub const ORPHANED: &str = "orphaned_job_type";
+
+/// How often a worker records that it claimed a job type, see
+/// [`MongoDbQueue::orphaned_job_types`].
+const CLAIM_MARK_INTERVAL: StdDuration = StdDuration::from_secs(60);
+
+/// A job type with due jobs that no worker has claimed for a while, likely because no worker
+/// handles it anymore. Found by [`MongoDbQueue::orphaned_job_types`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrphanedJobType {
+    pub job_type: String,
+    /// Due jobs of the type waiting longer than the idle time.
+    pub pending: u64,
+    /// When the longest waiting job became due.
+    pub oldest_due: DateTime,
+    /// When a worker last claimed a job of the type, if it was recorded.
+    pub last_claimed_at: Option<DateTime>,
+}
+
+/// What a [`MaintenanceRunner`](crate::MaintenanceRunner) does with orphaned job types, see
+/// [`MaintenanceRunner::with_orphan_check`](crate::MaintenanceRunner::with_orphan_check).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrphanAction {
+    /// Hand them to the [`ErrorReporter`](crate::ErrorReporter).
+    Report,
+    /// Report them and park their jobs with reason [`ORPHANED`].
+    Park,
+}
+
+/// When this process last recorded a claim per job type, shared by the clones of a queue.
+#[derive(Default)]
+pub(crate) struct ClaimMarks {
+    marked_at: Mutex<HashMap<String, Instant>>,
+}
+
+impl MongoDbQueue {
+    /// Job types with jobs that have been due for more than `idle` while no worker claimed a
+    /// job of the type in that time.
+    ///
+    /// Workers record in `adc_meta` when they last claimed each job type, at most once a
+    /// minute, so `idle` should be well above that.
+    #[instrument(skip_all, err)]
+    pub async fn orphaned_job_types(
+        &self,
+        idle: Duration,
+        now: DateTime,
+    ) -> Result<Vec<OrphanedJobType>, QueueError> {
+        let since = bson::DateTime::from_chrono(now - idle);
+        let pipeline = vec![
+            doc! { "$match": self.scoped(doc! {
+                "queue": &self.queue_name,
+                "status": JobStatus::Pending,
+                "scheduled_at": { "$lte": since },
+            }) },
+            doc! { "$group": {
+                "_id": "$job_type",
+                "pending": { "$sum": 1_i64 },
+                "oldest_due": { "$min": "$scheduled_at" },
+            } },
+            doc! { "$sort": { "_id": 1 } },
+        ];
+        let collection = self.collection();
+        let waiting: Vec<Document> = traced(
+            &collection,
+            "aggregate",
+            collection.aggregate(pipeline, None),
+        )
+        .await
+        .context("Failed to look for orphaned job types")?
+        .try_collect()
+        .await
+        .context("Failed to look for orphaned job types")?;
+
+        let mut orphans = Vec::new();
+        for row in waiting {
+            let job_type = row.get_str("_id").context("Job without job type")?;
+            let last_claimed_at = self.last_claimed_at(job_type).await?;
+            if last_claimed_at.is_some_and(|claimed_at| claimed_at > since) {
+                continue;
+            }
+            orphans.push(OrphanedJobType {
+                job_type: job_type.to_string(),
+                pending: row.get_i64("pending").context("Malformed count")? as u64,
+                oldest_due: row
+                    .get_datetime("oldest_due")
+                    .context("Malformed due time")?
+                    .to_chrono(),
+                last_claimed_at: last_claimed_at.map(bson::DateTime::to_chrono),
+            });
+        }
+        Ok(orphans)
+    }
+
+    /// Park the pending jobs of `job_type` that have been due for more than `idle` with reason
+    /// [`ORPHANED`], so they stop counting as ready. Nothing is parked if a worker claimed a job
+    /// of the type in that time, e.g. because one came back since
+    /// [`Self::orphaned_job_types`] looked. Returns the number of jobs parked.
+    #[instrument(skip_all, err, ret, fields(job_type = job_type))]
+    pub async fn park_orphaned(
+        &self,
+        job_type: &str,
+        idle: Duration,
+        now: DateTime,
+    ) -> Result<u64, QueueError> {
+        let since = bson::DateTime::from_chrono(now - idle);
+        if self
+            .last_claimed_at(job_type)
+            .await?
+            .is_some_and(|claimed_at| claimed_at > since)
+        {
+            return Ok(0);
+        }
+        let collection = self.collection();
+        let result = traced(
+            &collection,
+            "update_many",
+            collection.update_many(
+                self.scoped(doc! {
+                    "queue": &self.queue_name,
+                    "status": JobStatus::Pending,
+                    "job_type": job_type,
+                    "scheduled_at": { "$lte": since },
+                }),
+                doc! { "$set": { "status": JobStatus::Parked, "parked": ORPHANED } },
+                None,
+            ),
+        )
+        .await
+        .context("Failed to park orphaned jobs")?;
+        Ok(result.modified_count)
+    }
+
+    /// Record in the background that a job of `job_type` was claimed, unless this process did
+    /// so recently.
+    pub(crate) fn mark_claimed(&self, job_type: &str, now: DateTime) {
+        {
+            let mut marked_at = self.claim_marks.marked_at.lock().unwrap();
+            if marked_at
+                .get(job_type)
+                .is_some_and(|at| at.elapsed() < CLAIM_MARK_INTERVAL)
+            {
+                return;
+            }
+            marked_at.insert(job_type.to_string(), Instant::now());
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let queue = self.clone();
+        let job_type = job_type.to_string();
+        runtime.spawn(async move {
+            let collection = queue.claim_marks_collection();
+            let result: Result<_, QueueError> = traced(
+                &collection,
+                "update_one",
+                collection.update_one(
+                    doc! { "_id": queue.claim_mark_key(&job_type) },
+                    doc! { "$max": { "last_claimed_at": bson::DateTime::from_chrono(now) } },
+                    UpdateOptions::builder().upsert(true).build(),
+                ),
+            )
+            .await
+            .context("Failed to record claim")
+            .map_err(Into::into);
+            let context = ErrorContext {
+                operation: "mark_claimed",
+                jid: None,
+                job_type: Some(&job_type),
+                correlation_id: None,
+            };
+            let _ = queue.reported(&context, result);
+        });
+    }
+
+    async fn last_claimed_at(&self, job_type: &str) -> Result<Option<bson::DateTime>, QueueError> {
+        let collection = self.claim_marks_collection();
+        let row = traced(
+            &collection,
+            "find_one",
+            collection.find_one(doc! { "_id": self.claim_mark_key(job_type) }, None),
+        )
+        .await
+        .context("Failed to read last claim")?;
+        Ok(row.and_then(|row| row.get_datetime("last_claimed_at").ok().copied()))
+    }
+
+    fn claim_mark_key(&self, job_type: &str) -> String {
+        self.scoped_key(&format!("last_claimed:{}:{job_type}", self.queue_name))
+    }
+
+    fn claim_marks_collection(&self) -> Collection<Document> {
+        self.database.collection("adc_meta")
+    }
+}
@@ -0,0 +1,41 @@
+use aide_de_camp::core::queue::QueueError;
+
+use crate::{error::MongoDbQueueError, MongoDbQueue};
+
+impl MongoDbQueue {
+    /// Give jobs scheduled through this queue `owner`, e.g. the team or service name, unless
+    /// [`ScheduleOptions::owner`](crate::ScheduleOptions::owner) says otherwise. Owners can be
+    /// filtered on with [`JobFilter::owner`](crate::JobFilter::owner) and
+    /// [`Self::owner_stats`], so each team sees only its own jobs and dead letters.
+    pub fn with_owner(mut self, owner: impl Into<String>) -> Self {
+        self.owner = Some(owner.into());
+        self
+    }
+
+    /// Refuse to schedule jobs, including debounced and recurring ones, that end up without
+    /// an owner, with [`MongoDbQueueError::MissingOwner`].
+    pub fn with_required_owner(mut self) -> Self {
+        self.require_owner = true;
+        self
+    }
+
+    pub fn owner(&self) -> Option<&str> {
+        self.owner.as_deref()
+    }
+
+    /// The owner of a new job of `job_type` scheduled with `owner`.
+    pub(crate) fn owner_of(
+        &self,
+        job_type: &str,
+        owner: Option<&str>,
+    ) -> Result<Option<String>, QueueError> {
+        match owner.or(self.owner.as_deref()) {
+            Some(owner) => Ok(Some(owner.to_string())),
+            None if self.require_owner => Err(MongoDbQueueError::MissingOwner {
+                job_type: job_type.to_string(),
+            }
+            .into()),
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use aide_de_camp::core::{queue::QueueError, Xid};
+use anyhow::Context;
+use bson::{doc, Document};
+use tracing::instrument;
+
+use crate::{
+    budget::OVER_BUDGET, hooks::ErrorContext, status::JobStatus, tiering::move_jobs, trace::traced,
+    MongoDbQueue,
+};
+
+/// Park reason of jobs whose jid is not a valid [`Xid`], e.g. hand-inserted or corrupted
+/// documents. They are parked when a worker claims them instead of being handed out, and can
+/// only be fixed or deleted by hand.
+pub const MALFORMED_JID: &str = "malformed_jid";
+
+impl MongoDbQueue {
+    /// Hold a pending job until [`Self::unpark`] is called, e.g. while it waits
+    /// for manual action. Parked jobs are not claimed and not dead; [`Self::stats`] counts them
+    /// separately.
+    #[instrument(skip_all, err, fields(jid = %job_id, reason = reason))]
+    pub async fn park(&self, job_id: Xid, reason: &str) -> Result<(), QueueError> {
+        let jid = format!("{}", job_id);
+        let result = self
+            .set_parked(
+                &jid,
+                JobStatus::Pending,
+                doc! { "$set": { "status": JobStatus::Parked, "parked": reason } },
+            )
+            .await
+            .and_then(|found| found.then_some(()).ok_or(QueueError::JobNotFound(job_id)));
+        self.reported(&park_context("park", &jid), result)
+    }
+
+    /// Make a parked job claimable again, whatever parked it. A job diverted to cold storage
+    /// by a [`PendingBudget`](crate::PendingBudget) is moved back into the queue first.
+    #[instrument(skip_all, err, fields(jid = %job_id))]
+    pub async fn unpark(&self, job_id: Xid) -> Result<(), QueueError> {
+        let jid = format!("{}", job_id);
+        let result = async {
+            let cold = self.cold_collection();
+            let diverted = self.scoped(doc! {
+                "jid": &jid,
+                "status": JobStatus::Parked,
+                "parked": OVER_BUDGET,
+            });
+            // Checked first, so unparking other jobs doesn't need a transaction.
+            let in_cold = traced(
+                &cold,
+                "count_documents",
+                cold.count_documents(diverted.clone(), None),
+            )
+            .await
+            .context("Failed to look up diverted job")?;
+            if in_cold > 0 {
+                move_jobs(&cold, &self.collection(), diverted).await?;
+            }
+            self.set_parked(
+                &jid,
+                JobStatus::Parked,
+                doc! { "$set": { "status": JobStatus::Pending }, "$unset": { "parked": "" } },
+            )
+            .await
+        }
+        .await
+        .and_then(|found| found.then_some(()).ok_or(QueueError::JobNotFound(job_id)));
+        self.reported(&park_context("unpark", &jid), result)
+    }
+
+    /// Park a job that was just claimed because its jid is malformed, see [`MALFORMED_JID`].
+    pub(crate) async fn quarantine_malformed(&self, jid: &str) -> Result<(), QueueError> {
+        tracing::warn!(jid, "Parking claimed job with a malformed jid");
+        self.set_parked(
+            jid,
+            JobStatus::Running,
+            doc! {
+                "$set": {
+                    "status": JobStatus::Parked,
+                    "parked": MALFORMED_JID,
+                    "started_at": None::<bson::DateTime>,
+                },
+            },
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Apply `update` to job `jid` if it has status `from`, in the queue or in cold storage.
+    /// Returns whether the job was found.
+    async fn set_parked(
+        &self,
+        jid: &str,
+        from: JobStatus,
+        update: Document,
+    ) -> Result<bool, QueueError> {
+        for name in ["adc_queue", "adc_cold_queue"] {
+            let collection = self.database.collection::<Document>(name);
+            let result = traced(
+                &collection,
+                "update_one",
+                collection.update_one(
+                    self.scoped(doc! { "jid": jid, "status": from }),
+                    update.clone(),
+                    None,
+                ),
+            )
+            .await
+            .context("Failed to update parked state")?;
+            if result.matched_count > 0 {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+fn park_context<'a>(operation: &'static str, jid: &'a str) -> ErrorContext<'a> {
+    ErrorContext {
+        operation,
+        jid: Some(jid),
+        job_type: None,
+        correlation_id: None,
+    }
+}
@@ -0,0 +1,332 @@
+use std::{io::Write, sync::Arc};
+
+use aide_de_camp::core::{queue::QueueError, DateTime};
+use anyhow::Context;
+use arrow_array::{
+    builder::{ListBuilder, StringBuilder},
+    ArrayRef, BinaryArray, BooleanArray, Int64Array, RecordBatch, StringArray,
+    TimestampMillisecondArray, UInt32Array, UInt64Array,
+};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use bson::doc;
+use futures::{pin_mut, Stream, StreamExt, TryStreamExt};
+use mongodb::options::FindOptions;
+use parquet::{
+    arrow::ArrowWriter,
+    basic::{Compression, ZstdLevel},
+    file::properties::WriterProperties,
+};
+use tracing::instrument;
+
+use crate::{
+    archive::{ArchivedJob, ARCHIVE_COLLECTION},
+    events::EVENTS_COLLECTION,
+    history::StatsSnapshot,
+    trace::traced,
+    types::{ArchivedJobRow, EventRow},
+    MongoDbQueue,
+};
+
+/// Rows per Parquet row group, and per read from the database.
+pub const PARQUET_BATCH_SIZE: usize = 10_000;
+
+impl MongoDbQueue {
+    /// Write the jobs archived with [`Self::with_archive`] that completed from `from` up to
+    /// `to` to `writer` as a zstd-compressed Parquet file, oldest first, for loading into a
+    /// data warehouse instead of querying the production database. Annotations and headers
+    /// are JSON objects in string columns. Jobs are read and written [`PARQUET_BATCH_SIZE`]
+    /// at a time, and `writer` is only used on Tokio's blocking threads. Returns the number of
+    /// jobs written.
+    #[instrument(skip_all, err, ret)]
+    pub async fn export_archive_parquet<W: Write + Send + 'static>(
+        &self,
+        from: DateTime,
+        to: DateTime,
+        writer: W,
+    ) -> Result<u64, QueueError> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("jid", DataType::Utf8, false),
+            Field::new("public_id", DataType::Utf8, true),
+            Field::new("queue", DataType::Utf8, false),
+            Field::new("job_type", DataType::Utf8, false),
+            Field::new("payload", DataType::Binary, false),
+            Field::new("content_type", DataType::Utf8, true),
+            Field::new("retries", DataType::UInt32, false),
+            Field::new("attempts", DataType::UInt32, false),
+            Field::new("enqueued_at", timestamp(), false),
+            Field::new("started_at", timestamp(), true),
+            Field::new("completed_at", timestamp(), false),
+            Field::new("tenant", DataType::Utf8, true),
+            Field::new("correlation_id", DataType::Utf8, true),
+            Field::new(
+                "tags",
+                DataType::List(Arc::new(Field::new("item", DataType::Utf8, true))),
+                false,
+            ),
+            Field::new("annotations", DataType::Utf8, false),
+            Field::new("headers", DataType::Utf8, false),
+        ]));
+        let collection = self
+            .database
+            .collection::<ArchivedJobRow>(ARCHIVE_COLLECTION);
+        let filter = self.scoped(doc! { "completed_at": {
+            "$gte": bson::DateTime::from_chrono(from),
+            "$lt": bson::DateTime::from_chrono(to),
+        } });
+        let options = FindOptions::builder()
+            .sort(doc! { "completed_at": 1 })
+            .batch_size(PARQUET_BATCH_SIZE as u32)
+            .build();
+        let rows = traced(&collection, "find", collection.find(filter, options))
+            .await
+            .context("Failed to read archived jobs")?
+            .map(|row| row.context("Failed to read archived jobs")?.unpack());
+        write_parquet(rows, schema, writer, archive_batch).await
+    }
+
+    /// Write the [`Self::stats_history`] of this queue from `from` up to `to` to `writer` as a
+    /// zstd-compressed Parquet file, oldest first, like [`Self::export_archive_parquet`].
+    /// Returns the number of snapshots written.
+    #[instrument(skip_all, err, ret)]
+    pub async fn export_stats_history_parquet<W: Write + Send + 'static>(
+        &self,
+        from: DateTime,
+        to: DateTime,
+        writer: W,
+    ) -> Result<u64, QueueError> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("taken_at", timestamp(), false),
+            Field::new("queue", DataType::Utf8, false),
+            Field::new("ready", DataType::UInt64, false),
+            Field::new("scheduled", DataType::UInt64, false),
+            Field::new("running", DataType::UInt64, false),
+            Field::new("parked", DataType::UInt64, false),
+            Field::new("dead", DataType::UInt64, false),
+            Field::new("cancelled", DataType::UInt64, false),
+            Field::new("oldest_ready_age_ms", DataType::UInt64, true),
+            Field::new("consistent", DataType::Boolean, false),
+        ]));
+        let rows = self
+            .stats_history_stream(self.history_pipeline(from, to))
+            .await?;
+        write_parquet(rows, schema, writer, stats_batch).await
+    }
+
+    /// Write the events of this queue stored with [`Self::with_event_log`] from `from` up to
+    /// `to` to `writer` as a zstd-compressed Parquet file, oldest first, like
+    /// [`Self::export_archive_parquet`]. Returns the number of events written.
+    #[instrument(skip_all, err, ret)]
+    pub async fn export_events_parquet<W: Write + Send + 'static>(
+        &self,
+        from: DateTime,
+        to: DateTime,
+        writer: W,
+    ) -> Result<u64, QueueError> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("at", timestamp(), false),
+            Field::new("name", DataType::Utf8, false),
+            Field::new("jid", DataType::Utf8, false),
+            Field::new("job_type", DataType::Utf8, false),
+            Field::new("queue", DataType::Utf8, false),
+            Field::new("status", DataType::Utf8, false),
+            Field::new("attempt", DataType::UInt32, false),
+            Field::new("duration_ms", DataType::Int64, true),
+        ]));
+        let collection = self.database.collection::<EventRow>(EVENTS_COLLECTION);
+        let filter = doc! {
+            "meta.queue": &self.queue_name,
+            "meta.namespace": self.namespace.as_deref(),
+            "at": {
+                "$gte": bson::DateTime::from_chrono(from),
+                "$lt": bson::DateTime::from_chrono(to),
+            },
+        };
+        let options = FindOptions::builder()
+            .sort(doc! { "at": 1 })
+            .batch_size(PARQUET_BATCH_SIZE as u32)
+            .build();
+        let rows = traced(&collection, "find", collection.find(filter, options))
+            .await
+            .context("Failed to read job events")?
+            .map(|row| Ok(row.context("Failed to read job events")?));
+        write_parquet(rows, schema, writer, events_batch).await
+    }
+}
+
+/// Write `rows` to `writer` as a Parquet file, turning every [`PARQUET_BATCH_SIZE`] of them
+/// into a row group with `to_batch`. Returns the number of rows written.
+async fn write_parquet<T, W: Write + Send + 'static>(
+    rows: impl Stream<Item = Result<T, QueueError>>,
+    schema: Arc<Schema>,
+    writer: W,
+    to_batch: fn(Arc<Schema>, &[T]) -> Result<RecordBatch, QueueError>,
+) -> Result<u64, QueueError> {
+    let file_schema = schema.clone();
+    let mut writer = blocking(move || parquet_writer(writer, file_schema)).await?;
+    pin_mut!(rows);
+    let mut chunk = Vec::with_capacity(PARQUET_BATCH_SIZE);
+    let mut written = 0;
+    loop {
+        let row = rows.try_next().await?;
+        let done = row.is_none();
+        chunk.extend(row);
+        if chunk.len() == PARQUET_BATCH_SIZE || (done && !chunk.is_empty()) {
+            let batch = to_batch(schema.clone(), &chunk)?;
+            written += chunk.len() as u64;
+            chunk.clear();
+            writer = blocking(move || {
+                writer.write(&batch).context("Failed to write Parquet")?;
+                Ok(writer)
+            })
+            .await?;
+        }
+        if done {
+            break;
+        }
+    }
+    blocking(move || {
+        writer.close().context("Failed to write Parquet")?;
+        Ok(())
+    })
+    .await?;
+    Ok(written)
+}
+
+/// Run `f`, which does blocking IO, on Tokio's blocking threads.
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, QueueError> + Send + 'static,
+) -> Result<T, QueueError> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .context("Parquet writer panicked")?
+}
+
+fn timestamp() -> DataType {
+    DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into()))
+}
+
+fn parquet_writer<W: Write + Send>(
+    writer: W,
+    schema: Arc<Schema>,
+) -> Result<ArrowWriter<W>, QueueError> {
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::ZSTD(ZstdLevel::default()))
+        .set_max_row_group_size(PARQUET_BATCH_SIZE)
+        .build();
+    Ok(ArrowWriter::try_new(writer, schema, Some(properties))
+        .context("Failed to start Parquet file")?)
+}
+
+fn timestamps(values: impl Iterator<Item = Option<DateTime>>) -> ArrayRef {
+    Arc::new(
+        values
+            .map(|at| at.map(|at| at.timestamp_millis()))
+            .collect::<TimestampMillisecondArray>()
+            .with_timezone("UTC"),
+    )
+}
+
+fn archive_batch(schema: Arc<Schema>, jobs: &[ArchivedJob]) -> Result<RecordBatch, QueueError> {
+    let strings = |f: fn(&ArchivedJob) -> Option<&str>| -> ArrayRef {
+        Arc::new(jobs.iter().map(f).collect::<StringArray>())
+    };
+    let mut tags = ListBuilder::new(StringBuilder::new());
+    for job in jobs {
+        tags.append_value(job.tags.iter().map(Some));
+    }
+    let json = |f: fn(&ArchivedJob) -> String| -> ArrayRef {
+        Arc::new(jobs.iter().map(|job| Some(f(job))).collect::<StringArray>())
+    };
+    let columns: Vec<ArrayRef> = vec![
+        strings(|job| Some(&job.jid)),
+        strings(|job| job.public_id.as_deref()),
+        strings(|job| Some(&job.queue)),
+        strings(|job| Some(&job.job_type)),
+        Arc::new(
+            jobs.iter()
+                .map(|job| Some(job.payload.as_slice()))
+                .collect::<BinaryArray>(),
+        ),
+        strings(|job| job.content_type.as_deref()),
+        Arc::new(jobs.iter().map(|job| job.retries).collect::<UInt32Array>()),
+        Arc::new(jobs.iter().map(|job| job.attempts).collect::<UInt32Array>()),
+        timestamps(jobs.iter().map(|job| Some(job.enqueued_at))),
+        timestamps(jobs.iter().map(|job| job.started_at)),
+        timestamps(jobs.iter().map(|job| Some(job.completed_at))),
+        strings(|job| job.tenant.as_deref()),
+        strings(|job| job.correlation_id.as_deref()),
+        Arc::new(tags.finish()),
+        json(|job| serde_json::to_string(&job.annotations).unwrap_or_default()),
+        json(|job| serde_json::to_string(&job.headers).unwrap_or_default()),
+    ];
+    Ok(RecordBatch::try_new(schema, columns).context("Failed to build Parquet batch")?)
+}
+
+fn events_batch(schema: Arc<Schema>, events: &[EventRow]) -> Result<RecordBatch, QueueError> {
+    let strings = |f: fn(&EventRow) -> &str| -> ArrayRef {
+        Arc::new(
+            events
+                .iter()
+                .map(|event| Some(f(event)))
+                .collect::<StringArray>(),
+        )
+    };
+    let columns: Vec<ArrayRef> = vec![
+        timestamps(events.iter().map(|event| Some(event.at.to_chrono()))),
+        strings(|event| &event.name),
+        strings(|event| &event.jid),
+        strings(|event| &event.meta.job_type),
+        strings(|event| &event.meta.queue),
+        Arc::new(
+            events
+                .iter()
+                .map(|event| Some(event.status.to_string()))
+                .collect::<StringArray>(),
+        ),
+        Arc::new(
+            events
+                .iter()
+                .map(|event| Some(event.attempt as u32))
+                .collect::<UInt32Array>(),
+        ),
+        Arc::new(
+            events
+                .iter()
+                .map(|event| event.duration_ms)
+                .collect::<Int64Array>(),
+        ),
+    ];
+    Ok(RecordBatch::try_new(schema, columns).context("Failed to build Parquet batch")?)
+}
+
+fn stats_batch(
+    schema: Arc<Schema>,
+    snapshots: &[StatsSnapshot],
+) -> Result<RecordBatch, QueueError> {
+    let counts = |f: fn(&StatsSnapshot) -> Option<u64>| -> ArrayRef {
+        Arc::new(snapshots.iter().map(f).collect::<UInt64Array>())
+    };
+    let columns: Vec<ArrayRef> = vec![
+        timestamps(snapshots.iter().map(|snapshot| Some(snapshot.taken_at))),
+        Arc::new(
+            snapshots
+                .iter()
+                .map(|snapshot| Some(snapshot.queue.as_str()))
+                .collect::<StringArray>(),
+        ),
+        counts(|snapshot| Some(snapshot.stats.ready)),
+        counts(|snapshot| Some(snapshot.stats.scheduled)),
+        counts(|snapshot| Some(snapshot.stats.running)),
+        counts(|snapshot| Some(snapshot.stats.parked)),
+        counts(|snapshot| Some(snapshot.stats.dead)),
+        counts(|snapshot| Some(snapshot.stats.cancelled)),
+        counts(|snapshot| snapshot.stats.oldest_ready_age_ms),
+        Arc::new(
+            snapshots
+                .iter()
+                .map(|snapshot| Some(snapshot.stats.consistent))
+                .collect::<BooleanArray>(),
+        ),
+    ];
+    Ok(RecordBatch::try_new(schema, columns).context("Failed to build Parquet batch")?)
+}
@@ -0,0 +1,73 @@
+use bson::{Bson, Document};
+use chrono::Utc;
+
+use crate::status::JobStatus;
+
+/// Changes to some fields of a job that moves to another collection, applied to the stored
+/// document before it is copied instead of rebuilding the job from what this version knows
+/// about it. Fields the changes don't mention, including ones written by other components
+/// or newer versions of the crate, are kept as they are.
+#[derive(Debug, Default)]
+pub(crate) struct Patch {
+    set: Document,
+    unset: Vec<&'static str>,
+}
+
+impl Patch {
+    pub(crate) fn set(mut self, key: &str, value: impl Into<Bson>) -> Self {
+        self.set.insert(key, value);
+        self
+    }
+
+    pub(crate) fn unset(mut self, key: &'static str) -> Self {
+        self.unset.push(key);
+        self
+    }
+
+    /// Make the changes to `document`, as a `$set` and `$unset` would.
+    pub(crate) fn apply(&self, document: &mut Document) {
+        for key in &self.unset {
+            document.remove(*key);
+        }
+        document.extend(self.set.clone());
+    }
+
+    /// A job as it is kept in the dead queue. Its queue and priority are kept, so requeueing
+    /// it puts it back where it was.
+    pub(crate) fn dead_letter(reason: Option<&str>) -> Self {
+        let patch = Self::default()
+            .set("status", JobStatus::Dead)
+            .set("started_at", Bson::Null)
+            .set("cancel_requested", false)
+            .unset("cancelled_at")
+            .unset("cancelled_by")
+            .unset("completion_token")
+            .unset("shadow_of")
+            .unset("parked");
+        match reason {
+            Some(reason) => patch.set("dead_reason", reason),
+            None => patch.unset("dead_reason"),
+        }
+    }
+
+    /// A cancelled job as it is retained, see [`CancelMode::Retain`](crate::CancelMode::Retain).
+    pub(crate) fn cancelled(actor: Option<&str>) -> Self {
+        let patch = Self::default()
+            .set("status", JobStatus::Cancelled)
+            .set("cancelled_at", bson::DateTime::from_chrono(Utc::now()));
+        match actor {
+            Some(actor) => patch.set("cancelled_by", actor),
+            None => patch.unset("cancelled_by"),
+        }
+    }
+
+    /// A dead job put back in the queue as if it was new, due at `now`.
+    pub(crate) fn requeued(now: bson::DateTime) -> Self {
+        Self::default()
+            .set("status", JobStatus::Pending)
+            .set("retries", 0_i64)
+            .set("attempts", 0_i64)
+            .set("scheduled_at", now)
+            .set("started_at", Bson::Null)
+    }
+}
@@ -0,0 +1,199 @@
+use std::{
+    sync::Mutex,
+    time::{Duration as StdDuration, Instant},
+};
+
+use aide_de_camp::core::{queue::QueueError, DateTime, Duration};
+use anyhow::Context;
+use bson::doc;
+use chrono_tz::Tz;
+use cron::Schedule;
+use futures::TryStreamExt;
+use mongodb::{options::UpdateOptions, Collection};
+use tracing::instrument;
+
+use crate::{
+    recurring::{fire_times_after, parse_schedule, parse_timezone},
+    trace::traced,
+    types::PauseWindowRow,
+    MongoDbQueue,
+};
+
+/// How long a queue keeps using the pause windows it read before reading them again. Changes
+/// made through other queue instances take up to this long to be seen.
+const REFRESH_INTERVAL: StdDuration = StdDuration::from_secs(30);
+
+/// A recurring time in which a queue doesn't hand out jobs, e.g. every Sunday from 00:00 to
+/// 04:00 for database maintenance. See [`MongoDbQueue::add_pause_window`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PauseWindow {
+    /// Cron expression (with seconds) for when the window opens, e.g. `"0 0 0 * * Sun"`.
+    pub starts: String,
+    /// How long the window stays open.
+    pub duration: Duration,
+    /// Timezone `starts` is evaluated in. Defaults to UTC.
+    pub timezone: Option<Tz>,
+}
+
+impl PauseWindow {
+    pub fn new(starts: impl Into<String>, duration: Duration) -> Self {
+        Self {
+            starts: starts.into(),
+            duration,
+            timezone: None,
+        }
+    }
+
+    pub fn with_timezone(mut self, timezone: Tz) -> Self {
+        self.timezone = Some(timezone);
+        self
+    }
+}
+
+/// A stored window, parsed.
+struct ActiveWindow {
+    schedule: Schedule,
+    timezone: Tz,
+    duration: Duration,
+}
+
+impl ActiveWindow {
+    /// When the window open at `now` closes, if one is.
+    fn open_until(&self, now: DateTime) -> Option<DateTime> {
+        let opened = fire_times_after(&self.schedule, self.timezone, now - self.duration).next()?;
+        (opened <= now).then(|| opened + self.duration)
+    }
+}
+
+/// The pause windows of a queue as last read, shared by its clones.
+#[derive(Default)]
+pub(crate) struct PauseCalendar {
+    windows: Mutex<Option<(Instant, Vec<ActiveWindow>)>>,
+}
+
+impl PauseCalendar {
+    fn invalidate(&self) {
+        *self.windows.lock().unwrap() = None;
+    }
+}
+
+impl MongoDbQueue {
+    /// Add or replace the pause window `name` of this queue. Every worker of the queue stops
+    /// claiming jobs while a window is open and resumes when it closes, so routine maintenance
+    /// doesn't need a manual pause and resume. Jobs can still be scheduled.
+    #[instrument(skip_all, err, fields(name = name, starts = %window.starts))]
+    pub async fn add_pause_window(
+        &self,
+        name: &str,
+        window: PauseWindow,
+    ) -> Result<(), QueueError> {
+        parse_schedule(&window.starts)?;
+        let collection = self.pause_windows_collection();
+        traced(
+            &collection,
+            "update_one",
+            collection.update_one(
+                doc! { "_id": self.pause_window_key(name) },
+                doc! { "$set": {
+                    "queue": &self.queue_name,
+                    "name": name,
+                    "starts": &window.starts,
+                    "duration_ms": window.duration.num_milliseconds(),
+                    "timezone": window.timezone.map(|tz| tz.name()),
+                    "namespace": self.namespace.as_deref(),
+                } },
+                UpdateOptions::builder().upsert(true).build(),
+            ),
+        )
+        .await
+        .context("Failed to add pause window")?;
+        self.pause_calendar.invalidate();
+        Ok(())
+    }
+
+    /// Remove the pause window `name` of this queue. Returns whether there was one.
+    #[instrument(skip_all, err, fields(name = name))]
+    pub async fn remove_pause_window(&self, name: &str) -> Result<bool, QueueError> {
+        let collection = self.pause_windows_collection();
+        let result = traced(
+            &collection,
+            "delete_one",
+            collection.delete_one(doc! { "_id": self.pause_window_key(name) }, None),
+        )
+        .await
+        .context("Failed to remove pause window")?;
+        self.pause_calendar.invalidate();
+        Ok(result.deleted_count > 0)
+    }
+
+    /// The pause windows of this queue by name.
+    #[instrument(skip_all, err)]
+    pub async fn pause_windows(&self) -> Result<Vec<(String, PauseWindow)>, QueueError> {
+        self.read_pause_windows()
+            .await?
+            .into_iter()
+            .map(|row| {
+                let window = PauseWindow {
+                    starts: row.starts,
+                    duration: Duration::milliseconds(row.duration_ms),
+                    timezone: match row.timezone.as_deref() {
+                        Some(name) => Some(parse_timezone(Some(name))?),
+                        None => None,
+                    },
+                };
+                Ok((row.name, window))
+            })
+            .collect()
+    }
+
+    /// When the pause window open at `now` closes, or `None` when the queue isn't paused.
+    /// Uses the windows as read up to 30 seconds ago.
+    pub async fn paused_until(&self, now: DateTime) -> Result<Option<DateTime>, QueueError> {
+        let expired = match &*self.pause_calendar.windows.lock().unwrap() {
+            Some((read_at, _)) => read_at.elapsed() >= REFRESH_INTERVAL,
+            None => true,
+        };
+        if expired {
+            let windows = self
+                .read_pause_windows()
+                .await?
+                .into_iter()
+                .map(|row| {
+                    Ok(ActiveWindow {
+                        schedule: parse_schedule(&row.starts)?,
+                        timezone: parse_timezone(row.timezone.as_deref())?,
+                        duration: Duration::milliseconds(row.duration_ms),
+                    })
+                })
+                .collect::<Result<Vec<_>, QueueError>>()?;
+            *self.pause_calendar.windows.lock().unwrap() = Some((Instant::now(), windows));
+        }
+        let calendar = self.pause_calendar.windows.lock().unwrap();
+        let windows = calendar.iter().flat_map(|(_, windows)| windows);
+        // Overlapping windows pause the queue until the last of them closes.
+        Ok(windows.filter_map(|window| window.open_until(now)).max())
+    }
+
+    async fn read_pause_windows(&self) -> Result<Vec<PauseWindowRow>, QueueError> {
+        let collection = self.pause_windows_collection();
+        let rows = traced(
+            &collection,
+            "find",
+            collection.find(self.scoped(doc! { "queue": &self.queue_name }), None),
+        )
+        .await
+        .context("Failed to read pause windows")?
+        .try_collect()
+        .await
+        .context("Failed to read pause windows")?;
+        Ok(rows)
+    }
+
+    fn pause_window_key(&self, name: &str) -> String {
+        self.scoped_key(&format!("{}:{name}", self.queue_name))
+    }
+
+    fn pause_windows_collection(&self) -> Collection<PauseWindowRow> {
+        self.database.collection("adc_pause_windows")
+    }
+}
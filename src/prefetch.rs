@@ -0,0 +1,141 @@
+use std::{collections::VecDeque, sync::Mutex};
+
+use aide_de_camp::core::{
+    bincode::{Decode, Encode},
+    job_handle::JobHandle,
+    job_processor::JobProcessor,
+    queue::{Queue, QueueError},
+    DateTime, Xid,
+};
+use anyhow::Context;
+use async_trait::async_trait;
+use bson::{doc, Document};
+use tracing::instrument;
+
+use crate::{job_handle::MongoDbJobHandle, status, trace::traced, MongoDbQueue};
+
+/// A [`Queue`] for `JobRunner` that claims jobs in batches instead of one per poll.
+///
+/// When a worker polls and nothing is buffered, the backlog of the requested job types is
+/// counted and up to `slots` jobs (the runner's concurrency) are claimed at once. Polls from
+/// the other workers are then served from the buffer without a database round trip.
+///
+/// Buffered jobs are already claimed, so keep `slots` no larger than the number of jobs the
+/// runner can actually start.
+pub struct PrefetchQueue {
+    queue: MongoDbQueue,
+    slots: usize,
+    buffer: Mutex<VecDeque<MongoDbJobHandle>>,
+}
+
+impl PrefetchQueue {
+    pub fn new(queue: MongoDbQueue, slots: usize) -> Self {
+        Self {
+            queue,
+            slots: slots.max(1),
+            buffer: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Number of claimed jobs waiting for a worker.
+    pub fn buffered(&self) -> usize {
+        self.buffer.lock().unwrap().len()
+    }
+
+    fn take_buffered(&self, job_types: &[&str]) -> Option<MongoDbJobHandle> {
+        let mut buffer = self.buffer.lock().unwrap();
+        let position = buffer
+            .iter()
+            .position(|job| job_types.contains(&job.job_type()))?;
+        buffer.remove(position)
+    }
+}
+
+#[async_trait]
+impl Queue for PrefetchQueue {
+    type JobHandle = MongoDbJobHandle;
+
+    async fn schedule_at<J>(
+        &self,
+        payload: J::Payload,
+        scheduled_at: DateTime,
+        priority: i8,
+    ) -> Result<Xid, QueueError>
+    where
+        J: JobProcessor + 'static,
+        J::Payload: Encode,
+    {
+        self.queue
+            .schedule_at::<J>(payload, scheduled_at, priority)
+            .await
+    }
+
+    #[instrument(skip_all, err, fields(batch_size))]
+    async fn poll_next_with_instant(
+        &self,
+        job_types: &[&str],
+        now: DateTime,
+    ) -> Result<Option<MongoDbJobHandle>, QueueError> {
+        if let Some(job) = self.take_buffered(job_types) {
+            return Ok(Some(job));
+        }
+
+        let backlog = self.queue.ready_jobs(job_types, now).await?;
+        let batch_size = (backlog as usize).clamp(1, self.slots);
+        tracing::Span::current().record("batch_size", batch_size);
+
+        let mut batch = VecDeque::with_capacity(batch_size);
+        for _ in 0..batch_size {
+            match self.queue.poll_next_with_instant(job_types, now).await {
+                Ok(Some(job)) => batch.push_back(job),
+                Ok(None) => break,
+                Err(error) => {
+                    // Nobody would run the jobs claimed so far, hand them back. A failed
+                    // release is reported and leaves the job to the claim timeout.
+                    for job in batch {
+                        let _ = job.release().await;
+                    }
+                    return Err(error);
+                }
+            }
+        }
+        let first = batch.pop_front();
+        self.buffer.lock().unwrap().extend(batch);
+        Ok(first)
+    }
+
+    async fn cancel_job(&self, job_id: Xid) -> Result<(), QueueError> {
+        self.queue.cancel_job(job_id).await
+    }
+
+    async fn unschedule_job<J>(&self, job_id: Xid) -> Result<J::Payload, QueueError>
+    where
+        J: JobProcessor + 'static,
+        J::Payload: Decode,
+    {
+        self.queue.unschedule_job::<J>(job_id).await
+    }
+}
+
+impl MongoDbQueue {
+    /// Number of jobs of `job_types` that are due at `now` and not claimed yet.
+    #[instrument(skip_all, err, ret)]
+    pub async fn ready_jobs(&self, job_types: &[&str], now: DateTime) -> Result<u64, QueueError> {
+        let collection = self.database.collection::<Document>("adc_queue");
+        let count = traced(
+            &collection,
+            "count_documents",
+            collection.count_documents(
+                self.scoped(status::pending(doc! {
+                    "queue": &self.queue_name,
+                    "scheduled_at": { "$lte": bson::DateTime::from_millis(now.timestamp_millis()) },
+                    "job_type": { "$in": job_types },
+                })),
+                None,
+            ),
+        )
+        .await
+        .context("Failed to count ready jobs")?;
+        Ok(count)
+    }
+}
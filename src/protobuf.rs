@@ -0,0 +1,73 @@
+use bincode::{
+    de::Decoder,
+    enc::Encoder,
+    error::{DecodeError, EncodeError},
+    Decode, Encode,
+};
+
+use crate::{
+    framed::{frame, unframe},
+    hooks::PayloadCodec,
+};
+
+/// Media type of payloads stored by [`ProstCodec`].
+pub const PROTOBUF_CONTENT_TYPE: &str = "application/protobuf";
+
+/// A protobuf message as the payload of a job, e.g. `type Payload = Protobuf<SendEmail>`.
+/// Encodes to the message's protobuf bytes wrapped in bincode, which [`ProstCodec`] unwraps
+/// so the queue stores the bare message.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Protobuf<M>(pub M);
+
+impl<M: prost::Message> Encode for Protobuf<M> {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        self.0.encode_to_vec().encode(encoder)
+    }
+}
+
+impl<M: prost::Message + Default> Decode for Protobuf<M> {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        let bytes = Vec::<u8>::decode(decoder)?;
+        M::decode(bytes.as_slice())
+            .map(Protobuf)
+            .map_err(|error| DecodeError::OtherString(error.to_string()))
+    }
+}
+
+/// Stores [`Protobuf`] payloads as plain protobuf messages other services can read, tagged
+/// with [`PROTOBUF_CONTENT_TYPE`] unless built with another content type. Register it with
+/// [`MongoDbQueue::with_payload_codec`](crate::MongoDbQueue::with_payload_codec) for job types
+/// whose payload is a [`Protobuf`].
+#[derive(Debug, Clone)]
+pub struct ProstCodec {
+    content_type: String,
+}
+
+impl ProstCodec {
+    /// A codec that tags payloads with `content_type`, e.g. one naming the message type.
+    pub fn with_content_type(content_type: impl Into<String>) -> Self {
+        Self {
+            content_type: content_type.into(),
+        }
+    }
+}
+
+impl Default for ProstCodec {
+    fn default() -> Self {
+        Self::with_content_type(PROTOBUF_CONTENT_TYPE)
+    }
+}
+
+impl PayloadCodec for ProstCodec {
+    fn content_type(&self) -> &str {
+        &self.content_type
+    }
+
+    fn encode(&self, _job_type: &str, payload: &[u8]) -> Result<Vec<u8>, String> {
+        unframe(payload, "protobuf")
+    }
+
+    fn decode(&self, _job_type: &str, stored: &[u8]) -> Result<Vec<u8>, String> {
+        frame(stored)
+    }
+}
@@ -7,53 +7,387 @@ use aide_de_camp::core::{
 use anyhow::Context;
 use async_trait::async_trait;
 use bincode::Decode;
-use bson::{doc, Binary};
+use bson::{doc, spec::BinarySubtype, Bson, Document};
 use chrono::Utc;
+use futures::TryStreamExt;
 use mongodb::{
     options::{
-        ClientOptions, ConnectionString, FindOneAndUpdateOptions, ReturnDocument, Tls, TlsOptions,
+        ClientOptions, ConnectionString, FindOneAndUpdateOptions, FindOneOptions, FindOptions,
+        ReturnDocument, Tls, TlsOptions,
     },
     Client, Collection, Database,
 };
-use tracing::instrument;
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+    sync::Arc,
+    time::Instant,
+};
+use tracing::{field::Empty, instrument, instrument::WithSubscriber, Dispatch, Instrument};
+
+use crate::{
+    backoff::RetryBackoff,
+    backpressure::BackpressureThresholds,
+    budget::{BudgetCounts, BudgetVerdict, PendingBudget, OVER_BUDGET},
+    cancel::CancelMode,
+    contention::ClaimCounters,
+    credentials::{ConnectionSource, SharedDatabase},
+    decay::PriorityDecay,
+    drop_guard::DropBehavior,
+    error::MongoDbQueueError,
+    events::{self, EventLog},
+    hooks::{
+        ClaimDecision, ClaimFilter, ErrorContext, ErrorReporter, IdGenerator, PayloadCodec,
+        PayloadMerger, PayloadValidator, TracingErrorReporter,
+    },
+    jitter::ClaimJitter,
+    job_handle::{check_header_name, MongoDbJobHandle},
+    job_types::AllowedJobTypes,
+    lease::ClaimMode,
+    ordering::{ClaimOrder, ClaimPass, ClaimStrategy},
+    orphans::ClaimMarks,
+    pause::PauseCalendar,
+    region::RegionAffinity,
+    routes::{Canary, CanaryMode},
+    sampling::{self, PollTracing},
+    spillover::ClaimTier,
+    status::{self, JobStatus},
+    trace::traced,
+    types::JobRow,
+    uri::SafeUri,
+};
 
-use crate::{job_handle::MongoDbJobHandle, types::JobRow};
+/// Maximum number of jobs a single poll hands back to the queue because the [`ClaimFilter`]
+/// turned them down, before giving up and returning nothing.
+pub const MAX_CLAIM_FILTER_REJECTIONS: usize = 10;
+
+/// Options for [`MongoDbQueue::schedule_with`].
+#[derive(Debug, Clone, Default)]
+pub struct ScheduleOptions {
+    /// When the job becomes ready to run. Defaults to now.
+    pub scheduled_at: Option<DateTime>,
+    pub priority: i8,
+    /// Tenant or producer the job is accounted to for quotas, see
+    /// [`MongoDbQueue::set_quota`].
+    pub tenant: Option<String>,
+    /// Ties the job to a business transaction, see [`MongoDbQueue::jobs_by_correlation_id`].
+    pub correlation_id: Option<String>,
+    /// Region whose workers should run the job, see [`RegionAffinity`].
+    pub region: Option<String>,
+    /// When the job should be done by, used by [`ClaimOrder::EarliestDeadline`].
+    pub deadline: Option<DateTime>,
+    /// Jobs that have to complete before this one is claimed. One that is already gone when
+    /// this one is scheduled only counts as completed with [`MongoDbQueue::with_archive`] or
+    /// [`MongoDbQueue::with_event_log`]; otherwise, like a dead job, it keeps this one waiting.
+    pub depends_on: Vec<Xid>,
+    /// Labels workers can select jobs by, see [`MongoDbQueue::with_claim_tags`].
+    pub tags: Vec<String>,
+    /// Team or service the job belongs to. Defaults to the queue's, see
+    /// [`MongoDbQueue::with_owner`].
+    pub owner: Option<String>,
+    /// Values for middleware rather than for the job itself, e.g. a trace context or the id
+    /// of the key the payload is encrypted with, kept apart from the payload the way message
+    /// brokers keep headers. Read and changed through [`MongoDbJobHandle::headers`] and
+    /// [`MongoDbJobHandle::set_header`]. Keys can't contain `.` or start with `$`.
+    pub headers: BTreeMap<String, String>,
+    /// Media type of the payload. Defaults to the queue's, see
+    /// [`MongoDbQueue::with_content_type`]. Ignored for payloads that go through a
+    /// [`PayloadCodec`], which are tagged with the codec's.
+    pub content_type: Option<String>,
+    /// Version of the handler the payload was written for. Only workers that serve it claim
+    /// the job, see [`MongoDbQueue::with_handler_versions`].
+    pub handler_version: Option<u32>,
+}
 
 /// An implementation of the Queue backed by MongoDB
 #[derive(Clone)]
 pub struct MongoDbQueue {
-    database: Database,
-    bincode_config: bincode::config::Configuration,
+    pub(crate) database: SharedDatabase,
+    pub(crate) bincode_config: bincode::config::Configuration,
+    payload_validators: Arc<HashMap<String, Arc<dyn PayloadValidator>>>,
+    pub(crate) payload_mergers: Arc<HashMap<String, Arc<dyn PayloadMerger>>>,
+    pub(crate) payload_codecs: Arc<HashMap<String, Arc<dyn PayloadCodec>>>,
+    pub(crate) default_payload_codec: Option<Arc<dyn PayloadCodec>>,
+    claim_filter: Option<Arc<dyn ClaimFilter>>,
+    pub(crate) error_reporter: Arc<dyn ErrorReporter>,
+    pub(crate) backpressure_thresholds: BackpressureThresholds,
+    max_payload_size: Option<usize>,
+    pub(crate) cancel_mode: CancelMode,
+    pub(crate) queue_name: String,
+    region: Option<RegionAffinity>,
+    claim_jitter: Option<ClaimJitter>,
+    claim_strategy: Arc<dyn ClaimStrategy>,
+    priority_inheritance: bool,
+    poll_tracing: PollTracing,
+    /// Runs the polls [`PollTracing`] doesn't sample, built once since every new dispatcher
+    /// registers with all callsites.
+    quiet_polls: Option<Dispatch>,
+    max_claim_payload_size: Option<usize>,
+    pub(crate) payload_subtype: BinarySubtype,
+    pub(crate) content_type: Option<String>,
+    claim_tags: Vec<String>,
+    pub(crate) handler_versions: Vec<(String, Vec<u32>)>,
+    pub(crate) drop_behavior: DropBehavior,
+    id_generator: Option<Arc<dyn IdGenerator>>,
+    pub(crate) namespace: Option<String>,
+    pub(crate) event_log: Option<Arc<EventLog>>,
+    pub(crate) cloud_events_source: Option<String>,
+    pub(crate) archive: bool,
+    pub(crate) audit_trail: bool,
+    pub(crate) pause_calendar: Arc<PauseCalendar>,
+    pub(crate) priority_decay: Option<PriorityDecay>,
+    pub(crate) lifo_job_types: Vec<String>,
+    pub(crate) retry_backoffs: Arc<HashMap<String, RetryBackoff>>,
+    pub(crate) pending_budgets: Arc<HashMap<String, PendingBudget>>,
+    pub(crate) default_pending_budget: Option<PendingBudget>,
+    pub(crate) budget_counts: Arc<BudgetCounts>,
+    pub(crate) owner: Option<String>,
+    pub(crate) require_owner: bool,
+    pub(crate) registered_job_types_only: bool,
+    pub(crate) allowed_job_types: Arc<AllowedJobTypes>,
+    pub(crate) claim_marks: Arc<ClaimMarks>,
+    pub(crate) connection_source: Option<ConnectionSource>,
+    pub(crate) claim_mode: ClaimMode,
+    pub(crate) claim_counters: Arc<ClaimCounters>,
 }
 
 impl MongoDbQueue {
-    pub async fn new(uri: &str, cert_file: Option<String>) -> Result<Self, mongodb::error::Error> {
-        let client = Self::new_client(uri, cert_file).await?;
+    /// Connect to `uri`. With a `cert_file`, TLS is enabled and the server certificate is
+    /// verified against it, without checking the hostname unless built with the `rustls-tls`
+    /// feature only.
+    #[instrument(skip_all, err, fields(uri = Empty))]
+    pub async fn new(
+        uri: impl Into<SafeUri>,
+        cert_file: Option<String>,
+    ) -> Result<Self, mongodb::error::Error> {
+        let uri = uri.into();
+        tracing::Span::current().record("uri", tracing::field::display(&uri));
+        let client = Self::new_client(&uri, cert_file)
+            .await
+            .map_err(|error| uri.scrub_error(error))?;
         let database = client.default_database().unwrap_or(client.database("adc"));
+        Ok(Self::with_database(database))
+    }
 
-        Ok(Self {
-            database,
+    /// A queue in `database`, using the client it came from, e.g. one the application already
+    /// set up with its own pooling and options. [`Self::reconnect`] does nothing for such
+    /// queues.
+    pub fn with_database(database: Database) -> Self {
+        Self {
+            database: SharedDatabase::new(database),
             bincode_config: bincode::config::standard(),
-        })
+            payload_validators: Default::default(),
+            payload_mergers: Default::default(),
+            payload_codecs: Default::default(),
+            default_payload_codec: None,
+            claim_filter: None,
+            error_reporter: Arc::new(TracingErrorReporter),
+            backpressure_thresholds: BackpressureThresholds::default(),
+            max_payload_size: None,
+            cancel_mode: CancelMode::default(),
+            queue_name: "default".to_string(),
+            region: None,
+            claim_jitter: None,
+            claim_strategy: Arc::new(ClaimOrder::default()),
+            priority_inheritance: false,
+            poll_tracing: PollTracing::default(),
+            quiet_polls: None,
+            max_claim_payload_size: None,
+            payload_subtype: BinarySubtype::Generic,
+            content_type: None,
+            claim_tags: Vec::new(),
+            handler_versions: Vec::new(),
+            drop_behavior: DropBehavior::default(),
+            id_generator: None,
+            namespace: None,
+            event_log: None,
+            cloud_events_source: None,
+            archive: false,
+            audit_trail: false,
+            pause_calendar: Default::default(),
+            priority_decay: None,
+            lifo_job_types: Vec::new(),
+            retry_backoffs: Default::default(),
+            pending_budgets: Default::default(),
+            default_pending_budget: None,
+            budget_counts: Default::default(),
+            owner: None,
+            require_owner: false,
+            registered_job_types_only: false,
+            allowed_job_types: Default::default(),
+            claim_marks: Default::default(),
+            connection_source: None,
+            claim_mode: ClaimMode::default(),
+            claim_counters: Default::default(),
+        }
+    }
+
+    /// A queue in the database `name` of `client`, see [`Self::with_database`].
+    pub fn with_client(client: &Client, name: &str) -> Self {
+        Self::with_database(client.database(name))
+    }
+
+    /// Run `validator` on every payload of job type `J` before it is added to the queue, so
+    /// bad jobs are rejected with [`MongoDbQueueError::InvalidPayload`] at schedule time.
+    pub fn with_payload_validator<J>(mut self, validator: impl PayloadValidator + 'static) -> Self
+    where
+        J: JobProcessor + 'static,
+    {
+        Arc::make_mut(&mut self.payload_validators)
+            .insert(J::name().to_string(), Arc::new(validator));
+        self
+    }
+
+    /// Give every new job an id from `generator` next to its [`Xid`], see [`IdGenerator`].
+    pub fn with_id_generator(mut self, generator: impl IdGenerator + 'static) -> Self {
+        self.id_generator = Some(Arc::new(generator));
+        self
+    }
+
+    /// Combine payloads with `merger` when a job of type `J` is debounced onto a pending one,
+    /// see [`Self::schedule_debounced`].
+    pub fn with_payload_merger<J>(mut self, merger: impl PayloadMerger + 'static) -> Self
+    where
+        J: JobProcessor + 'static,
+    {
+        Arc::make_mut(&mut self.payload_mergers).insert(J::name().to_string(), Arc::new(merger));
+        self
+    }
+
+    /// Consult `filter` whenever a job is claimed, see [`ClaimFilter`].
+    pub fn with_claim_filter(mut self, filter: impl ClaimFilter + 'static) -> Self {
+        self.claim_filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Send failed operations and dead-lettered jobs to `reporter` instead of logging them
+    /// through [`TracingErrorReporter`].
+    pub fn with_error_reporter(mut self, reporter: impl ErrorReporter + 'static) -> Self {
+        self.error_reporter = Arc::new(reporter);
+        self
+    }
+
+    /// Thresholds used by [`Self::backpressure`].
+    pub fn with_backpressure_thresholds(mut self, thresholds: BackpressureThresholds) -> Self {
+        self.backpressure_thresholds = thresholds;
+        self
+    }
+
+    /// Reject payloads that encode to more than `bytes` with
+    /// [`MongoDbQueueError::PayloadTooLarge`] before they are sent to the database. Without a
+    /// limit, payloads close to 16MB fail with a driver error instead.
+    pub fn with_max_payload_size(mut self, bytes: usize) -> Self {
+        self.max_payload_size = Some(bytes);
+        self
+    }
+
+    /// Only claim jobs whose payload is at most `bytes`, e.g. on workers with little memory.
+    /// The limit is part of the claim query, larger jobs stay in the queue for other workers.
+    pub fn with_max_claim_payload_size(mut self, bytes: usize) -> Self {
+        self.max_claim_payload_size = Some(bytes);
+        self
+    }
+
+    /// Only claim jobs scheduled with all of `tags`, see [`ScheduleOptions::tags`].
+    pub fn with_claim_tags(mut self, tags: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.claim_tags = tags.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Whether cancelled jobs are deleted or retained, see [`CancelMode`].
+    pub fn with_cancel_mode(mut self, mode: CancelMode) -> Self {
+        self.cancel_mode = mode;
+        self
+    }
+
+    /// What happens to claimed jobs whose handle is dropped without an outcome, see
+    /// [`DropBehavior`].
+    pub fn with_drop_behavior(mut self, behavior: DropBehavior) -> Self {
+        self.drop_behavior = behavior;
+        self
+    }
+
+    /// Claim jobs from the queue called `name` instead of `"default"`, and schedule jobs there
+    /// unless a [`Route`](crate::Route) says otherwise. Counts, backpressure, recurring jobs
+    /// and the other queue-wide operations only see this queue, so queues sharing a database
+    /// don't affect each other.
+    pub fn with_queue_name(mut self, name: impl Into<String>) -> Self {
+        self.queue_name = name.into();
+        self
+    }
+
+    /// Only claim jobs of the worker's region, see [`RegionAffinity`].
+    pub fn with_region(mut self, affinity: RegionAffinity) -> Self {
+        self.region = Some(affinity);
+        self
+    }
+
+    /// Wait a short random time before each claim, see [`ClaimJitter`].
+    pub fn with_claim_jitter(mut self, jitter: ClaimJitter) -> Self {
+        self.claim_jitter = Some(jitter);
+        self
+    }
+
+    /// The order in which jobs are claimed, highest priority first by default.
+    pub fn with_claim_order(self, order: ClaimOrder) -> Self {
+        self.with_claim_strategy(order)
+    }
+
+    /// Claim jobs in an order of your own, see [`ClaimStrategy`].
+    pub fn with_claim_strategy(mut self, strategy: impl ClaimStrategy + 'static) -> Self {
+        self.claim_strategy = Arc::new(strategy);
+        self
+    }
+
+    /// Raise jobs that others depend on (see [`ScheduleOptions::depends_on`]) to the priority
+    /// of their highest priority dependent, so a low priority job doesn't stall a high
+    /// priority chain.
+    pub fn with_priority_inheritance(mut self, enabled: bool) -> Self {
+        self.priority_inheritance = enabled;
+        self
+    }
+
+    /// Sample or silence the spans of polls that find nothing, see [`PollTracing`]. Warnings
+    /// and errors of unsampled polls go to the subscriber that is the default when this is
+    /// called, so set up tracing first.
+    ///
+    /// # Panics
+    ///
+    /// If the sampling rate is NaN or outside 0.0 to 1.0.
+    pub fn with_poll_tracing(mut self, tracing: PollTracing) -> Self {
+        assert!(
+            tracing.is_valid(),
+            "poll tracing rate must be from 0.0 to 1.0, got {tracing:?}"
+        );
+        self.poll_tracing = tracing;
+        self.quiet_polls = match tracing {
+            PollTracing::Full => None,
+            PollTracing::SampleEmpty { .. } => Some(sampling::quiet()),
+        };
+        self
     }
 
     async fn new_client(
-        uri: &str,
+        uri: &SafeUri,
         cert_path: Option<String>,
     ) -> Result<Client, mongodb::error::Error> {
         match cert_path {
             Some(cert_path) => {
-                let conn_str = ConnectionString::parse(uri)?;
+                let conn_str = ConnectionString::parse(uri.expose())?;
                 let mut options = ClientOptions::parse_connection_string(conn_str).await?;
                 let mut tls_options = TlsOptions::default();
                 tls_options.ca_file_path = Some(cert_path.clone().into());
-                tls_options.allow_invalid_hostnames = Some(true);
+                // rustls has no switch for this, there the hostname must match the certificate.
+                #[cfg(feature = "openssl-tls")]
+                {
+                    tls_options.allow_invalid_hostnames = Some(true);
+                }
                 options.tls = Some(Tls::Enabled(tls_options));
                 let client = Client::with_options(options)?;
                 Ok(client)
             }
             None => {
-                let client = mongodb::Client::with_uri_str(uri).await?;
+                let client = mongodb::Client::with_uri_str(uri.expose()).await?;
                 Ok(client)
             }
         }
@@ -61,7 +395,7 @@ impl MongoDbQueue {
 
     #[cfg(test)]
     pub async fn delete_database(&self) -> Result<(), mongodb::error::Error> {
-        self.database.drop(None).await
+        self.database.drop().await
     }
 }
 
@@ -69,7 +403,6 @@ impl MongoDbQueue {
 impl Queue for MongoDbQueue {
     type JobHandle = MongoDbJobHandle;
 
-    #[instrument(skip_all, err, ret, fields(job_type = J::name(), payload_size))]
     async fn schedule_at<J>(
         &self,
         payload: J::Payload,
@@ -80,137 +413,851 @@ impl Queue for MongoDbQueue {
         J: JobProcessor + 'static,
         J::Payload: Encode,
     {
-        let payload = bincode::encode_to_vec(&payload, self.bincode_config)?;
-        let jid = new_xid();
-        let job_type = J::name();
+        let options = ScheduleOptions {
+            scheduled_at: Some(scheduled_at),
+            priority,
+            ..Default::default()
+        };
+        self.schedule_with::<J>(payload, options).await
+    }
 
-        tracing::Span::current().record("payload_size", payload.len());
-
-        self.database
-            .collection::<JobRow>("adc_queue")
-            .insert_one(
-                JobRow {
-                    jid: format!("{}", jid),
-                    queue: "default".to_string(),
-                    job_type: job_type.to_string(),
-                    payload: Binary {
-                        subtype: mongodb::bson::spec::BinarySubtype::Generic,
-                        bytes: payload.clone(),
-                    },
-                    retries: 0,
-                    scheduled_at: bson::DateTime::from_millis(scheduled_at.timestamp_millis()),
-                    enqueued_at: bson::DateTime::from_millis(Utc::now().timestamp_millis()),
-                    priority: priority as i64,
-                    started_at: None,
-                },
-                None,
+    async fn poll_next_with_instant(
+        &self,
+        job_types: &[&str],
+        now: DateTime,
+    ) -> Result<Option<MongoDbJobHandle>, QueueError> {
+        let poll_span = || {
+            tracing::info_span!(
+                "poll_next_with_instant",
+                jid = Empty,
+                job_type = Empty,
+                attempt = Empty
             )
+        };
+        let claim = || self.claim_next(job_types, now, None);
+        let (span, result) = match &self.quiet_polls {
+            Some(quiet) if !self.poll_tracing.sample() => {
+                let result = claim().with_subscriber(quiet.clone()).await;
+                if let Ok(None) = result {
+                    return Ok(None);
+                }
+                (poll_span(), result)
+            }
+            _ => {
+                let span = poll_span();
+                let result = claim().instrument(span.clone()).await;
+                (span, result)
+            }
+        };
+        span.in_scope(|| self.finish_poll(result))
+    }
+
+    #[instrument(skip_all, err)]
+    async fn cancel_job(&self, job_id: Xid) -> Result<(), QueueError> {
+        let jid: String = format!("{}", job_id);
+        let result = self
+            .retrying("cancel_job", || {
+                self.remove_unstarted(doc! { "jid": &jid }, None)
+            })
             .await
-            .context("Failed to add job to the queue")?;
+            .and_then(|row| row.map(|_| ()).ok_or(QueueError::JobNotFound(job_id)));
+        let context = ErrorContext {
+            operation: "cancel_job",
+            jid: Some(&jid),
+            job_type: None,
+            correlation_id: None,
+        };
+        self.reported(&context, result)
+    }
+
+    #[allow(clippy::or_fun_call)]
+    #[instrument(skip_all, err)]
+    async fn unschedule_job<J>(&self, job_id: Xid) -> Result<J::Payload, QueueError>
+    where
+        J: JobProcessor + 'static,
+        J::Payload: Decode,
+    {
+        let job_type = J::name();
+        let jid: String = format!("{}", job_id);
+        let result = async {
+            let filter_doc = doc! {
+                "jid": &jid,
+                "job_type": job_type
+            };
+            let row = self
+                .retrying("unschedule_job", || {
+                    self.remove_unstarted(filter_doc.clone(), None)
+                })
+                .await?;
+
+            match row {
+                Some(row) => {
+                    let payload = self.decode_stored(
+                        job_type,
+                        row.content_type.as_deref(),
+                        &row.payload.bytes,
+                    )?;
+                    let (decoded, _) = bincode::decode_from_slice(&payload, self.bincode_config)?;
+                    Ok(decoded)
+                }
+                None => Err(QueueError::JobNotFound(job_id)),
+            }
+        }
+        .await;
+        let context = ErrorContext {
+            operation: "unschedule_job",
+            jid: Some(&jid),
+            job_type: Some(job_type),
+            correlation_id: None,
+        };
+        self.reported(&context, result)
+    }
+}
+
+impl MongoDbQueue {
+    /// Add a job to the queue with options beyond what the [`Queue`] trait methods take.
+    #[instrument(skip_all, err, ret, fields(job_type = J::name(), payload_size, correlation_id = options.correlation_id.as_deref()))]
+    pub async fn schedule_with<J>(
+        &self,
+        payload: J::Payload,
+        options: ScheduleOptions,
+    ) -> Result<Xid, QueueError>
+    where
+        J: JobProcessor + 'static,
+        J::Payload: Encode,
+    {
+        let job_type = J::name();
+        let result = match self.encode_payload::<J>(&payload) {
+            Ok(payload) => {
+                tracing::Span::current().record("payload_size", payload.len());
+                self.insert_job(job_type, &payload, &options).await
+            }
+            Err(error) => Err(error),
+        };
+        let context = ErrorContext {
+            operation: "schedule_at",
+            jid: None,
+            job_type: Some(J::name()),
+            correlation_id: options.correlation_id.as_deref(),
+        };
+        self.reported(&context, result)
+    }
+
+    /// Add a new job with an encoded payload.
+    async fn insert_job(
+        &self,
+        job_type: &'static str,
+        payload: &[u8],
+        options: &ScheduleOptions,
+    ) -> Result<Xid, QueueError> {
+        let jid = new_xid();
+        let owner = self.owner_of(job_type, options.owner.as_deref())?;
+        for name in options.headers.keys() {
+            check_header_name(name)?;
+        }
+        self.check_job_type(job_type).await?;
+
+        let verdict = self.enforce_pending_budget(job_type).await?;
+        let diverted = verdict == BudgetVerdict::Divert;
+
+        let route = self.route(job_type).await?;
+        let (mut queue, priority, canary) = match route {
+            Some(route) => (
+                route.queue,
+                route.priority.unwrap_or(options.priority),
+                route.canary.filter(Canary::sample),
+            ),
+            None => (self.queue_name.clone(), options.priority, None),
+        };
+        if let Some(Canary {
+            queue: canary_queue,
+            mode: CanaryMode::Divert,
+            ..
+        }) = &canary
+        {
+            queue = canary_queue.clone();
+        }
+
+        let scheduled_at = options.scheduled_at.unwrap_or_else(Utc::now);
+        let depends_on: Vec<String> = options
+            .depends_on
+            .iter()
+            .map(|jid| format!("{}", jid))
+            .collect();
+        let waiting = self.pending_jids(&depends_on).await?;
+        let blocked_by = self.unfinished_dependencies(&depends_on, &waiting).await?;
+        let row = JobRow {
+            jid: format!("{}", jid),
+            queue,
+            job_type: job_type.to_string(),
+            payload: self.payload_binary(payload.to_vec()),
+            retries: 0,
+            scheduled_at: bson::DateTime::from_millis(scheduled_at.timestamp_millis()),
+            enqueued_at: bson::DateTime::from_millis(Utc::now().timestamp_millis()),
+            priority: priority as i64,
+            started_at: None,
+            recurring_key: None,
+            cancel_requested: false,
+            tenant: options.tenant.clone(),
+            correlation_id: options.correlation_id.clone(),
+            cancelled_at: None,
+            cancelled_by: None,
+            completion_token: None,
+            shadow_of: None,
+            region: options.region.clone(),
+            deadline: options.deadline.map(bson::DateTime::from_chrono),
+            dedup_key: None,
+            blocked_by,
+            annotations: BTreeMap::new(),
+            headers: options.headers.clone(),
+            content_type: self.content_type_of(job_type, options.content_type.as_deref()),
+            handler_version: options.handler_version.map(i64::from),
+            parked: diverted.then(|| OVER_BUDGET.to_string()),
+            status: if diverted {
+                JobStatus::Parked
+            } else {
+                JobStatus::Pending
+            },
+            dead_reason: None,
+            tags: options.tags.clone(),
+            attempts: 0,
+            public_id: self.public_id(&jid, job_type),
+            namespace: self.namespace.clone(),
+            owner,
+            extra: Document::new(),
+        };
+        let shadow = match &canary {
+            Some(Canary {
+                queue: canary_queue,
+                mode: CanaryMode::Duplicate,
+                ..
+            }) => {
+                let shadow_jid = new_xid();
+                Some(JobRow {
+                    jid: format!("{}", shadow_jid),
+                    public_id: self.public_id(&shadow_jid, job_type),
+                    queue: canary_queue.clone(),
+                    shadow_of: Some(row.jid.clone()),
+                    ..row.clone()
+                })
+            }
+            _ => None,
+        };
+        // Counted last, so a job rejected for another reason doesn't use up the quota.
+        let quota_hour = match &options.tenant {
+            Some(tenant) => self.enforce_quota(tenant).await?,
+            None => None,
+        };
+        let collection = if diverted {
+            self.cold_collection()
+        } else {
+            self.collection()
+        };
+        // Only the insert is tried again, the checks and reservations before it already ran.
+        // A canary copy goes in with the original, so neither is added without the other.
+        let inserted = self
+            .retrying("schedule", || async {
+                match &shadow {
+                    Some(shadow) => {
+                        traced(
+                            &collection,
+                            "insert_many",
+                            collection.insert_many([&row, shadow], None),
+                        )
+                        .await
+                        .context("Failed to add job and canary job to the queue")?;
+                    }
+                    None => {
+                        traced(&collection, "insert_one", collection.insert_one(&row, None))
+                            .await
+                            .context("Failed to add job to the queue")?;
+                    }
+                }
+                Ok(())
+            })
+            .await;
+        if let Err(error) = inserted {
+            if let (Some(tenant), Some(hour)) = (&options.tenant, quota_hour) {
+                if let Err(refund_error) = self.refund_quota(tenant, hour).await {
+                    let context = ErrorContext {
+                        operation: "refund_quota",
+                        jid: Some(&row.jid),
+                        job_type: Some(job_type),
+                        correlation_id: row.correlation_id.as_deref(),
+                    };
+                    self.report_error(&context, &refund_error);
+                }
+            }
+            return Err(error);
+        }
+        if let BudgetVerdict::DropOldest(filter) = verdict {
+            // The job is in, failing to make room for it is not worth failing it for.
+            if let Err(error) = self.drop_oldest(filter, &row.jid).await {
+                let context = ErrorContext {
+                    operation: "drop_oldest",
+                    jid: Some(&row.jid),
+                    job_type: Some(job_type),
+                    correlation_id: row.correlation_id.as_deref(),
+                };
+                self.report_error(&context, &error);
+            }
+        }
+        // A parent may have completed while the job was added and would never release it.
+        for added in std::iter::once(&row).chain(&shadow) {
+            self.drop_finished_dependencies(&added.jid, &waiting)
+                .await?;
+        }
+        if self.priority_inheritance && !depends_on.is_empty() {
+            self.inherit_priority(&depends_on, row.priority).await?;
+        }
+        events::scheduled(self, &row);
+        if let Some(shadow) = &shadow {
+            events::scheduled(self, shadow);
+        }
 
         Ok(jid)
     }
 
-    #[instrument(skip_all, err)]
-    async fn poll_next_with_instant(
+    pub(crate) async fn claim_next(
+        &self,
+        job_types: &[&str],
+        now: DateTime,
+        tiers: Option<&[ClaimTier]>,
+    ) -> Result<Option<MongoDbJobHandle>, QueueError> {
+        let started = Instant::now();
+        let result = self.try_claim(job_types, now, tiers).await;
+        if let Ok(job) = &result {
+            self.claim_counters.poll(job.is_some(), started.elapsed());
+        }
+        result
+    }
+
+    async fn try_claim(
         &self,
         job_types: &[&str],
         now: DateTime,
+        tiers: Option<&[ClaimTier]>,
     ) -> Result<Option<MongoDbJobHandle>, QueueError> {
+        if self.paused_until(now).await?.is_some() {
+            return Ok(None);
+        }
+        if let Some(jitter) = &self.claim_jitter {
+            jitter.wait().await;
+        }
+
         let job_types_doc = doc! {
             "$in": job_types
         };
 
-        let filter_doc = doc! {
-            "started_at": None::<bson::DateTime>,
-            "queue": "default",
+        let queue = match tiers {
+            Some(tiers) => {
+                let queues: Vec<&str> = tiers.iter().map(|tier| tier.queue.as_str()).collect();
+                Bson::from(doc! { "$in": queues })
+            }
+            None => Bson::from(&self.queue_name),
+        };
+        let mut filter_doc = self.scoped(status::pending(doc! {
+            "queue": queue,
             "scheduled_at": {
                 "$lte": bson::DateTime::from_millis(now.timestamp_millis())
             },
-            "job_type": job_types_doc
-        };
+            "job_type": job_types_doc,
+            "blocked_by.0": { "$exists": false },
+        }));
+        let mut conditions = Vec::new();
+        if let Some(query) = self.claim_filter.as_ref().and_then(|f| f.query()) {
+            conditions.push(query);
+        }
+        if let Some(region) = &self.region {
+            conditions.push(region.query(now));
+        }
+        if let Some(max) = self.max_claim_payload_size {
+            conditions.push(doc! {
+                "$expr": { "$lte": [{ "$binarySize": "$payload" }, max as i64] }
+            });
+        }
+        if !self.claim_tags.is_empty() {
+            conditions.push(doc! { "tags": { "$all": &self.claim_tags } });
+        }
+        if let Some(query) = self.handler_versions_query() {
+            conditions.push(query);
+        }
+        if let Some(tiers) = tiers {
+            let any_tier: Vec<Document> = tiers.iter().map(ClaimTier::query).collect();
+            conditions.push(doc! { "$or": any_tier });
+        }
+        if !conditions.is_empty() {
+            filter_doc.insert("$and", conditions);
+        }
 
-        let update_doc = doc! {
-            "$set": { "started_at": bson::DateTime::from_millis(Utc::now().timestamp_millis()) },
-            "$inc": { "retries": 1 }
-        };
+        let update = vec![
+            doc! {
+                "$set": {
+                    "status": JobStatus::Running,
+                    "started_at": bson::DateTime::from_millis(now.timestamp_millis()),
+                    // Older documents counted claims in `retries`, carry that over.
+                    "attempts": { "$add": [{ "$ifNull": ["$attempts", "$retries"] }, 1] },
+                },
+            },
+            // A completion that was never confirmed is being retried, stale tokens no longer count
+            doc! { "$unset": "completion_token" },
+        ];
 
-        let sort_doc = doc! {
-            "priority": -1
-        };
+        let mut rejected: Vec<String> = Vec::new();
+        while rejected.len() < MAX_CLAIM_FILTER_REJECTIONS {
+            if !rejected.is_empty() {
+                filter_doc.insert("jid", doc! { "$nin": &rejected });
+            }
+
+            let row = match tiers {
+                Some(tiers) => self.claim_tiered(tiers, &filter_doc, &update, now).await?,
+                None => {
+                    let candidates = match self.claim_strategy.head_candidates() {
+                        0 => Vec::new(),
+                        top_k => self.head_candidates(&filter_doc, top_k).await?,
+                    };
+                    let passes = self.claim_strategy.passes(&candidates);
+                    let passes = if self.lifo_job_types.is_empty() {
+                        passes
+                    } else {
+                        match self.lifo_passes(job_types, &filter_doc, passes).await? {
+                            Some(passes) => passes,
+                            None => return Ok(None),
+                        }
+                    };
+                    self.claim_first(&passes, &filter_doc, &update, now).await?
+                }
+            };
+            let Some(row) = row else {
+                return Ok(None);
+            };
+            let Ok(id) = Xid::from_str(&row.jid) else {
+                self.claim_counters.rejected();
+                self.quarantine_malformed(&row.jid).await?;
+                rejected.push(row.jid);
+                continue;
+            };
+            let decision = match &self.claim_filter {
+                Some(claim_filter) => claim_filter
+                    .decide(&bson::to_document(&row).context("Failed to inspect claimed job")?),
+                None => ClaimDecision::Accept,
+            };
+            match decision {
+                ClaimDecision::Accept => {
+                    self.mark_claimed(&row.job_type, now);
+                    self.claim_strategy.claimed(&row.job_type);
+                    return Ok(Some(MongoDbJobHandle::new(id, row, self.clone())));
+                }
+                ClaimDecision::Skip => {
+                    self.claim_counters.rejected();
+                    self.release_claim(&row, None).await?
+                }
+                ClaimDecision::Defer(delay) => {
+                    self.claim_counters.rejected();
+                    self.release_claim(&row, Some(now + delay)).await?
+                }
+            }
+            rejected.push(row.jid);
+        }
 
-        let options = FindOneAndUpdateOptions::builder()
-            .sort(sort_doc)
-            .return_document(ReturnDocument::After)
+        Ok(None)
+    }
+
+    /// Jids of the first `top_k` jobs matching `filter_doc`, highest priority first.
+    async fn head_candidates(
+        &self,
+        filter_doc: &Document,
+        top_k: usize,
+    ) -> Result<Vec<String>, QueueError> {
+        let options = FindOptions::builder()
+            .sort(doc! { "priority": -1 })
+            .projection(doc! { "jid": 1 })
+            .limit(top_k as i64)
             .build();
+        let collection = self.database.collection::<Document>("adc_queue");
+        let rows: Vec<Document> = traced(
+            &collection,
+            "find",
+            collection.find(filter_doc.clone(), options),
+        )
+        .await
+        .context("Failed to look up claim candidates")?
+        .try_collect()
+        .await
+        .context("Failed to read claim candidates")?;
+        Ok(rows
+            .iter()
+            .filter_map(|row| row.get_str("jid").ok().map(str::to_string))
+            .collect())
+    }
 
-        let row = self
-            .collection()
-            .find_one_and_update(filter_doc, update_doc, options)
-            .await
-            .context("Failed to check out a job from the queue")?;
+    /// Claim the first job found by `passes`. Each pass is a single `find_one_and_update`, so
+    /// two workers sharing the collection can never check out the same job.
+    pub(crate) async fn claim_first(
+        &self,
+        passes: &[ClaimPass],
+        filter_doc: &Document,
+        update: &[Document],
+        now: DateTime,
+    ) -> Result<Option<JobRow>, QueueError> {
+        if let ClaimMode::Lease(duration) = self.claim_mode {
+            return self.lease_first(passes, filter_doc, now, duration).await;
+        }
+        let collection = self.collection();
+        for pass in passes {
+            let filter_doc = pass.apply(filter_doc);
+            let options = FindOneAndUpdateOptions::builder()
+                .sort(pass.sort.clone())
+                .return_document(ReturnDocument::After)
+                .build();
+            // Only the claim itself is retried, it either took the job or left it alone.
+            let row = self
+                .retrying("claim", || async {
+                    let row = traced(
+                        &collection,
+                        "find_one_and_update",
+                        collection.find_one_and_update(
+                            filter_doc.clone(),
+                            update.to_vec(),
+                            options.clone(),
+                        ),
+                    )
+                    .await
+                    .context("Failed to check out a job from the queue")?;
+                    Ok(row)
+                })
+                .await?;
+            if row.is_some() {
+                return Ok(row);
+            }
+            if pass
+                .filter
+                .as_ref()
+                .is_some_and(|filter| filter.contains_key("jid"))
+            {
+                self.claim_counters.lost_race();
+            }
+        }
+        Ok(None)
+    }
 
-        if let Some(row) = row {
-            Ok(Some(MongoDbJobHandle::new(row, self.database.clone())))
-        } else {
-            Ok(None)
+    /// Encode a payload of job type `J`, run it through the registered validator and codec and
+    /// check the result against the size limit.
+    pub(crate) fn encode_payload<J>(&self, payload: &J::Payload) -> Result<Vec<u8>, QueueError>
+    where
+        J: JobProcessor + 'static,
+        J::Payload: Encode,
+    {
+        let payload = bincode::encode_to_vec(payload, self.bincode_config)?;
+        let payload = match self.payload_validators.get(J::name()) {
+            Some(validator) => validator.validate(J::name(), payload).map_err(|reason| {
+                MongoDbQueueError::InvalidPayload {
+                    job_type: J::name().to_string(),
+                    reason,
+                }
+            })?,
+            None => payload,
+        };
+        let payload = match self.payload_codec(J::name()) {
+            Some(codec) => codec.encode(J::name(), &payload).map_err(|reason| {
+                MongoDbQueueError::InvalidPayload {
+                    job_type: J::name().to_string(),
+                    reason,
+                }
+            })?,
+            None => payload,
+        };
+        self.check_payload_size(J::name(), &payload)?;
+        Ok(payload)
+    }
+
+    pub(crate) fn check_payload_size(
+        &self,
+        job_type: &str,
+        payload: &[u8],
+    ) -> Result<(), QueueError> {
+        match self.max_payload_size {
+            Some(limit) if payload.len() > limit => Err(MongoDbQueueError::PayloadTooLarge {
+                job_type: job_type.to_string(),
+                size: payload.len(),
+                limit,
+            }
+            .into()),
+            _ => Ok(()),
         }
     }
 
+    /// Record the outcome of a poll in the current span, which is the poll's own span.
+    pub(crate) fn finish_poll(
+        &self,
+        result: Result<Option<MongoDbJobHandle>, QueueError>,
+    ) -> Result<Option<MongoDbJobHandle>, QueueError> {
+        match &result {
+            Ok(Some(job)) => {
+                let row = job.row();
+                let span = tracing::Span::current();
+                span.record("jid", &row.jid);
+                span.record("job_type", &row.job_type);
+                span.record("attempt", row.attempts);
+                events::claimed(self, row);
+            }
+            Ok(None) => {}
+            Err(error) => tracing::error!(error = %error),
+        }
+        let context = ErrorContext {
+            operation: "poll_next_with_instant",
+            jid: None,
+            job_type: None,
+            correlation_id: None,
+        };
+        self.reported(&context, result)
+    }
+
+    /// Undo a claim without counting it as an attempt, optionally pushing the job back in time.
+    /// A claim that was already lost is left to its new holder.
+    pub(crate) async fn release_claim(
+        &self,
+        row: &JobRow,
+        scheduled_at: Option<DateTime>,
+    ) -> Result<(), QueueError> {
+        let jid = &row.jid;
+        if self.claim_mode != ClaimMode::Inline {
+            if self.hold_claims(&[row]).await?.is_empty() {
+                return Ok(());
+            }
+            if let Some(scheduled_at) = scheduled_at {
+                let collection = self.collection();
+                traced(
+                    &collection,
+                    "update_one",
+                    collection.update_one(
+                        self.scoped(doc! { "jid": jid }),
+                        doc! { "$set": { "scheduled_at": bson::DateTime::from_chrono(scheduled_at) } },
+                        None,
+                    ),
+                )
+                .await
+                .context("Failed to release job")?;
+            }
+            return self.end_leases(&[row]).await;
+        }
+        let mut set_doc =
+            doc! { "status": JobStatus::Pending, "started_at": None::<bson::DateTime> };
+        if let Some(scheduled_at) = scheduled_at {
+            set_doc.insert(
+                "scheduled_at",
+                bson::DateTime::from_millis(scheduled_at.timestamp_millis()),
+            );
+        }
+        let collection = self.collection();
+        traced(
+            &collection,
+            "update_one",
+            collection.update_one(
+                self.claim_fence(row),
+                doc! { "$set": set_doc, "$inc": { "attempts": -1 } },
+                None,
+            ),
+        )
+        .await
+        .context("Failed to release job")?;
+        Ok(())
+    }
+
+    /// Ask the worker running `job_id` to stop. This only sets a flag on the job, processors
+    /// observe it through [`MongoDbJobHandle::is_cancellation_requested`].
     #[instrument(skip_all, err)]
-    async fn cancel_job(&self, job_id: Xid) -> Result<(), QueueError> {
+    pub async fn request_cancellation(&self, job_id: Xid) -> Result<(), QueueError> {
         let jid: String = format!("{}", job_id);
-        let result = self
-            .collection()
-            .delete_one(
-                doc! { "started_at": None::<bson::DateTime>, "jid": jid },
+        let collection = self.collection();
+        let result = traced(
+            &collection,
+            "update_one",
+            collection.update_one(
+                self.scoped(doc! { "jid": jid, "status": JobStatus::Running }),
+                doc! { "$set": { "cancel_requested": true } },
                 None,
-            )
-            .await
-            .context("Failed to remove job from the queue")?;
+            ),
+        )
+        .await
+        .context("Failed to request job cancellation")?;
 
-        if result.deleted_count == 0 {
+        if result.matched_count == 0 {
             Err(QueueError::JobNotFound(job_id))
         } else {
             Ok(())
         }
     }
 
-    #[allow(clippy::or_fun_call)]
+    /// Hand `error` to the [`ErrorReporter`], unless it only says the job doesn't exist.
+    pub(crate) fn report_error(&self, context: &ErrorContext<'_>, error: &QueueError) {
+        if !matches!(error, QueueError::JobNotFound(_)) {
+            self.error_reporter.report_error(context, error);
+        }
+    }
+
+    pub(crate) fn reported<T>(
+        &self,
+        context: &ErrorContext<'_>,
+        result: Result<T, QueueError>,
+    ) -> Result<T, QueueError> {
+        if let Err(error) = &result {
+            self.report_error(context, error);
+        }
+        result
+    }
+
+    /// Confirm the completion of a job finished with
+    /// [`MongoDbJobHandle::complete_pending`], removing it from the queue. Fails with
+    /// `JobNotFound` when the token doesn't match, e.g. because the job is already being retried.
     #[instrument(skip_all, err)]
-    async fn unschedule_job<J>(&self, job_id: Xid) -> Result<J::Payload, QueueError>
-    where
-        J: JobProcessor + 'static,
-        J::Payload: Decode,
-    {
-        let job_type = J::name();
+    pub async fn confirm_complete(&self, job_id: Xid, token: &str) -> Result<(), QueueError> {
         let jid: String = format!("{}", job_id);
-
-        let filter_doc = doc! {
-            "started_at": None::<bson::DateTime>,
-            "jid": jid,
-            "job_type": job_type
+        let result = async {
+            let collection = self.collection();
+            let mut session = collection
+                .client()
+                .start_session(None)
+                .await
+                .context("Failed to start session")?;
+            session
+                .start_transaction(None)
+                .await
+                .context("Failed to start transaction")?;
+            let row = traced(
+                &collection,
+                "find_one_and_delete",
+                collection.find_one_and_delete_with_session(
+                    self.scoped(status::pending(doc! {
+                        "jid": &jid,
+                        "completion_token": token,
+                    })),
+                    None,
+                    &mut session,
+                ),
+            )
+            .await
+            .context("Failed to confirm job completion")?;
+            if row.is_some() {
+                self.release_dependents(&[&jid], &mut session).await?;
+            }
+            session
+                .commit_transaction()
+                .await
+                .context("Failed to commit transaction")?;
+            match row {
+                Some(mut row) => {
+                    row.status = JobStatus::Completed;
+                    events::completed(self, &row);
+                    if let Err(error) = self.archive_completed(&[&row]).await {
+                        let context = ErrorContext {
+                            operation: "archive",
+                            jid: Some(&jid),
+                            job_type: Some(&row.job_type),
+                            correlation_id: row.correlation_id.as_deref(),
+                        };
+                        self.report_error(&context, &error);
+                    }
+                    if let Err(error) = self.record_attempts(&[&row]).await {
+                        let context = ErrorContext {
+                            operation: "audit_trail",
+                            jid: Some(&jid),
+                            job_type: Some(&row.job_type),
+                            correlation_id: row.correlation_id.as_deref(),
+                        };
+                        self.report_error(&context, &error);
+                    }
+                    Ok(())
+                }
+                None => Err(QueueError::JobNotFound(job_id)),
+            }
+        }
+        .await;
+        let context = ErrorContext {
+            operation: "confirm_complete",
+            jid: Some(&jid),
+            job_type: None,
+            correlation_id: None,
         };
+        self.reported(&context, result)
+    }
 
-        let row = self
-            .collection()
-            .find_one_and_delete(filter_doc, None)
-            .await
-            .context("Failed to remove job from the queue")?;
+    /// Ids of the jobs in the queue that were scheduled with `correlation_id`.
+    #[instrument(skip_all, err, fields(correlation_id = correlation_id))]
+    pub async fn jobs_by_correlation_id(
+        &self,
+        correlation_id: &str,
+    ) -> Result<Vec<Xid>, QueueError> {
+        let options = FindOptions::builder()
+            .projection(doc! { "jid": 1 })
+            .sort(doc! { "enqueued_at": 1 })
+            .build();
+        let collection = self.database.collection::<Document>("adc_queue");
+        let rows: Vec<Document> = traced(
+            &collection,
+            "find",
+            collection.find(
+                self.scoped(doc! { "correlation_id": correlation_id }),
+                options,
+            ),
+        )
+        .await
+        .context("Failed to find jobs")?
+        .try_collect()
+        .await
+        .context("Failed to read jobs")?;
+
+        rows.iter()
+            .map(|row| {
+                let jid = row.get_str("jid").context("Job without jid")?;
+                Xid::from_str(jid)
+                    .with_context(|| format!("Malformed jid {jid:?}"))
+                    .map_err(QueueError::from)
+            })
+            .collect()
+    }
 
-        match row {
-            Some(row) => {
-                let payload: Vec<u8> = row.payload.bytes;
-                let (decoded, _) = bincode::decode_from_slice(&payload, self.bincode_config)?;
-                Ok(decoded)
+    /// Find the job with the id given by the [`IdGenerator`] in the queue, cold storage, dead
+    /// queue or retained cancelled jobs.
+    #[instrument(skip_all, err, fields(public_id = public_id))]
+    pub async fn resolve_id(&self, public_id: &str) -> Result<Option<Xid>, QueueError> {
+        let options = FindOneOptions::builder()
+            .projection(doc! { "jid": 1 })
+            .build();
+        for name in [
+            "adc_queue",
+            "adc_cold_queue",
+            "adc_dead_queue",
+            "adc_cancelled",
+        ] {
+            let collection = self.database.collection::<Document>(name);
+            let row = traced(
+                &collection,
+                "find_one",
+                collection.find_one(
+                    self.scoped(doc! { "public_id": public_id }),
+                    options.clone(),
+                ),
+            )
+            .await
+            .context("Failed to find job")?;
+            if let Some(row) = row {
+                let jid = row.get_str("jid").context("Job without jid")?;
+                return Ok(Some(
+                    Xid::from_str(jid).with_context(|| format!("Malformed jid {jid:?}"))?,
+                ));
             }
-            None => Err(QueueError::JobNotFound(job_id)),
         }
+        Ok(None)
     }
-}
 
-impl MongoDbQueue {
-    fn collection(&self) -> Collection<JobRow> {
+    pub(crate) fn public_id(&self, jid: &Xid, job_type: &str) -> Option<String> {
+        self.id_generator
+            .as_ref()
+            .map(|generator| generator.generate(jid, job_type))
+    }
+
+    pub(crate) fn collection(&self) -> Collection<JobRow> {
         self.database.collection("adc_queue")
     }
 }
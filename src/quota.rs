@@ -0,0 +1,205 @@
+use std::fmt;
+
+use aide_de_camp::core::{queue::QueueError, Duration};
+use anyhow::Context;
+use bson::doc;
+use chrono::{DurationRound, Utc};
+use mongodb::{options::UpdateOptions, Collection};
+use tracing::instrument;
+
+use crate::{error::MongoDbQueueError, trace::traced, types::QuotaRow, MongoDbQueue};
+
+/// Enqueue limits for one tenant, enforced by [`MongoDbQueue::schedule_with`] for jobs that
+/// carry a tenant. `None` means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    /// Jobs scheduled per clock hour.
+    pub max_jobs_per_hour: Option<u64>,
+    /// Jobs in the queue at once, running ones included.
+    pub max_pending: Option<u64>,
+}
+
+/// The limit of a [`Quota`] that was exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaKind {
+    JobsPerHour,
+    Pending,
+}
+
+impl fmt::Display for QuotaKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuotaKind::JobsPerHour => f.write_str("jobs per hour"),
+            QuotaKind::Pending => f.write_str("pending jobs"),
+        }
+    }
+}
+
+impl MongoDbQueue {
+    /// Set the enqueue limits for `tenant`, replacing earlier ones. Usage already counted in the
+    /// current hour is kept.
+    #[instrument(skip_all, err, fields(tenant = tenant))]
+    pub async fn set_quota(&self, tenant: &str, quota: Quota) -> Result<(), QueueError> {
+        let collection = self.quota_collection();
+        traced(
+            &collection,
+            "update_one",
+            collection.update_one(
+                doc! { "_id": self.scoped_key(tenant) },
+                doc! { "$set": {
+                    "max_jobs_per_hour": quota.max_jobs_per_hour.map(|max| max as i64),
+                    "max_pending": quota.max_pending.map(|max| max as i64),
+                } },
+                UpdateOptions::builder().upsert(true).build(),
+            ),
+        )
+        .await
+        .context("Failed to set quota")?;
+        Ok(())
+    }
+
+    /// Lift all limits for `tenant`.
+    #[instrument(skip_all, err, fields(tenant = tenant))]
+    pub async fn remove_quota(&self, tenant: &str) -> Result<(), QueueError> {
+        let collection = self.quota_collection();
+        traced(
+            &collection,
+            "delete_one",
+            collection.delete_one(doc! { "_id": self.scoped_key(tenant) }, None),
+        )
+        .await
+        .context("Failed to remove quota")?;
+        Ok(())
+    }
+
+    /// The limits set for `tenant`, if any.
+    #[instrument(skip_all, err, fields(tenant = tenant))]
+    pub async fn quota(&self, tenant: &str) -> Result<Option<Quota>, QueueError> {
+        let collection = self.quota_collection();
+        let row = traced(
+            &collection,
+            "find_one",
+            collection.find_one(doc! { "_id": self.scoped_key(tenant) }, None),
+        )
+        .await
+        .context("Failed to get quota")?;
+        Ok(row.map(|row| Quota {
+            max_jobs_per_hour: row.max_jobs_per_hour.map(|max| max as u64),
+            max_pending: row.max_pending.map(|max| max as u64),
+        }))
+    }
+
+    /// Check `tenant`'s limits before adding one of its jobs, counting the job against the
+    /// hourly limit if it passes. Returns the hour it was counted in, to hand back with
+    /// [`Self::refund_quota`] if the job isn't added after all.
+    pub(crate) async fn enforce_quota(
+        &self,
+        tenant: &str,
+    ) -> Result<Option<bson::DateTime>, QueueError> {
+        let collection = self.quota_collection();
+        let Some(quota) = traced(
+            &collection,
+            "find_one",
+            collection.find_one(doc! { "_id": self.scoped_key(tenant) }, None),
+        )
+        .await
+        .context("Failed to get quota")?
+        else {
+            return Ok(None);
+        };
+
+        if let Some(max_pending) = quota.max_pending {
+            let jobs = self.collection();
+            let pending = traced(
+                &jobs,
+                "count_documents",
+                jobs.count_documents(self.scoped(doc! { "tenant": tenant }), None),
+            )
+            .await
+            .context("Failed to count pending jobs")?;
+            if pending >= max_pending as u64 {
+                return Err(MongoDbQueueError::QuotaExceeded {
+                    tenant: tenant.to_string(),
+                    kind: QuotaKind::Pending,
+                    limit: max_pending as u64,
+                }
+                .into());
+            }
+        }
+
+        if let Some(max_jobs_per_hour) = quota.max_jobs_per_hour {
+            let hour = Utc::now()
+                .duration_trunc(Duration::hours(1))
+                .context("Failed to compute quota window")?;
+            let hour = bson::DateTime::from_millis(hour.timestamp_millis());
+            // Start a new count when the hour rolled over, otherwise add to it, but only while
+            // the count is below the limit, so concurrent producers can't overshoot it.
+            let update = vec![doc! { "$set": {
+                "hour_count": { "$cond": [
+                    { "$eq": ["$hour_window", hour] },
+                    { "$add": [{ "$ifNull": ["$hour_count", 0_i64] }, 1_i64] },
+                    1_i64,
+                ] },
+                "hour_window": hour,
+            } }];
+            let reserved = traced(
+                &collection,
+                "update_one",
+                collection.update_one(
+                    doc! {
+                        "_id": self.scoped_key(tenant),
+                        "$or": [
+                            { "hour_window": { "$ne": hour } },
+                            { "hour_count": { "$lt": max_jobs_per_hour } },
+                        ],
+                    },
+                    update,
+                    None,
+                ),
+            )
+            .await
+            .context("Failed to count job against quota")?;
+            if reserved.matched_count == 0 {
+                return Err(MongoDbQueueError::QuotaExceeded {
+                    tenant: tenant.to_string(),
+                    kind: QuotaKind::JobsPerHour,
+                    limit: max_jobs_per_hour as u64,
+                }
+                .into());
+            }
+            return Ok(Some(hour));
+        }
+
+        Ok(None)
+    }
+
+    /// Hand back a job counted against `tenant`'s hourly limit in `hour` by
+    /// [`Self::enforce_quota`], because it wasn't added.
+    pub(crate) async fn refund_quota(
+        &self,
+        tenant: &str,
+        hour: bson::DateTime,
+    ) -> Result<(), QueueError> {
+        let collection = self.quota_collection();
+        traced(
+            &collection,
+            "update_one",
+            collection.update_one(
+                doc! {
+                    "_id": self.scoped_key(tenant),
+                    "hour_window": hour,
+                    "hour_count": { "$gt": 0_i64 },
+                },
+                doc! { "$inc": { "hour_count": -1_i64 } },
+                None,
+            ),
+        )
+        .await
+        .context("Failed to refund quota")?;
+        Ok(())
+    }
+
+    fn quota_collection(&self) -> Collection<QuotaRow> {
+        self.database.collection("adc_quotas")
+    }
+}
@@ -0,0 +1,196 @@
+use std::time::Duration;
+
+use aide_de_camp::core::{
+    bincode::Encode,
+    job_processor::JobProcessor,
+    queue::{Queue, QueueError},
+    Xid,
+};
+use anyhow::Context;
+use bson::doc;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::time::Instant;
+use tracing::instrument;
+
+use crate::{
+    archive::ArchivedJob, error::MongoDbQueueError, events::JOB_COMPLETED, status::JobStatus,
+    trace::traced, types::JobRow, MongoDbQueue,
+};
+
+/// How often [`MongoDbQueue::await_completion`] looks the job up.
+pub const COMPLETION_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Annotation [`MongoDbQueue::set_result`] keeps the result of a job in, as JSON.
+pub const RESULT_ANNOTATION: &str = "result";
+
+/// How a job ended, see [`MongoDbQueue::await_completion`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobOutcome {
+    /// The job completed. The archived copy, with the annotations the job left, is there with
+    /// [`MongoDbQueue::with_archive`].
+    Completed { archived: Option<Box<ArchivedJob>> },
+    /// The job was moved to the dead queue.
+    Dead { reason: Option<String> },
+    /// The job was cancelled and retained, see [`CancelMode::Retain`](crate::CancelMode::Retain).
+    Cancelled,
+    /// The job was taken out of the queue by [`MongoDbQueue::drain_to_export`].
+    Exported,
+    /// The job is gone without a record of how it ended: it completed without an archive or
+    /// event log, or was cancelled and deleted.
+    Removed,
+}
+
+impl MongoDbQueue {
+    /// Wait until job `job_id` finishes and return how it ended, or `None` if it is still
+    /// pending or running after `timeout`, so callers can enqueue work and wait for it like a
+    /// request. Looks the job up every [`COMPLETION_POLL_INTERVAL`], which works without a
+    /// replica set. Completions are only told apart from deletions with
+    /// [`Self::with_archive`] or [`Self::with_event_log`].
+    #[instrument(skip_all, err, fields(jid = %job_id))]
+    pub async fn await_completion(
+        &self,
+        job_id: Xid,
+        timeout: Duration,
+    ) -> Result<Option<JobOutcome>, QueueError> {
+        let deadline = Instant::now() + timeout;
+        let mut missing = false;
+        loop {
+            match self.find_job(job_id).await? {
+                Some(row) => {
+                    missing = false;
+                    match row.status {
+                        JobStatus::Dead => {
+                            return Ok(Some(JobOutcome::Dead {
+                                reason: row.dead_reason,
+                            }))
+                        }
+                        JobStatus::Cancelled => return Ok(Some(JobOutcome::Cancelled)),
+                        JobStatus::Exported => return Ok(Some(JobOutcome::Exported)),
+                        _ => {}
+                    }
+                }
+                None => {
+                    if let Some(outcome) = self.completion_record(job_id).await? {
+                        return Ok(Some(outcome));
+                    }
+                    // The archive and the event log are written right after the job is
+                    // removed, give them another poll before giving up on them.
+                    if missing || !(self.archive || self.event_log.is_some()) {
+                        return Ok(Some(JobOutcome::Removed));
+                    }
+                    missing = true;
+                }
+            }
+            if Instant::now() + COMPLETION_POLL_INTERVAL > deadline {
+                return Ok(None);
+            }
+            tokio::time::sleep(COMPLETION_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Schedule a job of type `J` now and wait up to `timeout` for its result, for work that
+    /// is offloaded to the workers but needed right away. The job has to complete with a
+    /// result recorded by [`Self::set_result`], and the queue has to keep completed jobs
+    /// with [`Self::with_archive`] for the result to be read back.
+    ///
+    /// Fails with [`MongoDbQueueError::JobTimedOut`] if the job hasn't finished in time,
+    /// [`MongoDbQueueError::JobNotCompleted`] if it ended some other way and
+    /// [`MongoDbQueueError::MissingResult`] if it completed without a result. The job is
+    /// left alone when waiting for it times out. Without the archive, fails with
+    /// [`MongoDbQueueError::ArchiveRequired`] before scheduling anything.
+    #[instrument(skip_all, err, fields(job_type = J::name()))]
+    pub async fn run_job<J, R>(
+        &self,
+        payload: J::Payload,
+        timeout: Duration,
+    ) -> Result<R, QueueError>
+    where
+        J: JobProcessor + 'static,
+        J::Payload: Encode,
+        R: DeserializeOwned,
+    {
+        if !self.archive {
+            return Err(MongoDbQueueError::ArchiveRequired.into());
+        }
+        let job_id = self.schedule::<J>(payload, 0).await?;
+        let jid = job_id.to_string();
+        let archived = match self.await_completion(job_id, timeout).await? {
+            None => return Err(MongoDbQueueError::JobTimedOut { jid }.into()),
+            Some(JobOutcome::Completed { archived }) => archived,
+            Some(outcome) => return Err(MongoDbQueueError::JobNotCompleted { jid, outcome }.into()),
+        };
+        let Some(result) = archived.and_then(|job| job.annotations.get(RESULT_ANNOTATION).cloned())
+        else {
+            return Err(MongoDbQueueError::MissingResult { jid }.into());
+        };
+        Ok(serde_json::from_str(&result).context("Failed to decode job result")?)
+    }
+
+    /// Record `result` as the result of job `job_id`, for a caller waiting in
+    /// [`Self::run_job`]. Call it from the job processor before it returns.
+    #[instrument(skip_all, err, fields(jid = %job_id))]
+    pub async fn set_result<R: Serialize>(
+        &self,
+        job_id: Xid,
+        result: &R,
+    ) -> Result<(), QueueError> {
+        let result = serde_json::to_string(result).context("Failed to encode job result")?;
+        self.annotate(job_id, RESULT_ANNOTATION, &result).await
+    }
+
+    /// The job wherever it is still stored, see [`Self::status`]. The collections are read one
+    /// after another, so a job moving between them, e.g. requeued from the dead queue, can be
+    /// missed; a job that isn't found is looked for once more before it counts as gone.
+    async fn find_job(&self, job_id: Xid) -> Result<Option<JobRow>, QueueError> {
+        let jid = format!("{}", job_id);
+        for _ in 0..2 {
+            if let Some(row) = self.find_job_once(&jid).await? {
+                return Ok(Some(row));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn find_job_once(&self, jid: &str) -> Result<Option<JobRow>, QueueError> {
+        for name in [
+            "adc_queue",
+            "adc_cold_queue",
+            "adc_dead_queue",
+            "adc_cancelled",
+        ] {
+            let collection = self.database.collection::<JobRow>(name);
+            let row = traced(
+                &collection,
+                "find_one",
+                collection.find_one(self.scoped(doc! { "jid": jid }), None),
+            )
+            .await
+            .context("Failed to find job")?;
+            if row.is_some() {
+                return Ok(row);
+            }
+        }
+        Ok(None)
+    }
+
+    /// The outcome of a job that is no longer stored, from the archive or the event log.
+    pub(crate) async fn completion_record(
+        &self,
+        job_id: Xid,
+    ) -> Result<Option<JobOutcome>, QueueError> {
+        if self.archive {
+            if let Some(archived) = self.archived_job(job_id).await? {
+                return Ok(Some(JobOutcome::Completed {
+                    archived: Some(Box::new(archived)),
+                }));
+            }
+        }
+        if self.event_log.is_some() {
+            let events = self.job_events(job_id).await?;
+            if events.iter().any(|event| event.name == JOB_COMPLETED) {
+                return Ok(Some(JobOutcome::Completed { archived: None }));
+            }
+        }
+        Ok(None)
+    }
+}
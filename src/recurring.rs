@@ -0,0 +1,576 @@
+use std::{collections::BTreeMap, str::FromStr};
+
+use aide_de_camp::core::{
+    bincode::Encode, job_processor::JobProcessor, new_xid, queue::QueueError, DateTime, Duration,
+};
+use anyhow::Context;
+use bson::{doc, Document};
+use chrono::{LocalResult, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use cron::Schedule;
+use futures::TryStreamExt;
+use mongodb::{error::ErrorKind, options::ReplaceOptions, Collection};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::{
+    error::MongoDbQueueError,
+    events,
+    status::JobStatus,
+    trace::traced,
+    types::{JobRow, RecurringJobRow},
+    MongoDbQueue,
+};
+
+/// Upper bound on the number of occurrences materialized in one go by
+/// [`MisfirePolicy::RunAllMissed`], so a fast schedule that was down for days doesn't flood the
+/// queue.
+pub const MAX_CATCH_UP_RUNS: usize = 1000;
+
+/// What to do with fire times that passed while no scheduler was materializing recurring jobs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MisfirePolicy {
+    /// Drop missed fire times and wait for the next one.
+    #[default]
+    Skip,
+    /// Run once right away for all missed fire times, then continue with the schedule.
+    RunOnceNow,
+    /// Run once for every missed fire time (up to [`MAX_CATCH_UP_RUNS`]).
+    RunAllMissed,
+}
+
+/// Options for registering a recurring job.
+#[derive(Debug, Clone)]
+pub struct RecurringOptions {
+    pub priority: i8,
+    pub misfire_policy: MisfirePolicy,
+    /// A fire time materialized later than this is considered missed.
+    pub misfire_grace: Duration,
+    /// Timezone the cron expression is evaluated in. Defaults to UTC.
+    pub timezone: Option<Tz>,
+    /// Owner of the occurrences, see [`ScheduleOptions::owner`](crate::ScheduleOptions::owner).
+    pub owner: Option<String>,
+}
+
+impl Default for RecurringOptions {
+    fn default() -> Self {
+        Self {
+            priority: 0,
+            misfire_policy: MisfirePolicy::default(),
+            misfire_grace: Duration::minutes(1),
+            timezone: None,
+            owner: None,
+        }
+    }
+}
+
+impl MongoDbQueue {
+    /// Register a job that is scheduled according to a cron expression (with seconds, e.g.
+    /// `"0 0 9 * * *"`). Occurrences are added to the queue by [`Self::materialize_recurring`].
+    ///
+    /// With a timezone set, the expression follows the local wall clock: a time skipped by a DST
+    /// transition fires once the clocks have moved forward, and a time repeated by one fires
+    /// only the first time around.
+    #[instrument(skip_all, err, fields(key = key, job_type = J::name()))]
+    pub async fn register_recurring<J>(
+        &self,
+        key: &str,
+        schedule: &str,
+        payload: J::Payload,
+        options: RecurringOptions,
+    ) -> Result<(), QueueError>
+    where
+        J: JobProcessor + 'static,
+        J::Payload: Encode,
+    {
+        let parsed = parse_schedule(schedule)?;
+        let timezone = options.timezone.unwrap_or(Tz::UTC);
+        let payload = self.encode_payload::<J>(&payload)?;
+        let owner = self.owner_of(J::name(), options.owner.as_deref())?;
+        self.check_job_type(J::name()).await?;
+
+        let row = RecurringJobRow {
+            key: self.scoped_key(key),
+            queue: self.queue_name.clone(),
+            job_type: J::name().to_string(),
+            schedule: schedule.to_string(),
+            timezone: options.timezone.map(|tz| tz.name().to_string()),
+            payload: self.payload_binary(payload),
+            content_type: self.content_type_of(J::name(), None),
+            priority: options.priority as i64,
+            misfire_policy: options.misfire_policy,
+            misfire_grace_ms: options.misfire_grace.num_milliseconds(),
+            next_fire_at: fire_times_after(&parsed, timezone, Utc::now())
+                .next()
+                .map(to_bson),
+            last_fired_at: None,
+            namespace: self.namespace.clone(),
+            owner,
+        };
+
+        let recurring = self.recurring_collection();
+        match traced(&recurring, "insert_one", recurring.insert_one(row, None)).await {
+            Ok(_) => Ok(()),
+            Err(e) if is_duplicate_key(&e) => {
+                Err(MongoDbQueueError::RecurringJobExists(key.to_string()).into())
+            }
+            Err(e) => Err(anyhow::Error::new(e)
+                .context("Failed to register recurring job")
+                .into()),
+        }
+    }
+
+    /// Create or replace the recurring job `key` with default options.
+    /// See [`Self::upsert_recurring_with`].
+    pub async fn upsert_recurring<J>(
+        &self,
+        key: &str,
+        schedule: &str,
+        payload: J::Payload,
+    ) -> Result<u64, QueueError>
+    where
+        J: JobProcessor + 'static,
+        J::Payload: Encode,
+    {
+        self.upsert_recurring_with::<J>(key, schedule, payload, RecurringOptions::default())
+            .await
+    }
+
+    /// Create or replace the recurring job `key`, e.g. when deploying schedules from
+    /// configuration.
+    ///
+    /// Runs in a transaction: if the schedule or timezone changed, pending occurrences that are
+    /// not a fire time of the new schedule are removed from the queue. The remaining ones get
+    /// the new payload and priority. Returns the number of occurrences removed.
+    #[instrument(skip_all, err, ret, fields(key = key, job_type = J::name()))]
+    pub async fn upsert_recurring_with<J>(
+        &self,
+        key: &str,
+        schedule: &str,
+        payload: J::Payload,
+        options: RecurringOptions,
+    ) -> Result<u64, QueueError>
+    where
+        J: JobProcessor + 'static,
+        J::Payload: Encode,
+    {
+        let parsed = parse_schedule(schedule)?;
+        let timezone = options.timezone.unwrap_or(Tz::UTC);
+        let timezone_name = options.timezone.map(|tz| tz.name().to_string());
+        let owner = self.owner_of(J::name(), options.owner.as_deref())?;
+        self.check_job_type(J::name()).await?;
+        let payload = self.payload_binary(self.encode_payload::<J>(&payload)?);
+        let id = self.scoped_key(key);
+
+        let collection = self.collection();
+        let recurring = self.recurring_collection();
+        let mut session = collection
+            .client()
+            .start_session(None)
+            .await
+            .context("Failed to start session")?;
+        session
+            .start_transaction(None)
+            .await
+            .context("Failed to start transaction")?;
+
+        let existing = traced(
+            &recurring,
+            "find_one",
+            recurring.find_one_with_session(doc! { "_id": &id }, None, &mut session),
+        )
+        .await
+        .context("Failed to fetch recurring job")?;
+        let rescheduled = existing.as_ref().is_some_and(|existing| {
+            existing.schedule != schedule || existing.timezone != timezone_name
+        });
+        // Keep the position in the schedule unless the schedule itself changed.
+        let (next_fire_at, last_fired_at) = match existing {
+            Some(existing) if !rescheduled => (existing.next_fire_at, existing.last_fired_at),
+            existing => (
+                fire_times_after(&parsed, timezone, Utc::now())
+                    .next()
+                    .map(to_bson),
+                existing.and_then(|existing| existing.last_fired_at),
+            ),
+        };
+
+        traced(
+            &recurring,
+            "replace_one",
+            recurring.replace_one_with_session(
+                doc! { "_id": &id },
+                RecurringJobRow {
+                    key: id.clone(),
+                    queue: self.queue_name.clone(),
+                    job_type: J::name().to_string(),
+                    schedule: schedule.to_string(),
+                    timezone: timezone_name,
+                    payload: payload.clone(),
+                    content_type: self.content_type_of(J::name(), None),
+                    priority: options.priority as i64,
+                    misfire_policy: options.misfire_policy,
+                    misfire_grace_ms: options.misfire_grace.num_milliseconds(),
+                    next_fire_at,
+                    last_fired_at,
+                    namespace: self.namespace.clone(),
+                    owner,
+                },
+                ReplaceOptions::builder().upsert(true).build(),
+                &mut session,
+            ),
+        )
+        .await
+        .context("Failed to save recurring job")?;
+
+        let pending_filter =
+            self.scoped(doc! { "recurring_key": &id, "status": JobStatus::Pending });
+        let pending: Vec<JobRow> = traced(
+            &collection,
+            "find",
+            collection.find_with_session(pending_filter.clone(), None, &mut session),
+        )
+        .await
+        .context("Failed to fetch pending occurrences")?
+        .stream(&mut session)
+        .try_collect()
+        .await
+        .context("Failed to fetch pending occurrences")?;
+        // Occurrences off the schedule, e.g. a misfire fired late, are only stale once the
+        // schedule they came from is replaced.
+        let stale: Vec<&str> = pending
+            .iter()
+            .filter(|job| {
+                rescheduled && !is_fire_time(&parsed, timezone, job.scheduled_at.to_chrono())
+            })
+            .map(|job| job.jid.as_str())
+            .collect();
+
+        let mut removed = 0;
+        if !stale.is_empty() {
+            removed = traced(
+                &collection,
+                "delete_many",
+                collection.delete_many_with_session(
+                    doc! { "jid": { "$in": stale } },
+                    None,
+                    &mut session,
+                ),
+            )
+            .await
+            .context("Failed to remove stale occurrences")?
+            .deleted_count;
+        }
+        traced(
+            &collection,
+            "update_many",
+            collection.update_many_with_session(
+                pending_filter,
+                doc! { "$set": {
+                    "job_type": J::name(),
+                    "payload": payload,
+                    "content_type": self.content_type_of(J::name(), None),
+                    "priority": options.priority as i64,
+                } },
+                None,
+                &mut session,
+            ),
+        )
+        .await
+        .context("Failed to update pending occurrences")?;
+
+        session
+            .commit_transaction()
+            .await
+            .context("Failed to commit transaction")?;
+
+        Ok(removed)
+    }
+
+    /// Remove a recurring job definition. Occurrences already in the queue are left alone.
+    #[instrument(skip_all, err, fields(key = key))]
+    pub async fn unregister_recurring(&self, key: &str) -> Result<(), QueueError> {
+        let recurring = self.recurring_collection();
+        let result = traced(
+            &recurring,
+            "delete_one",
+            recurring.delete_one(doc! { "_id": self.scoped_key(key) }, None),
+        )
+        .await
+        .context("Failed to remove recurring job")?;
+
+        if result.deleted_count == 0 {
+            Err(MongoDbQueueError::RecurringJobNotFound(key.to_string()).into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The next `count` fire times of a recurring job, starting from now.
+    #[instrument(skip_all, err, fields(key = key))]
+    pub async fn next_fire_times(
+        &self,
+        key: &str,
+        count: usize,
+    ) -> Result<Vec<DateTime>, QueueError> {
+        let recurring = self.recurring_collection();
+        let row = traced(
+            &recurring,
+            "find_one",
+            recurring.find_one(doc! { "_id": self.scoped_key(key) }, None),
+        )
+        .await
+        .context("Failed to fetch recurring job")?
+        .ok_or_else(|| MongoDbQueueError::RecurringJobNotFound(key.to_string()))?;
+        let schedule = parse_schedule(&row.schedule)?;
+        let timezone = parse_timezone(row.timezone.as_deref())?;
+
+        Ok(fire_times_after(&schedule, timezone, Utc::now())
+            .take(count)
+            .collect())
+    }
+
+    /// Add due occurrences of this queue's recurring jobs to the queue, applying each job's
+    /// [`MisfirePolicy`] to fire times that were missed. Safe to call from several processes at
+    /// once: every fire time is materialized at most once. Returns the number of jobs added.
+    #[instrument(skip_all, err, ret)]
+    pub async fn materialize_recurring(&self, now: DateTime) -> Result<usize, QueueError> {
+        let recurring = self.recurring_collection();
+        let due: Vec<RecurringJobRow> = traced(
+            &recurring,
+            "find",
+            recurring.find(
+                self.scoped(doc! {
+                    "queue": &self.queue_name,
+                    "next_fire_at": { "$lte": to_bson(now) },
+                }),
+                None,
+            ),
+        )
+        .await
+        .context("Failed to fetch due recurring jobs")?
+        .try_collect()
+        .await
+        .context("Failed to fetch due recurring jobs")?;
+
+        let mut added = 0;
+        for row in due {
+            added += self.materialize_one(row, now).await?;
+        }
+        Ok(added)
+    }
+
+    async fn materialize_one(
+        &self,
+        row: RecurringJobRow,
+        now: DateTime,
+    ) -> Result<usize, QueueError> {
+        let Some(next_fire_at) = row.next_fire_at else {
+            return Ok(0);
+        };
+        let schedule = parse_schedule(&row.schedule)?;
+        let timezone = parse_timezone(row.timezone.as_deref())?;
+        let first = next_fire_at.to_chrono();
+
+        let mut due = vec![first];
+        due.extend(
+            fire_times_after(&schedule, timezone, first)
+                .take_while(|fire_at| *fire_at <= now)
+                .take(MAX_CATCH_UP_RUNS - 1),
+        );
+        let grace = Duration::milliseconds(row.misfire_grace_ms);
+        let runs = misfire_runs(row.misfire_policy, &due, now, grace);
+        let following = fire_times_after(&schedule, timezone, now)
+            .next()
+            .map(to_bson);
+
+        let jobs: Vec<JobRow> = runs
+            .iter()
+            .map(|scheduled_at| {
+                let jid = new_xid();
+                JobRow {
+                    jid: format!("{}", jid),
+                    public_id: self.public_id(&jid, &row.job_type),
+                    namespace: self.namespace.clone(),
+                    queue: row.queue.clone(),
+                    job_type: row.job_type.clone(),
+                    payload: row.payload.clone(),
+                    retries: 0,
+                    priority: row.priority,
+                    scheduled_at: to_bson(*scheduled_at),
+                    enqueued_at: to_bson(Utc::now()),
+                    started_at: None,
+                    recurring_key: Some(row.key.clone()),
+                    cancel_requested: false,
+                    tenant: None,
+                    correlation_id: None,
+                    cancelled_at: None,
+                    cancelled_by: None,
+                    completion_token: None,
+                    shadow_of: None,
+                    region: None,
+                    deadline: None,
+                    dedup_key: None,
+                    blocked_by: Vec::new(),
+                    annotations: BTreeMap::new(),
+                    headers: BTreeMap::new(),
+                    content_type: row.content_type.clone(),
+                    handler_version: None,
+                    parked: None,
+                    status: JobStatus::Pending,
+                    dead_reason: None,
+                    tags: Vec::new(),
+                    attempts: 0,
+                    owner: row.owner.clone(),
+                    extra: Document::new(),
+                }
+            })
+            .collect();
+        // Advancing next_fire_at is what claims these fire times; whoever loses the race skips.
+        // It commits together with the jobs, so a failed insert doesn't skip them.
+        let collection = self.collection();
+        let recurring = self.recurring_collection();
+        let mut session = collection
+            .client()
+            .start_session(None)
+            .await
+            .context("Failed to start session")?;
+        session
+            .start_transaction(None)
+            .await
+            .context("Failed to start transaction")?;
+        let claimed = traced(
+            &recurring,
+            "update_one",
+            recurring.update_one_with_session(
+                doc! { "_id": &row.key, "next_fire_at": next_fire_at },
+                doc! { "$set": { "next_fire_at": following, "last_fired_at": to_bson(now) } },
+                None,
+                &mut session,
+            ),
+        )
+        .await;
+        let claimed = match claimed {
+            Ok(claimed) => claimed.modified_count > 0,
+            // Another process is advancing it right now.
+            Err(error) if error.contains_label("TransientTransactionError") => false,
+            Err(error) => {
+                return Err(anyhow::Error::new(error)
+                    .context("Failed to advance recurring job")
+                    .into())
+            }
+        };
+        if !claimed {
+            return Ok(0);
+        }
+        if !jobs.is_empty() {
+            traced(
+                &collection,
+                "insert_many",
+                collection.insert_many_with_session(&jobs, None, &mut session),
+            )
+            .await
+            .context("Failed to add recurring job to the queue")?;
+        }
+        session
+            .commit_transaction()
+            .await
+            .context("Failed to commit transaction")?;
+        jobs.iter().for_each(|job| events::scheduled(self, job));
+
+        Ok(runs.len())
+    }
+
+    fn recurring_collection(&self) -> Collection<RecurringJobRow> {
+        self.database.collection("adc_recurring")
+    }
+}
+
+/// Decide which of the `due` fire times (oldest first) to run.
+fn misfire_runs(
+    policy: MisfirePolicy,
+    due: &[DateTime],
+    now: DateTime,
+    grace: Duration,
+) -> Vec<DateTime> {
+    let (missed, on_time): (Vec<DateTime>, Vec<DateTime>) = due
+        .iter()
+        .copied()
+        .partition(|fire_at| now - *fire_at > grace);
+
+    if missed.is_empty() {
+        return on_time;
+    }
+    match policy {
+        MisfirePolicy::Skip => on_time,
+        MisfirePolicy::RunOnceNow => vec![now],
+        MisfirePolicy::RunAllMissed => due.to_vec(),
+    }
+}
+
+/// Fire times strictly after `after`, in order.
+///
+/// The expression is evaluated on the wall clock of `timezone` and every wall-clock time is
+/// resolved here, because the cron crate silently drops times that fall into a DST gap or
+/// overlap.
+pub(crate) fn fire_times_after(
+    schedule: &Schedule,
+    timezone: Tz,
+    after: DateTime,
+) -> impl Iterator<Item = DateTime> + '_ {
+    let wall_clock = Utc.from_utc_datetime(&after.with_timezone(&timezone).naive_local());
+    let mut last = after;
+
+    schedule.after(&wall_clock).filter_map(move |local| {
+        let fire_at = resolve_local(timezone, local.naive_utc())?;
+        // Times in a gap resolve past the gap and may collide with the ones that follow it.
+        (fire_at > last).then(|| {
+            last = fire_at;
+            fire_at
+        })
+    })
+}
+
+fn is_fire_time(schedule: &Schedule, timezone: Tz, datetime: DateTime) -> bool {
+    fire_times_after(schedule, timezone, datetime - Duration::milliseconds(1)).next()
+        == Some(datetime)
+}
+
+fn resolve_local(timezone: Tz, local: NaiveDateTime) -> Option<DateTime> {
+    match timezone.from_local_datetime(&local) {
+        LocalResult::Single(fire_at) => Some(fire_at.with_timezone(&Utc)),
+        // Clocks were set back and this time happens twice, only the first one counts.
+        LocalResult::Ambiguous(earliest, _) => Some(earliest.with_timezone(&Utc)),
+        // Clocks jumped over this time, shift it by the length of the (one hour) gap.
+        LocalResult::None => timezone
+            .from_local_datetime(&(local + Duration::hours(1)))
+            .earliest()
+            .map(|fire_at| fire_at.with_timezone(&Utc)),
+    }
+}
+
+pub(crate) fn parse_timezone(name: Option<&str>) -> Result<Tz, MongoDbQueueError> {
+    match name {
+        Some(name) => Tz::from_str(name).map_err(MongoDbQueueError::InvalidTimezone),
+        None => Ok(Tz::UTC),
+    }
+}
+
+pub(crate) fn parse_schedule(expression: &str) -> Result<Schedule, MongoDbQueueError> {
+    Schedule::from_str(expression).map_err(|e| MongoDbQueueError::InvalidSchedule {
+        expression: expression.to_string(),
+        reason: e.to_string(),
+    })
+}
+
+pub(crate) fn is_duplicate_key(error: &mongodb::error::Error) -> bool {
+    matches!(
+        error.kind.as_ref(),
+        ErrorKind::Write(mongodb::error::WriteFailure::WriteError(e)) if e.code == 11000
+    )
+}
+
+fn to_bson(datetime: DateTime) -> bson::DateTime {
+    bson::DateTime::from_millis(datetime.timestamp_millis())
+}
@@ -0,0 +1,37 @@
+use aide_de_camp::core::{DateTime, Duration};
+use bson::{doc, Document};
+
+/// Restricts the jobs a worker claims to those of its own region, see
+/// [`MongoDbQueue::with_region`](crate::MongoDbQueue::with_region). Jobs scheduled without a
+/// region can run anywhere, and workers without a region claim jobs of every region.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegionAffinity {
+    pub region: String,
+    /// Also claim jobs of other regions once they have been due for this long, so a region
+    /// without workers doesn't stall. `None` keeps jobs in their region no matter what.
+    pub overflow_after: Option<Duration>,
+}
+
+impl RegionAffinity {
+    pub fn new(region: impl Into<String>) -> Self {
+        Self {
+            region: region.into(),
+            overflow_after: None,
+        }
+    }
+
+    pub fn with_overflow_after(mut self, overflow_after: Duration) -> Self {
+        self.overflow_after = Some(overflow_after);
+        self
+    }
+
+    /// Claim query condition for jobs this worker may take at `now`.
+    pub(crate) fn query(&self, now: DateTime) -> Document {
+        let mut allowed = vec![doc! { "region": { "$in": [&self.region, bson::Bson::Null] } }];
+        if let Some(overflow_after) = self.overflow_after {
+            let due_before = bson::DateTime::from_chrono(now - overflow_after);
+            allowed.push(doc! { "scheduled_at": { "$lte": due_before } });
+        }
+        doc! { "$or": allowed }
+    }
+}
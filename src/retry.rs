@@ -0,0 +1,200 @@
+//! Retrying queue operations, with a span per attempt.
+
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use aide_de_camp::core::queue::QueueError;
+use mongodb::error::{Error, ErrorKind, WriteFailure};
+use tracing::{field::Empty, Instrument};
+
+use crate::MongoDbQueue;
+
+/// Retries after transient failures. Rejected credentials are retried once on top of these.
+const MAX_RETRIES: u32 = 2;
+
+/// Wait before the first retry of a transient failure, doubled for every retry after it.
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// What kind of failure an error is, recorded as `error_class` on attempt spans.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ErrorClass {
+    /// The server rejected the credentials.
+    Auth,
+    /// The connection failed or was reset.
+    Network,
+    /// No server was available in time.
+    ServerSelection,
+    /// The operation hit a server time limit.
+    Timeout,
+    /// Lost a race with a concurrent write.
+    WriteConflict,
+    DuplicateKey,
+    /// Any other error reported by the server.
+    Command,
+    JobNotFound,
+    /// The payload could not be encoded or decoded.
+    Payload,
+    Other,
+}
+
+impl ErrorClass {
+    pub(crate) fn of(error: &QueueError) -> Self {
+        match error {
+            QueueError::JobNotFound(_) => Self::JobNotFound,
+            QueueError::Other(error) => error
+                .chain()
+                .find_map(|cause| cause.downcast_ref::<Error>())
+                .map_or(Self::Other, Self::of_mongodb),
+            _ => Self::Payload,
+        }
+    }
+
+    fn of_mongodb(error: &Error) -> Self {
+        if error.contains_label("TransientTransactionError") {
+            return Self::WriteConflict;
+        }
+        match error.kind.as_ref() {
+            ErrorKind::Authentication { .. } => Self::Auth,
+            ErrorKind::Io(_) | ErrorKind::ConnectionPoolCleared { .. } => Self::Network,
+            ErrorKind::ServerSelection { .. } => Self::ServerSelection,
+            ErrorKind::Command(command) => match command.code {
+                // AuthenticationFailed
+                18 => Self::Auth,
+                // MaxTimeMSExpired, ExceededTimeLimit
+                50 | 262 => Self::Timeout,
+                // WriteConflict
+                112 => Self::WriteConflict,
+                11000 => Self::DuplicateKey,
+                _ => Self::Command,
+            },
+            ErrorKind::Write(WriteFailure::WriteError(error)) if error.code == 11000 => {
+                Self::DuplicateKey
+            }
+            ErrorKind::Write(_) | ErrorKind::BulkWrite(_) => Self::Command,
+            _ => Self::Other,
+        }
+    }
+
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Self::Auth => "auth",
+            Self::Network => "network",
+            Self::ServerSelection => "server_selection",
+            Self::Timeout => "timeout",
+            Self::WriteConflict => "write_conflict",
+            Self::DuplicateKey => "duplicate_key",
+            Self::Command => "command",
+            Self::JobNotFound => "job_not_found",
+            Self::Payload => "payload",
+            Self::Other => "other",
+        }
+    }
+
+    /// Whether the same operation may well succeed if tried again later.
+    pub(crate) fn is_transient(self) -> bool {
+        matches!(
+            self,
+            Self::Network | Self::ServerSelection | Self::Timeout | Self::WriteConflict
+        )
+    }
+
+    /// Whether a failure of this class means the operation was not applied, so running it
+    /// again can't apply it twice. A network error can come after the server applied a write,
+    /// those are left to the driver's retryable writes.
+    fn is_unapplied(self) -> bool {
+        matches!(
+            self,
+            Self::ServerSelection | Self::Timeout | Self::WriteConflict
+        )
+    }
+}
+
+impl MongoDbQueue {
+    /// Run `operation`, and run it again after failures that mean it wasn't applied: once
+    /// after reconnecting if the server rejected the credentials of a queue created with
+    /// [`Self::connect`], and up to [`MAX_RETRIES`] times after a backoff for transient
+    /// failures like a lost election or a write conflict. That makes it safe for a single write that
+    /// must not be applied twice, e.g. the `findOneAndUpdate` of a claim, but not for
+    /// operations of several steps: retrying those after a later step failed would repeat the
+    /// steps that already succeeded.
+    ///
+    /// Every attempt runs in an `attempt` span with the attempt number, the time waited
+    /// before it (`backoff_ms`, spent reconnecting or backing off) and the `error_class` of its
+    /// failure. A failure after the last attempt emits a `queue.operation_failed` event with
+    /// the number of attempts and whether the error class is transient, a WARN unless the job
+    /// was simply not found.
+    pub(crate) async fn retrying<T, F, Fut>(
+        &self,
+        name: &'static str,
+        operation: F,
+    ) -> Result<T, QueueError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, QueueError>>,
+    {
+        let mut attempt = 1;
+        let mut retries = 0;
+        let mut reconnected = false;
+        let mut backoff_ms = 0;
+        loop {
+            let span = tracing::debug_span!(
+                "attempt",
+                operation = name,
+                attempt,
+                backoff_ms,
+                error_class = Empty,
+            );
+            let error = match operation().instrument(span.clone()).await {
+                Ok(output) => return Ok(output),
+                Err(error) => error,
+            };
+            let class = ErrorClass::of(&error);
+            span.record("error_class", class.as_str());
+
+            if class == ErrorClass::Auth && !reconnected && self.connection_source.is_some() {
+                tracing::warn!(?error, "Credentials rejected, reconnecting");
+                let started = Instant::now();
+                self.reconnect().await?;
+                reconnected = true;
+                backoff_ms = started.elapsed().as_millis() as u64;
+                attempt += 1;
+                continue;
+            }
+            if retries < MAX_RETRIES && class.is_unapplied() {
+                let backoff = RETRY_BACKOFF * 2_u32.pow(retries);
+                tokio::time::sleep(backoff).await;
+                backoff_ms = backoff.as_millis() as u64;
+                retries += 1;
+                attempt += 1;
+                continue;
+            }
+
+            if class == ErrorClass::JobNotFound {
+                tracing::event!(
+                    name: "queue.operation_failed",
+                    tracing::Level::DEBUG,
+                    operation = name,
+                    attempts = attempt,
+                    error_class = class.as_str(),
+                    transient = false,
+                    ?error,
+                    "Queue operation found no job"
+                );
+            } else {
+                tracing::event!(
+                    name: "queue.operation_failed",
+                    tracing::Level::WARN,
+                    operation = name,
+                    attempts = attempt,
+                    error_class = class.as_str(),
+                    transient = class.is_transient(),
+                    ?error,
+                    "Queue operation failed"
+                );
+            }
+            return Err(error);
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use aide_de_camp::core::{queue::QueueError, Duration};
+use anyhow::Context;
+use bson::{doc, Document};
+use chrono::{DurationRound, Utc};
+use mongodb::{
+    options::{FindOneAndUpdateOptions, ReturnDocument, UpdateOptions},
+    Collection,
+};
+use tracing::instrument;
+
+use crate::{status::JobStatus, trace::traced, types::RetryBudgetRow, MongoDbQueue};
+
+/// Why jobs parked by an exhausted [`RetryBudget`] are parked.
+pub const BUDGET_EXHAUSTED: &str = "budget_exhausted";
+
+/// Cluster-wide limit on retries of one job type, see [`MongoDbQueue::set_retry_budget`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryBudget {
+    /// Failed attempts that may be retried per window.
+    pub max_retries: u64,
+    /// Length of the windows, which start at multiples of it since the Unix epoch.
+    pub window: Duration,
+}
+
+impl MongoDbQueue {
+    /// Limit how often jobs of `job_type` are retried across all workers, replacing an earlier
+    /// budget. Jobs failing once the budget of the current window is spent are parked with
+    /// reason [`BUDGET_EXHAUSTED`] instead of retried, so a downstream outage doesn't turn into
+    /// a retry storm. Bring them back with [`Self::requeue_budget_exhausted`].
+    #[instrument(skip_all, err, fields(job_type = job_type))]
+    pub async fn set_retry_budget(
+        &self,
+        job_type: &str,
+        budget: RetryBudget,
+    ) -> Result<(), QueueError> {
+        let collection = self.retry_budget_collection();
+        traced(
+            &collection,
+            "update_one",
+            collection.update_one(
+                doc! { "_id": self.scoped_key(job_type) },
+                doc! { "$set": {
+                    "max_retries": budget.max_retries as i64,
+                    "window_ms": budget.window.num_milliseconds(),
+                } },
+                UpdateOptions::builder().upsert(true).build(),
+            ),
+        )
+        .await
+        .context("Failed to set retry budget")?;
+        Ok(())
+    }
+
+    /// Retry failing jobs of `job_type` without limit again.
+    #[instrument(skip_all, err, fields(job_type = job_type))]
+    pub async fn remove_retry_budget(&self, job_type: &str) -> Result<(), QueueError> {
+        let collection = self.retry_budget_collection();
+        traced(
+            &collection,
+            "delete_one",
+            collection.delete_one(doc! { "_id": self.scoped_key(job_type) }, None),
+        )
+        .await
+        .context("Failed to remove retry budget")?;
+        Ok(())
+    }
+
+    /// Make jobs of `job_type` parked by an exhausted retry budget claimable again. Returns the
+    /// number of jobs requeued.
+    #[instrument(skip_all, err, ret, fields(job_type = job_type))]
+    pub async fn requeue_budget_exhausted(&self, job_type: &str) -> Result<u64, QueueError> {
+        let collection = self.database.collection::<Document>("adc_queue");
+        let result = traced(
+            &collection,
+            "update_many",
+            collection.update_many(
+                self.scoped(doc! {
+                    "job_type": job_type,
+                    "status": JobStatus::Parked,
+                    "parked": BUDGET_EXHAUSTED,
+                }),
+                doc! { "$set": { "status": JobStatus::Pending }, "$unset": { "parked": "" } },
+                None,
+            ),
+        )
+        .await
+        .context("Failed to requeue jobs")?;
+        Ok(result.modified_count)
+    }
+
+    /// Count a retry of `job_type` against its budget. Returns `false` if the budget of the
+    /// current window is spent and the job should not be retried.
+    pub(crate) async fn spend_retry(&self, job_type: &str) -> Result<bool, QueueError> {
+        let collection = self.retry_budget_collection();
+        let Some(budget) = traced(
+            &collection,
+            "find_one",
+            collection.find_one(doc! { "_id": self.scoped_key(job_type) }, None),
+        )
+        .await
+        .context("Failed to get retry budget")?
+        else {
+            return Ok(true);
+        };
+
+        let window = Utc::now()
+            .duration_trunc(Duration::milliseconds(budget.window_ms.max(1)))
+            .context("Failed to compute retry budget window")?;
+        let window = bson::DateTime::from_chrono(window);
+        // Start a new count when the window rolled over, otherwise add to it.
+        let update = vec![doc! { "$set": {
+            "window_count": { "$cond": [
+                { "$eq": ["$window_start", window] },
+                { "$add": [{ "$ifNull": ["$window_count", 0_i64] }, 1_i64] },
+                1_i64,
+            ] },
+            "window_start": window,
+        } }];
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+        let row = traced(
+            &collection,
+            "find_one_and_update",
+            collection.find_one_and_update(
+                doc! { "_id": self.scoped_key(job_type) },
+                update,
+                options,
+            ),
+        )
+        .await
+        .context("Failed to count retry against budget")?;
+        match row {
+            Some(row) => Ok(row.window_count <= row.max_retries),
+            None => Ok(true),
+        }
+    }
+
+    fn retry_budget_collection(&self) -> Collection<RetryBudgetRow> {
+        self.database.collection("adc_retry_budgets")
+    }
+}
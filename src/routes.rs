@@ -0,0 +1,146 @@
+use aide_de_camp::core::queue::QueueError;
+use anyhow::Context;
+use bson::doc;
+use futures::TryStreamExt;
+use mongodb::{options::ReplaceOptions, Collection};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::{trace::traced, types::RouteRow, MongoDbQueue};
+
+/// Where jobs of one type go, stored in the `adc_routes` collection and applied when jobs are
+/// scheduled, so routing can be changed without redeploying producers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    /// Queue the jobs are added to. Only workers bound to this queue with
+    /// [`MongoDbQueue::with_queue_name`] claim them.
+    pub queue: String,
+    /// Replaces the priority given by the producer.
+    pub priority: Option<i8>,
+    /// Send a share of the jobs to a canary queue.
+    pub canary: Option<Canary>,
+}
+
+/// Sends a share of the jobs of one type to another queue, so a new processor version running
+/// on canary workers can be validated on real traffic before it is rolled out everywhere.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Canary {
+    pub queue: String,
+    /// Share of the jobs sampled, from 0 to 100.
+    pub percent: u8,
+    pub mode: CanaryMode,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CanaryMode {
+    /// Add a copy of sampled jobs to the canary queue; the original runs as usual. Copies are
+    /// marked with `shadow_of`, so their processor should avoid external side effects.
+    #[default]
+    Duplicate,
+    /// Move sampled jobs to the canary queue instead.
+    Divert,
+}
+
+impl Canary {
+    pub(crate) fn sample(&self) -> bool {
+        rand::thread_rng().gen_range(0..100) < self.percent
+    }
+}
+
+impl MongoDbQueue {
+    /// Route jobs of `job_type` scheduled from now on, replacing an earlier route. Jobs already
+    /// in the queue stay where they are.
+    #[instrument(skip_all, err, fields(job_type = job_type, queue = %route.queue))]
+    pub async fn set_route(&self, job_type: &str, route: Route) -> Result<(), QueueError> {
+        let collection = self.routes_collection();
+        traced(
+            &collection,
+            "replace_one",
+            collection.replace_one(
+                doc! { "_id": self.scoped_key(job_type) },
+                RouteRow {
+                    job_type: self.scoped_key(job_type),
+                    queue: route.queue,
+                    priority: route.priority.map(i64::from),
+                    canary: route.canary,
+                    namespace: self.namespace.clone(),
+                },
+                ReplaceOptions::builder().upsert(true).build(),
+            ),
+        )
+        .await
+        .context("Failed to set route")?;
+        Ok(())
+    }
+
+    /// Send jobs of `job_type` back to the queue the producer picked.
+    #[instrument(skip_all, err, fields(job_type = job_type))]
+    pub async fn remove_route(&self, job_type: &str) -> Result<(), QueueError> {
+        let collection = self.routes_collection();
+        traced(
+            &collection,
+            "delete_one",
+            collection.delete_one(doc! { "_id": self.scoped_key(job_type) }, None),
+        )
+        .await
+        .context("Failed to remove route")?;
+        Ok(())
+    }
+
+    /// All routes, by job type.
+    #[instrument(skip_all, err)]
+    pub async fn routes(&self) -> Result<Vec<(String, Route)>, QueueError> {
+        let collection = self.routes_collection();
+        let rows: Vec<RouteRow> = traced(
+            &collection,
+            "find",
+            collection.find(self.scoped(doc! {}), None),
+        )
+        .await
+        .context("Failed to list routes")?
+        .try_collect()
+        .await
+        .context("Failed to read routes")?;
+        Ok(rows.into_iter().map(RouteRow::into_route).collect())
+    }
+
+    /// The route for `job_type`, if there is one.
+    pub(crate) async fn route(&self, job_type: &str) -> Result<Option<Route>, QueueError> {
+        let collection = self.routes_collection();
+        let row = traced(
+            &collection,
+            "find_one",
+            collection.find_one(doc! { "_id": self.scoped_key(job_type) }, None),
+        )
+        .await
+        .context("Failed to look up route")?;
+        Ok(row.map(|row| row.into_route().1))
+    }
+
+    fn routes_collection(&self) -> Collection<RouteRow> {
+        self.database.collection("adc_routes")
+    }
+}
+
+impl RouteRow {
+    fn into_route(self) -> (String, Route) {
+        let route = Route {
+            queue: self.queue,
+            priority: self.priority.map(|priority| priority as i8),
+            canary: self.canary,
+        };
+        // Routes of a namespace are stored under `<namespace>:<job type>`.
+        let job_type = match &self.namespace {
+            Some(namespace) => self
+                .job_type
+                .strip_prefix(namespace.as_str())
+                .and_then(|job_type| job_type.strip_prefix(':'))
+                .map(str::to_string)
+                .unwrap_or(self.job_type),
+            None => self.job_type,
+        };
+        (job_type, route)
+    }
+}
@@ -0,0 +1,85 @@
+use std::{collections::BTreeMap, io::Write};
+
+use aide_de_camp::core::{new_xid, queue::QueueError};
+use anyhow::Context;
+use async_trait::async_trait;
+use aws_sdk_s3::{primitives::ByteStream, Client};
+use chrono::NaiveDate;
+use flate2::{write::GzEncoder, Compression};
+use tracing::instrument;
+
+use crate::archive::{ArchiveSink, ArchivedJob};
+
+/// Writes archived jobs to S3 or S3-compatible storage as gzip-compressed NDJSON, one object
+/// per completion date and batch under `{prefix}dt={YYYY-MM-DD}/`, so query engines like
+/// Athena can prune by date. Each line is an [`ArchivedJob`] as JSON.
+///
+/// The client is configured by the caller, e.g. from `aws-config` or with the endpoint of
+/// another S3-compatible store.
+#[derive(Debug, Clone)]
+pub struct S3ArchiveSink {
+    client: Client,
+    bucket: String,
+    prefix: String,
+}
+
+impl S3ArchiveSink {
+    pub fn new(client: Client, bucket: impl Into<String>) -> Self {
+        Self {
+            client,
+            bucket: bucket.into(),
+            prefix: String::new(),
+        }
+    }
+
+    /// Put the objects under `prefix`, e.g. `"adc/archive/"`.
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+}
+
+#[async_trait]
+impl ArchiveSink for S3ArchiveSink {
+    #[instrument(skip_all, err, fields(bucket = %self.bucket, jobs = jobs.len()))]
+    async fn write(&self, jobs: &[ArchivedJob]) -> Result<(), QueueError> {
+        let mut by_date: BTreeMap<NaiveDate, Vec<&ArchivedJob>> = BTreeMap::new();
+        for job in jobs {
+            by_date
+                .entry(job.completed_at.date_naive())
+                .or_default()
+                .push(job);
+        }
+        for (date, jobs) in by_date {
+            let body = encode_ndjson_gz(&jobs)?;
+            self.client
+                .put_object()
+                .bucket(&self.bucket)
+                .key(object_key(&self.prefix, date, &new_xid().to_string()))
+                .content_type("application/x-ndjson")
+                .content_encoding("gzip")
+                .body(ByteStream::from(body))
+                .send()
+                .await
+                .context("Failed to write archive object")?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn object_key(prefix: &str, date: NaiveDate, id: &str) -> String {
+    format!("{prefix}dt={}/{id}.ndjson.gz", date.format("%Y-%m-%d"))
+}
+
+pub(crate) fn encode_ndjson_gz(jobs: &[&ArchivedJob]) -> Result<Vec<u8>, QueueError> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    for job in jobs {
+        serde_json::to_writer(&mut encoder, job).context("Failed to encode archived job")?;
+        encoder
+            .write_all(b"\n")
+            .context("Failed to encode archived job")?;
+    }
+    Ok(encoder
+        .finish()
+        .context("Failed to compress archived jobs")?)
+}
@@ -0,0 +1,92 @@
+use rand::Rng;
+use tracing::{
+    level_filters::LevelFilter,
+    span::{Attributes, Id, Record},
+    subscriber::Interest,
+    Dispatch, Event, Level, Metadata, Subscriber,
+};
+
+/// How much of polling is traced, see [`MongoDbQueue::with_poll_tracing`].
+///
+/// Polls that claim a job or fail always get their `poll_next_with_instant` span and events.
+/// [`PollTracing::SampleEmpty`] only thins out the polls that found nothing, which on an idle
+/// fleet are most of them. Warnings and errors of unsampled polls still get through.
+///
+/// [`MongoDbQueue::with_poll_tracing`]: crate::MongoDbQueue::with_poll_tracing
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum PollTracing {
+    /// Trace every poll with the spans of its database round trips.
+    #[default]
+    Full,
+    /// Fully trace this share of polls, from 0.0 to 1.0. The others run without spans; if one
+    /// of them claims a job or fails, its poll span is recorded afterwards, without the spans
+    /// of the round trips. 0.0 silences empty polls entirely.
+    SampleEmpty { rate: f64 },
+}
+
+impl PollTracing {
+    /// Whether the sampling rate, if any, is a share from 0.0 to 1.0. NaN is not.
+    pub(crate) fn is_valid(&self) -> bool {
+        match self {
+            PollTracing::Full => true,
+            PollTracing::SampleEmpty { rate } => (0.0..=1.0).contains(rate),
+        }
+    }
+
+    /// Whether the next poll is traced in full.
+    pub(crate) fn sample(&self) -> bool {
+        match self {
+            PollTracing::Full => true,
+            PollTracing::SampleEmpty { rate } => rand::thread_rng().gen_bool(*rate),
+        }
+    }
+}
+
+/// Runs unsampled polls: passes their WARN and ERROR events on to the current subscriber and
+/// drops their spans and everything more verbose.
+pub(crate) fn quiet() -> Dispatch {
+    Dispatch::new(Quiet(tracing::dispatcher::get_default(Dispatch::clone)))
+}
+
+struct Quiet(Dispatch);
+
+impl Quiet {
+    fn passes(metadata: &Metadata<'_>) -> bool {
+        metadata.is_event() && *metadata.level() <= Level::WARN
+    }
+}
+
+impl Subscriber for Quiet {
+    // The same interest as the current subscriber, so callsites keep caching theirs. Callsites
+    // it always wants skip `enabled`, so spans and events are also checked when they arrive.
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        self.0.register_callsite(metadata)
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        Self::passes(metadata) && self.0.enabled(metadata)
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(LevelFilter::WARN)
+    }
+
+    // Spans are dropped.
+    fn new_span(&self, _span: &Attributes<'_>) -> Id {
+        Id::from_u64(1)
+    }
+
+    fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        if Self::passes(event.metadata()) {
+            self.0.event(event);
+        }
+    }
+
+    fn enter(&self, _span: &Id) {}
+
+    fn exit(&self, _span: &Id) {}
+}
@@ -0,0 +1,64 @@
+use aide_de_camp::core::queue::QueueError;
+use anyhow::Context;
+use bson::doc;
+use futures::TryStreamExt;
+use mongodb::{options::FindOptions, IndexModel};
+use tracing::instrument;
+
+use crate::{
+    trace::traced,
+    types::{DeadJobInfo, JobRow},
+    MongoDbQueue,
+};
+
+impl MongoDbQueue {
+    /// Create the text index [`Self::search_jobs`] needs on the dead queue, over job types, tags,
+    /// dead-letter reasons and park reasons. Kept out of [`Self::create_indexes`] because text
+    /// indexes are costly to maintain on busy collections.
+    #[instrument(skip_all, err)]
+    pub async fn create_search_index(&self) -> Result<(), QueueError> {
+        let index = IndexModel::builder()
+            .keys(doc! {
+                "job_type": "text",
+                "tags": "text",
+                "dead_reason": "text",
+                "parked": "text",
+            })
+            .build();
+        let collection = self.database.collection::<JobRow>("adc_dead_queue");
+        traced(
+            &collection,
+            "create_indexes",
+            collection.create_indexes([index], None),
+        )
+        .await
+        .context("Failed to create search index")?;
+        Ok(())
+    }
+
+    /// Up to `limit` dead jobs matching the free text `query`, e.g. `"timeout payment"`, best
+    /// matches first. Needs the index from [`Self::create_search_index`].
+    #[instrument(skip_all, err, fields(query = query))]
+    pub async fn search_jobs(
+        &self,
+        query: &str,
+        limit: i64,
+    ) -> Result<Vec<DeadJobInfo>, QueueError> {
+        let options = FindOptions::builder()
+            .sort(doc! { "score": { "$meta": "textScore" } })
+            .limit(limit)
+            .build();
+        let collection = self.database.collection::<JobRow>("adc_dead_queue");
+        let rows: Vec<JobRow> = traced(
+            &collection,
+            "find",
+            collection.find(self.scoped(doc! { "$text": { "$search": query } }), options),
+        )
+        .await
+        .context("Failed to search jobs")?
+        .try_collect()
+        .await
+        .context("Failed to read jobs")?;
+        Ok(rows.into_iter().map(JobRow::into_dead_info).collect())
+    }
+}
@@ -0,0 +1,455 @@
+use std::{collections::VecDeque, convert::Infallible};
+
+use aide_de_camp::core::{
+    job_handle::JobHandle,
+    job_processor::JobProcessor,
+    queue::{Queue, QueueError},
+    CancellationToken, Xid,
+};
+use anyhow::Context;
+use async_trait::async_trait;
+use bson::{doc, Document};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tracing::instrument;
+
+use crate::{
+    error::MongoDbQueueError,
+    invariants::{InvariantChecker, Violation},
+    job_handle::MongoDbJobHandle,
+    lease::{ClaimMode, CLAIMS_COLLECTION},
+    status::JobStatus,
+    trace::traced,
+    MongoDbQueue,
+};
+
+/// Job type of the jobs scheduled by a [`Simulation`].
+pub const SIMULATION_JOB_TYPE: &str = "adc_simulation";
+
+/// One operation of a simulation script, done by a virtual worker where it names one.
+///
+/// [`Step::Reap`] takes away the claim on the oldest job the worker holds, as if the worker
+/// had stalled until its claim was reaped or its lease expired. The worker keeps the stale
+/// claim and tries to finish the job with a later step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    Schedule { priority: i8 },
+    Claim { worker: usize },
+    Complete { worker: usize },
+    Fail { worker: usize },
+    DeadQueue { worker: usize },
+    Reap { worker: usize },
+}
+
+/// What a [`Step`] did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    Scheduled(Xid),
+    /// The claimed job, or `None` if nothing was due.
+    Claimed(Option<Xid>),
+    /// The job the worker completed, failed or dead-lettered.
+    Finished(Xid),
+    /// The worker tried to finish the job after losing its claim and was turned away.
+    ClaimLost(Xid),
+    /// The job whose claim was taken away.
+    Reaped(Xid),
+    /// The worker held no job.
+    Idle,
+}
+
+/// How a worker finishes a job, see [`SimulationBackend::finish`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Finish {
+    Complete,
+    Fail,
+    DeadQueue,
+}
+
+/// Outcome of [`Simulation::run`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SimulationReport {
+    /// One per step.
+    pub outcomes: Vec<StepOutcome>,
+    /// Invariant violations, with the index of the step after which they were found.
+    pub violations: Vec<(usize, Violation)>,
+}
+
+/// The storage a [`Simulation`] runs against: [`MongoBackend`], the queue on a real database,
+/// or [`MemoryBackend`], an in-memory stand-in for it.
+#[async_trait]
+pub trait SimulationBackend: Send {
+    /// A claimed job as a worker holds it.
+    type Claim: Send;
+
+    async fn schedule(&mut self, priority: i8) -> Result<Xid, QueueError>;
+
+    /// Claim the next job that is due, if any.
+    async fn claim(&mut self) -> Result<Option<Self::Claim>, QueueError>;
+
+    fn jid(claim: &Self::Claim) -> Xid;
+
+    /// Take the job away from `claim` so it can be claimed again.
+    async fn reap(&mut self, claim: &Self::Claim) -> Result<(), QueueError>;
+
+    /// Finish the job of `claim`. Returns `false` if the claim was lost and the job was left
+    /// alone.
+    async fn finish(&mut self, claim: Self::Claim, finish: Finish) -> Result<bool, QueueError>;
+
+    /// The queue invariants broken right now.
+    async fn check(&mut self) -> Result<Vec<Violation>, QueueError>;
+}
+
+/// Runs scripted interleavings of schedule, claim, complete, fail, dead-letter and reap
+/// operations by virtual workers one step at a time, checking the queue invariants after every
+/// step, so races found in the field can be replayed as deterministic regression tests.
+///
+/// Steps run against a [`SimulationBackend`]. With [`Self::new`] that is the queue on a real
+/// database, since the queue's logic lives in its queries; give every simulation a database of
+/// its own. Jobs of equal priority are then claimed in whatever order the server returns them,
+/// give them distinct priorities where order matters. [`MemoryBackend`] runs scripts without a
+/// server.
+pub struct Simulation<B: SimulationBackend = MongoBackend> {
+    backend: B,
+    workers: Vec<VecDeque<B::Claim>>,
+}
+
+struct SimulationJob;
+
+#[async_trait]
+impl JobProcessor for SimulationJob {
+    type Payload = u32;
+    type Error = Infallible;
+
+    async fn handle(
+        &self,
+        _jid: Xid,
+        _payload: Self::Payload,
+        _cancellation_token: CancellationToken,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn name() -> &'static str
+    where
+        Self: Sized,
+    {
+        SIMULATION_JOB_TYPE
+    }
+}
+
+impl Simulation {
+    pub fn new(queue: MongoDbQueue, workers: usize) -> Self {
+        Self::with_backend(MongoBackend::new(queue), workers)
+    }
+
+    /// A script of `len` steps for `workers` workers, the same for the same `seed`.
+    pub fn random_script(seed: u64, workers: usize, len: usize) -> Vec<Step> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let workers = workers.max(1);
+        (0..len)
+            .map(|_| {
+                let worker = rng.gen_range(0..workers);
+                match rng.gen_range(0..11) {
+                    0..=2 => Step::Schedule {
+                        priority: rng.gen_range(0..10),
+                    },
+                    3..=5 => Step::Claim { worker },
+                    6..=7 => Step::Complete { worker },
+                    8 => Step::Fail { worker },
+                    9 => Step::DeadQueue { worker },
+                    _ => Step::Reap { worker },
+                }
+            })
+            .collect()
+    }
+}
+
+impl<B: SimulationBackend> Simulation<B> {
+    pub fn with_backend(backend: B, workers: usize) -> Self {
+        Self {
+            backend,
+            workers: (0..workers.max(1)).map(|_| VecDeque::new()).collect(),
+        }
+    }
+
+    /// Run `script` in order. Workers finish and lose the jobs they hold oldest first.
+    #[instrument(skip_all, err, fields(steps = script.len()))]
+    pub async fn run(&mut self, script: &[Step]) -> Result<SimulationReport, QueueError> {
+        let mut report = SimulationReport::default();
+        for (index, step) in script.iter().enumerate() {
+            let outcome = self.step(*step).await?;
+            report.outcomes.push(outcome);
+            for violation in self.backend.check().await? {
+                report.violations.push((index, violation));
+            }
+        }
+        Ok(report)
+    }
+
+    async fn step(&mut self, step: Step) -> Result<StepOutcome, QueueError> {
+        let (worker, finish) = match step {
+            Step::Schedule { priority } => {
+                let jid = self.backend.schedule(priority).await?;
+                return Ok(StepOutcome::Scheduled(jid));
+            }
+            Step::Claim { worker } => {
+                let claim = self.backend.claim().await?;
+                let jid = claim.as_ref().map(B::jid);
+                if let Some(claim) = claim {
+                    self.worker(worker).push_back(claim);
+                }
+                return Ok(StepOutcome::Claimed(jid));
+            }
+            Step::Reap { worker } => {
+                let count = self.workers.len();
+                let Some(claim) = self.workers[worker % count].front() else {
+                    return Ok(StepOutcome::Idle);
+                };
+                self.backend.reap(claim).await?;
+                return Ok(StepOutcome::Reaped(B::jid(claim)));
+            }
+            Step::Complete { worker } => (worker, Finish::Complete),
+            Step::Fail { worker } => (worker, Finish::Fail),
+            Step::DeadQueue { worker } => (worker, Finish::DeadQueue),
+        };
+        let Some(claim) = self.worker(worker).pop_front() else {
+            return Ok(StepOutcome::Idle);
+        };
+        let jid = B::jid(&claim);
+        if self.backend.finish(claim, finish).await? {
+            Ok(StepOutcome::Finished(jid))
+        } else {
+            Ok(StepOutcome::ClaimLost(jid))
+        }
+    }
+
+    fn worker(&mut self, worker: usize) -> &mut VecDeque<B::Claim> {
+        let count = self.workers.len();
+        &mut self.workers[worker % count]
+    }
+}
+
+/// Runs simulations against a [`MongoDbQueue`], checked with an [`InvariantChecker`].
+pub struct MongoBackend {
+    queue: MongoDbQueue,
+    checker: InvariantChecker,
+}
+
+impl MongoBackend {
+    pub fn new(queue: MongoDbQueue) -> Self {
+        Self {
+            checker: InvariantChecker::new(queue.clone()),
+            queue,
+        }
+    }
+}
+
+#[async_trait]
+impl SimulationBackend for MongoBackend {
+    type Claim = MongoDbJobHandle;
+
+    async fn schedule(&mut self, priority: i8) -> Result<Xid, QueueError> {
+        self.queue.schedule::<SimulationJob>(0, priority).await
+    }
+
+    async fn claim(&mut self) -> Result<Option<Self::Claim>, QueueError> {
+        self.queue.poll_next(&[SIMULATION_JOB_TYPE]).await
+    }
+
+    fn jid(claim: &Self::Claim) -> Xid {
+        claim.id()
+    }
+
+    /// Put the job back as a reaper would, keeping its attempt count, or drop its lease.
+    async fn reap(&mut self, claim: &Self::Claim) -> Result<(), QueueError> {
+        let row = claim.row();
+        match self.queue.claim_mode {
+            ClaimMode::Inline => {
+                let collection = self.queue.collection();
+                traced(
+                    &collection,
+                    "update_one",
+                    collection.update_one(
+                        self.queue.claim_fence(row),
+                        doc! { "$set": {
+                            "status": JobStatus::Pending,
+                            "started_at": None::<bson::DateTime>,
+                        } },
+                        None,
+                    ),
+                )
+                .await
+                .context("Failed to reap job")?;
+            }
+            ClaimMode::Lease(_) => {
+                let collection = self
+                    .queue
+                    .database
+                    .collection::<Document>(CLAIMS_COLLECTION);
+                traced(
+                    &collection,
+                    "delete_one",
+                    collection.delete_one(doc! { "_id": &row.jid }, None),
+                )
+                .await
+                .context("Failed to reap lease")?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn finish(&mut self, claim: Self::Claim, finish: Finish) -> Result<bool, QueueError> {
+        let result = match finish {
+            Finish::Complete => claim.complete().await,
+            Finish::Fail => claim.fail().await,
+            Finish::DeadQueue => claim.dead_queue().await,
+        };
+        match result {
+            Ok(()) => Ok(true),
+            Err(error)
+                if matches!(
+                    MongoDbQueueError::from_queue_error(&error),
+                    Some(MongoDbQueueError::ClaimLost { .. })
+                ) =>
+            {
+                Ok(false)
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    async fn check(&mut self) -> Result<Vec<Violation>, QueueError> {
+        self.checker.check().await
+    }
+}
+
+/// An in-memory stand-in for the queue, so scripts run deterministically without a server.
+///
+/// It follows the rules the queries enforce for [`ClaimMode::Inline`]: the highest priority
+/// due job is claimed, the oldest first among equals, and a claim only finishes its job while
+/// the job is still running under the same attempt number. Jobs are always due. It models
+/// those rules rather than running the queries, so a fix to a query needs a script run
+/// against [`MongoBackend`] as well. Jids are numbered from 1, so reports are the same on
+/// every run.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    /// In the order they were scheduled.
+    jobs: Vec<MemoryJob>,
+    dead: Vec<Xid>,
+    scheduled: u64,
+}
+
+#[derive(Debug)]
+struct MemoryJob {
+    jid: Xid,
+    priority: i8,
+    running: bool,
+    attempts: u32,
+}
+
+/// A job claimed from a [`MemoryBackend`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryClaim {
+    jid: Xid,
+    attempts: u32,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The running job `claim` still holds.
+    fn held(&mut self, claim: &MemoryClaim) -> Option<usize> {
+        self.jobs
+            .iter()
+            .position(|job| job.jid == claim.jid && job.running && job.attempts == claim.attempts)
+    }
+}
+
+#[async_trait]
+impl SimulationBackend for MemoryBackend {
+    type Claim = MemoryClaim;
+
+    async fn schedule(&mut self, priority: i8) -> Result<Xid, QueueError> {
+        self.scheduled += 1;
+        let mut bytes = [0; 12];
+        bytes[4..].copy_from_slice(&self.scheduled.to_be_bytes());
+        let jid = Xid(bytes);
+        self.jobs.push(MemoryJob {
+            jid,
+            priority,
+            running: false,
+            attempts: 0,
+        });
+        Ok(jid)
+    }
+
+    async fn claim(&mut self) -> Result<Option<Self::Claim>, QueueError> {
+        let next = self
+            .jobs
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, job)| !job.running)
+            .min_by_key(|(index, job)| (std::cmp::Reverse(job.priority), *index))
+            .map(|(_, job)| job);
+        Ok(next.map(|job| {
+            job.running = true;
+            job.attempts += 1;
+            MemoryClaim {
+                jid: job.jid,
+                attempts: job.attempts,
+            }
+        }))
+    }
+
+    fn jid(claim: &Self::Claim) -> Xid {
+        claim.jid
+    }
+
+    async fn reap(&mut self, claim: &Self::Claim) -> Result<(), QueueError> {
+        if let Some(index) = self.held(claim) {
+            self.jobs[index].running = false;
+        }
+        Ok(())
+    }
+
+    async fn finish(&mut self, claim: Self::Claim, finish: Finish) -> Result<bool, QueueError> {
+        let Some(index) = self.held(&claim) else {
+            return Ok(false);
+        };
+        match finish {
+            Finish::Complete => {
+                self.jobs.remove(index);
+            }
+            Finish::Fail => self.jobs[index].running = false,
+            Finish::DeadQueue => {
+                self.jobs.remove(index);
+                self.dead.push(claim.jid);
+            }
+        }
+        Ok(true)
+    }
+
+    async fn check(&mut self) -> Result<Vec<Violation>, QueueError> {
+        let mut violations = Vec::new();
+        for (index, job) in self.jobs.iter().enumerate() {
+            let jid = job.jid.to_string();
+            if self.dead.contains(&job.jid) {
+                violations.push(Violation::InQueueAndDeadQueue { jid: jid.clone() });
+            }
+            let count = self
+                .jobs
+                .iter()
+                .filter(|other| other.jid == job.jid)
+                .count();
+            let first = self.jobs.iter().position(|other| other.jid == job.jid);
+            if count > 1 && first == Some(index) {
+                violations.push(Violation::DuplicateJid {
+                    jid,
+                    count: count as u64,
+                });
+            }
+        }
+        Ok(violations)
+    }
+}
@@ -0,0 +1,150 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use bson::doc;
+
+use crate::ordering::{ClaimPass, ClaimStrategy};
+
+/// How urgently a job type's jobs must be picked up, see [`SlaClasses`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum SlaClass {
+    Realtime,
+    #[default]
+    Standard,
+    Batch,
+}
+
+impl SlaClass {
+    const ALL: [SlaClass; 3] = [SlaClass::Realtime, SlaClass::Standard, SlaClass::Batch];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// A [`ClaimStrategy`] that maps job types to [`SlaClass`]es and guarantees each class a
+/// share of the claims, so a backfill of batch jobs can't hold up realtime jobs of the same
+/// priority.
+///
+/// Each poll first tries the class furthest behind its share, then the others from realtime
+/// to batch, so claims go to other classes when one has nothing ready. Claims are charged to
+/// the class that got them, so a class that had nothing ready keeps its turn, catching up by
+/// at most one round of shares once it has jobs again. Within a class, jobs are claimed
+/// highest priority first. Job types without a class are [`SlaClass::Standard`].
+///
+/// Shares are kept per queue instance, so with several workers each one keeps to them on its
+/// own.
+#[derive(Debug)]
+pub struct SlaClasses {
+    job_types: HashMap<String, SlaClass>,
+    shares: [u32; 3],
+    /// Smooth weighted round robin credit of each class.
+    credit: Mutex<[i64; 3]>,
+}
+
+impl Default for SlaClasses {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SlaClasses {
+    /// Realtime, standard and batch get 60%, 30% and 10% of the claims.
+    pub fn new() -> Self {
+        Self {
+            job_types: HashMap::new(),
+            shares: [60, 30, 10],
+            credit: Mutex::new([0; 3]),
+        }
+    }
+
+    pub fn with_job_type(mut self, job_type: impl Into<String>, class: SlaClass) -> Self {
+        self.job_types.insert(job_type.into(), class);
+        self
+    }
+
+    /// Weight of `class` relative to the other classes' shares. A class with a share of 0 is
+    /// only claimed from when the others have nothing ready.
+    pub fn with_share(mut self, class: SlaClass, share: u32) -> Self {
+        self.shares[class.index()] = share;
+        self
+    }
+
+    pub fn class_of(&self, job_type: &str) -> SlaClass {
+        self.job_types.get(job_type).copied().unwrap_or_default()
+    }
+
+    fn total_share(&self) -> i64 {
+        self.shares.iter().map(|&share| share as i64).sum()
+    }
+
+    /// The class whose turn it is, or `None` when no class has a share. The turn only passes
+    /// on once a claim is charged in [`ClaimStrategy::claimed`].
+    fn next_class(&self) -> Option<SlaClass> {
+        if self.total_share() == 0 {
+            return None;
+        }
+        let credit = self.credit.lock().unwrap();
+        SlaClass::ALL.into_iter().max_by_key(|class| {
+            (
+                credit[class.index()] + self.shares[class.index()] as i64,
+                std::cmp::Reverse(class.index()),
+            )
+        })
+    }
+
+    fn pass(&self, class: SlaClass) -> ClaimPass {
+        let mapped = |wanted: SlaClass| {
+            self.job_types
+                .iter()
+                .filter(|(_, class)| **class == wanted)
+                .map(|(job_type, _)| job_type.as_str())
+                .collect::<Vec<_>>()
+        };
+        let filter = match class {
+            // Unmapped job types are standard, so match everything the other classes don't.
+            SlaClass::Standard => {
+                let mut others = mapped(SlaClass::Realtime);
+                others.extend(mapped(SlaClass::Batch));
+                doc! { "job_type": { "$nin": others } }
+            }
+            class => doc! { "job_type": { "$in": mapped(class) } },
+        };
+        ClaimPass {
+            filter: Some(filter),
+            sort: doc! { "priority": -1 },
+        }
+    }
+}
+
+impl ClaimStrategy for SlaClasses {
+    fn passes(&self, _candidates: &[String]) -> Vec<ClaimPass> {
+        let first = self.next_class();
+        first
+            .into_iter()
+            .chain(
+                SlaClass::ALL
+                    .into_iter()
+                    .filter(|class| Some(*class) != first),
+            )
+            .map(|class| self.pass(class))
+            .collect()
+    }
+
+    /// One step of smooth weighted round robin, charged to the class of the claimed job.
+    fn claimed(&self, job_type: &str) {
+        let total = self.total_share();
+        if total == 0 {
+            return;
+        }
+        let claimed = self.class_of(job_type);
+        let mut credit = self.credit.lock().unwrap();
+        for class in SlaClass::ALL {
+            credit[class.index()] += self.shares[class.index()] as i64;
+        }
+        credit[claimed.index()] -= total;
+        // A class with nothing ready would otherwise build up credit without bound.
+        for credit in credit.iter_mut() {
+            *credit = (*credit).clamp(-total, total);
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use aide_de_camp::core::{queue::QueueError, DateTime};
+use bson::{doc, Document};
+use tracing::{field::Empty, instrument};
+
+use crate::{job_handle::MongoDbJobHandle, ordering::ClaimPass, types::JobRow, MongoDbQueue};
+
+/// One step of a tiered claim: jobs on `queue` with a priority of at least `min_priority`,
+/// see [`MongoDbQueue::poll_tiers`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClaimTier {
+    pub queue: String,
+    pub min_priority: i8,
+}
+
+impl ClaimTier {
+    pub fn new(queue: impl Into<String>, min_priority: i8) -> Self {
+        Self {
+            queue: queue.into(),
+            min_priority,
+        }
+    }
+
+    pub(crate) fn query(&self) -> Document {
+        doc! {
+            "queue": &self.queue,
+            "priority": { "$gte": self.min_priority as i32 },
+        }
+    }
+}
+
+impl MongoDbQueue {
+    /// Like [`poll_next_with_instant`](aide_de_camp::core::queue::Queue::poll_next_with_instant),
+    /// but claims from the first of `tiers` that has a job ready, e.g. a critical queue and
+    /// then a bulk queue as spillover, instead of from this queue's own
+    /// [`queue_name`](Self::with_queue_name). Within a tier, jobs are claimed highest priority
+    /// first.
+    ///
+    /// MongoDB can't sort a `findOneAndUpdate` on which tier a job falls in, so the tiers are
+    /// tried in turn, each with a single `findOneAndUpdate`. A claim takes one round trip when
+    /// the first tier has a job ready, and one more per empty tier before the one that does.
+    /// The [`ClaimStrategy`](crate::ClaimStrategy) isn't used here.
+    #[instrument(skip_all, fields(jid = Empty, job_type = Empty, attempt = Empty))]
+    pub async fn poll_tiers(
+        &self,
+        tiers: &[ClaimTier],
+        job_types: &[&str],
+        now: DateTime,
+    ) -> Result<Option<MongoDbJobHandle>, QueueError> {
+        if tiers.is_empty() {
+            return Ok(None);
+        }
+        let result = self.claim_next(job_types, now, Some(tiers)).await;
+        self.finish_poll(result)
+    }
+
+    /// Claim the highest priority job of the first of `tiers` with one matching `filter_doc`.
+    pub(crate) async fn claim_tiered(
+        &self,
+        tiers: &[ClaimTier],
+        filter_doc: &Document,
+        update: &[Document],
+        now: DateTime,
+    ) -> Result<Option<JobRow>, QueueError> {
+        let passes: Vec<ClaimPass> = tiers
+            .iter()
+            .map(|tier| ClaimPass {
+                filter: Some(tier.query()),
+                sort: doc! { "priority": -1 },
+            })
+            .collect();
+        self.claim_first(&passes, filter_doc, update, now).await
+    }
+}
@@ -0,0 +1,240 @@
+use aide_de_camp::core::{queue::QueueError, DateTime, Duration};
+use anyhow::Context;
+use bson::{doc, Document};
+use chrono::Utc;
+use mongodb::{
+    options::{FindOneOptions, SessionOptions},
+    ClientSession,
+};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::{
+    status::{self, JobStatus},
+    trace::traced,
+    MongoDbQueue,
+};
+
+/// Job counts across the queue collections, see [`MongoDbQueue::stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueStats {
+    /// Due and waiting for a worker.
+    pub ready: u64,
+    /// Scheduled for later.
+    pub scheduled: u64,
+    /// Claimed by a worker.
+    pub running: u64,
+    /// Held back with [`MongoDbQueue::park`] or by an exhausted
+    /// [`RetryBudget`](crate::RetryBudget).
+    pub parked: u64,
+    /// In the dead queue.
+    pub dead: u64,
+    /// Retained after being cancelled, see [`CancelMode::Retain`](crate::CancelMode::Retain).
+    pub cancelled: u64,
+    /// How long the oldest ready job has been waiting, in milliseconds, see
+    /// [`MongoDbQueue::oldest_runnable_age`].
+    #[serde(default)]
+    pub oldest_ready_age_ms: Option<u64>,
+    /// Whether all counts were read from one snapshot. Without snapshot support (MongoDB
+    /// before 5.0 or a standalone server) the counts are read one after the other and may not
+    /// add up under load.
+    pub consistent: bool,
+}
+
+impl MongoDbQueue {
+    /// Count jobs in every state. Counts are read at the same point in time when the server
+    /// supports snapshot reads.
+    #[instrument(skip_all, err, ret)]
+    pub async fn stats(&self) -> Result<QueueStats, QueueError> {
+        self.stats_of(None).await
+    }
+
+    /// Like [`Self::stats`], counting only the jobs of `owner`, see
+    /// [`ScheduleOptions::owner`](crate::ScheduleOptions::owner).
+    #[instrument(skip_all, err, ret, fields(owner = owner))]
+    pub async fn owner_stats(&self, owner: &str) -> Result<QueueStats, QueueError> {
+        self.stats_of(Some(owner)).await
+    }
+
+    /// How long the oldest job of `job_types` (of any job type when empty) that is due but not
+    /// claimed has been waiting, or `None` when there is none. The age of the head of the
+    /// queue shows a backlog that workers don't keep up with sooner than the number of jobs
+    /// does. Reads the first entry of an index from [`Self::create_indexes`], so it is cheap
+    /// enough to poll for alerting.
+    #[instrument(skip_all, err, ret)]
+    pub async fn oldest_runnable_age(
+        &self,
+        job_types: &[&str],
+    ) -> Result<Option<Duration>, QueueError> {
+        let now = Utc::now();
+        let oldest = self
+            .oldest_runnable(job_types, now, None, &mut None)
+            .await?;
+        Ok(oldest.map(|scheduled_at| now - scheduled_at))
+    }
+
+    async fn stats_of(&self, owner: Option<&str>) -> Result<QueueStats, QueueError> {
+        let now = Utc::now();
+        match self.snapshot_session().await {
+            Ok(mut session) => match self.count_states(now, owner, Some(&mut session)).await {
+                Ok(stats) => return Ok(stats),
+                Err(error) => tracing::debug!(?error, "Snapshot read failed, reading without"),
+            },
+            Err(error) => tracing::debug!(?error, "Snapshot session unavailable"),
+        }
+        self.count_states(now, owner, None).await
+    }
+
+    /// A session whose reads all see the same snapshot of the data.
+    pub(crate) async fn snapshot_session(&self) -> Result<ClientSession, QueueError> {
+        let session = self
+            .collection()
+            .client()
+            .start_session(SessionOptions::builder().snapshot(true).build())
+            .await
+            .context("Failed to start snapshot session")?;
+        Ok(session)
+    }
+
+    async fn count_states(
+        &self,
+        now: DateTime,
+        owner: Option<&str>,
+        mut session: Option<&mut ClientSession>,
+    ) -> Result<QueueStats, QueueError> {
+        let now = bson::DateTime::from_chrono(now);
+        let scoped = |mut filter: Document| {
+            if let Some(owner) = owner {
+                filter.insert("owner", owner);
+            }
+            self.scoped(filter)
+        };
+        let unstarted = |scheduled_at: Document| {
+            scoped(status::pending(doc! {
+                "queue": &self.queue_name,
+                "scheduled_at": scheduled_at,
+            }))
+        };
+        let consistent = session.is_some();
+        let oldest_ready = self
+            .oldest_runnable(&[], now.to_chrono(), owner, &mut session)
+            .await?;
+        Ok(QueueStats {
+            ready: self
+                .count("adc_queue", unstarted(doc! { "$lte": now }), &mut session)
+                .await?,
+            scheduled: self
+                .count("adc_queue", unstarted(doc! { "$gt": now }), &mut session)
+                .await?,
+            running: self
+                .count(
+                    "adc_queue",
+                    scoped(doc! { "queue": &self.queue_name, "status": JobStatus::Running }),
+                    &mut session,
+                )
+                .await?,
+            parked: self
+                .count(
+                    "adc_queue",
+                    scoped(doc! { "queue": &self.queue_name, "status": JobStatus::Parked }),
+                    &mut session,
+                )
+                .await?,
+            dead: self
+                .count(
+                    "adc_dead_queue",
+                    scoped(doc! { "queue": &self.queue_name }),
+                    &mut session,
+                )
+                .await?,
+            cancelled: self
+                .count(
+                    "adc_cancelled",
+                    scoped(doc! { "queue": &self.queue_name }),
+                    &mut session,
+                )
+                .await?,
+            oldest_ready_age_ms: oldest_ready.map(|scheduled_at| {
+                (now.to_chrono() - scheduled_at).num_milliseconds().max(0) as u64
+            }),
+            consistent,
+        })
+    }
+
+    /// When the oldest job of `job_types` that is due at `now` was scheduled.
+    async fn oldest_runnable(
+        &self,
+        job_types: &[&str],
+        now: DateTime,
+        owner: Option<&str>,
+        session: &mut Option<&mut ClientSession>,
+    ) -> Result<Option<DateTime>, QueueError> {
+        let mut filter = status::pending(doc! {
+            "queue": &self.queue_name,
+            "scheduled_at": { "$lte": bson::DateTime::from_chrono(now) },
+        });
+        if !job_types.is_empty() {
+            filter.insert("job_type", doc! { "$in": job_types });
+        }
+        if let Some(owner) = owner {
+            filter.insert("owner", owner);
+        }
+        let options = FindOneOptions::builder()
+            .sort(doc! { "scheduled_at": 1 })
+            .projection(doc! { "scheduled_at": 1 })
+            .build();
+        let collection = self.database.collection::<Document>("adc_queue");
+        let filter = self.scoped(filter);
+        let oldest = match session {
+            Some(session) => {
+                traced(
+                    &collection,
+                    "find_one",
+                    collection.find_one_with_session(filter, options, session),
+                )
+                .await
+            }
+            None => {
+                traced(
+                    &collection,
+                    "find_one",
+                    collection.find_one(filter, options),
+                )
+                .await
+            }
+        }
+        .context("Failed to find the oldest ready job")?;
+        Ok(oldest
+            .and_then(|row| row.get_datetime("scheduled_at").ok().copied())
+            .map(bson::DateTime::to_chrono))
+    }
+
+    async fn count(
+        &self,
+        collection: &str,
+        filter: Document,
+        session: &mut Option<&mut ClientSession>,
+    ) -> Result<u64, QueueError> {
+        let collection = self.database.collection::<Document>(collection);
+        let count = match session {
+            Some(session) => {
+                traced(
+                    &collection,
+                    "count_documents",
+                    collection.count_documents_with_session(filter, None, session),
+                )
+                .await
+            }
+            None => {
+                traced(
+                    &collection,
+                    "count_documents",
+                    collection.count_documents(filter, None),
+                )
+                .await
+            }
+        }
+        .context("Failed to count jobs")?;
+        Ok(count)
+    }
+}
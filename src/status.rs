@@ -0,0 +1,138 @@
+use std::fmt;
+
+use aide_de_camp::core::{queue::QueueError, Xid};
+use anyhow::Context;
+use bson::{doc, Bson, Document};
+use mongodb::options::FindOneOptions;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::{
+    trace::traced,
+    types::{JobInfo, JobRow},
+    MongoDbQueue,
+};
+
+/// Where a job is in its lifecycle, stored in the `status` field of every job document.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    /// Waiting to be claimed, possibly scheduled for later or blocked by dependencies.
+    #[default]
+    Pending,
+    /// Claimed by a worker.
+    Running,
+    /// Held back until unparked, see [`MongoDbQueue::park`].
+    Parked,
+    /// Retained after being cancelled, see [`CancelMode::Retain`](crate::CancelMode::Retain).
+    Cancelled,
+    /// In the dead queue.
+    Dead,
+    /// Finished successfully. Completed jobs are deleted, so this status is only seen in
+    /// lifecycle events.
+    Completed,
+    /// Taken out of the queue by [`MongoDbQueue::drain_to_export`].
+    Exported,
+}
+
+impl JobStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Pending => "pending",
+            JobStatus::Running => "running",
+            JobStatus::Parked => "parked",
+            JobStatus::Cancelled => "cancelled",
+            JobStatus::Dead => "dead",
+            JobStatus::Completed => "completed",
+            JobStatus::Exported => "exported",
+        }
+    }
+}
+
+impl fmt::Display for JobStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<JobStatus> for Bson {
+    fn from(status: JobStatus) -> Self {
+        Bson::String(status.as_str().to_string())
+    }
+}
+
+/// Narrow `filter` to pending jobs. Until [`MongoDbQueue::backfill_status`] has run, this
+/// includes documents from older versions that have no status and were never started or
+/// parked, so they can be claimed and are counted during a rolling upgrade.
+pub(crate) fn pending(mut filter: Document) -> Document {
+    filter.insert(
+        "$or",
+        bson::bson!([
+            { "status": JobStatus::Pending },
+            {
+                "status": { "$exists": false },
+                "started_at": None::<bson::DateTime>,
+                "parked": None::<String>,
+            },
+        ]),
+    );
+    filter
+}
+
+impl MongoDbQueue {
+    /// The status of a job that is still stored somewhere: in the queue, in cold storage, in
+    /// the dead queue or among retained cancelled jobs.
+    #[instrument(skip_all, err, ret, fields(jid = %job_id))]
+    pub async fn status(&self, job_id: Xid) -> Result<JobStatus, QueueError> {
+        let jid = format!("{}", job_id);
+        let options = FindOneOptions::builder()
+            .projection(doc! { "status": 1 })
+            .build();
+        for name in [
+            "adc_queue",
+            "adc_cold_queue",
+            "adc_dead_queue",
+            "adc_cancelled",
+        ] {
+            let collection = self.database.collection::<Document>(name);
+            let row = traced(
+                &collection,
+                "find_one",
+                collection.find_one(self.scoped(doc! { "jid": &jid }), options.clone()),
+            )
+            .await
+            .context("Failed to find job")?;
+            if let Some(row) = row {
+                let status = row.get("status").cloned().unwrap_or(Bson::Null);
+                return Ok(bson::from_bson(status).context("Failed to read job status")?);
+            }
+        }
+        Err(QueueError::JobNotFound(job_id))
+    }
+
+    /// Everything but the payload of a job that is still stored somewhere, see
+    /// [`Self::status`].
+    #[instrument(skip_all, err, fields(jid = %job_id))]
+    pub async fn job_info(&self, job_id: Xid) -> Result<JobInfo, QueueError> {
+        let jid = format!("{}", job_id);
+        for name in [
+            "adc_queue",
+            "adc_cold_queue",
+            "adc_dead_queue",
+            "adc_cancelled",
+        ] {
+            let collection = self.database.collection::<JobRow>(name);
+            let row = traced(
+                &collection,
+                "find_one",
+                collection.find_one(self.scoped(doc! { "jid": &jid }), None),
+            )
+            .await
+            .context("Failed to find job")?;
+            if let Some(row) = row {
+                return Ok(row.into_info());
+            }
+        }
+        Err(QueueError::JobNotFound(job_id))
+    }
+}
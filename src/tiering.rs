@@ -0,0 +1,160 @@
+use aide_de_camp::core::{queue::QueueError, DateTime, Duration};
+use anyhow::Context;
+use bson::doc;
+use futures::TryStreamExt;
+use mongodb::{options::FindOptions, Collection};
+use tracing::instrument;
+
+use crate::{budget::OVER_BUDGET, status::JobStatus, trace::traced, types::JobRow, MongoDbQueue};
+
+/// Maximum number of jobs moved between tiers in one transaction.
+pub const TIERING_BATCH_SIZE: i64 = 500;
+
+/// When jobs move between the queue and the `adc_cold_queue` collection, see
+/// [`MongoDbQueue::tier_jobs`].
+#[derive(Debug, Clone, Copy)]
+pub struct TieringOptions {
+    /// Jobs scheduled further out than this are moved to cold storage.
+    pub cold_after: Duration,
+    /// Cold jobs are moved back this long before they are due.
+    pub promote_before: Duration,
+}
+
+impl Default for TieringOptions {
+    fn default() -> Self {
+        Self {
+            cold_after: Duration::days(7),
+            promote_before: Duration::hours(1),
+        }
+    }
+}
+
+/// Jobs moved by one [`MongoDbQueue::tier_jobs`] run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TieringReport {
+    pub demoted: u64,
+    pub promoted: u64,
+}
+
+impl MongoDbQueue {
+    /// Move far-future jobs out of the queue collection into `adc_cold_queue`, and cold jobs
+    /// that are about to be due back, so the claim index only covers jobs that matter soon.
+    /// Cold jobs can still be cancelled; they are not claimed until promoted. Jobs diverted by
+    /// a [`PendingBudget`](crate::PendingBudget) stay cold until they are unparked.
+    #[instrument(skip_all, err, ret)]
+    pub async fn tier_jobs(
+        &self,
+        options: TieringOptions,
+        now: DateTime,
+    ) -> Result<TieringReport, QueueError> {
+        let hot = self.collection();
+        let cold = self.cold_collection();
+        let promote_until = bson::DateTime::from_chrono(now + options.promote_before);
+        let demote_after = bson::DateTime::from_chrono(now + options.cold_after);
+
+        let mut report = TieringReport::default();
+        loop {
+            let promoted = move_jobs(
+                &cold,
+                &hot,
+                self.scoped(doc! {
+                    "scheduled_at": { "$lte": promote_until },
+                    "parked": { "$ne": OVER_BUDGET },
+                }),
+            )
+            .await?;
+            report.promoted += promoted;
+            if promoted < TIERING_BATCH_SIZE as u64 {
+                break;
+            }
+        }
+        loop {
+            let demoted = move_jobs(
+                &hot,
+                &cold,
+                self.scoped(doc! {
+                    "status": { "$in": [JobStatus::Pending, JobStatus::Parked] },
+                    "scheduled_at": { "$gt": demote_after },
+                }),
+            )
+            .await?;
+            report.demoted += demoted;
+            if demoted < TIERING_BATCH_SIZE as u64 {
+                break;
+            }
+        }
+        Ok(report)
+    }
+
+    pub(crate) fn cold_collection(&self) -> Collection<JobRow> {
+        self.database.collection("adc_cold_queue")
+    }
+}
+
+/// Move up to [`TIERING_BATCH_SIZE`] jobs matching `filter` in one transaction. A job claimed
+/// in the meantime makes the transaction fail instead of being moved while it runs.
+pub(crate) async fn move_jobs(
+    from: &Collection<JobRow>,
+    to: &Collection<JobRow>,
+    filter: bson::Document,
+) -> Result<u64, QueueError> {
+    let mut session = from
+        .client()
+        .start_session(None)
+        .await
+        .context("Failed to start session")?;
+    session
+        .start_transaction(None)
+        .await
+        .context("Failed to start transaction")?;
+
+    let options = FindOptions::builder()
+        .sort(doc! { "scheduled_at": 1 })
+        .limit(TIERING_BATCH_SIZE)
+        .build();
+    let rows: Vec<JobRow> = traced(
+        from,
+        "find",
+        from.find_with_session(filter.clone(), options, &mut session),
+    )
+    .await
+    .context("Failed to find jobs to move")?
+    .stream(&mut session)
+    .try_collect()
+    .await
+    .context("Failed to read jobs to move")?;
+    if rows.is_empty() {
+        return Ok(0);
+    }
+
+    let jids: Vec<&str> = rows.iter().map(|row| row.jid.as_str()).collect();
+    traced(
+        to,
+        "insert_many",
+        to.insert_many_with_session(&rows, None, &mut session),
+    )
+    .await
+    .context("Failed to copy jobs")?;
+    let mut delete_filter = filter;
+    delete_filter.insert("jid", doc! { "$in": &jids });
+    let deleted = traced(
+        from,
+        "delete_many",
+        from.delete_many_with_session(delete_filter, None, &mut session),
+    )
+    .await
+    .context("Failed to remove moved jobs")?;
+    if deleted.deleted_count != rows.len() as u64 {
+        session
+            .abort_transaction()
+            .await
+            .context("Failed to abort transaction")?;
+        return Ok(0);
+    }
+
+    session
+        .commit_transaction()
+        .await
+        .context("Failed to commit transaction")?;
+    Ok(rows.len() as u64)
+}
@@ -0,0 +1,75 @@
+use aide_de_camp::core::{queue::QueueError, Duration};
+use anyhow::Context;
+use bson::{doc, Document};
+use mongodb::{
+    error::{Error, ErrorKind},
+    options::{CreateCollectionOptions, IndexOptions, TimeseriesGranularity, TimeseriesOptions},
+    IndexModel,
+};
+
+use crate::{trace::traced, MongoDbQueue};
+
+impl MongoDbQueue {
+    /// Create `name` as a time-series collection over `time_field`, bucketed by the `meta`
+    /// field, dropping documents older than `retention`. Servers without time-series support
+    /// (before MongoDB 5.0) get a plain collection with a TTL index instead. Does nothing if
+    /// the collection exists. The server compresses time-series buckets itself, so unlike
+    /// [`Self::create_archive`] no block compressor is set.
+    pub(crate) async fn create_time_series(
+        &self,
+        name: &str,
+        time_field: &str,
+        granularity: TimeseriesGranularity,
+        retention: Duration,
+    ) -> Result<(), QueueError> {
+        let retention = retention.to_std().unwrap_or_default();
+        let timeseries = TimeseriesOptions::builder()
+            .time_field(time_field.to_string())
+            .meta_field(Some("meta".to_string()))
+            .granularity(Some(granularity))
+            .build();
+        let options = CreateCollectionOptions::builder()
+            .timeseries(timeseries)
+            .expire_after_seconds(retention)
+            .build();
+        let error = match self.database.create_collection(name, options).await {
+            Ok(()) => return Ok(()),
+            Err(error) => error,
+        };
+        match error.kind.as_ref() {
+            _ if is_namespace_exists(&error) => return Ok(()),
+            ErrorKind::Command(_) => {
+                tracing::debug!(
+                    ?error,
+                    collection = name,
+                    "Time-series collections unavailable, using a TTL index"
+                )
+            }
+            _ => {
+                return Err(anyhow::Error::new(error)
+                    .context(format!("Failed to create {name}"))
+                    .into())
+            }
+        }
+
+        let index = IndexModel::builder()
+            .keys(doc! { time_field: 1 })
+            .options(IndexOptions::builder().expire_after(retention).build())
+            .build();
+        let collection = self.database.collection::<Document>(name);
+        traced(
+            &collection,
+            "create_indexes",
+            collection.create_indexes([index], None),
+        )
+        .await
+        .with_context(|| format!("Failed to create index on {name}"))?;
+        Ok(())
+    }
+}
+
+/// Whether `error` is the server refusing to create a collection that exists.
+pub(crate) fn is_namespace_exists(error: &Error) -> bool {
+    // NamespaceExists
+    matches!(error.kind.as_ref(), ErrorKind::Command(command) if command.code == 48)
+}
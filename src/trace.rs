@@ -0,0 +1,88 @@
+//! Child spans for individual MongoDB operations.
+
+use std::{future::Future, time::Instant};
+
+use mongodb::{
+    results::{CreateIndexesResult, DeleteResult, InsertManyResult, InsertOneResult, UpdateResult},
+    Collection, Cursor, SessionCursor,
+};
+use tracing::{field::Empty, Instrument, Span};
+
+/// Run one MongoDB operation in a `mongodb` span carrying the operation and collection name,
+/// the matched/modified document counts and the round trip time in milliseconds.
+pub(crate) async fn traced<C, T, F>(
+    collection: &Collection<C>,
+    operation: &'static str,
+    future: F,
+) -> mongodb::error::Result<T>
+where
+    F: Future<Output = mongodb::error::Result<T>>,
+    T: RecordCounts,
+{
+    let span = tracing::debug_span!(
+        "mongodb",
+        operation,
+        collection = collection.name(),
+        matched = Empty,
+        modified = Empty,
+        round_trip_ms = Empty,
+    );
+    let started = Instant::now();
+    let result = future.instrument(span.clone()).await;
+    span.record("round_trip_ms", started.elapsed().as_millis() as u64);
+    if let Ok(output) = &result {
+        output.record_counts(&span);
+    }
+    result
+}
+
+/// Operation results that know how many documents they touched.
+pub(crate) trait RecordCounts {
+    fn record_counts(&self, _span: &Span) {}
+}
+
+impl RecordCounts for UpdateResult {
+    fn record_counts(&self, span: &Span) {
+        span.record("matched", self.matched_count);
+        span.record("modified", self.modified_count);
+    }
+}
+
+impl RecordCounts for DeleteResult {
+    fn record_counts(&self, span: &Span) {
+        span.record("matched", self.deleted_count);
+        span.record("modified", self.deleted_count);
+    }
+}
+
+impl RecordCounts for InsertOneResult {
+    fn record_counts(&self, span: &Span) {
+        span.record("modified", 1);
+    }
+}
+
+impl RecordCounts for InsertManyResult {
+    fn record_counts(&self, span: &Span) {
+        span.record("modified", self.inserted_ids.len());
+    }
+}
+
+/// Single document reads and find-and-modify operations.
+impl<T> RecordCounts for Option<T> {
+    fn record_counts(&self, span: &Span) {
+        span.record("matched", self.is_some() as u64);
+    }
+}
+
+/// Counts.
+impl RecordCounts for u64 {
+    fn record_counts(&self, span: &Span) {
+        span.record("matched", *self);
+    }
+}
+
+impl RecordCounts for CreateIndexesResult {}
+
+impl<T> RecordCounts for Cursor<T> {}
+
+impl<T> RecordCounts for SessionCursor<T> {}
@@ -1,7 +1,73 @@
-use bson::{Binary, DateTime};
-use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
-#[derive(Debug, Serialize, Deserialize)]
+use bson::{Binary, DateTime, Document};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{audit::AttemptOutcome, recurring::MisfirePolicy, routes::Canary};
+
+pub use crate::status::JobStatus;
+
+/// A job as shown by the listing and admin APIs, e.g. [`MongoDbQueue::job_info`]. Leaves out
+/// the payload and storage details, and serializes to plain JSON for HTTP or gRPC services.
+///
+/// [`MongoDbQueue::job_info`]: crate::MongoDbQueue::job_info
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobInfo {
+    pub jid: String,
+    /// See [`IdGenerator`](crate::IdGenerator).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_id: Option<String>,
+    pub queue: String,
+    pub job_type: String,
+    pub status: JobStatus,
+    pub priority: i8,
+    /// Failed attempts.
+    pub retries: u32,
+    /// Times the job was claimed.
+    pub attempts: u32,
+    pub scheduled_at: aide_de_camp::core::DateTime,
+    pub enqueued_at: aide_de_camp::core::DateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<aide_de_camp::core::DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    /// Team or service the job belongs to, see [`ScheduleOptions::owner`](crate::ScheduleOptions::owner).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+    /// See [`ScheduleOptions::headers`](crate::ScheduleOptions::headers).
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    /// Media type of the payload, see [`ScheduleOptions::content_type`](crate::ScheduleOptions::content_type).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// See [`ScheduleOptions::handler_version`](crate::ScheduleOptions::handler_version).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub handler_version: Option<u32>,
+    /// Why the job is parked, if it is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parked: Option<String>,
+    /// Jobs this one still waits for.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub blocked_by: Vec<String>,
+}
+
+/// A job in the dead queue, see [`MongoDbQueue::dead_jobs`](crate::MongoDbQueue::dead_jobs).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeadJobInfo {
+    #[serde(flatten)]
+    pub job: JobInfo,
+    /// Reason given when the job was dead-lettered, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct JobRow {
     pub jid: String,
     pub queue: String,
@@ -12,4 +78,279 @@ pub(crate) struct JobRow {
     pub scheduled_at: DateTime,
     pub enqueued_at: DateTime,
     pub started_at: Option<DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recurring_key: Option<String>,
+    #[serde(default)]
+    pub cancel_requested: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cancelled_at: Option<DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cancelled_by: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completion_token: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shadow_of: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deadline: Option<DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dedup_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub blocked_by: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub handler_version: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parked: Option<String>,
+    #[serde(default)]
+    pub status: JobStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dead_reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Times the job was claimed. `retries` only counts failed attempts. Documents written
+    /// before this field existed counted claims in `retries`, claiming them carries that count
+    /// over.
+    #[serde(default)]
+    pub attempts: i64,
+    /// Set by the [`IdGenerator`](crate::IdGenerator), if there is one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_id: Option<String>,
+    /// See [`MongoDbQueue::with_namespace`](crate::MongoDbQueue::with_namespace).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    /// Fields this version doesn't know, e.g. added by a newer version of the crate, so that
+    /// moving or rewriting the document keeps them. `_id` is left out, copies to other
+    /// collections get their own.
+    #[serde(flatten, deserialize_with = "unknown_fields")]
+    pub extra: Document,
+}
+
+fn unknown_fields<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Document, D::Error> {
+    let mut fields = Document::deserialize(deserializer)?;
+    fields.remove("_id");
+    Ok(fields)
+}
+
+impl JobRow {
+    pub(crate) fn into_info(self) -> JobInfo {
+        JobInfo {
+            jid: self.jid,
+            public_id: self.public_id,
+            queue: self.queue,
+            job_type: self.job_type,
+            status: self.status,
+            priority: self.priority as i8,
+            retries: self.retries as u32,
+            attempts: self.attempts as u32,
+            scheduled_at: self.scheduled_at.to_chrono(),
+            enqueued_at: self.enqueued_at.to_chrono(),
+            started_at: self.started_at.map(DateTime::to_chrono),
+            tenant: self.tenant,
+            correlation_id: self.correlation_id,
+            owner: self.owner,
+            tags: self.tags,
+            annotations: self.annotations,
+            headers: self.headers,
+            content_type: self.content_type,
+            handler_version: self.handler_version.map(|version| version as u32),
+            parked: self.parked,
+            blocked_by: self.blocked_by,
+        }
+    }
+
+    pub(crate) fn into_dead_info(mut self) -> DeadJobInfo {
+        let reason = self.dead_reason.take();
+        DeadJobInfo {
+            job: self.into_info(),
+            reason,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct RouteRow {
+    #[serde(rename = "_id")]
+    pub job_type: String,
+    pub queue: String,
+    pub priority: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub canary: Option<Canary>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct QuotaRow {
+    #[serde(rename = "_id")]
+    pub tenant: String,
+    pub max_jobs_per_hour: Option<i64>,
+    pub max_pending: Option<i64>,
+    #[serde(default)]
+    pub hour_window: Option<DateTime>,
+    #[serde(default)]
+    pub hour_count: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct RetryBudgetRow {
+    #[serde(rename = "_id")]
+    pub job_type: String,
+    pub max_retries: i64,
+    pub window_ms: i64,
+    #[serde(default)]
+    pub window_start: Option<DateTime>,
+    #[serde(default)]
+    pub window_count: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct RecurringJobRow {
+    #[serde(rename = "_id")]
+    pub key: String,
+    pub queue: String,
+    pub job_type: String,
+    pub schedule: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    pub payload: Binary,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    pub priority: i64,
+    pub misfire_policy: MisfirePolicy,
+    pub misfire_grace_ms: i64,
+    pub next_fire_at: Option<DateTime>,
+    pub last_fired_at: Option<DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct StatsSnapshotRow {
+    pub taken_at: DateTime,
+    pub meta: StatsSnapshotMeta,
+    pub ready: i64,
+    pub scheduled: i64,
+    pub running: i64,
+    pub parked: i64,
+    pub dead: i64,
+    pub cancelled: i64,
+    #[serde(default)]
+    pub oldest_ready_age_ms: Option<i64>,
+    pub consistent: bool,
+}
+
+/// The time-series meta field, snapshots are bucketed by it.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct StatsSnapshotMeta {
+    pub queue: String,
+    pub namespace: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct EventRow {
+    pub at: DateTime,
+    pub meta: EventMeta,
+    pub name: String,
+    pub jid: String,
+    pub status: JobStatus,
+    pub attempt: i64,
+    pub duration_ms: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct EventMeta {
+    pub queue: String,
+    pub job_type: String,
+    pub namespace: Option<String>,
+}
+
+/// A completed job in the archive. The payload and annotations are in `body`, see
+/// [`MongoDbQueue::with_archive`](crate::MongoDbQueue::with_archive).
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ArchivedJobRow {
+    pub jid: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_id: Option<String>,
+    pub queue: String,
+    pub job_type: String,
+    pub body: Binary,
+    pub retries: i64,
+    pub attempts: i64,
+    pub enqueued_at: DateTime,
+    pub started_at: Option<DateTime>,
+    pub completed_at: DateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+}
+
+/// All attempts at one job, see [`MongoDbQueue::with_audit_trail`](crate::MongoDbQueue::with_audit_trail).
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct AuditRow {
+    #[serde(rename = "_id")]
+    pub jid: String,
+    pub job_type: String,
+    pub queue: String,
+    pub scheduled_at: DateTime,
+    pub namespace: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    pub outcome: AttemptOutcome,
+    pub attempts: Vec<AuditAttemptRow>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct AuditAttemptRow {
+    pub attempt: i64,
+    pub claimed_by: String,
+    pub started_at: Option<DateTime>,
+    pub finished_at: DateTime,
+    pub outcome: AttemptOutcome,
+    pub error: Option<String>,
+}
+
+/// See [`MongoDbQueue::add_pause_window`](crate::MongoDbQueue::add_pause_window).
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct PauseWindowRow {
+    pub queue: String,
+    pub name: String,
+    pub starts: String,
+    pub duration_ms: i64,
+    pub timezone: Option<String>,
+    pub namespace: Option<String>,
+}
+
+/// See [`MongoDbQueue::add_monitor`](crate::MongoDbQueue::add_monitor).
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct MonitorRow {
+    pub name: String,
+    pub deadline: String,
+    pub window_ms: i64,
+    pub timezone: Option<String>,
+    pub namespace: Option<String>,
+    pub last_check_in: Option<DateTime>,
+    /// The last deadline that was checked, or when the monitor was added.
+    pub checked_until: DateTime,
 }
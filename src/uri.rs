@@ -0,0 +1,115 @@
+use std::fmt;
+
+use mongodb::error::{Error, ErrorKind};
+
+/// Query options whose values are secrets.
+const SECRET_OPTIONS: [&str; 2] = ["authmechanismproperties", "tlscertificatekeyfilepassword"];
+
+const REDACTED: &str = "****";
+
+/// A MongoDB connection string that keeps its secrets out of logs. `Display` and `Debug` show
+/// it with the password and secret options replaced by `****`, so it can go into spans and
+/// error messages as is.
+///
+/// Every constructor takes one, converted from a `&str` or `String`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SafeUri(String);
+
+impl SafeUri {
+    pub fn new(uri: impl Into<String>) -> Self {
+        Self(uri.into())
+    }
+
+    /// The connection string with its secrets, to hand to the driver.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// The user info and secret options of the connection string as they appear in it, each
+    /// with its redacted form. Only these are replaced, so a password that happens to occur
+    /// elsewhere, e.g. in a host name, is left alone.
+    fn redactions(&self) -> Vec<(String, String)> {
+        let (authority, query) = self.split();
+        let user_info = authority
+            .rsplit_once('@')
+            .and_then(|(user_info, _)| user_info.split_once(':'))
+            .filter(|(_, password)| !password.is_empty())
+            .map(|(user, password)| (format!("{user}:{password}@"), format!("{user}:{REDACTED}@")));
+        let options = query.into_iter().flat_map(|query| query.split('&'));
+        user_info
+            .into_iter()
+            .chain(options.filter_map(|option| {
+                let (key, value) = option.split_once('=')?;
+                (SECRET_OPTIONS.contains(&key.to_ascii_lowercase().as_str()) && !value.is_empty())
+                    .then(|| (option.to_string(), format!("{key}={REDACTED}")))
+            }))
+            .collect()
+    }
+
+    /// The part after the scheme up to the path, and the query string.
+    fn split(&self) -> (&str, Option<&str>) {
+        let rest = self.0.split_once("://").map_or(&*self.0, |(_, rest)| rest);
+        let (rest, query) = match rest.split_once('?') {
+            Some((rest, query)) => (rest, Some(query)),
+            None => (rest, None),
+        };
+        let authority = rest
+            .split_once('/')
+            .map_or(rest, |(authority, _)| authority);
+        (authority, query)
+    }
+
+    /// `text` with the password in the user info and the secret options of the connection
+    /// string replaced wherever they appear.
+    pub(crate) fn scrub(&self, text: &str) -> String {
+        self.redactions()
+            .into_iter()
+            .fold(text.to_string(), |text, (secret, redacted)| {
+                text.replace(&secret, &redacted)
+            })
+    }
+
+    /// `error` with the secrets scrubbed from its message, for errors raised while parsing
+    /// the connection string or connecting with it.
+    pub(crate) fn scrub_error(&self, mut error: Error) -> Error {
+        match error.kind.as_mut() {
+            ErrorKind::InvalidArgument { message, .. }
+            | ErrorKind::Authentication { message, .. }
+            | ErrorKind::DnsResolve { message, .. }
+            | ErrorKind::InvalidTlsConfig { message, .. }
+            | ErrorKind::Internal { message, .. } => *message = self.scrub(message),
+            _ => {}
+        }
+        error
+    }
+}
+
+impl fmt::Display for SafeUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.scrub(&self.0))
+    }
+}
+
+impl fmt::Debug for SafeUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SafeUri").field(&self.to_string()).finish()
+    }
+}
+
+impl From<&str> for SafeUri {
+    fn from(uri: &str) -> Self {
+        Self::new(uri)
+    }
+}
+
+impl From<&String> for SafeUri {
+    fn from(uri: &String) -> Self {
+        Self::new(uri.as_str())
+    }
+}
+
+impl From<String> for SafeUri {
+    fn from(uri: String) -> Self {
+        Self(uri)
+    }
+}
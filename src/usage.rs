@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+
+use aide_de_camp::core::{queue::QueueError, Duration};
+use anyhow::Context;
+use bson::{doc, Document};
+use chrono::{NaiveDate, TimeZone, Utc};
+use futures::TryStreamExt;
+use mongodb::options::UpdateOptions;
+use serde::Deserialize;
+use tracing::instrument;
+
+use crate::{error::MongoDbQueueError, trace::traced, types::JobRow, MongoDbQueue};
+
+/// Resources used by one job type over a month, see [`MongoDbQueue::monthly_usage`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JobTypeUsage {
+    pub job_type: String,
+    /// Completed jobs.
+    pub executions: u64,
+    /// Time from claim to completion, summed over all executions.
+    pub total_duration: Duration,
+    /// Encoded payload size, summed over all executions.
+    pub payload_bytes: u64,
+}
+
+#[derive(Deserialize)]
+struct UsageRollup {
+    #[serde(rename = "_id")]
+    job_type: String,
+    executions: i64,
+    duration_ms: i64,
+    payload_bytes: i64,
+}
+
+impl MongoDbQueue {
+    /// Usage per job type for the given calendar month (UTC), for chargeback of shared worker
+    /// fleets. Usage is recorded in daily buckets when jobs complete.
+    #[instrument(skip_all, err, fields(year = year, month = month))]
+    pub async fn monthly_usage(
+        &self,
+        year: i32,
+        month: u32,
+    ) -> Result<Vec<JobTypeUsage>, QueueError> {
+        let invalid = || MongoDbQueueError::InvalidMonth { year, month };
+        let start = NaiveDate::from_ymd_opt(year, month, 1).ok_or_else(invalid)?;
+        let end = match month {
+            12 => NaiveDate::from_ymd_opt(year + 1, 1, 1),
+            _ => NaiveDate::from_ymd_opt(year, month + 1, 1),
+        }
+        .ok_or_else(invalid)?;
+        let to_bson = |date: NaiveDate| {
+            bson::DateTime::from_chrono(Utc.from_utc_datetime(&date.and_time(Default::default())))
+        };
+
+        let pipeline = vec![
+            doc! { "$match": self.scoped(doc! { "day": { "$gte": to_bson(start), "$lt": to_bson(end) } }) },
+            doc! { "$group": {
+                "_id": "$job_type",
+                "executions": { "$sum": "$executions" },
+                "duration_ms": { "$sum": "$duration_ms" },
+                "payload_bytes": { "$sum": "$payload_bytes" },
+            } },
+            doc! { "$sort": { "_id": 1 } },
+        ];
+        let collection = self.database.collection::<Document>("adc_usage");
+        let rows: Vec<Document> = traced(
+            &collection,
+            "aggregate",
+            collection.aggregate(pipeline, None),
+        )
+        .await
+        .context("Failed to aggregate usage")?
+        .try_collect()
+        .await
+        .context("Failed to read usage")?;
+
+        rows.into_iter()
+            .map(|row| {
+                let row: UsageRollup =
+                    bson::from_document(row).context("Failed to decode usage")?;
+                Ok(JobTypeUsage {
+                    job_type: row.job_type,
+                    executions: row.executions as u64,
+                    total_duration: Duration::milliseconds(row.duration_ms),
+                    payload_bytes: row.payload_bytes as u64,
+                })
+            })
+            .collect()
+    }
+
+    /// Add a completed job to today's usage bucket for its job type.
+    pub(crate) async fn record_usage(&self, row: &JobRow) -> Result<(), QueueError> {
+        self.record_usage_many(&[row]).await
+    }
+
+    /// Add completed jobs to today's usage buckets, one update per job type.
+    pub(crate) async fn record_usage_many(&self, rows: &[&JobRow]) -> Result<(), QueueError> {
+        let now = Utc::now();
+        let day = Utc.from_utc_datetime(&now.date_naive().and_time(Default::default()));
+        let mut totals: BTreeMap<&str, (i64, i64, i64)> = BTreeMap::new();
+        for row in rows {
+            let duration_ms = row
+                .started_at
+                .map(|started_at| (now - started_at.to_chrono()).num_milliseconds().max(0))
+                .unwrap_or(0);
+            let total = totals.entry(&row.job_type).or_default();
+            total.0 += 1;
+            total.1 += duration_ms;
+            total.2 += row.payload.bytes.len() as i64;
+        }
+
+        let collection = self.database.collection::<Document>("adc_usage");
+        for (job_type, (executions, duration_ms, payload_bytes)) in totals {
+            traced(
+                &collection,
+                "update_one",
+                collection.update_one(
+                    self.scoped(
+                        doc! { "job_type": job_type, "day": bson::DateTime::from_chrono(day) },
+                    ),
+                    doc! { "$inc": {
+                        "executions": executions,
+                        "duration_ms": duration_ms,
+                        "payload_bytes": payload_bytes,
+                    } },
+                    UpdateOptions::builder().upsert(true).build(),
+                ),
+            )
+            .await
+            .context("Failed to record usage")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,45 @@
+use aide_de_camp::core::job_processor::JobProcessor;
+use bson::{doc, Bson, Document};
+
+use crate::MongoDbQueue;
+
+impl MongoDbQueue {
+    /// Only claim jobs of type `J` scheduled for one of `versions` of its handler, see
+    /// [`ScheduleOptions::handler_version`](crate::ScheduleOptions::handler_version), so a
+    /// change to the payload can be rolled out by running old and new workers side by side.
+    /// Jobs scheduled without a version are claimed by every worker, and job types without
+    /// advertised versions claim jobs of every version.
+    pub fn with_handler_versions<J>(mut self, versions: impl IntoIterator<Item = u32>) -> Self
+    where
+        J: JobProcessor + 'static,
+    {
+        let versions = versions.into_iter().collect();
+        self.handler_versions
+            .retain(|(job_type, _)| job_type.as_str() != J::name());
+        self.handler_versions
+            .push((J::name().to_string(), versions));
+        self
+    }
+
+    /// Claim query condition for jobs whose version the worker serves.
+    pub(crate) fn handler_versions_query(&self) -> Option<Document> {
+        if self.handler_versions.is_empty() {
+            return None;
+        }
+        let versioned: Vec<&str> = self
+            .handler_versions
+            .iter()
+            .map(|(job_type, _)| job_type.as_str())
+            .collect();
+        let mut allowed = vec![doc! { "job_type": { "$nin": versioned } }];
+        for (job_type, versions) in &self.handler_versions {
+            let mut served: Vec<Bson> = versions
+                .iter()
+                .map(|version| Bson::Int64(*version as i64))
+                .collect();
+            served.push(Bson::Null);
+            allowed.push(doc! { "job_type": job_type, "handler_version": { "$in": served } });
+        }
+        Some(doc! { "$or": allowed })
+    }
+}